@@ -0,0 +1,257 @@
+//! Throughput of the exposure pipeline: fetching, decrypting and writing out
+//! secrets. Run with `cargo bench --features testing`.
+
+use std::collections::HashMap;
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use age::secrecy::ExposeSecret;
+use age::x25519;
+use async_trait::async_trait;
+use credible::hooks::NoHooks;
+use credible::testing::{
+    decrypt_bytes,
+    encrypt_bytes,
+    expose_files,
+    FileExposeArgs,
+    ObjectMetadata,
+};
+use credible::util::BoxedAsyncReader;
+use credible::{ExposureSpec, Secret, SecretError, SecretStorage, DEFAULT_LARGE_SECRET_THRESHOLD};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::future::try_join_all;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::runtime::Runtime;
+
+/// Roughly how many secrets a host mounts.
+const SECRETS_PER_HOST: usize = 200;
+
+#[derive(thiserror::Error, Debug)]
+enum MockStorageError {
+    #[error("no object at {0}")]
+    NotFound(PathBuf),
+    #[error("{0}")]
+    Io(std::io::Error),
+}
+
+impl SecretError for MockStorageError {
+    fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound(_))
+    }
+}
+
+/// Benchmarks only read, so there's nothing to change.
+fn read_only() -> MockStorageError {
+    MockStorageError::Io(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "benchmark storage is read-only",
+    ))
+}
+
+/// In-memory stand-in for S3, with a fixed per-request latency.
+struct MockStorage {
+    objects: HashMap<PathBuf, Vec<u8>>,
+    latency: Duration,
+}
+
+#[async_trait]
+impl SecretStorage for MockStorage {
+    type Error = MockStorageError;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        let data = self
+            .objects
+            .get(p)
+            .ok_or_else(|| MockStorageError::NotFound(p.to_owned()))?;
+        Ok(BoxedAsyncReader::from_async_read(Cursor::new(data.clone())))
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        let data = self
+            .objects
+            .get(p)
+            .ok_or_else(|| MockStorageError::NotFound(p.to_owned()))?;
+        Ok(ObjectMetadata {
+            size: data.len() as u64,
+            last_modified: None,
+        })
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        _p: &Path,
+        _new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        Err(read_only())
+    }
+
+    async fn delete(&self, _p: &Path) -> Result<(), Self::Error> {
+        Err(read_only())
+    }
+}
+
+struct Keys {
+    identity: x25519::Identity,
+    public_keys: Vec<String>,
+}
+
+impl Keys {
+    fn generate() -> Self {
+        let identity = x25519::Identity::generate();
+        let public_keys = vec![identity.to_public().to_string()];
+        Self {
+            identity,
+            public_keys,
+        }
+    }
+
+    fn identities(&self) -> Vec<Box<dyn age::Identity>> {
+        let identity = self
+            .identity
+            .to_string()
+            .expose_secret()
+            .parse::<x25519::Identity>();
+        vec![Box::new(identity.unwrap())]
+    }
+
+    async fn encrypt(&self, plaintext: Vec<u8>) -> Vec<u8> {
+        encrypt_bytes(Cursor::new(plaintext), &self.public_keys)
+            .await
+            .unwrap()
+    }
+}
+
+async fn decrypt(ciphertext: Vec<u8>, identities: &[Box<dyn age::Identity>]) -> usize {
+    let mut plaintext = Vec::new();
+    decrypt_bytes(Cursor::new(ciphertext), identities)
+        .await
+        .unwrap()
+        .read_to_end(&mut plaintext)
+        .await
+        .unwrap()
+}
+
+/// Decrypting a host's worth of small secrets, one after another and all at
+/// once.
+fn decrypt_many(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let keys = Keys::generate();
+    let identities = keys.identities();
+    let ciphertexts = rt.block_on(async {
+        let mut ciphertexts = Vec::new();
+        for i in 0..SECRETS_PER_HOST {
+            ciphertexts.push(keys.encrypt(format!("secret-value-{i}").into_bytes()).await);
+        }
+        ciphertexts
+    });
+
+    let mut group = c.benchmark_group("decrypt_many");
+    group.throughput(Throughput::Elements(SECRETS_PER_HOST as u64));
+    group.bench_function("sequential", |b| {
+        b.to_async(&rt).iter(|| async {
+            for ciphertext in &ciphertexts {
+                decrypt(ciphertext.clone(), &identities).await;
+            }
+        })
+    });
+    group.bench_function("concurrent", |b| {
+        b.to_async(&rt).iter(|| async {
+            let decryptions = ciphertexts.iter().map(|ciphertext| async {
+                Ok::<_, ()>(decrypt(ciphertext.clone(), &identities).await)
+            });
+            try_join_all(decryptions).await.unwrap();
+        })
+    });
+    group.finish();
+}
+
+/// Streaming single large secrets through decryption.
+fn decrypt_large(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let keys = Keys::generate();
+    let identities = keys.identities();
+
+    let mut group = c.benchmark_group("decrypt_large");
+    group.sample_size(10);
+    for size in [1 << 20, 16 << 20, 64 << 20] {
+        let ciphertext = rt.block_on(keys.encrypt(vec![0x5a; size]));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(size),
+            &ciphertext,
+            |b, ciphertext| {
+                b.to_async(&rt)
+                    .iter(|| decrypt(ciphertext.clone(), &identities))
+            },
+        );
+    }
+    group.finish();
+}
+
+/// The whole file exposure path (fetch, verify, decrypt, write) for a host's
+/// worth of secrets, against storage with varying request latency.
+fn expose(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let keys = Keys::generate();
+    let identities = keys.identities();
+    let secret_dir = tempfile::tempdir().unwrap();
+    // Vanity paths are kept out of the working directory too
+    let vanity_dir = tempfile::tempdir().unwrap();
+
+    let mut objects = HashMap::new();
+    let mut secrets = Vec::new();
+    let mut specs = Vec::new();
+    for i in 0..SECRETS_PER_HOST {
+        let name = format!("secret-{i}");
+        let secret: Secret = serde_yaml::from_str(&format!(
+            "{{name: {name}, path: {name}.age, encryption_keys: []}}"
+        ))
+        .unwrap();
+        let ciphertext = rt.block_on(keys.encrypt(format!("value-{i}").into_bytes()));
+        objects.insert(secret.path.clone(), ciphertext);
+        let vanity_path = vanity_dir.path().join(&name);
+        let spec = match ExposureSpec::file_from_str(name.clone(), &vanity_path.to_string_lossy()) {
+            ExposureSpec::File(args) => *args,
+            _ => unreachable!(),
+        };
+        secrets.push(secret);
+        specs.push(vec![spec]);
+    }
+    let exposures = secrets
+        .iter()
+        .zip(specs.iter())
+        .collect::<Vec<(&Secret, &Vec<FileExposeArgs>)>>();
+
+    let mut group = c.benchmark_group("expose_files");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SECRETS_PER_HOST as u64));
+    for latency_ms in [0, 1, 5] {
+        let storage = MockStorage {
+            objects: objects.clone(),
+            latency: Duration::from_millis(latency_ms),
+        };
+        let id = BenchmarkId::new("latency_ms", latency_ms);
+        group.bench_with_input(id, &storage, |b, storage| {
+            b.to_async(&rt).iter(|| async {
+                expose_files(
+                    secret_dir.path(),
+                    storage,
+                    &exposures,
+                    &identities,
+                    &NoHooks,
+                    DEFAULT_LARGE_SECRET_THRESHOLD,
+                )
+                .await
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decrypt_many, decrypt_large, expose);
+criterion_main!(benches);
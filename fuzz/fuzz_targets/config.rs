@@ -0,0 +1,8 @@
+#![no_main]
+
+use credible::testing::parse_config;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_config(data);
+});
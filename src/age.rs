@@ -1,16 +1,25 @@
 use std::path::Path;
 
+use age::armor::{ArmoredWriter, Format};
 use age::cli_common::read_identities;
 use age::{Decryptor, Encryptor, Identity, Recipient};
-use tokio::io::{AsyncRead, AsyncWriteExt};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_util::compat::{
     FuturesAsyncReadCompatExt,
     FuturesAsyncWriteCompatExt,
     TokioAsyncReadCompatExt,
+    TokioAsyncWriteCompatExt,
 };
 
 use crate::util::BoxedAsyncReader;
 
+mod sealed;
+pub use sealed::{keychain_service, seal_tpm2, store_in_keychain};
+use sealed::{parse_identities, SealedIdentity};
+
 #[derive(thiserror::Error, Debug)]
 pub enum EncryptionError {
     #[error("error creating data pipe: {0}")]
@@ -47,17 +56,207 @@ pub enum DecryptionError {
     PassphraseEncryptedFile,
     #[error("writing secret to file: {0}")]
     WritingSecret(std::io::Error),
+    #[error("error reading identity file: {0}")]
+    ReadingIdentityFile(std::io::Error),
+    #[error("error unsealing identity: {0}")]
+    UnsealingIdentity(String),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HeaderError {
+    #[error("not an age-encrypted file")]
+    NotAgeFile,
+    #[error("not ASCII-armored")]
+    NotArmored,
+    #[error("error decoding armored data: {0}")]
+    DecodingArmor(base64::DecodeError),
+    #[error("header is truncated")]
+    Truncated,
+    #[error("error reading ciphertext: {0}")]
+    Reading(std::io::Error),
+}
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----";
+
+/// A recipient stanza from an age header (e.g. `-> X25519 <share>`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderStanza {
+    pub tag: String,
+    pub args: Vec<String>,
+}
+
+fn dearmor(data: &[u8]) -> Result<Vec<u8>, HeaderError> {
+    let text = String::from_utf8_lossy(data);
+    let body = text
+        .lines()
+        .map(str::trim)
+        .skip_while(|l| *l != ARMOR_BEGIN)
+        .skip(1)
+        .take_while(|l| *l != ARMOR_END)
+        .collect::<String>();
+
+    STANDARD.decode(body).map_err(HeaderError::DecodingArmor)
+}
+
+/// Decodes ASCII-armored ciphertext (like an inline value in config), checking
+/// that it's an age-encrypted file.
+pub fn dearmor_ciphertext(armored: &str) -> Result<Vec<u8>, HeaderError> {
+    if !armored.trim_start().starts_with(ARMOR_BEGIN) {
+        return Err(HeaderError::NotArmored);
+    }
+    let data = dearmor(armored.as_bytes())?;
+    read_header_stanzas(&data)?;
+
+    Ok(data)
+}
+
+/// Reads the recipient stanzas from the header of an age-encrypted file
+/// (armored or not), without decrypting anything.
+pub fn read_header_stanzas(data: &[u8]) -> Result<Vec<HeaderStanza>, HeaderError> {
+    let dearmored;
+    let data = match data.starts_with(ARMOR_BEGIN.as_bytes()) {
+        true => {
+            dearmored = dearmor(data)?;
+            &dearmored[..]
+        }
+        false => data,
+    };
+
+    let mut lines = data.split(|b| *b == b'\n');
+    if lines.next() != Some(AGE_MAGIC) {
+        return Err(HeaderError::NotAgeFile);
+    }
+
+    let mut stanzas = Vec::new();
+    for line in lines {
+        // The header ends with its MAC, everything after is payload
+        if line.starts_with(b"---") {
+            return Ok(stanzas);
+        }
+
+        // Anything else is the body of the previous stanza (a wrapped file
+        // key), which tells us nothing about the recipient
+        if let Some(stanza) = line.strip_prefix(b"-> ") {
+            let stanza = String::from_utf8_lossy(stanza);
+            let mut fields = stanza.split(' ').map(str::to_string);
+            let tag = fields.next().unwrap_or_default();
+            // age adds random "grease" stanzas to keep parsers lenient, which
+            // don't belong to any recipient
+            if tag.ends_with("-grease") {
+                continue;
+            }
+            let args = fields.collect();
+            stanzas.push(HeaderStanza { tag, args });
+        }
+    }
+
+    Err(HeaderError::Truncated)
+}
+
+/// The header of an age-encrypted file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub stanzas: Vec<HeaderStanza>,
+    pub armored: bool,
+    /// Bytes of ciphertext read to find the end of the header
+    pub bytes_read: usize,
+}
+
+const HEADER_READ_CHUNK: usize = 4096;
+
+/// Reads only as much of an age-encrypted stream as is needed to parse its
+/// header, so that large ciphertexts needn't be downloaded in full.
+pub async fn read_header<R: AsyncRead + Unpin>(mut r: R) -> Result<Header, HeaderError> {
+    let mut data = Vec::new();
+    let mut chunk = vec![0; HEADER_READ_CHUNK];
+    loop {
+        let n = r.read(&mut chunk).await.map_err(HeaderError::Reading)?;
+        data.extend_from_slice(&chunk[..n]);
+
+        match read_header_stanzas(&data) {
+            Ok(stanzas) => {
+                return Ok(Header {
+                    stanzas,
+                    armored: data.starts_with(ARMOR_BEGIN.as_bytes()),
+                    bytes_read: data.len(),
+                })
+            }
+            // Armored data may be cut off mid-line, which won't decode until
+            // we have the rest of it
+            Err(HeaderError::Truncated | HeaderError::DecodingArmor(_)) if n > 0 => continue,
+            // Too short to tell if it's an age file yet
+            Err(HeaderError::NotAgeFile) if n > 0 && data.len() < ARMOR_BEGIN.len() => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Computes the tag age uses to identify an SSH public key in the stanzas it
+/// writes for it, or None if the key isn't a valid SSH public key.
+pub fn ssh_recipient_tag(key: &str) -> Option<String> {
+    let key = ssh_key::PublicKey::from_openssh(key).ok()?;
+    let digest = Sha256::digest(key.to_bytes().ok()?);
+    Some(STANDARD_NO_PAD.encode(&digest[..4]))
 }
 
 fn path_to_string<P: AsRef<Path>>(path: P) -> String {
     path.as_ref().to_str().unwrap().to_string()
 }
 
+/// Reads identities from the given files. Sealed identities (e.g.
+/// `tpm2:/path/to/key`) are unsealed first.
 pub fn get_identities<P: AsRef<Path>>(
     paths: &[P],
 ) -> Result<Vec<Box<dyn Identity>>, DecryptionError> {
-    let path_strings = paths.iter().map(path_to_string).collect::<Vec<_>>();
-    read_identities(path_strings, None).map_err(DecryptionError::ReadingSecretKey)
+    let mut identities = Vec::new();
+    let mut files = Vec::new();
+    for path in paths.iter().map(path_to_string) {
+        match SealedIdentity::parse(&path) {
+            Some(sealed) => identities.extend(parse_identities(&sealed.unseal()?, &path)?),
+            None => files.push(path),
+        }
+    }
+
+    identities.extend(read_identities(files, None).map_err(DecryptionError::ReadingSecretKey)?);
+
+    Ok(identities)
+}
+
+/// Whether there's an identity at the given location, which may be sealed.
+pub fn identity_exists(path: &Path) -> bool {
+    match SealedIdentity::parse(&path_to_string(path)) {
+        Some(sealed) => sealed.exists(),
+        None => path.exists(),
+    }
+}
+
+/// Derives the public keys (recipients) for the identities in the given file.
+pub fn identity_public_keys(path: &Path) -> Result<Vec<String>, DecryptionError> {
+    if let Some(sealed) = SealedIdentity::parse(&path_to_string(path)) {
+        return Ok(public_keys_of(&sealed.unseal()?));
+    }
+
+    let contents = std::fs::read_to_string(path).map_err(DecryptionError::ReadingIdentityFile)?;
+
+    Ok(public_keys_of(&contents))
+}
+
+fn public_keys_of(contents: &str) -> Vec<String> {
+    // Public keys are stored unencrypted in OpenSSH private keys, so this
+    // works without a passphrase
+    if let Ok(key) = ssh_key::PrivateKey::from_openssh(contents) {
+        if let Ok(public_key) = key.public_key().to_openssh() {
+            return vec![public_key];
+        }
+    }
+
+    contents
+        .lines()
+        .filter_map(|line| line.trim().parse::<age::x25519::Identity>().ok())
+        .map(|identity| identity.to_public().to_string())
+        .collect()
 }
 
 pub async fn decrypt_bytes<R>(
@@ -84,12 +283,62 @@ where
     Ok(BoxedAsyncReader::from_async_read(reader))
 }
 
-pub async fn encrypt_bytes<R>(
-    mut reader: R,
+pub async fn encrypt_bytes<R>(reader: R, public_keys: &[String]) -> Result<Vec<u8>, EncryptionError>
+where
+    R: AsyncRead + Send + Unpin + Send + 'static,
+{
+    encrypt_bytes_as(reader, public_keys, Format::Binary).await
+}
+
+/// Like [encrypt_bytes], but ASCII-armored, so that the ciphertext can be
+/// pasted into config.
+pub async fn encrypt_bytes_armored<R>(
+    reader: R,
+    public_keys: &[String],
+) -> Result<Vec<u8>, EncryptionError>
+where
+    R: AsyncRead + Send + Unpin + Send + 'static,
+{
+    encrypt_bytes_as(reader, public_keys, Format::AsciiArmor).await
+}
+
+async fn encrypt_bytes_as<R>(
+    reader: R,
     public_keys: &[String],
+    format: Format,
 ) -> Result<Vec<u8>, EncryptionError>
 where
     R: AsyncRead + Send + Unpin + Send + 'static,
+{
+    let mut encrypted = Vec::new();
+    encrypt_as(reader, public_keys, format, &mut encrypted).await?;
+
+    Ok(encrypted)
+}
+
+/// Encrypts everything from the reader into the writer as it's read, so that
+/// neither plaintext nor ciphertext is ever held in memory in full.
+pub async fn encrypt<R, W>(
+    reader: R,
+    public_keys: &[String],
+    writer: W,
+) -> Result<(), EncryptionError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    encrypt_as(reader, public_keys, Format::Binary, writer).await
+}
+
+async fn encrypt_as<R, W>(
+    mut reader: R,
+    public_keys: &[String],
+    format: Format,
+    writer: W,
+) -> Result<(), EncryptionError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
 {
     let recipients = public_keys
         .iter()
@@ -99,10 +348,10 @@ where
         return Err(EncryptionError::NoRecipientsFound);
     }
 
-    let mut encrypted = Vec::new();
+    let output = ArmoredWriter::wrap_async_output(writer.compat_write(), format);
     let mut encrypted_writer = Encryptor::with_recipients(recipients)
         .ok_or(EncryptionError::NoRecipientsFound)?
-        .wrap_async_output(&mut encrypted)
+        .wrap_async_output(output)
         .await
         .map_err(EncryptionError::CreatingStream)?
         .compat_write();
@@ -114,14 +363,12 @@ where
     encrypted_writer
         .shutdown()
         .await
-        .map_err(EncryptionError::ClosingOutput)?;
-
-    Ok(encrypted)
+        .map_err(EncryptionError::ClosingOutput)
 }
 
 // [Adapted from str4d/rage (ASL-2.0)](
 // https://github.com/str4d/rage/blob/85c0788dc511f1410b4c1811be6b8904d91f85db/rage/src/bin/rage/main.rs)
-fn parse_recipient(s: &str) -> Result<Box<dyn Recipient + Send>, EncryptionError> {
+pub fn parse_recipient(s: &str) -> Result<Box<dyn Recipient + Send>, EncryptionError> {
     if let Ok(pk) = s.parse::<age::x25519::Recipient>() {
         Ok(Box::new(pk))
     } else if let Ok(pk) = s.parse::<age::ssh::Recipient>() {
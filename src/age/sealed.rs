@@ -0,0 +1,216 @@
+//! Identities that aren't kept in a plaintext file, referred to as
+//! `<scheme>:<location>` wherever an identity file path is expected.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use age::cli_common::UiCallbacks;
+use age::Identity;
+
+use super::DecryptionError;
+use crate::process_utils::process_msg;
+
+const TPM2_PREFIX: &str = "tpm2:";
+const KEYCHAIN_PREFIX: &str = "keychain:";
+
+/// Account Keychain items are stored under (the service is named by the
+/// user).
+#[cfg(target_os = "macos")]
+const KEYCHAIN_ACCOUNT: &str = "credible";
+
+/// Name sealed credentials are bound to, which has to match when unsealing.
+const CREDENTIAL_NAME: &str = "credible";
+
+/// An identity that has to be unsealed before it can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SealedIdentity<'a> {
+    /// Identity file encrypted with a key held by this machine's TPM (using
+    /// `systemd-creds`), so that it's useless on any other machine.
+    Tpm2(&'a Path),
+    /// Identity file stored as a generic password in the macOS Keychain,
+    /// under the given service name. macOS asks before releasing it (with
+    /// Touch ID, where configured).
+    Keychain(&'a str),
+}
+
+impl<'a> SealedIdentity<'a> {
+    /// Parses an identity location, if it refers to a sealed identity.
+    pub(crate) fn parse(location: &'a str) -> Option<Self> {
+        if let Some(path) = location.strip_prefix(TPM2_PREFIX) {
+            return Some(Self::Tpm2(Path::new(path)));
+        }
+
+        location.strip_prefix(KEYCHAIN_PREFIX).map(Self::Keychain)
+    }
+
+    /// Whether the sealed identity is there to be unsealed.
+    pub(crate) fn exists(&self) -> bool {
+        match self {
+            Self::Tpm2(path) => path.exists(),
+            // Checking would prompt the user, so find out when unsealing
+            Self::Keychain(_) => true,
+        }
+    }
+
+    /// The unsealed identity file contents.
+    pub(crate) fn unseal(&self) -> Result<String, DecryptionError> {
+        match self {
+            Self::Tpm2(path) => {
+                log::debug!("unsealing {} with the TPM", path.to_string_lossy());
+                let output = Command::new("systemd-creds")
+                    .arg("decrypt")
+                    .arg(format!("--name={CREDENTIAL_NAME}"))
+                    .arg(path)
+                    .arg("-")
+                    .output()
+                    .map_err(|e| DecryptionError::UnsealingIdentity(e.to_string()))?;
+                if !output.status.success() {
+                    let msg = process_msg("systemd-creds", output.stderr);
+                    return Err(DecryptionError::UnsealingIdentity(msg));
+                }
+
+                String::from_utf8(output.stdout).map_err(|_| {
+                    DecryptionError::UnsealingIdentity("identity isn't valid UTF-8".into())
+                })
+            }
+            Self::Keychain(service) => read_keychain(service),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn read_keychain(service: &str) -> Result<String, DecryptionError> {
+    log::debug!("reading {service} from the Keychain");
+    let output = Command::new("security")
+        .arg("find-generic-password")
+        .arg("-s")
+        .arg(service)
+        .arg("-a")
+        .arg(KEYCHAIN_ACCOUNT)
+        .arg("-w")
+        .output()
+        .map_err(|e| DecryptionError::UnsealingIdentity(e.to_string()))?;
+    if !output.status.success() {
+        let msg = process_msg("security", output.stderr);
+        return Err(DecryptionError::UnsealingIdentity(msg));
+    }
+
+    // Multi-line passwords come back hex-encoded
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let contents = match decode_hex(&stdout) {
+        Some(bytes) => String::from_utf8(bytes).unwrap_or(stdout),
+        None => stdout,
+    };
+
+    Ok(contents)
+}
+
+#[cfg(target_os = "macos")]
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn read_keychain(_service: &str) -> Result<String, DecryptionError> {
+    Err(DecryptionError::UnsealingIdentity(
+        "the Keychain is only available on macOS".into(),
+    ))
+}
+
+/// Stores an identity file's contents in the macOS Keychain, under the given
+/// service name (replacing whatever is there).
+#[cfg(target_os = "macos")]
+pub fn store_in_keychain(service: &str, contents: &str) -> Result<(), String> {
+    // Passed through stdin, so that the key never appears in the process
+    // list, and hex-encoded, so that it can span multiple lines
+    let hex = contents
+        .bytes()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    let command = format!(
+        "add-generic-password -U -s \"{}\" -a {KEYCHAIN_ACCOUNT} -X {hex}\n",
+        service.replace('"', "\\\"")
+    );
+    run_with_input(Command::new("security").arg("-i"), "security", &command).map(|_| ())
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn store_in_keychain(_service: &str, _contents: &str) -> Result<(), String> {
+    Err("the Keychain is only available on macOS".into())
+}
+
+/// Runs a command with the given input, returning its output.
+fn run_with_input(command: &mut Command, name: &str, input: &str) -> Result<Vec<u8>, String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("couldn't run {name}: {e}"))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin
+        .write_all(input.as_bytes())
+        .map_err(|e| format!("couldn't write to {name}: {e}"))?;
+    drop(stdin);
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("couldn't run {name}: {e}"))?;
+    match output.status.success() {
+        true => Ok(output.stdout),
+        false => Err(process_msg(name, output.stderr)),
+    }
+}
+
+/// Seals an identity file's contents to this machine's TPM, returning the
+/// sealed credential to write out.
+pub fn seal_tpm2(contents: &str) -> Result<Vec<u8>, String> {
+    let mut command = Command::new("systemd-creds");
+    command
+        .arg("encrypt")
+        .arg("--with-key=tpm2")
+        .arg(format!("--name={CREDENTIAL_NAME}"))
+        .arg("-")
+        .arg("-");
+    run_with_input(&mut command, "systemd-creds", contents)
+}
+
+/// The Keychain service an identity location refers to, if any.
+pub fn keychain_service(location: &str) -> Option<&str> {
+    match SealedIdentity::parse(location) {
+        Some(SealedIdentity::Keychain(service)) => Some(service),
+        _ => None,
+    }
+}
+
+/// Parses the contents of an identity file: an SSH private key, or any number
+/// of age identities (one per line).
+pub(crate) fn parse_identities(
+    contents: &str,
+    name: &str,
+) -> Result<Vec<Box<dyn Identity>>, DecryptionError> {
+    if let Ok(identity) = age::ssh::Identity::from_buffer(contents.as_bytes(), Some(name.into())) {
+        if let age::ssh::Identity::Unsupported(k) = identity {
+            let msg = format!("{name} is an unsupported SSH key ({k:?})");
+            return Err(DecryptionError::UnsealingIdentity(msg));
+        }
+        return Ok(vec![Box::new(identity.with_callbacks(UiCallbacks))]);
+    }
+
+    let identities = contents
+        .lines()
+        .filter_map(|line| line.trim().parse::<age::x25519::Identity>().ok())
+        .map(|identity| Box::new(identity) as Box<dyn Identity>)
+        .collect();
+
+    Ok(identities)
+}
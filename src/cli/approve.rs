@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use super::{ApproveArgs, ApprovedOperation, State};
+use crate::secret::{signing_public_key, Approval, ApprovalError, Operation, SigningError};
+use crate::util::exit_status;
+use crate::{SecretError, SecretStorage};
+
+impl From<ApprovedOperation> for Operation {
+    fn from(operation: ApprovedOperation) -> Self {
+        match operation {
+            ApprovedOperation::SecretRemove { secret_name } => {
+                Operation::RemoveSecret { name: secret_name }
+            }
+            ApprovedOperation::Rekey {
+                remove,
+                secret_names,
+                sync,
+            } => Operation::rekey(&secret_names, &remove, sync),
+        }
+    }
+}
+
+/// Signs an approval for the operation, for someone else to use.
+pub async fn approve(args: ApproveArgs) -> Result<ExitStatus, ApproveError> {
+    let operation = Operation::from(args.operation);
+    log::info!(
+        "approving {operation} for {}",
+        humantime::format_duration(args.valid_for)
+    );
+    let approval = Approval::sign(&args.sign_with, operation, args.valid_for)?;
+    let data = serde_yaml::to_string(&approval).map_err(ApproveError::Encoding)?;
+
+    match &args.out {
+        Some(path) => tokio::fs::write(path, data)
+            .await
+            .map_err(|e| ApproveError::WritingApproval(path.to_owned(), e))?,
+        None => print!("{data}"),
+    }
+
+    Ok(exit_status(0))
+}
+
+/// Checks that the operation has been approved by a second keyholder, if
+/// approvals are required.
+pub async fn ensure_approved<S, E>(
+    state: &State<S, E>,
+    operation: &Operation,
+    approval: Option<&Path>,
+    requester_key: Option<&Path>,
+) -> Result<(), ApproveError>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let config = match &state.approvals {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+
+    let approval_path = approval.ok_or_else(|| ApproveError::Required(operation.clone()))?;
+    let requester_key = requester_key.ok_or(ApproveError::RequesterUnknown)?;
+    let requester = signing_public_key(requester_key)?;
+
+    let data = tokio::fs::read(approval_path)
+        .await
+        .map_err(|e| ApproveError::ReadingApproval(approval_path.to_owned(), e))?;
+    let approval: Approval = serde_yaml::from_slice(&data)
+        .map_err(|e| ApproveError::DecodingApproval(approval_path.to_owned(), e))?;
+    approval.verify(&config.keys, operation, &requester)?;
+    log::info!("{operation} approved");
+
+    Ok(())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApproveError {
+    #[error("{0} requires approval from a second keyholder (see `credible approve`)")]
+    Required(Operation),
+    #[error("approved operations must be requested with --sign-with, to identify the requester")]
+    RequesterUnknown,
+    #[error("{0}")]
+    ReadingKey(#[from] SigningError),
+    #[error("{0}")]
+    Invalid(#[from] ApprovalError),
+    #[error("error encoding approval: {0}")]
+    Encoding(serde_yaml::Error),
+    #[error("error writing approval to {0}: {1}")]
+    WritingApproval(PathBuf, std::io::Error),
+    #[error("error reading approval at {0}: {1}")]
+    ReadingApproval(PathBuf, std::io::Error),
+    #[error("error decoding approval at {0}: {1}")]
+    DecodingApproval(PathBuf, serde_yaml::Error),
+}
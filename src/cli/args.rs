@@ -1,6 +1,7 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use simplelog::LevelFilter;
 
 use crate::secret::ExposureSpec;
@@ -16,6 +17,21 @@ pub struct CliParams {
     /// Specify multiple in an environment variable by separating with commas
     pub config_file: Vec<PathBuf>,
 
+    #[arg(long, env = "CREDIBLE_NO_LOCAL_CONFIG")]
+    /// Don't apply local overrides from a credible.local.yaml next to the
+    /// (last) config file.
+    pub no_local_config: bool,
+
+    #[arg(long, env = "CREDIBLE_RECORD", conflicts_with = "replay")]
+    /// Save the ciphertext of every secret read from storage to this
+    /// directory, so that the run can be replayed later with --replay.
+    pub record: Option<PathBuf>,
+
+    #[arg(long, env = "CREDIBLE_REPLAY")]
+    /// Serve secrets only from ciphertext previously saved with --record in
+    /// this directory, without contacting storage. Writes are refused.
+    pub replay: Option<PathBuf>,
+
     /// Secrets to expose, in the following formats:
     ///
     /// - env:secret-name:ENV_VAR_NAME
@@ -25,6 +41,11 @@ pub struct CliParams {
     #[arg(long, env = "CREDIBLE_EXPOSURE_CONFIGS", value_delimiter = ',')]
     pub exposure: Vec<ExposureSpec>,
 
+    #[arg(long, env = "CREDIBLE_EXPOSURE_ROOT")]
+    /// Directory to resolve relative vanity paths against (overrides
+    /// `exposure_root` in config)
+    pub exposure_root: Option<PathBuf>,
+
     #[arg(short, long, env = "CREDIBLE_PRIVATE_KEY_PATHS", value_delimiter = ',')]
     /// Comma-separated list of local private keys to use for decryption.
     ///
@@ -35,6 +56,27 @@ pub struct CliParams {
     /// Level to display logs at (off, error, warn, info, debug, trace)
     pub log_level: LevelFilter,
 
+    #[arg(short, long, env = "CREDIBLE_QUIET")]
+    /// Don't log anything, including errors (overrides --log-level). The exit
+    /// status still reports failures.
+    pub quiet: bool,
+
+    #[arg(long, env = "CREDIBLE_DEBUG_STORAGE")]
+    /// Log the outcome of every request to storage (status, error code,
+    /// request IDs) and any retries, whatever the log level. Credentials and
+    /// request signatures are never logged.
+    pub debug_storage: bool,
+
+    #[arg(long, env = "CREDIBLE_RUNTIME", default_value = "multi-thread")]
+    /// Async runtime to run on. `current-thread` does everything on a single
+    /// thread, which saves memory and threads on small devices.
+    pub runtime: RuntimeFlavor,
+
+    #[arg(long, env = "CREDIBLE_WORKER_THREADS", value_parser = clap::value_parser!(u16).range(1..))]
+    /// Number of worker threads for the multi-threaded runtime (default: one
+    /// per CPU core)
+    pub worker_threads: Option<u16>,
+
     #[arg(short = 'z', long, env = "CREDIBLE_CREDENTIALS_FILE")]
     /// Path to a key=value file that will set environment variables for the
     /// process (useful for providing credentials to secret storage providers).
@@ -43,36 +85,214 @@ pub struct CliParams {
     /// loaded.
     pub credentials_file: Option<PathBuf>,
 
+    #[arg(long, env = "CREDIBLE_EVENTS_FD", conflicts_with = "events_json")]
+    /// Write machine-readable lifecycle events (as newline-delimited JSON) to
+    /// this inherited file descriptor
+    pub events_fd: Option<i32>,
+
+    #[arg(long)]
+    /// Write machine-readable lifecycle events (as newline-delimited JSON) to
+    /// stderr
+    pub events_json: bool,
+
+    #[arg(long, env = "CREDIBLE_FORCE_ADOPT")]
+    /// Move regular files that are in the way of symlinked vanity paths aside
+    /// (to `<path>.credible-backup`), instead of refusing to replace them
+    pub force_adopt: bool,
+
+    #[arg(long, env = "CREDIBLE_READ_ONLY")]
+    /// Refuse to run commands that modify stored secrets (in addition to
+    /// `read_only` in config)
+    pub read_only: bool,
+
+    #[arg(long, env = "CREDIBLE_TENANT")]
+    /// Only load this tenant's secrets and exposures (along with those
+    /// outside of `tenants`)
+    pub tenant: Option<String>,
+
+    #[arg(long, env = "CREDIBLE_ENV", value_enum, default_value_t)]
+    /// Environment to run in. `dev` uses `dev_values` from config instead of
+    /// storage, and a throwaway key instead of real identities.
+    pub env: RunEnvironment,
+
     #[command(subcommand)]
     pub action: Actions,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RuntimeFlavor {
+    /// Run everything on the current thread
+    CurrentThread,
+    /// Spread work across a pool of worker threads
+    #[default]
+    MultiThread,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunEnvironment {
+    #[default]
+    Default,
+    Dev,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum SystemAction {
     /// Mount all secrets in the configuration file on the current system
     Mount(Box<MountArgs>),
     /// Unmount our currently-mounted secrets, if any
     Unmount(UnmountArgs),
+    /// Compare the mounted secrets against what mounting now would produce
+    Diff(DiffArgs),
 }
 
 #[derive(Subcommand, Debug)]
 pub enum SecretAction {
     /// Upload a new secret to the store
     Upload(UploadCommandArgs),
+    /// Upload a directory of plaintext files, named after their secrets
+    UploadDir(UploadDirCommandArgs),
     /// Edit a currently-managed secret
     Edit(EditCommandArgs),
+    /// List configured secrets, with their descriptions
+    List(ListCommandArgs),
+    /// Check that stored secrets are encrypted to their configured recipients
+    Verify(VerifyCommandArgs),
+    /// Report size, modification time, recipients and usage of stored secrets
+    #[command(alias = "stats")]
+    Audit(AuditCommandArgs),
+    /// Print the recipients in a secret's ciphertext header, without
+    /// decrypting it
+    Inspect(InspectCommandArgs),
+    /// Encrypt plaintext from stdin to a secret's recipients, writing
+    /// ciphertext to stdout
+    Encrypt(EncryptCommandArgs),
+    /// Decrypt a secret's ciphertext from stdin, writing plaintext to stdout
+    Decrypt(PipeCommandArgs),
+    /// Remove a secret's ciphertext from the store
+    Remove(RemoveCommandArgs),
+    /// Append stdin to a secret, on a new line
+    ///
+    /// Appends are retried if the secret changes in the meantime. Only S3 and
+    /// Azure Blob storage check for changes as part of the upload; elsewhere a
+    /// change made just before the upload can still be overwritten.
+    Append(AppendCommandArgs),
+    /// Set a single field of a JSON or YAML secret
+    Set(SetCommandArgs),
+    /// Remove a single field from a JSON or YAML secret
+    Unset(UnsetCommandArgs),
+    /// Bring back the version of a secret from before it was last overwritten
+    /// (running it again undoes the restore)
+    Restore(RestoreCommandArgs),
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Actions {
     /// Perform system-level functionality (persistent mounting)
+    #[cfg(unix)]
     #[command(subcommand)]
     System(SystemAction),
     /// Perform secret management (create/edit)
-    #[command(subcommand)]
-    Secret(SecretAction),
+    Secret(SecretArgs),
     /// Run a command with populated secrets
     RunCommand(RunCommandArgs),
+    /// Run a shell script (from a file or stdin) with populated secrets
+    RunScript(RunScriptArgs),
+    /// Run a container with `docker run`, with populated secrets inside it
+    #[cfg(unix)]
+    DockerRun(DockerRunArgs),
+    /// Build an image with `docker build`, with secrets passed as build
+    /// secrets (for `RUN --mount=type=secret`)
+    #[cfg(unix)]
+    BuildkitSecret(BuildkitSecretArgs),
+    /// Re-encrypt stored secrets to a new set of recipients
+    Rekey(RekeyArgs),
+    /// Back up (or restore) all stored ciphertext
+    #[command(subcommand)]
+    Backup(BackupAction),
+    /// Emergency access to secrets using break-glass keys
+    #[command(subcommand, name = "breakglass")]
+    BreakGlass(BreakGlassAction),
+    /// Remove secrets left behind by crashed runs and old mounts
+    Clean(CleanArgs),
+    /// Generate an identity for this machine, and print its public key
+    Keygen(KeygenArgs),
+    /// Approve someone else performing a destructive operation, when
+    /// approvals are required
+    Approve(ApproveArgs),
+    /// Download (but don't decrypt) ciphertext into the cache, so that later
+    /// mounts don't need the backing store
+    Prefetch(PrefetchArgs),
+    /// Report on how secrets are used
+    #[command(subcommand)]
+    Report(ReportAction),
+    /// Show what's been loaded from config, for other tools to consume
+    #[command(subcommand)]
+    Inspect(InspectAction),
+    /// Manage the storage backend
+    #[command(subcommand)]
+    Storage(StorageAction),
+    /// Serve secrets to local processes over a unix socket, authorizing each
+    /// request by the connecting process's user
+    #[cfg(unix)]
+    Serve(ServeArgs),
+    /// Print what CI should mask in job logs, for the exposed secrets
+    MaskList(MaskListArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SecretArgs {
+    #[arg(long, global = true, value_name = "NAME_OR_SPEC")]
+    /// Storage to use instead of the configured one, either the name of an
+    /// entry in `storages`, or an inline spec (e.g. `{type: S3, bucket: prod,
+    /// region: us-east-1}`)
+    pub storage: Option<String>,
+
+    #[arg(long, global = true, env = "CREDIBLE_ALLOW_ROOT")]
+    /// Allow uploading and editing secrets as root, which is refused by
+    /// default (the editor, temporary files and shell history would all
+    /// belong to root)
+    pub allow_root: bool,
+
+    #[command(subcommand)]
+    pub action: SecretAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BackupAction {
+    /// Write all stored ciphertext to a single encrypted archive
+    Create(BackupCreateArgs),
+    /// Write all ciphertext from an archive to the configured store
+    Restore(BackupRestoreArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum StorageAction {
+    /// List objects that haven't been moved to the new storage yet, when
+    /// storage is being migrated
+    Migrate(StorageMigrateArgs),
+    /// Check that storage can be reached, and that the credentials in use can
+    /// list, read, write and delete objects
+    Check,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ReportAction {
+    /// Show every exposure of each secret, across config files, to find
+    /// secrets that aren't used anywhere
+    Exposures(ReportExposuresArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum InspectAction {
+    /// Print the fully merged and validated state (secrets, exposures,
+    /// storage type and identity paths), without any plaintext
+    State(InspectStateArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BreakGlassAction {
+    /// Decrypt a secret with a break-glass identity, recording an audit entry
+    Decrypt(BreakGlassDecryptArgs),
 }
 
 #[derive(clap::Args, Debug)]
@@ -104,6 +324,62 @@ pub struct MountArgs {
     /// Default group to own secrets (if not provided, current group will be
     /// used)
     pub group: Option<GroupWrapper>,
+
+    #[arg(long, env = "CREDIBLE_OFFLINE")]
+    /// Don't contact the backing store, and mount the last successfully
+    /// fetched ciphertext from the cache instead.
+    pub offline: bool,
+
+    #[clap(
+        long,
+        env = "CREDIBLE_CACHE_DIR",
+        default_value = "/var/cache/credible"
+    )]
+    /// Directory to cache fetched ciphertext in, when `fallback: cache` is
+    /// configured or `--offline` is given.
+    pub cache_dir: PathBuf,
+    #[arg(long, env = "CREDIBLE_WATCH", conflicts_with = "offline")]
+    /// Keep running after mounting, and refresh the exposures of secrets
+    /// whose ciphertext changes in the backing store.
+    pub watch: bool,
+
+    #[arg(
+        long,
+        env = "CREDIBLE_POLL_INTERVAL",
+        default_value = "1m",
+        value_parser = humantime::parse_duration
+    )]
+    /// How often to check for changed secrets in watch mode (e.g. `30s`,
+    /// `5m`).
+    pub poll_interval: Duration,
+
+    #[arg(
+        long,
+        env = "CREDIBLE_POLL_JITTER",
+        default_value_t = 0.1,
+        value_parser = parse_fraction
+    )]
+    /// Fraction of the poll interval to randomly spread each check by, so
+    /// that hosts don't all poll at once.
+    pub poll_jitter: f64,
+
+    #[arg(
+        long,
+        env = "CREDIBLE_MAX_BACKOFF",
+        default_value = "15m",
+        value_parser = humantime::parse_duration
+    )]
+    /// Longest to wait between checks, when backing off after errors.
+    pub max_backoff: Duration,
+
+    #[arg(long, env = "CREDIBLE_CONTAINER_BINDS", value_delimiter = ',')]
+    /// File to write after mounting, binding the secrets into containers
+    /// started with it, as format:file or format:file:target (where they
+    /// appear in the container, default: the secret dir). Formats are
+    /// `nspawn` (a settings file), `nspawn-args` and `podman-args`. Can be
+    /// repeated.
+    #[cfg(unix)]
+    pub container_bind: Vec<crate::system::ContainerBind>,
 }
 
 #[derive(clap::Args, Debug)]
@@ -122,12 +398,321 @@ pub struct UnmountArgs {
     pub secret_dir: PathBuf,
 }
 
+#[derive(clap::Args, Debug)]
+pub struct DiffArgs {
+    #[clap(
+        long,
+        short,
+        env = "CREDIBLE_SECRET_DIR",
+        default_value = "/run/credible"
+    )]
+    /// Directory users access secrets from, which points at the current
+    /// generation.
+    pub secret_dir: PathBuf,
+
+    #[arg(long)]
+    /// Print changes as JSON, rather than a line per changed file.
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CleanArgs {
+    #[cfg(unix)]
+    #[clap(
+        long,
+        short,
+        env = "CREDIBLE_MOUNT_POINT",
+        default_value = "/run/credible.d"
+    )]
+    /// System-managed directory secrets are mounted in. Generations other
+    /// than the current one are removed.
+    pub mount_point: PathBuf,
+
+    #[cfg(unix)]
+    #[clap(
+        long,
+        short,
+        env = "CREDIBLE_SECRET_DIR",
+        default_value = "/run/credible"
+    )]
+    /// Directory users access secrets from, which points at the current
+    /// generation.
+    pub secret_dir: PathBuf,
+
+    #[arg(long, env = "CREDIBLE_SECRETS_TMPDIR")]
+    /// Directory run-command created secret file directories in, if
+    /// `secrets_tmpdir` is configured (default: the system temp directory).
+    /// Nothing outside of it is removed.
+    pub secrets_tmpdir: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyType {
+    #[default]
+    Age,
+    SshEd25519,
+}
+
+/// Where to seal generated keys to.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SealWith {
+    /// This machine's TPM (using `systemd-creds`). Use `tpm2:<path>` as the
+    /// identity path to read it.
+    Tpm2,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct StorageMigrateArgs {
+    #[arg(long)]
+    /// Copy every object that's only in the old storage to the new storage
+    pub copy_all: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ReportExposuresArgs {
+    /// Other config files to include (e.g. those of other hosts or profiles),
+    /// which are only read for their secrets and exposures
+    pub config_files: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InspectStateArgs {
+    #[arg(long)]
+    /// Print the state as JSON, rather than YAML.
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PrefetchArgs {
+    #[clap(
+        long,
+        env = "CREDIBLE_CACHE_DIR",
+        default_value = "/var/cache/credible"
+    )]
+    /// Directory to cache fetched ciphertext in.
+    pub cache_dir: PathBuf,
+
+    /// Secrets to fetch (default: all configured secrets)
+    pub secret_names: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct MaskListArgs {
+    #[arg(long, env = "CREDIBLE_MASK_FORMAT", value_enum, default_value_t)]
+    /// How to print masks
+    pub format: MaskFormat,
+
+    #[arg(long)]
+    /// Print secret values themselves, rather than their SHA-256 digests
+    pub literal: bool,
+
+    /// Secrets to list (default: every secret with a file or env exposure)
+    pub secret_names: Vec<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MaskFormat {
+    /// One mask per line (multi-line secrets are masked a line at a time)
+    #[default]
+    Lines,
+    /// A JSON object of secret names to masks (as read by `buildkite-agent
+    /// redactor add --format json`)
+    Json,
+    /// GitHub Actions `::add-mask::` workflow commands (needs `--literal`)
+    Github,
+    /// A JSON array of masked GitLab CI/CD variables, as taken by its
+    /// variables API (needs `--literal`)
+    Gitlab,
+}
+
+#[cfg(unix)]
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    #[arg(long, env = "CREDIBLE_SOCKET", default_value = "/run/credible.sock")]
+    /// Unix socket to listen on.
+    pub socket: PathBuf,
+
+    #[arg(long, env = "CREDIBLE_HTTP")]
+    /// Also serve secrets over HTTP, at `unix:<path>` or `address:port` (e.g.
+    /// an address only reachable from a container's network namespace).
+    pub http: Option<super::serve::HttpAddress>,
+
+    #[arg(
+        long,
+        requires = "http",
+        env = "CREDIBLE_HTTP_SECRETS",
+        value_delimiter = ','
+    )]
+    /// Secrets to serve over HTTP. Required when listening on an address,
+    /// where anyone who can connect may read them.
+    pub http_secret: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct KeygenArgs {
+    #[arg(long = "type", value_enum, default_value_t)]
+    /// Type of key to generate
+    pub key_type: KeyType,
+
+    #[arg(long)]
+    /// Where to write the private key (or `keychain:<name>` to store it in
+    /// the macOS Keychain)
+    pub out: PathBuf,
+
+    #[arg(long)]
+    /// Replace an existing key at --out (and registry entry)
+    pub force: bool,
+
+    #[arg(long, value_name = "STORAGE_PATH")]
+    /// Add the public key to the recipients registry at this path in storage
+    pub register: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Name to register the key under (default: this machine's hostname)
+    pub name: Option<String>,
+
+    #[arg(long, requires = "register")]
+    /// Generate a short-lived key (e.g. at boot) that replaces this machine's
+    /// registered key. --out must be on a memory-backed filesystem, so the
+    /// key never outlives the machine's uptime.
+    pub ephemeral: bool,
+
+    #[arg(long, value_enum)]
+    /// Seal the private key, so that it's useless if copied off this machine
+    pub seal: Option<SealWith>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ApproveArgs {
+    #[arg(long, env = "CREDIBLE_SIGNING_KEY")]
+    /// SSH private key to sign the approval with
+    pub sign_with: PathBuf,
+
+    #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    /// How long the approval can be used for
+    pub valid_for: Duration,
+
+    #[arg(short, long)]
+    /// Where to write the approval (default: stdout)
+    pub out: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub operation: ApprovedOperation,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ApprovedOperation {
+    /// Removing a secret (`secret remove`)
+    SecretRemove {
+        /// Name of the secret
+        secret_name: String,
+    },
+    /// Rekeying that removes recipients (`rekey --remove-recipient` or
+    /// `rekey --sync`). Arguments must match the rekey's.
+    Rekey {
+        #[arg(long = "remove-recipient")]
+        remove: Vec<String>,
+
+        #[arg(long = "secret")]
+        secret_names: Vec<String>,
+
+        #[arg(long)]
+        sync: bool,
+    },
+}
+
 #[derive(clap::Args, Debug)]
 pub struct RunCommandArgs {
+    #[arg(long, env = "CREDIBLE_SECRETS_DIR_ENV", value_delimiter = ',')]
+    /// Environment variable to export the secret file directory under
+    /// (overrides `secrets_dir_env` in config). Can be repeated.
+    pub secrets_dir_env: Vec<String>,
+
+    #[arg(long, env = "CREDIBLE_SECRETS_TMPDIR")]
+    /// Directory to create the secret file directory in (overrides
+    /// `secrets_tmpdir` in config, default: the system temp directory)
+    pub secrets_tmpdir: Option<PathBuf>,
+
     /// Command arguments to run
     pub cmd: Vec<String>,
 }
 
+#[derive(clap::Args, Debug)]
+pub struct RunScriptArgs {
+    #[arg(long, env = "CREDIBLE_SECRETS_DIR_ENV", value_delimiter = ',')]
+    /// Environment variable to export the secret file directory under
+    /// (overrides `secrets_dir_env` in config). Can be repeated.
+    pub secrets_dir_env: Vec<String>,
+
+    #[arg(long, env = "CREDIBLE_SECRETS_TMPDIR")]
+    /// Directory to create the secret file directory in (overrides
+    /// `secrets_tmpdir` in config, default: the system temp directory)
+    pub secrets_tmpdir: Option<PathBuf>,
+
+    #[arg(long, env = "SHELL", default_value = "/bin/sh")]
+    /// Shell to run the script with, which is given it on stdin (`-s`)
+    pub shell: String,
+
+    /// Script to run
+    #[clap(default_value = "/dev/stdin")]
+    pub script: PathBuf,
+
+    /// Arguments to pass to the script (as `$1`, `$2`, ...)
+    pub args: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DockerRunArgs {
+    #[arg(long, env = "CREDIBLE_SECRETS_DIR_ENV", value_delimiter = ',')]
+    /// Environment variable to export the secret file directory under in the
+    /// container (overrides `secrets_dir_env` in config). Can be repeated.
+    pub secrets_dir_env: Vec<String>,
+
+    #[arg(long, env = "CREDIBLE_SECRETS_TMPDIR")]
+    /// Directory to create the secret file directory in on this host
+    /// (overrides `secrets_tmpdir` in config, default: the system temp
+    /// directory)
+    pub secrets_tmpdir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "CREDIBLE_CONTAINER_SECRETS_DIR",
+        default_value = "/run/secrets"
+    )]
+    /// Where to mount the secret file directory in the container
+    pub secrets_dir: PathBuf,
+
+    #[arg(long, env = "CREDIBLE_DOCKER", default_value = "docker")]
+    /// Container runtime to run (anything with a compatible `run`, like
+    /// `podman`)
+    pub docker: String,
+
+    /// Arguments to `docker run` (options, the image, and its command)
+    pub args: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BuildkitSecretArgs {
+    #[arg(long, env = "CREDIBLE_SECRETS_TMPDIR")]
+    /// Directory to create the secret file directory in (overrides
+    /// `secrets_tmpdir` in config, default: the system temp directory)
+    pub secrets_tmpdir: Option<PathBuf>,
+
+    #[arg(long, env = "CREDIBLE_DOCKER", default_value = "docker")]
+    /// Container runtime to run (anything with a compatible `build`, like
+    /// `podman`)
+    pub docker: String,
+
+    /// Secrets to pass to the build, each with its name as the id, on top of
+    /// any file exposures
+    pub names: Vec<String>,
+
+    #[arg(last = true)]
+    /// Arguments to `docker build` (options and the build context), after
+    /// `--`
+    pub args: Vec<String>,
+}
+
 #[derive(clap::Args, Debug)]
 pub struct UploadCommandArgs {
     /// Name of the secret (as defined in conf file) to upload
@@ -136,13 +721,260 @@ pub struct UploadCommandArgs {
     /// Plaintext file to read content from
     #[clap(default_value = "/dev/stdin")]
     pub source_file: PathBuf,
+
+    #[arg(long, env = "CREDIBLE_SIGNING_KEY")]
+    /// SSH private key to sign the uploaded ciphertext with
+    pub sign_with: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Upload plaintext that's empty, or under a tenth of the size of the
+    /// secret it replaces (which is refused, as it's probably a mistake)
+    pub allow_empty: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct UploadDirCommandArgs {
+    /// Directory of plaintext files. Each file's path relative to this
+    /// directory is the name of the secret it's uploaded as.
+    pub source_dir: PathBuf,
+
+    #[arg(long)]
+    /// Upload files with no matching secret too, and print config for them
+    pub generate_config: bool,
+
+    #[arg(long = "encryption-key")]
+    /// Public key to encrypt generated secrets to. Can be repeated.
+    pub encryption_keys: Vec<String>,
+
+    #[arg(long, env = "CREDIBLE_SIGNING_KEY")]
+    /// SSH private key to sign the uploaded ciphertext with
+    pub sign_with: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Upload plaintext that's empty, or under a tenth of the size of the
+    /// secret it replaces (which is refused, as it's probably a mistake)
+    pub allow_empty: bool,
 }
 
 #[derive(clap::Args, Debug)]
 pub struct EditCommandArgs {
     #[arg(short, long, env = "EDITOR")]
-    /// Editor to open for editing the secret
+    /// Editor to open for editing the secret. May include arguments (e.g.
+    /// `code --wait`), split the way a shell would split them
     pub editor: String,
     /// Name of the secret to edit
+    #[arg(required_unless_present = "all_matching")]
+    pub secret_name: Option<String>,
+
+    #[arg(long, value_name = "PATTERN", conflicts_with = "secret_name")]
+    /// Edit every secret whose name matches this glob pattern together, in a
+    /// single editor session on a temporary directory
+    pub all_matching: Option<String>,
+
+    #[arg(long, env = "CREDIBLE_SIGNING_KEY")]
+    /// SSH private key to sign the updated ciphertext with
+    pub sign_with: Option<PathBuf>,
+
+    #[arg(short, long)]
+    /// Upload changes without asking for confirmation
+    pub yes: bool,
+
+    #[arg(long)]
+    /// Show which lines changed (with their contents redacted) before
+    /// confirming
+    pub diff: bool,
+
+    #[arg(long)]
+    /// Upload plaintext that's empty, or under a tenth of the size of the
+    /// secret it replaces (which is refused, as it's probably a mistake)
+    pub allow_empty: bool,
+
+    #[arg(
+        long,
+        env = "CREDIBLE_EDIT_NO_TEMPFILE",
+        conflicts_with = "all_matching"
+    )]
+    /// Pass the secret to the editor on stdin and read it back from its
+    /// stdout (like `vipe`), instead of writing it to a temporary file. The
+    /// editor must support this, using the terminal for its own interface.
+    pub no_tempfile: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RemoveCommandArgs {
+    /// Name of the secret (as defined in conf file) to remove
     pub secret_name: String,
+
+    #[arg(long, env = "CREDIBLE_APPROVAL")]
+    /// Another keyholder's approval, from `credible approve`, when approvals
+    /// are required
+    pub approval: Option<PathBuf>,
+
+    #[arg(long, env = "CREDIBLE_SIGNING_KEY")]
+    /// SSH private key identifying you as a keyholder, when approvals are
+    /// required
+    pub sign_with: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AppendCommandArgs {
+    /// Name of the secret (as defined in conf file) to append to
+    pub secret_name: String,
+
+    #[arg(long, env = "CREDIBLE_SIGNING_KEY")]
+    /// SSH private key to sign the updated ciphertext with
+    pub sign_with: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SetCommandArgs {
+    /// Name of the secret (as defined in conf file) to modify
+    pub secret_name: String,
+    /// Field to set. Nested fields are separated by dots (e.g. `db.password`)
+    pub key: String,
+    /// Value to set the field to. Read from stdin if not given, which keeps
+    /// it out of shell history
+    pub value: Option<String>,
+
+    #[arg(long, env = "CREDIBLE_SIGNING_KEY")]
+    /// SSH private key to sign the updated ciphertext with
+    pub sign_with: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct UnsetCommandArgs {
+    /// Name of the secret (as defined in conf file) to modify
+    pub secret_name: String,
+    /// Field to remove. Nested fields are separated by dots (e.g.
+    /// `db.password`)
+    pub key: String,
+
+    #[arg(long, env = "CREDIBLE_SIGNING_KEY")]
+    /// SSH private key to sign the updated ciphertext with
+    pub sign_with: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RestoreCommandArgs {
+    /// Name of the secret (as defined in conf file) to restore
+    pub secret_name: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyCommandArgs {
+    /// Names of secrets to verify (if not provided, all secrets are verified)
+    pub secret_names: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ListCommandArgs {
+    #[arg(long, short)]
+    /// Also print each secret's other metadata (e.g. its owner)
+    pub long: bool,
+
+    /// Names of secrets to list (if not provided, all secrets are listed)
+    pub secret_names: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AuditCommandArgs {
+    /// Names of secrets to report on (if not provided, all secrets are
+    /// included)
+    pub secret_names: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InspectCommandArgs {
+    /// Name of the secret to inspect
+    pub secret_name: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PipeCommandArgs {
+    /// Name of the secret whose keys should be used
+    pub secret_name: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct EncryptCommandArgs {
+    /// Name of the secret whose keys should be used
+    pub secret_name: String,
+
+    #[arg(long)]
+    /// ASCII-armor the ciphertext, to use as a secret's inline `value`
+    pub armor: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RekeyArgs {
+    #[arg(long = "add-recipient")]
+    /// Public key to add to each secret's recipients. Can be repeated.
+    pub add: Vec<String>,
+
+    #[arg(long = "remove-recipient")]
+    /// Public key to remove from each secret's recipients. Can be repeated.
+    pub remove: Vec<String>,
+
+    #[arg(long, conflicts_with_all = ["add", "remove"])]
+    /// Rekey each secret to exactly its configured recipients (including the
+    /// current members of any key groups)
+    pub sync: bool,
+
+    #[arg(long = "secret")]
+    /// Name of a secret to rekey. Can be repeated (if not provided, all
+    /// secrets are rekeyed).
+    pub secret_names: Vec<String>,
+
+    #[arg(long, env = "CREDIBLE_SIGNING_KEY")]
+    /// SSH private key to sign the re-encrypted ciphertext with (and to
+    /// identify you, when approvals are required)
+    pub sign_with: Option<PathBuf>,
+
+    #[arg(long, env = "CREDIBLE_APPROVAL")]
+    /// Another keyholder's approval, from `credible approve`, when approvals
+    /// are required for removing recipients
+    pub approval: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BreakGlassDecryptArgs {
+    /// Name of the secret to decrypt
+    pub secret_name: String,
+
+    #[arg(short, long)]
+    /// Break-glass private key to decrypt with
+    pub identity: PathBuf,
+
+    #[arg(short, long)]
+    /// Reason for access, recorded in the audit record
+    pub reason: String,
+
+    #[arg(short, long)]
+    /// File to write the plaintext to (stdout if not provided)
+    pub output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BackupCreateArgs {
+    /// File to write the archive to
+    pub file: PathBuf,
+
+    #[arg(short, long = "recipient", required = true)]
+    /// Public key to encrypt the archive to. Can be repeated.
+    pub recipients: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BackupRestoreArgs {
+    /// Archive to restore from
+    pub file: PathBuf,
+}
+
+/// Parses a fraction between 0 and 1 (inclusive).
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    let value = s.parse::<f64>().map_err(|e| e.to_string())?;
+    match (0.0..=1.0).contains(&value) {
+        true => Ok(value),
+        false => Err(format!("{s} isn't between 0 and 1")),
+    }
 }
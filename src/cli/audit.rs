@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+
+use super::secret::select_secrets;
+use super::State;
+use crate::age::{read_header, read_header_stanzas, ssh_recipient_tag, HeaderError, HeaderStanza};
+use crate::secret::{normalize_recipient, ObjectMetadata};
+use crate::util::{exit_status, BoxedAsyncReader};
+use crate::{Secret, SecretError, SecretStorage};
+
+#[derive(Serialize, Debug)]
+struct SecretReport {
+    name: String,
+    path: PathBuf,
+    defined_in: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    deprecated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replaced_by: Option<String>,
+    size: Option<u64>,
+    last_modified: Option<String>,
+    /// Recipients found in the ciphertext's header
+    recipients: Vec<String>,
+    exposures: Vec<String>,
+    /// Where the exposures of a deprecated secret are configured, so that
+    /// they can be migrated
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    used_by: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+}
+
+/// Describes a header stanza, naming the configured key it belongs to where
+/// that's possible.
+fn describe_stanza(stanza: &HeaderStanza, secret: &Secret) -> String {
+    match (stanza.tag.as_str(), stanza.args.first()) {
+        // X25519 stanzas only contain an ephemeral share, which can't be tied
+        // back to a recipient without its identity
+        ("X25519", _) => "X25519 (recipient not identifiable)".to_string(),
+        (tag @ ("ssh-ed25519" | "ssh-rsa"), Some(key_tag)) => secret
+            .encryption_keys
+            .iter()
+            .find(|k| ssh_recipient_tag(k).as_ref() == Some(key_tag))
+            .map(|k| normalize_recipient(k))
+            .unwrap_or_else(|| format!("{tag} {key_tag} (unknown key)")),
+        (tag, _) => format!("{tag} (unknown recipient type)"),
+    }
+}
+
+fn describe_exposures<S, E>(state: &State<S, E>, secret: &Secret) -> Vec<String>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let files = state
+        .exposures
+        .files
+        .get(&secret.name)
+        .into_iter()
+        .flatten();
+    let envs = state.exposures.envs.get(&secret.name).into_iter().flatten();
+
+    files
+        .map(|f| match &f.vanity_path {
+            Some(p) => format!("file:{}", p.to_string_lossy()),
+            None => "file".to_string(),
+        })
+        .chain(envs.map(|e| format!("env:{}", e.name)))
+        .collect()
+}
+
+/// Reads a secret's ciphertext from storage, adding its metadata to the
+/// report.
+async fn read_stored<S, E>(
+    state: &State<S, E>,
+    secret: &Secret,
+    report: &mut SecretReport,
+    ciphertext: &mut Vec<u8>,
+) -> Result<(), String>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    match state.storage.metadata(&secret.path).await {
+        Ok(metadata) => {
+            report.size = Some(metadata.size);
+            report.last_modified = metadata
+                .last_modified
+                .map(|t| humantime::format_rfc3339_seconds(t).to_string());
+        }
+        Err(e) => report.errors.push(format!("error fetching metadata: {e}")),
+    }
+
+    match state.storage.read(&secret.path).await {
+        Ok(mut r) => r
+            .read_to_end(ciphertext)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn report<S, E>(state: &State<S, E>, secret: &Secret) -> SecretReport
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let mut report = SecretReport {
+        name: secret.name.clone(),
+        path: secret.path.clone(),
+        defined_in: secret.defined_in.clone(),
+        tenant: secret.tenant.clone(),
+        metadata: secret.metadata.clone(),
+        deprecated: secret.deprecated,
+        replaced_by: secret.replaced_by.clone(),
+        size: None,
+        last_modified: None,
+        recipients: Vec::new(),
+        exposures: describe_exposures(state, secret),
+        used_by: Vec::new(),
+        errors: Vec::new(),
+    };
+    if secret.deprecated {
+        report.used_by = state
+            .used_by(&secret.name)
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+    }
+
+    let mut ciphertext = Vec::new();
+    if let Some(value) = &secret.value {
+        report.size = Some(value.len() as u64);
+        ciphertext.extend_from_slice(value.as_bytes());
+    }
+    let read = match secret.value.is_some() {
+        true => Ok(()),
+        false => read_stored(state, secret, &mut report, &mut ciphertext).await,
+    };
+    if let Err(e) = read {
+        report
+            .errors
+            .push(format!("error fetching ciphertext: {e}"));
+        return report;
+    }
+
+    match read_header_stanzas(&ciphertext) {
+        Ok(stanzas) => {
+            report.recipients = stanzas.iter().map(|s| describe_stanza(s, secret)).collect()
+        }
+        Err(e) => report.errors.push(format!("error reading header: {e}")),
+    }
+
+    report
+}
+
+/// Prints an inventory of the given secrets (or all secrets) as YAML.
+pub async fn audit<S, E>(
+    state: &State<S, E>,
+    secret_names: &[String],
+) -> Result<ExitStatus, AuditError>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let secrets = select_secrets(&state.secrets, secret_names).map_err(AuditError::NoSuchSecret)?;
+
+    let mut reports = Vec::new();
+    for secret in secrets {
+        reports.push(report(state, secret).await);
+    }
+
+    let output = serde_yaml::to_string(&reports).map_err(AuditError::EncodingReport)?;
+    print!("{output}");
+
+    let still_used = reports.iter().filter(|r| !r.used_by.is_empty()).count();
+    if still_used > 0 {
+        log::warn!("{still_used} deprecated secret(s) are still in use");
+    }
+    let failed = reports.iter().filter(|r| !r.errors.is_empty()).count();
+    if failed > 0 {
+        log::warn!("{failed} secret(s) couldn't be fully inspected");
+        return Ok(exit_status(1));
+    }
+
+    Ok(exit_status(0))
+}
+
+/// Prints the names of the given secrets (or all secrets), with their
+/// descriptions, and their other metadata if `long` is set. Nothing is fetched
+/// from storage.
+pub fn list<S, E>(
+    state: &State<S, E>,
+    secret_names: &[String],
+    long: bool,
+) -> Result<ExitStatus, AuditError>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let secrets = select_secrets(&state.secrets, secret_names).map_err(AuditError::NoSuchSecret)?;
+
+    let width = secrets.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    for secret in secrets {
+        match secret.metadata.get("description") {
+            Some(description) => println!("{:width$}  {description}", secret.name),
+            None => println!("{}", secret.name),
+        }
+        if !long {
+            continue;
+        }
+        for (key, value) in secret.metadata.iter().filter(|(k, _)| *k != "description") {
+            println!("{:width$}    {key}: {value}", "");
+        }
+    }
+
+    Ok(exit_status(0))
+}
+
+#[derive(Serialize, Debug)]
+struct StanzaReport {
+    #[serde(rename = "type")]
+    kind: String,
+    /// Key tag age writes for SSH recipients (a truncated SHA-256 of the key)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+    /// Configured key the stanza belongs to, if it could be identified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipient: Option<String>,
+}
+
+impl StanzaReport {
+    fn new(stanza: &HeaderStanza, secret: &Secret) -> Self {
+        let fingerprint = match stanza.tag.as_str() {
+            "ssh-ed25519" | "ssh-rsa" => stanza.args.first().cloned(),
+            _ => None,
+        };
+        let recipient = fingerprint.as_ref().and_then(|key_tag| {
+            secret
+                .encryption_keys
+                .iter()
+                .find(|k| ssh_recipient_tag(k).as_ref() == Some(key_tag))
+                .map(|k| normalize_recipient(k))
+        });
+
+        Self {
+            kind: stanza.tag.clone(),
+            fingerprint,
+            recipient,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct InspectReport {
+    name: String,
+    path: PathBuf,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+    size: u64,
+    last_modified: Option<String>,
+    armored: bool,
+    stanzas: Vec<StanzaReport>,
+}
+
+/// Prints the recipient stanzas from a secret's ciphertext header, along with
+/// its storage metadata, without decrypting it. Only the header is fetched.
+pub async fn inspect<S, E>(
+    state: &State<S, E>,
+    secret_name: &str,
+) -> Result<ExitStatus, InspectError>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError + 'static,
+{
+    let secret = state
+        .secrets
+        .get(secret_name)
+        .ok_or_else(|| InspectError::NoSuchSecret(secret_name.to_string()))?;
+
+    let (metadata, reader) = match &secret.value {
+        Some(value) => {
+            let metadata = ObjectMetadata {
+                size: value.len() as u64,
+                last_modified: None,
+            };
+            let reader = Cursor::new(value.as_bytes().to_vec());
+            (metadata, BoxedAsyncReader::from_async_read(reader))
+        }
+        None => {
+            let metadata = state
+                .storage
+                .metadata(&secret.path)
+                .await
+                .map_err(|e| InspectError::FetchingMetadata(Box::new(e)))?;
+            let reader = state
+                .storage
+                .read(&secret.path)
+                .await
+                .map_err(|e| InspectError::FetchingCiphertext(Box::new(e)))?;
+            (metadata, reader)
+        }
+    };
+    let header = read_header(reader)
+        .await
+        .map_err(InspectError::ReadingHeader)?;
+    log::debug!(
+        "read {} of {} bytes to parse header",
+        header.bytes_read,
+        metadata.size
+    );
+
+    let report = InspectReport {
+        name: secret.name.clone(),
+        path: secret.path.clone(),
+        metadata: secret.metadata.clone(),
+        size: metadata.size,
+        last_modified: metadata
+            .last_modified
+            .map(|t| humantime::format_rfc3339_seconds(t).to_string()),
+        armored: header.armored,
+        stanzas: header
+            .stanzas
+            .iter()
+            .map(|s| StanzaReport::new(s, secret))
+            .collect(),
+    };
+
+    let output = serde_yaml::to_string(&report).map_err(InspectError::EncodingReport)?;
+    print!("{output}");
+
+    Ok(exit_status(0))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum InspectError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("error fetching metadata: {0}")]
+    FetchingMetadata(Box<dyn std::error::Error>),
+    #[error("error fetching ciphertext: {0}")]
+    FetchingCiphertext(Box<dyn std::error::Error>),
+    #[error("error reading header: {0}")]
+    ReadingHeader(HeaderError),
+    #[error("error encoding report: {0}")]
+    EncodingReport(serde_yaml::Error),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuditError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("error encoding report: {0}")]
+    EncodingReport(serde_yaml::Error),
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::State;
+use crate::age::{decrypt_bytes, encrypt_bytes, get_identities, DecryptionError, EncryptionError};
+use crate::secret::{sidecar_path, CiphertextPin, SIDECAR_SUFFIXES};
+use crate::util::{exit_status, open_options_with_mode};
+use crate::{SecretError, SecretStorage};
+
+const BACKUP_PERMISSIONS: u32 = 0o0600;
+const MANIFEST_NAME: &str = "manifest.yaml";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BackupManifest {
+    created: u64,
+    secrets: Vec<String>,
+    objects: Vec<BackupObject>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BackupObject {
+    /// Path of the object in storage
+    path: PathBuf,
+    /// Digest of the object's content, verified before restoring
+    digest: CiphertextPin,
+}
+
+fn object_entry_name(index: usize) -> String {
+    // Objects are stored by index rather than by their storage path, so that
+    // nothing in the archive can refer outside of it
+    format!("objects/{index}")
+}
+
+fn append_entry(
+    builder: &mut tar::Builder<Vec<u8>>,
+    name: &str,
+    data: &[u8],
+) -> Result<(), BackupError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(BACKUP_PERMISSIONS);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, name, data)
+        .map_err(BackupError::BuildingArchive)
+}
+
+async fn read_object<S: SecretStorage>(storage: &S, p: &Path) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::new();
+    storage
+        .read(p)
+        .await
+        .map_err(|e| e.to_string())?
+        .read_to_end(&mut buf)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(buf)
+}
+
+pub async fn create<S, E>(
+    state: &State<S, E>,
+    file: &Path,
+    recipients: &[String],
+) -> Result<ExitStatus, BackupError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    // Inline values are backed up with the config they're in
+    let mut secrets = state
+        .secrets
+        .values()
+        .filter(|s| s.value.is_none())
+        .collect::<Vec<_>>();
+    secrets.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut objects = Vec::new();
+    for secret in secrets.iter() {
+        let ciphertext = read_object(&state.storage, &secret.path)
+            .await
+            .map_err(|e| BackupError::FetchingSecret(secret.name.clone(), e))?;
+        append_entry(&mut builder, &object_entry_name(objects.len()), &ciphertext)?;
+        objects.push(BackupObject {
+            path: secret.path.clone(),
+            digest: CiphertextPin::sha256(&ciphertext),
+        });
+
+        for suffix in SIDECAR_SUFFIXES {
+            let path = sidecar_path(&secret.path, suffix);
+            let data = match read_object(&state.storage, &path).await {
+                Ok(d) => d,
+                Err(e) => {
+                    log::debug!("not backing up {}: {e}", path.to_string_lossy());
+                    continue;
+                }
+            };
+            append_entry(&mut builder, &object_entry_name(objects.len()), &data)?;
+            objects.push(BackupObject {
+                path,
+                digest: CiphertextPin::sha256(&data),
+            });
+        }
+
+        log::info!("backed up {}", secret.name);
+    }
+
+    let manifest = BackupManifest {
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock is before the unix epoch")
+            .as_secs(),
+        secrets: secrets.iter().map(|s| s.name.clone()).collect(),
+        objects,
+    };
+    let manifest_data = serde_yaml::to_string(&manifest).map_err(BackupError::EncodingManifest)?;
+    append_entry(&mut builder, MANIFEST_NAME, manifest_data.as_bytes())?;
+    let archive = builder.into_inner().map_err(BackupError::BuildingArchive)?;
+
+    let encrypted = encrypt_bytes(Cursor::new(archive), recipients).await?;
+    let mut output = open_options_with_mode(BACKUP_PERMISSIONS)
+        .create_new(true)
+        .write(true)
+        .open(file)
+        .await
+        .map_err(BackupError::WritingBackup)?;
+    output
+        .write_all(&encrypted)
+        .await
+        .map_err(BackupError::WritingBackup)?;
+
+    eprintln!(
+        "backed up {} secret(s) ({} objects) to {}",
+        manifest.secrets.len(),
+        manifest.objects.len(),
+        file.to_string_lossy()
+    );
+
+    Ok(exit_status(0))
+}
+
+pub async fn restore<S, E>(state: &State<S, E>, file: &Path) -> Result<ExitStatus, BackupError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let identities = get_identities(&state.private_key_paths)?;
+    let encrypted = tokio::fs::File::open(file)
+        .await
+        .map_err(BackupError::ReadingBackup)?;
+    let mut archive = Vec::new();
+    // The archive was encrypted to whoever made the backup, rather than to
+    // any one secret's recipients, so secrets' identities don't apply
+    decrypt_bytes(encrypted, &identities)
+        .await?
+        .read_to_end(&mut archive)
+        .await
+        .map_err(BackupError::ReadingBackup)?;
+
+    let mut entries = HashMap::new();
+    let mut reader = tar::Archive::new(Cursor::new(archive));
+    for entry in reader.entries().map_err(BackupError::ReadingArchive)? {
+        let mut entry = entry.map_err(BackupError::ReadingArchive)?;
+        let name = entry
+            .path()
+            .map_err(BackupError::ReadingArchive)?
+            .to_string_lossy()
+            .to_string();
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .map_err(BackupError::ReadingArchive)?;
+        entries.insert(name, data);
+    }
+
+    let manifest_data = entries
+        .get(MANIFEST_NAME)
+        .ok_or(BackupError::MissingManifest)?;
+    let manifest: BackupManifest =
+        serde_yaml::from_slice(manifest_data).map_err(BackupError::DecodingManifest)?;
+
+    // Check everything before writing anything, so we never restore half of a
+    // corrupted backup
+    for (i, object) in manifest.objects.iter().enumerate() {
+        let data = entries
+            .get(&object_entry_name(i))
+            .ok_or_else(|| BackupError::MissingObject(object.path.clone()))?;
+        object
+            .digest
+            .verify(data)
+            .map_err(|_| BackupError::CorruptObject(object.path.clone()))?;
+    }
+
+    for (i, object) in manifest.objects.iter().enumerate() {
+        let data = &entries[&object_entry_name(i)];
+        state
+            .storage
+            .write(&object.path, data.as_slice())
+            .await
+            .map_err(|e| BackupError::WritingToStore(object.path.clone(), Box::new(e)))?;
+        log::info!("restored {}", object.path.to_string_lossy());
+    }
+
+    eprintln!(
+        "restored {} secret(s) ({} objects) from {}",
+        manifest.secrets.len(),
+        manifest.objects.len(),
+        file.to_string_lossy()
+    );
+
+    Ok(exit_status(0))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BackupError {
+    #[error("error fetching {0} from store: {1}")]
+    FetchingSecret(String, String),
+    #[error("error building archive: {0}")]
+    BuildingArchive(std::io::Error),
+    #[error("error encoding manifest: {0}")]
+    EncodingManifest(serde_yaml::Error),
+    #[error("error encrypting backup: {0}")]
+    EncryptingBackup(#[from] EncryptionError),
+    #[error("error writing backup: {0}")]
+    WritingBackup(std::io::Error),
+    #[error("error reading backup: {0}")]
+    ReadingBackup(std::io::Error),
+    #[error("error decrypting backup: {0}")]
+    DecryptingBackup(#[from] DecryptionError),
+    #[error("error reading archive: {0}")]
+    ReadingArchive(std::io::Error),
+    #[error("backup has no manifest")]
+    MissingManifest,
+    #[error("error decoding manifest: {0}")]
+    DecodingManifest(serde_yaml::Error),
+    #[error("backup is missing object for {0}")]
+    MissingObject(PathBuf),
+    #[error("backup object for {0} does not match its digest")]
+    CorruptObject(PathBuf),
+    #[error("error writing {0} to store: {1}")]
+    WritingToStore(PathBuf, Box<dyn std::error::Error>),
+}
@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use super::State;
+use crate::age::{decrypt_bytes, get_identities, identity_public_keys, DecryptionError};
+use crate::secret::{read_secret, RecipientsRecord};
+use crate::util::{current_host, current_user, exit_status, open_options_with_mode};
+use crate::{SecretError, SecretStorage};
+
+const OUTPUT_PERMISSIONS: u32 = 0o0600;
+
+/// Record of a break-glass access, written to storage before any plaintext is
+/// produced.
+#[derive(Serialize, Debug)]
+struct AuditRecord<'a> {
+    secret: &'a str,
+    reason: &'a str,
+    identity: &'a str,
+    user: String,
+    host: String,
+    timestamp: u64,
+}
+
+pub async fn decrypt<S, E>(
+    state: &State<S, E>,
+    secret_name: &str,
+    identity: &Path,
+    reason: &str,
+    output: Option<&Path>,
+) -> Result<ExitStatus, BreakGlassError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let config = state
+        .break_glass
+        .as_ref()
+        .ok_or(BreakGlassError::NotConfigured)?;
+    let secret = state
+        .secrets
+        .get(secret_name)
+        .ok_or_else(|| BreakGlassError::NoSuchSecret(secret_name.to_string()))?;
+
+    // Only the break-glass identity may be used here, so that this can't be
+    // used as an unaudited side door with regular keys
+    let break_glass_keys = RecipientsRecord::new(&config.encryption_keys);
+    let public_key = identity_public_keys(identity)?
+        .into_iter()
+        .find(|k| break_glass_keys.contains(k))
+        .ok_or_else(|| BreakGlassError::NotBreakGlassIdentity(identity.to_owned()))?;
+    let identities = get_identities(&[identity])?;
+
+    let user = current_user();
+    let host = current_host();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_secs();
+    let record = AuditRecord {
+        secret: &secret.name,
+        reason,
+        identity: &public_key,
+        user,
+        host,
+        timestamp,
+    };
+
+    // Refuse to decrypt anything if we can't leave an audit trail
+    let audit_path = config.audit_prefix.join(format!(
+        "{}-{}-{}.yaml",
+        record.timestamp, record.user, record.secret
+    ));
+    let data = serde_yaml::to_string(&record).map_err(BreakGlassError::EncodingAuditRecord)?;
+    state
+        .storage
+        .write(&audit_path, data.as_bytes())
+        .await
+        .map_err(|e| BreakGlassError::WritingAuditRecord(Box::new(e)))?;
+    log::warn!(
+        "break-glass access to {} recorded at {}",
+        secret.name,
+        audit_path.to_string_lossy()
+    );
+
+    let reader = read_secret(&state.storage, secret, state.large_secret_threshold)
+        .await
+        .map_err(|e| BreakGlassError::FetchingFromStore(Box::new(e)))?;
+    // Only break-glass identities are accepted here, so the secret's own
+    // identities are deliberately not used
+    let mut reader = decrypt_bytes(reader, &identities).await?;
+    match output {
+        Some(path) => {
+            let mut file = open_options_with_mode(OUTPUT_PERMISSIONS)
+                .create_new(true)
+                .write(true)
+                .open(path)
+                .await
+                .map_err(BreakGlassError::WritingOutput)?;
+            tokio::io::copy(&mut reader, &mut file)
+                .await
+                .map_err(BreakGlassError::WritingOutput)?;
+        }
+        None => {
+            tokio::io::copy(&mut reader, &mut tokio::io::stdout())
+                .await
+                .map_err(BreakGlassError::WritingOutput)?;
+        }
+    }
+
+    Ok(exit_status(0))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BreakGlassError {
+    #[error("no break-glass keys are configured")]
+    NotConfigured,
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("{0} is not a break-glass identity")]
+    NotBreakGlassIdentity(PathBuf),
+    #[error("error loading identity: {0}")]
+    LoadingIdentity(#[from] DecryptionError),
+    #[error("error encoding audit record: {0}")]
+    EncodingAuditRecord(serde_yaml::Error),
+    #[error("error writing audit record: {0}")]
+    WritingAuditRecord(Box<dyn std::error::Error>),
+    #[error("error fetching secret from store: {0}")]
+    FetchingFromStore(Box<dyn std::error::Error>),
+    #[error("error writing plaintext: {0}")]
+    WritingOutput(std::io::Error),
+}
@@ -0,0 +1,104 @@
+use std::path::Path;
+use std::process::ExitStatus;
+
+use crate::cli::CleanArgs;
+use crate::hooks::Hooks;
+use crate::process::RunRecord;
+use crate::system::Host;
+use crate::util::exit_status;
+
+/// Prints everything that gets cleaned up.
+struct Report;
+
+impl Hooks for Report {
+    fn on_cleanup(&self, path: &Path) {
+        println!("removed {}", path.to_string_lossy());
+    }
+}
+
+/// Cleans up after `run-command`s that exited without doing so themselves.
+async fn clean_runs(tmpdir: &Path) -> Result<usize, CleanError> {
+    let mut failed = 0;
+    for p in RunRecord::list().await.map_err(CleanError::ListingRuns)? {
+        let record = match RunRecord::read(&p).await {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("{e}");
+                failed += 1;
+                continue;
+            }
+        };
+        if record.is_running() {
+            log::debug!("run {} is still going, skipping", record.pid);
+            continue;
+        }
+
+        log::info!("cleaning up after run {}", record.pid);
+        match record.clean_up(tmpdir, &Report).await {
+            Ok(()) => {
+                if let Err(e) = RunRecord::remove(&p).await {
+                    log::warn!("{e}");
+                    failed += 1;
+                }
+            }
+            Err(e) => {
+                log::error!("couldn't clean up after run {}: {e}", record.pid);
+                failed += 1;
+            }
+        }
+    }
+
+    Ok(failed)
+}
+
+/// Removes mounted generations that the secret dir no longer points to.
+#[cfg(unix)]
+async fn clean_generations(mount_point: &Path, secret_dir: &Path) -> Result<(), CleanError> {
+    if !mount_point.exists() {
+        return Ok(());
+    }
+
+    // Without a current generation, we can't tell a leftover apart from a
+    // mount that's still in progress
+    let current = match tokio::fs::read_link(secret_dir).await {
+        Ok(target) => target.file_name().map(|n| n.to_string_lossy().to_string()),
+        Err(e) => {
+            log::info!(
+                "not cleaning up mounts, couldn't read {}: {e}",
+                secret_dir.to_string_lossy()
+            );
+            return Ok(());
+        }
+    };
+
+    crate::system::unmount(mount_point, None, current.as_deref(), &Report, &Host).await?;
+
+    Ok(())
+}
+
+pub async fn clean(args: &CleanArgs) -> Result<ExitStatus, CleanError> {
+    let tmpdir = args
+        .secrets_tmpdir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir);
+    let failed = clean_runs(&tmpdir).await?;
+
+    #[cfg(unix)]
+    clean_generations(&args.mount_point, &args.secret_dir).await?;
+    #[cfg(not(unix))]
+    let _ = args;
+
+    Ok(exit_status(match failed {
+        0 => 0,
+        _ => 1,
+    }))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CleanError {
+    #[error("error listing runs: {0}")]
+    ListingRuns(crate::RunRecordError),
+    #[cfg(unix)]
+    #[error("error cleaning up old mounts: {0}")]
+    CleaningMounts(#[from] crate::system::UnmountSecretsError),
+}
@@ -0,0 +1,213 @@
+//! Editing single fields of structured (JSON or YAML) secrets, without opening
+//! an editor.
+
+use std::io::Cursor;
+use std::path::Path;
+use std::process::ExitStatus;
+
+use serde_yaml::{Mapping, Value};
+use tokio::io::AsyncReadExt;
+
+use super::secret::fetch_plaintext;
+use super::State;
+use crate::age::{encrypt_bytes, get_identities, DecryptionError, EncryptionError};
+use crate::secret::write_secret;
+use crate::util::exit_status;
+use crate::{SecretError, SecretStorage};
+
+/// A change to one field of a structured secret.
+pub enum FieldChange<'a> {
+    /// Sets the field to a string, or to stdin if no value is given (so that
+    /// it doesn't end up in shell history)
+    Set(Option<&'a str>),
+    Unset,
+}
+
+/// How a structured secret is written, which is kept when it's re-encoded.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Json,
+    Yaml,
+}
+
+/// Sets or removes a single field of a JSON or YAML secret. Nested fields are
+/// named by dot-separated keys (e.g. `db.password`).
+pub async fn edit_field<S, E>(
+    state: &State<S, E>,
+    secret_name: &str,
+    key: &str,
+    change: FieldChange<'_>,
+    signing_key: Option<&Path>,
+) -> Result<ExitStatus, FieldEditError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let secret = state
+        .secrets
+        .get(secret_name)
+        .ok_or_else(|| FieldEditError::NoSuchSecret(secret_name.to_string()))?;
+    let path = key.split('.').collect::<Vec<_>>();
+    if path.iter().any(|k| k.is_empty()) {
+        return Err(FieldEditError::InvalidKey(key.to_string()));
+    }
+    let value = match change {
+        FieldChange::Set(Some(value)) => Some(value.to_string()),
+        FieldChange::Set(None) => Some(read_stdin_value().await?),
+        FieldChange::Unset => None,
+    };
+
+    let identities = get_identities(&state.private_key_paths)?;
+    let original = fetch_plaintext(
+        &state.storage,
+        secret,
+        &identities,
+        state.large_secret_threshold,
+    )
+    .await
+    .map_err(|e| FieldEditError::FetchingSecret(Box::new(e)))?;
+    let (format, mut document) = parse(secret_name, &original)?;
+
+    let changed = match value {
+        Some(value) => set(&mut document, &path, value)?,
+        None => unset(&mut document, &path)?,
+    };
+    if !changed {
+        eprintln!("{secret_name}: {key} is unchanged, not uploading");
+        return Ok(exit_status(0));
+    }
+
+    let updated = encode(format, &document)?;
+    let encrypted_data = encrypt_bytes(Cursor::new(updated), &secret.encryption_keys).await?;
+    write_secret(
+        &state.storage,
+        secret,
+        encrypted_data.into(),
+        &secret.encryption_keys,
+        signing_key,
+        state.keep_previous(),
+    )
+    .await
+    .map_err(|e| FieldEditError::WritingToStore(Box::new(e)))?;
+    eprintln!("{secret_name}: updated {key}");
+
+    Ok(exit_status(0))
+}
+
+/// Reads a field's new value from stdin, without the trailing newline `echo`
+/// (or a heredoc) would add.
+async fn read_stdin_value() -> Result<String, FieldEditError> {
+    let mut value = String::new();
+    tokio::io::stdin()
+        .read_to_string(&mut value)
+        .await
+        .map_err(FieldEditError::ReadingValue)?;
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+
+    Ok(value)
+}
+
+/// Parses a secret's plaintext as a JSON or YAML mapping (JSON being valid
+/// YAML), remembering which it was.
+fn parse(secret_name: &str, plaintext: &[u8]) -> Result<(Format, Mapping), FieldEditError> {
+    let not_structured = || FieldEditError::NotStructured(secret_name.to_string());
+    let document = match serde_yaml::from_slice::<Value>(plaintext) {
+        Ok(Value::Mapping(m)) => m,
+        Ok(Value::Null) if plaintext.iter().all(u8::is_ascii_whitespace) => Mapping::new(),
+        _ => return Err(not_structured()),
+    };
+    let format = match plaintext.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') => Format::Json,
+        _ => Format::Yaml,
+    };
+
+    Ok((format, document))
+}
+
+fn encode(format: Format, document: &Mapping) -> Result<Vec<u8>, FieldEditError> {
+    match format {
+        Format::Json => {
+            let mut data = serde_json::to_vec_pretty(document)
+                .map_err(|e| FieldEditError::Encoding(e.to_string()))?;
+            data.push(b'\n');
+            Ok(data)
+        }
+        Format::Yaml => serde_yaml::to_string(document)
+            .map(String::into_bytes)
+            .map_err(|e| FieldEditError::Encoding(e.to_string())),
+    }
+}
+
+/// Sets the field at the given path, creating mappings on the way as needed.
+/// Returns whether anything changed.
+fn set(document: &mut Mapping, path: &[&str], value: String) -> Result<bool, FieldEditError> {
+    let (last, parents) = path.split_last().expect("keys are never empty");
+    let mut current = document;
+    for (i, key) in parents.iter().enumerate() {
+        let entry = current
+            .entry(Value::from(*key))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+        current = match entry {
+            Value::Mapping(m) => m,
+            _ => return Err(FieldEditError::NotAMapping(path[..=i].join("."))),
+        };
+    }
+
+    let value = Value::from(value);
+    match current.get(*last) {
+        Some(existing) if *existing == value => Ok(false),
+        _ => {
+            current.insert(Value::from(*last), value);
+            Ok(true)
+        }
+    }
+}
+
+/// Removes the field at the given path. Returns whether anything changed.
+fn unset(document: &mut Mapping, path: &[&str]) -> Result<bool, FieldEditError> {
+    let (last, parents) = path.split_last().expect("keys are never empty");
+    let mut current = document;
+    for (i, key) in parents.iter().enumerate() {
+        current = match current.get_mut(*key) {
+            Some(Value::Mapping(m)) => m,
+            Some(_) => return Err(FieldEditError::NotAMapping(path[..=i].join("."))),
+            None => return Ok(false),
+        };
+    }
+
+    // Retained rather than removed, which would move the last key into its
+    // place
+    let before = current.len();
+    current.retain(|k, _| k.as_str() != Some(*last));
+    Ok(current.len() != before)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum FieldEditError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("invalid key {0:?}")]
+    InvalidKey(String),
+    #[error("error reading value from stdin: {0}")]
+    ReadingValue(std::io::Error),
+    #[error("error reading identities: {0}")]
+    ReadingIdentities(#[from] DecryptionError),
+    #[error("{0}")]
+    FetchingSecret(Box<dyn std::error::Error>),
+    #[error("{0} isn't a JSON or YAML mapping")]
+    NotStructured(String),
+    #[error("{0} isn't a mapping")]
+    NotAMapping(String),
+    #[error("error encoding updated secret: {0}")]
+    Encoding(String),
+    #[error("error encrypting updated secret: {0}")]
+    EncryptingSecret(#[from] EncryptionError),
+    #[error("error uploading updated secret: {0}")]
+    WritingToStore(Box<dyn std::error::Error>),
+}
@@ -0,0 +1,29 @@
+//! Read-only introspection of the loaded state, for other tools (e.g. config
+//! drift detection) to consume.
+
+use std::process::ExitStatus;
+
+use super::State;
+use crate::util::exit_status;
+use crate::{SecretError, SecretStorage};
+
+/// Prints the state's snapshot, as YAML or JSON.
+pub fn state<S, E>(state: &State<S, E>, json: bool) -> ExitStatus
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let snapshot = state.snapshot();
+    match json {
+        true => println!(
+            "{}",
+            serde_json::to_string(&snapshot).expect("serializable")
+        ),
+        false => print!(
+            "{}",
+            serde_yaml::to_string(&snapshot).expect("serializable")
+        ),
+    }
+
+    exit_status(0)
+}
@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use age::secrecy::ExposeSecret;
+use ssh_key::rand_core::OsRng;
+use ssh_key::{Algorithm, LineEnding, PrivateKey};
+use tokio::io::AsyncWriteExt;
+
+use super::{KeyType, KeygenArgs, SealWith, State};
+use crate::age::{keychain_service, seal_tpm2, store_in_keychain};
+use crate::process::is_memory_backed;
+use crate::secret::{normalize_recipient, RecipientsRegistry};
+use crate::util::{exit_status, open_options_with_mode};
+use crate::{SecretError, SecretStorage};
+
+/// Generates a private key, returning it (in the format it's stored in) and
+/// its public key.
+fn generate(key_type: KeyType, comment: &str) -> Result<(String, String), KeygenError> {
+    match key_type {
+        KeyType::Age => {
+            let identity = age::x25519::Identity::generate();
+            let public_key = identity.to_public().to_string();
+            let created = humantime::format_rfc3339_seconds(std::time::SystemTime::now());
+            let private_key = format!(
+                "# created: {created}\n# public key: {public_key}\n{}\n",
+                identity.to_string().expose_secret()
+            );
+            Ok((private_key, public_key))
+        }
+        KeyType::SshEd25519 => {
+            let mut key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+                .map_err(KeygenError::Generating)?;
+            key.set_comment(comment);
+            let private_key = key
+                .to_openssh(LineEnding::LF)
+                .map_err(KeygenError::Generating)?
+                .to_string();
+            let public_key = key
+                .public_key()
+                .to_openssh()
+                .map_err(KeygenError::Generating)?;
+            Ok((private_key, public_key))
+        }
+    }
+}
+
+/// Writes a private key, readable only by us.
+async fn write_key(path: &Path, key: &[u8], force: bool) -> Result<(), KeygenError> {
+    let writing = |e| KeygenError::WritingKey(path.to_owned(), e);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(parent).await.map_err(writing)?;
+    }
+
+    let mut options = open_options_with_mode(0o600);
+    match force {
+        true => options.create(true).truncate(true),
+        false => options.create_new(true),
+    };
+    let mut file = options.write(true).open(path).await.map_err(writing)?;
+    file.write_all(key).await.map_err(writing)?;
+    file.flush().await.map_err(writing)
+}
+
+fn host_name() -> Result<String, KeygenError> {
+    #[cfg(unix)]
+    return nix::unistd::gethostname()
+        .map_err(|e| KeygenError::GettingHostName(e.into()))?
+        .into_string()
+        .map_err(|_| KeygenError::GettingHostName(std::io::ErrorKind::InvalidData.into()));
+    #[cfg(not(unix))]
+    return std::env::var("COMPUTERNAME")
+        .map_err(|_| KeygenError::GettingHostName(std::io::ErrorKind::NotFound.into()));
+}
+
+/// Checks that an ephemeral key won't be written to disk, where it would
+/// outlive the boot it was generated for.
+fn check_ephemeral_out(out: &Path) -> Result<(), KeygenError> {
+    // The key's directory may not exist yet, so check the closest one that
+    // does
+    let Some(dir) = out.ancestors().skip(1).find(|p| p.is_dir()) else {
+        return Ok(());
+    };
+    match is_memory_backed(dir) {
+        Some(true) => Ok(()),
+        Some(false) => Err(KeygenError::EphemeralOnDisk(out.to_owned())),
+        None => {
+            log::warn!(
+                "can't tell whether {} is memory-backed, make sure it doesn't persist across reboots",
+                out.to_string_lossy()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Generates and writes a key, printing its public key. Returns the public
+/// key and the name it belongs to.
+async fn create_key(args: &KeygenArgs) -> Result<(String, String), KeygenError> {
+    let name = match &args.name {
+        Some(name) => name.clone(),
+        None => host_name()?,
+    };
+    if args.ephemeral {
+        check_ephemeral_out(&args.out)?;
+    }
+    let (private_key, public_key) = generate(args.key_type, &name)?;
+    if let Some(service) = keychain_service(&args.out.to_string_lossy()) {
+        store_in_keychain(service, &private_key).map_err(KeygenError::Sealing)?;
+        log::info!("stored private key in the Keychain as {service}");
+        println!("{public_key}");
+        return Ok((name, public_key));
+    }
+
+    let private_key = match args.seal {
+        Some(SealWith::Tpm2) => seal_tpm2(&private_key).map_err(KeygenError::Sealing)?,
+        None => private_key.into_bytes(),
+    };
+    // Ephemeral keys replace whatever the last boot generated
+    write_key(&args.out, &private_key, args.force || args.ephemeral).await?;
+    match args.seal {
+        Some(SealWith::Tpm2) => log::info!(
+            "wrote sealed private key to {} (use it as tpm2:{0})",
+            args.out.to_string_lossy()
+        ),
+        None => log::info!("wrote private key to {}", args.out.to_string_lossy()),
+    }
+    println!("{public_key}");
+
+    Ok((name, public_key))
+}
+
+/// Generates a key without registering it (which doesn't need any config).
+pub async fn keygen(args: &KeygenArgs) -> Result<ExitStatus, KeygenError> {
+    create_key(args).await?;
+
+    Ok(exit_status(0))
+}
+
+/// Generates a key, and adds it to the recipients registry in storage.
+pub async fn keygen_and_register<S, E>(
+    state: &State<S, E>,
+    args: &KeygenArgs,
+    registry_path: &Path,
+) -> Result<ExitStatus, KeygenError>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError + 'static,
+{
+    let fetching = |e| KeygenError::UpdatingRegistry(Box::new(e));
+    // Check for conflicts before generating anything, so that a clash doesn't
+    // leave behind an unregistered key
+    let mut registry = RecipientsRegistry::read(&state.storage, registry_path)
+        .await
+        .map_err(fetching)?;
+    // Ephemeral keys always replace this machine's previous key
+    let force = args.force || args.ephemeral;
+    if let Some(name) = &args.name {
+        if registry.keys.contains_key(name) && !force {
+            return Err(KeygenError::AlreadyRegistered(name.clone()));
+        }
+    }
+
+    let (name, public_key) = create_key(args).await?;
+    match registry.keys.get(&name) {
+        Some(existing) if !force && existing != &normalize_recipient(&public_key) => {
+            return Err(KeygenError::AlreadyRegistered(name));
+        }
+        _ => (),
+    }
+    registry
+        .keys
+        .insert(name.clone(), normalize_recipient(&public_key));
+    registry
+        .write(&state.storage, registry_path)
+        .await
+        .map_err(fetching)?;
+    log::info!("registered {name} in {}", registry_path.to_string_lossy());
+
+    Ok(exit_status(0))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeygenError {
+    #[error("error generating key: {0}")]
+    Generating(ssh_key::Error),
+    #[error("error sealing key: {0}")]
+    Sealing(String),
+    #[error("error writing key to {0}: {1}")]
+    WritingKey(PathBuf, std::io::Error),
+    #[error("couldn't determine host name (use --name): {0}")]
+    GettingHostName(std::io::Error),
+    #[error("refusing to write ephemeral key to {0}, which is not memory-backed")]
+    EphemeralOnDisk(PathBuf),
+    #[error("{0} is already registered (use --force to replace it)")]
+    AlreadyRegistered(String),
+    #[error("error updating recipients registry: {0}")]
+    UpdatingRegistry(Box<dyn std::error::Error>),
+}
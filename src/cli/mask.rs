@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::process::ExitStatus;
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use super::secret::select_secrets;
+use super::{MaskFormat, State};
+use crate::age::{get_identities, DecryptionError};
+use crate::secret::{decrypt_secret, read_secret};
+use crate::util::exit_status;
+use crate::{MultiError, Secret, SecretError, SecretStorage};
+
+/// Shortest value GitLab will mask.
+const GITLAB_MIN_MASKED_LEN: usize = 8;
+
+/// Prints what CI should mask in its logs for the given secrets (or every
+/// exposed one): SHA-256 digests of their values, or the values themselves if
+/// `literal` is set.
+pub async fn mask_list<S, E>(
+    state: &State<S, E>,
+    secret_names: &[String],
+    format: MaskFormat,
+    literal: bool,
+) -> Result<ExitStatus, MaskListError>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError + 'static,
+{
+    if !literal && matches!(format, MaskFormat::Github | MaskFormat::Gitlab) {
+        let name = format.to_possible_value().expect("no skipped formats");
+        return Err(MaskListError::NeedsLiteral(name.get_name().to_string()));
+    }
+    let secrets = match secret_names.is_empty() {
+        true => exposed_secrets(state)?,
+        false => {
+            select_secrets(&state.secrets, secret_names).map_err(MaskListError::NoSuchSecret)?
+        }
+    };
+    let identities = get_identities(&state.private_key_paths)?;
+
+    let mut values = BTreeMap::new();
+    let mut errors = MultiError::<Box<dyn std::error::Error>>::default();
+    for secret in secrets {
+        match fetch_value(
+            &state.storage,
+            secret,
+            &identities,
+            state.large_secret_threshold,
+        )
+        .await
+        {
+            Ok(Some(value)) => {
+                values.insert(secret.name.as_str(), value);
+            }
+            Ok(None) => log::warn!("{} isn't text, so it can't be masked", secret.name),
+            Err(e) => errors.push(&secret.name, e),
+        }
+    }
+    // Nothing is printed unless everything can be, so that a job doesn't
+    // carry on with only some of its secrets masked
+    errors.into_result().map_err(MaskListError::Fetching)?;
+
+    let mask = |s: &str| match literal {
+        true => s.to_string(),
+        false => format!("{:x}", Sha256::digest(s)),
+    };
+    match format {
+        MaskFormat::Lines => {
+            for line in values.values().flat_map(|v| masked_lines(v)) {
+                println!("{}", mask(line));
+            }
+        }
+        MaskFormat::Github => {
+            for line in values.values().flat_map(|v| masked_lines(v)) {
+                println!("::add-mask::{}", mask(line));
+            }
+        }
+        MaskFormat::Json => {
+            let object = values
+                .iter()
+                .map(|(name, value)| (*name, mask(value)))
+                .collect::<BTreeMap<_, _>>();
+            println!("{}", serde_json::to_string(&object).expect("serializable"));
+        }
+        MaskFormat::Gitlab => {
+            let variables = values
+                .iter()
+                .map(|(name, value)| {
+                    if value.contains('\n') || value.len() < GITLAB_MIN_MASKED_LEN {
+                        log::warn!(
+                            "GitLab won't mask {name} (masked values are a single line of at least {GITLAB_MIN_MASKED_LEN} characters)"
+                        );
+                    }
+                    serde_json::json!({
+                        "key": gitlab_key(name),
+                        "value": mask(value),
+                        "masked": true,
+                        "raw": true,
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::to_string(&variables).expect("serializable")
+            );
+        }
+    }
+
+    Ok(exit_status(0))
+}
+
+/// Every secret with a file or env exposure.
+fn exposed_secrets<S, E>(state: &State<S, E>) -> Result<Vec<&Secret>, MaskListError>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+{
+    let exposures = &state.exposures;
+    let mut names = exposures
+        .files
+        .keys()
+        .chain(exposures.envs.keys())
+        .cloned()
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    if names.is_empty() {
+        return Err(MaskListError::NothingExposed);
+    }
+
+    select_secrets(&state.secrets, &names).map_err(MaskListError::NoSuchSecret)
+}
+
+/// Fetches and decrypts a secret, without its trailing newline (which
+/// doesn't show up in logs). Secrets that aren't text give `None`.
+async fn fetch_value<S>(
+    storage: &S,
+    secret: &Secret,
+    identities: &[Box<dyn age::Identity>],
+    threshold: u64,
+) -> Result<Option<String>, Box<dyn std::error::Error>>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let reader = read_secret(storage, secret, threshold).await?;
+    let mut plaintext = Vec::new();
+    decrypt_secret(reader, secret, identities)
+        .await?
+        .read_to_end(&mut plaintext)
+        .await?;
+    let Ok(mut value) = String::from_utf8(plaintext) else {
+        return Ok(None);
+    };
+    let len = value.trim_end_matches(['\n', '\r']).len();
+    value.truncate(len);
+
+    Ok(Some(value))
+}
+
+/// Lines of a value to mask individually, for formats that can't mask
+/// multi-line values (logs are scrubbed a line at a time).
+fn masked_lines(value: &str) -> impl Iterator<Item = &str> {
+    value.lines().filter(|l| !l.trim().is_empty())
+}
+
+/// Name of the CI variable holding a secret, which GitLab only allows letters,
+/// digits and underscores in.
+fn gitlab_key(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect()
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MaskListError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("no secrets are exposed (name some to list them instead)")]
+    NothingExposed,
+    #[error("{0} masks need the values themselves, pass --literal to print them")]
+    NeedsLiteral(String),
+    #[error("loading identities: {0}")]
+    LoadingIdentities(#[from] DecryptionError),
+    #[error("error fetching secrets: {0}")]
+    Fetching(MultiError<Box<dyn std::error::Error>>),
+}
@@ -1,28 +1,121 @@
-use std::os::unix::process::ExitStatusExt;
 use std::process::ExitStatus;
 
+pub mod approve;
 pub mod args;
 pub use args::*;
+pub mod audit;
+pub mod backup;
+pub mod breakglass;
+pub mod clean;
+pub mod fields;
+pub mod inspect;
+pub mod keygen;
+pub mod mask;
+pub mod prefetch;
 pub mod process;
+pub mod report;
 pub mod secret;
+#[cfg(unix)]
+pub mod serve;
 pub mod state;
+pub mod storage;
+#[cfg(unix)]
 pub mod system;
 pub use state::*;
 
-use crate::{ProcessRunningError, SecretError, SecretStorage};
+use crate::secret::Operation;
+use crate::util::exit_status;
+use crate::watch::PollSchedule;
+use crate::{MigratingSecretStorage, ProcessRunningError, SecretError, SecretStorage};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[cfg(unix)]
     #[error("mounting secrets: {0}")]
     MountingSecrets(#[from] system::MountSecretsError),
+    #[cfg(unix)]
     #[error("unmounting secrets: {0}")]
     UnmountingSecrets(#[from] system::UnmountSecretsError),
+    #[cfg(unix)]
+    #[error("comparing generations: {0}")]
+    DiffingGenerations(#[from] crate::system::DiffGenerationError),
     #[error("running subcommand: {0}")]
     RunningProcess(#[from] process::ProcessRunningError),
     #[error("uploading secret: {0}")]
     UploadingSecret(#[from] secret::CreateUpdateSecretError),
     #[error("editing secret: {0}")]
     EditingSecret(#[from] secret::EditSecretError),
+    #[error("uploading secrets: {0}")]
+    UploadingSecrets(#[from] secret::UploadDirError),
+    #[error("verifying secrets: {0}")]
+    VerifyingSecrets(#[from] secret::VerifySecretsError),
+    #[error("piping secret: {0}")]
+    PipingSecret(#[from] secret::PipeSecretError),
+    #[error("auditing secrets: {0}")]
+    AuditingSecrets(#[from] audit::AuditError),
+    #[error("inspecting secret: {0}")]
+    InspectingSecret(#[from] audit::InspectError),
+    #[error("rekeying secrets: {0}")]
+    RekeyingSecrets(#[from] secret::RekeyError),
+    #[error("backing up secrets: {0}")]
+    BackingUp(#[from] backup::BackupError),
+    #[error("break-glass access: {0}")]
+    BreakGlass(#[from] breakglass::BreakGlassError),
+    #[error("cleaning up: {0}")]
+    CleaningUp(#[from] clean::CleanError),
+    #[error("generating key: {0}")]
+    GeneratingKey(#[from] keygen::KeygenError),
+    #[error("approval: {0}")]
+    Approving(#[from] approve::ApproveError),
+    #[error("removing secret: {0}")]
+    RemovingSecret(#[from] secret::RemoveSecretError),
+    #[error("appending to secret: {0}")]
+    AppendingSecret(#[from] secret::AppendSecretError),
+    #[error("editing secret field: {0}")]
+    EditingField(#[from] fields::FieldEditError),
+    #[error("restoring secret: {0}")]
+    RestoringSecret(#[from] secret::RestoreSecretError),
+    #[error("migrating storage: {0}")]
+    MigratingStorage(#[from] storage::MigrateError),
+    #[error("reporting: {0}")]
+    Reporting(#[from] report::ReportError),
+    #[error("prefetching secrets: {0}")]
+    Prefetching(#[from] prefetch::PrefetchError),
+    #[error("listing masks: {0}")]
+    ListingMasks(#[from] mask::MaskListError),
+    #[cfg(unix)]
+    #[error("serving secrets: {0}")]
+    Serving(#[from] serve::ServeError),
+    #[error("{0} modifies stored secrets, which is disabled in read-only mode")]
+    ReadOnly(&'static str),
+    #[error("refusing to run {0} as root (pass --allow-root to do so anyway)")]
+    RunningAsRoot(&'static str),
+}
+
+/// Refuses to run commands that modify stored secrets, if configured to be
+/// read-only.
+fn ensure_writable<S, E>(state: &State<S, E>, command: &'static str) -> Result<(), Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+{
+    match state.read_only {
+        true => Err(Error::ReadOnly(command)),
+        false => Ok(()),
+    }
+}
+
+/// Refuses to handle plaintext as root unless allowed to, since files (and
+/// shell history) written on the way would end up belonging to root.
+fn ensure_not_root(command: &'static str, allow_root: bool) -> Result<(), Error> {
+    #[cfg(unix)]
+    if !allow_root && nix::unistd::geteuid().is_root() {
+        return Err(Error::RunningAsRoot(command));
+    }
+    #[cfg(not(unix))]
+    let _ = (command, allow_root);
+
+    Ok(())
 }
 
 pub async fn process<S, E>(state: &State<S, E>, args: RunCommandArgs) -> Result<ExitStatus, Error>
@@ -32,34 +125,379 @@ where
     <S as SecretStorage>::Error: 'static,
     ProcessRunningError: From<E>,
 {
-    let res = process::run(state, &args.cmd).await?;
+    let options = process::RunOptions {
+        secrets_dir_env: (!args.secrets_dir_env.is_empty()).then_some(&*args.secrets_dir_env),
+        secrets_tmpdir: args.secrets_tmpdir.as_deref(),
+        ..Default::default()
+    };
+    let res = process::run(state, &args.cmd, options).await?;
     Ok(res)
 }
 
-pub async fn system<S, E>(state: &State<S, E>, action: SystemAction) -> Result<ExitStatus, Error>
+pub async fn run_script<S, E>(state: &State<S, E>, args: RunScriptArgs) -> Result<ExitStatus, Error>
 where
     S: SecretStorage<Error = E>,
     E: SecretError,
     <S as SecretStorage>::Error: 'static,
+    ProcessRunningError: From<E>,
 {
-    match action {
-        SystemAction::Mount(a) => system::mount(state, &a.mount_point, &a.secret_dir).await?,
+    let script = tokio::fs::read(&args.script)
+        .await
+        .map_err(|e| process::ProcessRunningError::ReadingScript(args.script.clone(), e))?;
+    // `--` stops the shell from reading the script's arguments as its own
+    let argv = [args.shell, "-s".to_string(), "--".to_string()]
+        .into_iter()
+        .chain(args.args)
+        .collect::<Vec<_>>();
+    let options = process::RunOptions {
+        secrets_dir_env: (!args.secrets_dir_env.is_empty()).then_some(&*args.secrets_dir_env),
+        secrets_tmpdir: args.secrets_tmpdir.as_deref(),
+        stdin: Some(&script),
+        ..Default::default()
+    };
+    let res = process::run(state, &argv, options).await?;
+    Ok(res)
+}
+
+#[cfg(unix)]
+pub async fn docker_run<S, E>(state: &State<S, E>, args: DockerRunArgs) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+    ProcessRunningError: From<E>,
+{
+    let options = process::RunOptions {
+        secrets_dir_env: (!args.secrets_dir_env.is_empty()).then_some(&*args.secrets_dir_env),
+        secrets_tmpdir: args.secrets_tmpdir.as_deref(),
+        container: Some(crate::Container::Run {
+            runtime: &args.docker,
+            secrets_dir: &args.secrets_dir,
+        }),
+        ..Default::default()
+    };
+    let res = process::run(state, &args.args, options).await?;
+    Ok(res)
+}
+
+#[cfg(unix)]
+pub async fn buildkit_secret<S, E>(
+    state: &State<S, E>,
+    args: BuildkitSecretArgs,
+) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+    ProcessRunningError: From<E>,
+{
+    let options = process::RunOptions {
+        secrets_tmpdir: args.secrets_tmpdir.as_deref(),
+        files: &args.names,
+        container: Some(crate::Container::Build {
+            runtime: &args.docker,
+        }),
+        ..Default::default()
+    };
+    let res = process::run(state, &args.args, options).await?;
+    Ok(res)
+}
+
+#[cfg(unix)]
+pub async fn system<S, E>(state: &State<S, E>, action: SystemAction) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E> + Sync,
+    E: SecretError + Send,
+    <S as SecretStorage>::Error: 'static,
+{
+    let status = match action {
+        SystemAction::Mount(a) => {
+            system::mount(
+                state,
+                &a.mount_point,
+                &a.secret_dir,
+                a.offline,
+                &a.cache_dir,
+                &a.container_bind,
+                a.watch.then_some(PollSchedule {
+                    interval: a.poll_interval,
+                    jitter: a.poll_jitter,
+                    max_backoff: a.max_backoff,
+                }),
+            )
+            .await?
+        }
         SystemAction::Unmount(a) => system::unmount(&a.mount_point, &a.secret_dir).await?,
+        SystemAction::Diff(a) => system::diff(state, &a.secret_dir, a.json).await?,
+    };
+
+    Ok(status)
+}
+
+pub async fn secret<S, E>(s: &State<S, E>, args: SecretArgs) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E> + Sync,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let allow_root = args.allow_root;
+    match args.action {
+        SecretAction::Edit(a) => {
+            ensure_writable(s, "secret edit")?;
+            ensure_not_root("secret edit", allow_root)?;
+            let signing_key = a.sign_with.as_deref();
+            let allow_empty = a.allow_empty;
+            let confirm = match a.yes {
+                true => secret::Confirm::Skip,
+                false => secret::Confirm::Ask { diff: a.diff },
+            };
+            let res = match (&a.secret_name, &a.all_matching) {
+                (_, Some(pattern)) => {
+                    secret::edit_matching(s, &a.editor, pattern, signing_key, confirm, allow_empty)
+                        .await
+                }
+                (Some(name), None) => {
+                    let no_tempfile = a.no_tempfile;
+                    secret::edit(
+                        s,
+                        &a.editor,
+                        name,
+                        signing_key,
+                        confirm,
+                        allow_empty,
+                        no_tempfile,
+                    )
+                    .await
+                }
+                (None, None) => unreachable!("clap requires a secret name or pattern"),
+            };
+            return Ok(res?);
+        }
+        SecretAction::Upload(a) => {
+            ensure_writable(s, "secret upload")?;
+            ensure_not_root("secret upload", allow_root)?;
+            let signing_key = a.sign_with.as_deref();
+            let source = Some(a.source_file.as_path());
+            secret::create(s, &a.secret_name, source, signing_key, a.allow_empty).await?
+        }
+        SecretAction::UploadDir(a) => {
+            ensure_writable(s, "secret upload-dir")?;
+            ensure_not_root("secret upload-dir", allow_root)?;
+            let signing_key = a.sign_with.as_deref();
+            let keys = &a.encryption_keys;
+            let res = secret::upload_dir(
+                s,
+                &a.source_dir,
+                a.generate_config,
+                keys,
+                signing_key,
+                a.allow_empty,
+            );
+            return Ok(res.await?);
+        }
+        SecretAction::List(a) => return Ok(audit::list(s, &a.secret_names, a.long)?),
+        SecretAction::Verify(a) => return Ok(secret::verify(s, &a.secret_names).await?),
+        SecretAction::Audit(a) => return Ok(audit::audit(s, &a.secret_names).await?),
+        SecretAction::Inspect(a) => return Ok(audit::inspect(s, &a.secret_name).await?),
+        SecretAction::Encrypt(a) => secret::encrypt_stream(s, &a.secret_name, a.armor).await?,
+        SecretAction::Decrypt(a) => secret::decrypt_stream(s, &a.secret_name).await?,
+        SecretAction::Remove(a) => {
+            ensure_writable(s, "secret remove")?;
+            let operation = Operation::RemoveSecret {
+                name: a.secret_name.clone(),
+            };
+            let approval = a.approval.as_deref();
+            approve::ensure_approved(s, &operation, approval, a.sign_with.as_deref()).await?;
+            secret::remove(s, &a.secret_name).await?
+        }
+        SecretAction::Append(a) => {
+            ensure_writable(s, "secret append")?;
+            ensure_not_root("secret append", allow_root)?;
+            secret::append(s, &a.secret_name, a.sign_with.as_deref()).await?
+        }
+        SecretAction::Set(a) => {
+            ensure_writable(s, "secret set")?;
+            ensure_not_root("secret set", allow_root)?;
+            let change = fields::FieldChange::Set(a.value.as_deref());
+            let signing_key = a.sign_with.as_deref();
+            return Ok(fields::edit_field(s, &a.secret_name, &a.key, change, signing_key).await?);
+        }
+        SecretAction::Unset(a) => {
+            ensure_writable(s, "secret unset")?;
+            ensure_not_root("secret unset", allow_root)?;
+            let change = fields::FieldChange::Unset;
+            let signing_key = a.sign_with.as_deref();
+            return Ok(fields::edit_field(s, &a.secret_name, &a.key, change, signing_key).await?);
+        }
+        SecretAction::Restore(a) => {
+            ensure_writable(s, "secret restore")?;
+            secret::restore(s, &a.secret_name).await?
+        }
     };
 
-    Ok(ExitStatus::from_raw(0))
+    Ok(exit_status(0))
 }
 
-pub async fn secret<S, E>(s: &State<S, E>, action: SecretAction) -> Result<ExitStatus, Error>
+pub async fn rekey<S, E>(s: &State<S, E>, args: RekeyArgs) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    ensure_writable(s, "rekey")?;
+    let signing_key = args.sign_with.as_deref();
+    if !args.remove.is_empty() || args.sync {
+        let operation = Operation::rekey(&args.secret_names, &args.remove, args.sync);
+        let approval = args.approval.as_deref();
+        approve::ensure_approved(s, &operation, approval, signing_key).await?;
+    }
+    let change = match args.sync {
+        true => secret::RecipientChange::Sync,
+        false => secret::RecipientChange::Modify {
+            add: &args.add,
+            remove: &args.remove,
+        },
+    };
+    let res = secret::rekey(s, &args.secret_names, change, signing_key).await?;
+    Ok(res)
+}
+
+pub async fn break_glass<S, E>(
+    s: &State<S, E>,
+    action: BreakGlassAction,
+) -> Result<ExitStatus, Error>
 where
     S: SecretStorage<Error = E>,
     E: SecretError,
     <S as SecretStorage>::Error: 'static,
 {
     match action {
-        SecretAction::Edit(a) => secret::edit(s, &a.editor, &a.secret_name).await?,
-        SecretAction::Upload(a) => secret::create(s, &a.secret_name, Some(&a.source_file)).await?,
+        BreakGlassAction::Decrypt(a) => {
+            let output = a.output.as_deref();
+            breakglass::decrypt(s, &a.secret_name, &a.identity, &a.reason, output).await?
+        }
+    };
+
+    Ok(exit_status(0))
+}
+
+pub async fn backup<S, E>(s: &State<S, E>, action: BackupAction) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let res = match action {
+        BackupAction::Create(a) => backup::create(s, &a.file, &a.recipients).await?,
+        BackupAction::Restore(a) => {
+            ensure_writable(s, "backup restore")?;
+            backup::restore(s, &a.file).await?
+        }
+    };
+
+    Ok(res)
+}
+
+/// Approves an operation, which doesn't need any config.
+pub async fn prefetch<S, E>(s: &State<S, E>, args: PrefetchArgs) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E> + Sync,
+    E: SecretError + Send + 'static,
+{
+    Ok(prefetch::prefetch(s, &args.cache_dir, &args.secret_names).await?)
+}
+
+pub async fn mask_list<S, E>(s: &State<S, E>, args: MaskListArgs) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError + 'static,
+{
+    Ok(mask::mask_list(s, &args.secret_names, args.format, args.literal).await?)
+}
+
+pub async fn storage<S, E>(
+    s: &State<MigratingSecretStorage<S>, E>,
+    action: StorageAction,
+) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E> + Sync + Send,
+    E: SecretError + Send + 'static,
+{
+    let res = match action {
+        StorageAction::Migrate(a) => {
+            if a.copy_all {
+                ensure_writable(s, "storage migrate --copy-all")?;
+            }
+            storage::migrate(s, a.copy_all).await?
+        }
+        StorageAction::Check => storage::check(s).await,
+    };
+
+    Ok(res)
+}
+
+pub async fn report<S, E>(s: &State<S, E>, action: ReportAction) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+{
+    let res = match action {
+        ReportAction::Exposures(a) => report::exposures(s, &a.config_files).await?,
     };
 
-    Ok(ExitStatus::from_raw(0))
+    Ok(res)
+}
+
+pub async fn inspect<S, E>(s: &State<S, E>, action: InspectAction) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+{
+    let res = match action {
+        InspectAction::State(a) => inspect::state(s, a.json),
+    };
+
+    Ok(res)
+}
+
+#[cfg(unix)]
+pub async fn serve<S, E>(s: &State<S, E>, args: ServeArgs) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let http = args.http.as_ref().map(|address| serve::HttpEndpoint {
+        address,
+        secrets: &args.http_secret,
+    });
+    Ok(serve::serve(s, &args.socket, http).await?)
+}
+
+pub async fn approve(args: ApproveArgs) -> Result<ExitStatus, Error> {
+    Ok(approve::approve(args).await?)
+}
+
+pub async fn clean(args: CleanArgs) -> Result<ExitStatus, Error> {
+    Ok(clean::clean(&args).await?)
+}
+
+/// Generates a key without registering it, which doesn't need any config.
+pub async fn keygen(args: &KeygenArgs) -> Result<ExitStatus, Error> {
+    Ok(keygen::keygen(args).await?)
+}
+
+/// Generates a key, registering it in storage if asked to.
+pub async fn register_key<S, E>(state: &State<S, E>, args: KeygenArgs) -> Result<ExitStatus, Error>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError + 'static,
+{
+    match &args.register {
+        Some(path) => {
+            ensure_writable(state, "keygen --register")?;
+            Ok(keygen::keygen_and_register(state, &args, path).await?)
+        }
+        None => Ok(keygen::keygen(&args).await?),
+    }
 }
@@ -0,0 +1,62 @@
+use std::path::Path;
+use std::process::ExitStatus;
+
+use super::secret::select_secrets;
+use super::State;
+use crate::secret::{read_secret, ReadSecretError};
+use crate::util::exit_status;
+use crate::{CacheMode, CachedSecretStorage, MultiError, SecretError, SecretStorage};
+
+/// Downloads the ciphertext of the given secrets (or all of them) into the
+/// cache, without decrypting anything, so that later mounts can use it.
+pub async fn prefetch<S, E>(
+    state: &State<S, E>,
+    cache_dir: &Path,
+    secret_names: &[String],
+) -> Result<ExitStatus, PrefetchError>
+where
+    S: SecretStorage<Error = E> + Sync,
+    E: SecretError + Send + 'static,
+{
+    let secrets =
+        select_secrets(&state.secrets, secret_names).map_err(PrefetchError::NoSuchSecret)?;
+    let threshold = state.large_secret_threshold;
+    let storage = CachedSecretStorage::new(
+        &state.storage,
+        cache_dir.to_owned(),
+        CacheMode::Refresh,
+        threshold,
+    );
+
+    let mut errors = MultiError::<Box<dyn std::error::Error>>::default();
+    for secret in secrets.iter() {
+        // Reading through the cache is what fills it, including signatures,
+        // and checks pins and signatures on the way
+        let res = match read_secret(&storage, secret, threshold).await {
+            Ok(mut reader) => tokio::io::copy(&mut reader, &mut tokio::io::sink())
+                .await
+                .map_err(ReadSecretError::ReadingCiphertext),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(size) => log::info!("cached {} ({size} bytes)", secret.name),
+            Err(e) => errors.push(&secret.name, Box::new(e)),
+        }
+    }
+    errors.into_result().map_err(PrefetchError::Fetching)?;
+
+    eprintln!(
+        "prefetched {} secret(s) into {}",
+        secrets.len(),
+        cache_dir.to_string_lossy()
+    );
+    Ok(exit_status(0))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PrefetchError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("error fetching ciphertext: {0}")]
+    Fetching(MultiError<Box<dyn std::error::Error>>),
+}
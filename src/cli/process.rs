@@ -1,12 +1,33 @@
+use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
 
 use super::{ExposureLoadingError, State};
 use crate::age::{get_identities, DecryptionError};
+use crate::secret::FileExposeArgs;
+#[cfg(unix)]
+use crate::Container;
 use crate::{process, SecretError, SecretStorage};
 
+/// How `run` runs its command, beyond what's in config.
+#[derive(Default)]
+pub struct RunOptions<'a> {
+    /// Overrides `secrets_dir_env` in config
+    pub secrets_dir_env: Option<&'a [String]>,
+    /// Overrides `secrets_tmpdir` in config
+    pub secrets_tmpdir: Option<&'a Path>,
+    /// Passed to the command on stdin
+    pub stdin: Option<&'a [u8]>,
+    /// Secrets to expose as files (without vanity paths), if config doesn't
+    /// already
+    pub files: &'a [String],
+    #[cfg(unix)]
+    pub container: Option<Container<'a>>,
+}
+
 pub async fn run<S, E>(
     state: &State<S, E>,
     argv: &[String],
+    options: RunOptions<'_>,
 ) -> Result<ExitStatus, ProcessRunningError>
 where
     S: SecretStorage<Error = E>,
@@ -16,16 +37,47 @@ where
 {
     log::debug!("{} env exposures", state.exposures.envs.len());
     log::debug!("{} file exposures", state.exposures.files.len());
+    state.warn_deprecated();
     let identities = get_identities(&state.private_key_paths)?;
     log::debug!("found {} identities", identities.len());
-    let result = process::run_process(
-        argv,
-        &state.secrets,
-        &state.exposures,
-        &identities,
-        &state.storage,
-    )
-    .await?;
+    let cwd = std::env::current_dir().map_err(ProcessRunningError::GettingWorkingDirectory)?;
+    let mut exposures = state.exposures.with_root(&cwd);
+    for name in options.files {
+        exposures.files.entry(name.clone()).or_insert_with(|| {
+            vec![FileExposeArgs {
+                secret_name: name.clone(),
+                vanity_path: None,
+                mode: None,
+                owner: None,
+                group: None,
+                link_mode: Default::default(),
+                acl: Vec::new(),
+                optional: false,
+                after: Vec::new(),
+                force_adopt: false,
+            }]
+        });
+    }
+    let secrets_dir_env = options.secrets_dir_env.unwrap_or(&state.secrets_dir_env);
+    let secrets_tmpdir = options.secrets_tmpdir.or(state.secrets_tmpdir.as_deref());
+    let mut runner = process::CommandRunner::new(argv.iter().cloned(), &state.storage)
+        .secrets_dir_env(secrets_dir_env.iter().cloned())
+        .secrets(&state.secrets)
+        .exposures(&exposures)
+        .identities(&identities)
+        .disk_backed_tmpdir(state.disk_backed_tmpdir)
+        .large_secret_threshold(state.large_secret_threshold);
+    if let Some(dir) = secrets_tmpdir {
+        runner = runner.secrets_tmpdir(dir);
+    }
+    if let Some(input) = options.stdin {
+        runner = runner.stdin(input);
+    }
+    #[cfg(unix)]
+    if let Some(container) = options.container {
+        runner = runner.container(container);
+    }
+    let result = runner.run().await?;
     log::debug!(
         "process exited with status {}",
         result
@@ -38,8 +90,12 @@ where
 
 #[derive(thiserror::Error, Debug)]
 pub enum ProcessRunningError {
+    #[error("reading script {0}: {1}")]
+    ReadingScript(PathBuf, std::io::Error),
     #[error("loading exposures: {0}")]
     LoadingExposures(#[from] ExposureLoadingError),
+    #[error("getting working directory: {0}")]
+    GettingWorkingDirectory(std::io::Error),
     #[error("loading identities: {0}")]
     LoadingIdentities(#[from] DecryptionError),
     #[error("running process: {0}")]
@@ -0,0 +1,146 @@
+//! Reports on how secrets are used, across the loaded config and any other
+//! config files (e.g. those of other hosts or profiles).
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use serde::Serialize;
+
+use super::{ExposureSource, SecretReference, State};
+use crate::util::{exit_status, partition_specs};
+use crate::{ExposureSpec, SecretError, SecretManagerConfig, SecretStorage};
+
+#[derive(Serialize, Debug)]
+struct ExposureReport {
+    exposure: String,
+    source: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct SecretUsage {
+    /// Set for secrets that are exposed, but not defined in any of the config
+    /// files read
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    undefined: bool,
+    exposures: Vec<ExposureReport>,
+}
+
+/// Secrets used by the given exposures, from a config file.
+async fn references(
+    file: &Path,
+    source: &ExposureSource,
+    specs: Vec<ExposureSpec>,
+) -> Result<Vec<SecretReference>, ReportError> {
+    let (files, envs, templates) = partition_specs(specs);
+    let mut references = files
+        .iter()
+        .map(|e| SecretReference::file(e, source))
+        .chain(envs.iter().map(|e| SecretReference::env(e, source)))
+        .collect::<Vec<_>>();
+    for mut template in templates {
+        // As when loading config, templates are relative to their config file
+        if template.template.is_relative() {
+            let dir = file.parent().unwrap_or(Path::new("."));
+            template.template = dir.join(&template.template);
+        }
+        let found = SecretReference::template(&template, source)
+            .await
+            .map_err(|e| ReportError::ReadingTemplate(template.template.clone(), e))?;
+        references.extend(found);
+    }
+
+    Ok(references)
+}
+
+/// Reads a config file's exposures (including its tenants'), and the names of
+/// the secrets it defines. Overrides from the environment aren't applied, as
+/// the file may be for another host.
+async fn read_config_file(file: &Path) -> Result<(Vec<SecretReference>, Vec<String>), ReportError> {
+    let data = tokio::fs::read(file)
+        .await
+        .map_err(|e| ReportError::ReadingConfigFile(file.to_owned(), e))?;
+    let configs = SecretManagerConfig::from_documents(&data, &mut Default::default())
+        .map_err(|e| ReportError::ParsingConfigFile(file.to_owned(), e))?;
+
+    let mut found = Vec::new();
+    let mut secrets = Vec::new();
+    for config in configs {
+        let source = ExposureSource::ConfigFile(file.to_owned());
+        let specs = config.exposures.unwrap_or_default();
+        found.extend(references(file, &source, specs).await?);
+        secrets.extend(config.secrets.into_iter().flatten().map(|s| s.name));
+        for (name, tenant) in config.tenants.into_iter().flatten() {
+            let source = ExposureSource::Tenant(file.to_owned(), name.into());
+            found.extend(references(file, &source, tenant.exposures).await?);
+            secrets.extend(tenant.secrets.into_iter().map(|s| s.name));
+        }
+    }
+
+    Ok((found, secrets))
+}
+
+/// Prints every exposure of each secret, from the loaded config and the given
+/// config files, as YAML. Secrets that aren't exposed anywhere are listed with
+/// no exposures, so that they can be found before being deleted.
+pub async fn exposures<S, E>(
+    state: &State<S, E>,
+    config_files: &[PathBuf],
+) -> Result<ExitStatus, ReportError>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let mut usage = state
+        .secrets
+        .keys()
+        .map(|name| (name.clone(), SecretUsage::default()))
+        .collect::<BTreeMap<_, _>>();
+    let mut references = state.references.clone();
+    for file in config_files {
+        let (found, secrets) = read_config_file(file).await?;
+        references.extend(found);
+        for name in secrets {
+            usage.entry(name).or_default();
+        }
+    }
+
+    for reference in references {
+        let entry = usage
+            .entry(reference.secret_name)
+            .or_insert_with(|| SecretUsage {
+                undefined: true,
+                ..Default::default()
+            });
+        entry.exposures.push(ExposureReport {
+            exposure: reference.exposure,
+            source: reference.source.to_string(),
+        });
+    }
+
+    let output = serde_yaml::to_string(&usage).map_err(ReportError::EncodingReport)?;
+    print!("{output}");
+
+    let unused = usage.values().filter(|u| u.exposures.is_empty()).count();
+    if unused > 0 {
+        log::info!("{unused} secret(s) aren't exposed anywhere");
+    }
+    let undefined = usage.values().filter(|u| u.undefined).count();
+    if undefined > 0 {
+        log::warn!("{undefined} exposed secret(s) aren't defined in any config file");
+    }
+
+    Ok(exit_status(0))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReportError {
+    #[error("error reading config file {0}: {1}")]
+    ReadingConfigFile(PathBuf, std::io::Error),
+    #[error("error parsing config file {0}: {1}")]
+    ParsingConfigFile(PathBuf, serde_yaml::Error),
+    #[error("error reading template {0}: {1}")]
+    ReadingTemplate(PathBuf, String),
+    #[error("error encoding report: {0}")]
+    EncodingReport(serde_yaml::Error),
+}
@@ -1,19 +1,72 @@
-use std::os::unix::process::ExitStatusExt;
-use std::path::Path;
-use std::process::ExitStatus;
+use std::collections::HashMap;
+use std::io::{Cursor, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
 
+use age::Identity;
+use globset::Glob;
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
 use tempfile::NamedTempFile;
-use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
 
 use super::State;
-use crate::age::{decrypt_bytes, encrypt_bytes, get_identities, DecryptionError, EncryptionError};
-use crate::{SecretError, SecretStorage};
+use crate::age::{
+    encrypt_bytes,
+    encrypt_bytes_armored,
+    get_identities,
+    parse_recipient,
+    DecryptionError,
+    EncryptionError,
+};
+use crate::secret::{
+    decrypt_secret,
+    normalize_recipient,
+    read_recipients,
+    read_secret,
+    read_secret_if_changed,
+    restore_previous,
+    sidecar_path,
+    write_secret,
+    write_secret_if_unchanged,
+    CiphertextPin,
+    ConditionalRead,
+    KeepPrevious,
+    RecipientsRecord,
+    Spooled,
+    WriteSecretError,
+    SIDECAR_SUFFIXES,
+};
+use crate::signals::shielded;
+use crate::util::exit_status;
+use crate::{Secret, SecretError, SecretStorage};
+
+/// Looks up the named secrets (or all secrets, if no names are given), in name
+/// order. Returns the first name that isn't configured as an error.
+pub(super) fn select_secrets<'a>(
+    secrets: &'a HashMap<String, Secret>,
+    names: &[String],
+) -> Result<Vec<&'a Secret>, String> {
+    let mut selected = match names.is_empty() {
+        true => secrets.values().collect::<Vec<_>>(),
+        false => names
+            .iter()
+            .map(|n| secrets.get(n).ok_or_else(|| n.to_string()))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    selected.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(selected)
+}
 
 pub async fn create<S, E>(
     state: &State<S, E>,
     secret_name: &str,
     source_file: Option<&Path>,
+    signing_key: Option<&Path>,
+    allow_empty: bool,
 ) -> Result<ExitStatus, CreateUpdateSecretError>
 where
     S: SecretStorage,
@@ -25,30 +78,463 @@ where
         .get(secret_name)
         .ok_or_else(|| CreateUpdateSecretError::NoSuchSecret(secret_name.to_string()))?;
     // TODO: Check to see if this exists?
-    let data = match source_file {
-        Some(file) => File::open(file)
-            .await
-            .map_err(CreateUpdateSecretError::ReadSourceData)?,
+    let source_file = match source_file {
+        Some(file) => file,
         None => todo!("Secure tempdir editing"),
     };
 
-    log::debug!("uploading from {}", source_file.unwrap().to_string_lossy());
-    let encrypted_data = encrypt_bytes(data, &secret.encryption_keys)
+    upload_file(
+        &state.storage,
+        secret,
+        source_file,
+        signing_key,
+        allow_empty,
+        state.large_secret_threshold,
+        state.keep_previous(),
+    )
+    .await?;
+
+    Ok(exit_status(0))
+}
+
+/// Plaintext (or ciphertext) less than this fraction of the size of what it
+/// replaces is assumed to be truncated...
+const TRUNCATION_RATIO: u64 = 10;
+/// ...unless what it replaces is smaller than this anyway.
+const MIN_TRUNCATION_CHECK_SIZE: u64 = 1024;
+
+/// Refuses to upload empty plaintext, unless allowed to, since it's far more
+/// likely to come from a mistyped redirect than be intended.
+fn check_not_empty(name: &str, size: usize, allow_empty: bool) -> Result<(), SuspiciousUpload> {
+    match size == 0 && !allow_empty {
+        true => Err(SuspiciousUpload::Empty(name.to_string())),
+        false => Ok(()),
+    }
+}
+
+/// Refuses to replace a secret with something much smaller than it, unless
+/// allowed to.
+fn check_not_truncated(
+    name: &str,
+    new: u64,
+    old: u64,
+    allow_empty: bool,
+) -> Result<(), SuspiciousUpload> {
+    match !allow_empty && old >= MIN_TRUNCATION_CHECK_SIZE && new * TRUNCATION_RATIO < old {
+        true => Err(SuspiciousUpload::Truncated(name.to_string(), new, old)),
+        false => Ok(()),
+    }
+}
+
+/// Encrypts the given plaintext file, and writes it to storage as the given
+/// secret. The plaintext is streamed through encryption, and the ciphertext
+/// spooled to disk once it's larger than `threshold` bytes.
+async fn upload_file<S>(
+    storage: &S,
+    secret: &Secret,
+    source_file: &Path,
+    signing_key: Option<&Path>,
+    allow_empty: bool,
+    threshold: u64,
+    keep: KeepPrevious,
+) -> Result<(), CreateUpdateSecretError>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let file = tokio::fs::File::open(source_file)
+        .await
+        .map_err(CreateUpdateSecretError::ReadSourceData)?;
+    // Peeked rather than sized, since the size of a pipe can't be known up
+    // front
+    let mut reader = BufReader::new(file);
+    let peeked = reader
+        .fill_buf()
+        .await
+        .map_err(CreateUpdateSecretError::ReadSourceData)?;
+    check_not_empty(&secret.name, peeked.len(), allow_empty)?;
+
+    log::debug!("uploading from {}", source_file.to_string_lossy());
+    let ciphertext = Spooled::encrypt(reader, &secret.encryption_keys, &secret.name, threshold)
         .await
         .map_err(CreateUpdateSecretError::EncryptingSecret)?;
-    state
-        .storage
-        .write(&secret.path, encrypted_data.as_slice())
+    // Only ciphertext is stored, so that's what sizes are compared by
+    match storage.metadata(&secret.path).await {
+        Ok(existing) => {
+            let new = ciphertext.size();
+            check_not_truncated(&secret.name, new, existing.size, allow_empty)?;
+        }
+        Err(e) if e.is_not_found() => (),
+        Err(e) => log::debug!("couldn't check existing size of {}: {e}", secret.name),
+    }
+    write_secret(
+        storage,
+        secret,
+        ciphertext,
+        &secret.encryption_keys,
+        signing_key,
+        keep,
+    )
+    .await
+    .map_err(|e| CreateUpdateSecretError::WritingToStore(Box::new(e)))?;
+
+    Ok(())
+}
+
+/// Lists all regular files under the given directory, as paths relative to it,
+/// in sorted order.
+async fn list_files(dir: &Path) -> Result<Vec<PathBuf>, UploadDirError> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let current = dir.join(&relative);
+        let mut entries = tokio::fs::read_dir(&current)
+            .await
+            .map_err(|e| UploadDirError::ReadingDirectory(current.clone(), e))?;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| UploadDirError::ReadingDirectory(current.clone(), e))?
+        {
+            let file_type = entry
+                .file_type()
+                .await
+                .map_err(|e| UploadDirError::ReadingDirectory(entry.path(), e))?;
+            let path = relative.join(entry.file_name());
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() {
+                files.push(path);
+            } else {
+                log::warn!("skipping {}, not a regular file", path.to_string_lossy());
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+#[derive(Serialize)]
+struct GeneratedSecret<'a> {
+    name: &'a str,
+    encryption_keys: &'a [String],
+    path: &'a Path,
+}
+
+#[derive(Serialize)]
+struct GeneratedConfig<'a> {
+    secrets: Vec<GeneratedSecret<'a>>,
+}
+
+pub async fn upload_dir<S, E>(
+    state: &State<S, E>,
+    source_dir: &Path,
+    generate_config: bool,
+    encryption_keys: &[String],
+    signing_key: Option<&Path>,
+    allow_empty: bool,
+) -> Result<ExitStatus, UploadDirError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    if generate_config && encryption_keys.is_empty() {
+        return Err(UploadDirError::NoEncryptionKeys);
+    }
+
+    // Generated secrets must be encrypted to break-glass keys, just like
+    // configured ones
+    let mut generated_keys = encryption_keys.to_vec();
+    if let Some(break_glass) = &state.break_glass {
+        let present = RecipientsRecord::new(&generated_keys);
+        for key in break_glass.encryption_keys.iter() {
+            if !present.contains(key) {
+                generated_keys.push(key.clone());
+            }
+        }
+    }
+
+    let files = list_files(source_dir).await?;
+    let total = files.len();
+    let (mut uploaded, mut skipped, mut failed) = (0, 0, 0);
+    let mut generated = Vec::new();
+    for (i, relative) in files.iter().enumerate() {
+        let name = match relative.to_str() {
+            Some(n) => n,
+            None => {
+                log::warn!("skipping {}, not valid UTF-8", relative.to_string_lossy());
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let secret = match (state.secrets.get(name), generate_config) {
+            (Some(secret), _) => secret.clone(),
+            (None, true) => Secret {
+                name: name.to_string(),
+                encryption_keys: generated_keys.clone(),
+                path: relative.clone(),
+                ..Default::default()
+            },
+            (None, false) => {
+                log::warn!("[{}/{total}] skipping {name}, no such secret", i + 1);
+                skipped += 1;
+                continue;
+            }
+        };
+
+        match upload_file(
+            &state.storage,
+            &secret,
+            &source_dir.join(relative),
+            signing_key,
+            allow_empty,
+            state.large_secret_threshold,
+            state.keep_previous(),
+        )
+        .await
+        {
+            Ok(()) => {
+                log::info!("[{}/{total}] uploaded {name}", i + 1);
+                uploaded += 1;
+                // Printed with the keys it was actually encrypted to, so
+                // that the config matches what's stored
+                if !state.secrets.contains_key(name) {
+                    generated.push(GeneratedSecret {
+                        name,
+                        encryption_keys: &generated_keys,
+                        path: relative,
+                    });
+                }
+            }
+            Err(e) => {
+                log::error!("[{}/{total}] failed to upload {name}: {e}", i + 1);
+                failed += 1;
+            }
+        }
+    }
+
+    if !generated.is_empty() {
+        let config = GeneratedConfig { secrets: generated };
+        let yaml = serde_yaml::to_string(&config).map_err(UploadDirError::GeneratingConfig)?;
+        println!("{yaml}");
+    }
+
+    eprintln!("uploaded {uploaded}, skipped {skipped}, failed {failed} (of {total} files)");
+    if failed > 0 {
+        return Ok(exit_status(1));
+    }
+
+    Ok(exit_status(0))
+}
+
+/// Whether to ask before uploading an edited secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confirm {
+    Skip,
+    Ask { diff: bool },
+}
+
+/// Summarises the change between two versions of a secret, without revealing
+/// any of their content.
+fn summarize_change(name: &str, before: &[u8], after: &[u8], show_diff: bool) -> String {
+    let diff = TextDiff::from_lines(before, after);
+    let mut summary = String::new();
+    let mut changed = 0;
+    for change in diff.iter_all_changes() {
+        let (sign, index) = match change.tag() {
+            ChangeTag::Equal => continue,
+            ChangeTag::Delete => ('-', change.old_index()),
+            ChangeTag::Insert => ('+', change.new_index()),
+        };
+        changed += 1;
+        if show_diff {
+            let line = index.map(|i| i + 1).unwrap_or_default();
+            let len = change.value().len();
+            summary.push_str(&format!("{sign} line {line}: <redacted, {len} bytes>\n"));
+        }
+    }
+
+    summary.push_str(&format!(
+        "{name}: {} -> {} bytes, {changed} line(s) added or removed",
+        before.len(),
+        after.len()
+    ));
+    summary
+}
+
+/// Asks the user a yes/no question on the terminal, defaulting to no.
+pub(super) async fn confirm(prompt: &str) -> Result<bool, std::io::Error> {
+    eprint!("{prompt} [y/N] ");
+    let mut answer = String::new();
+    BufReader::new(tokio::io::stdin())
+        .read_line(&mut answer)
+        .await?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Fetches and decrypts the current content of a secret.
+pub(super) async fn fetch_plaintext<S>(
+    storage: &S,
+    secret: &Secret,
+    identities: &[Box<dyn Identity>],
+    threshold: u64,
+) -> Result<Vec<u8>, EditSecretError>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let reader = read_secret(storage, secret, threshold)
+        .await
+        .map_err(|e| EditSecretError::FetchingFromStore(Box::new(e)))?;
+    let mut plaintext = Vec::new();
+    decrypt_secret(reader, secret, identities)
+        .await?
+        .read_to_end(&mut plaintext)
+        .await
+        .map_err(EditSecretError::ReadingSecret)?;
+
+    Ok(plaintext)
+}
+
+/// Editors that exit sooner than this without changing anything probably
+/// opened a window and returned straight away.
+const QUICK_EDITOR_EXIT: Duration = Duration::from_secs(1);
+
+/// Refuses to edit secrets without a terminal, where neither terminal editors
+/// nor the confirmation prompt would work.
+fn ensure_terminal() -> Result<(), EditSecretError> {
+    match std::io::stdin().is_terminal() {
+        true => Ok(()),
+        false => Err(EditSecretError::NotATerminal),
+    }
+}
+
+/// Splits the editor command (which may include arguments) like a shell would.
+fn editor_argv(editor: &str) -> Result<(String, Vec<String>), EditSecretError> {
+    let mut argv = shell_words::split(editor)
+        .map_err(|e| EditSecretError::InvalidEditor(editor.to_string(), e.to_string()))?;
+    if argv.is_empty() {
+        return Err(EditSecretError::InvalidEditor(
+            editor.to_string(),
+            "no command given".into(),
+        ));
+    }
+    let program = argv.remove(0);
+
+    Ok((program, argv))
+}
+
+/// Runs the editor on the given path, returning how long it ran for.
+async fn run_editor(editor: &str, path: &Path) -> Result<Duration, EditSecretError> {
+    let (program, args) = editor_argv(editor)?;
+
+    log::debug!("executing `{} {}`", editor, path.to_string_lossy());
+    let started = Instant::now();
+    let editor_result = Command::new(&program)
+        .args(args)
+        .arg(path)
+        .status()
+        .await
+        .map_err(|e| EditSecretError::InvokingEditor(program, e))?;
+
+    log::debug!("editor exited with status {}", editor_result);
+    match editor_result.success() {
+        true => Ok(started.elapsed()),
+        false => Err(EditSecretError::EditorBadExit(editor_result)),
+    }
+}
+
+/// Runs the editor as a filter, passing it the plaintext on stdin and reading
+/// the edited plaintext from its stdout, so that it's never written to a
+/// file. The editor has to talk to the user through `/dev/tty` itself.
+/// Returns the edited plaintext, and how long the editor ran for.
+async fn run_editor_piped(
+    editor: &str,
+    plaintext: &[u8],
+) -> Result<(Vec<u8>, Duration), EditSecretError> {
+    let (program, args) = editor_argv(editor)?;
+
+    log::debug!("executing `{editor}` with the secret on stdin");
+    let started = Instant::now();
+    let mut child = Command::new(&program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| EditSecretError::InvokingEditor(program.clone(), e))?;
+    let mut stdin = child.stdin.take().expect("editor stdin is piped");
+    let mut stdout = child.stdout.take().expect("editor stdout is piped");
+
+    // Written and read at the same time, so that an editor that streams its
+    // output can't fill the pipe and block us both
+    let write = async {
+        stdin.write_all(plaintext).await?;
+        // Closing stdin tells the editor it has all of the secret
+        drop(stdin);
+        Ok::<_, std::io::Error>(())
+    };
+    let mut updated = Vec::new();
+    let (written, read) = tokio::join!(write, stdout.read_to_end(&mut updated));
+    // An editor that exits without reading everything closes the pipe early,
+    // which its exit status explains better
+    if let Err(e) = written {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(EditSecretError::PipingToEditor(e));
+        }
+    }
+    read.map_err(EditSecretError::PipingToEditor)?;
+    let editor_result = child
+        .wait()
         .await
-        .map_err(|e| CreateUpdateSecretError::WritingToStore(Box::new(e)))?;
+        .map_err(|e| EditSecretError::InvokingEditor(program, e))?;
+
+    log::debug!("editor exited with status {}", editor_result);
+    match editor_result.success() {
+        true => Ok((updated, started.elapsed())),
+        false => Err(EditSecretError::EditorBadExit(editor_result)),
+    }
+}
 
-    Ok(ExitStatus::from_raw(0))
+/// Refuses to upload an edit that emptied or truncated a secret, unless
+/// allowed to.
+fn check_edit(
+    name: &str,
+    original: &[u8],
+    updated: &[u8],
+    allow_empty: bool,
+) -> Result<(), SuspiciousUpload> {
+    check_not_empty(name, updated.len(), allow_empty)?;
+    check_not_truncated(
+        name,
+        updated.len() as u64,
+        original.len() as u64,
+        allow_empty,
+    )
+}
+
+/// Points out the likely cause of an edit making no changes, if the editor
+/// didn't stay open long enough for anyone to make any.
+fn hint_quick_exit(elapsed: Duration) {
+    if elapsed < QUICK_EDITOR_EXIT {
+        log::warn!(
+            "the editor exited straight away; editors that open a window need to be told to wait (e.g. EDITOR=\"code --wait\")"
+        );
+    }
 }
 
 pub async fn edit<S, E>(
     state: &State<S, E>,
     editor: &str,
     secret_name: &str,
+    signing_key: Option<&Path>,
+    confirmation: Confirm,
+    allow_empty: bool,
+    no_tempfile: bool,
 ) -> Result<ExitStatus, EditSecretError>
 where
     S: SecretStorage,
@@ -59,54 +545,684 @@ where
         .secrets
         .get(secret_name)
         .ok_or_else(|| EditSecretError::NoSuchSecret(secret_name.to_string()))?;
+    ensure_terminal()?;
     let identities = get_identities(&state.private_key_paths)?;
     // NOTE: It would be nice if this supported creating new files, too
-    let reader = state
-        .storage
-        .read(&secret.path)
-        .await
-        .map_err(|e| EditSecretError::WritingToStore(Box::new(e)))?;
-    let temp_file = NamedTempFile::new().map_err(EditSecretError::CreatingTempFile)?;
-    let temp_file_path = temp_file.path();
-    // Scope ensures temp file is closed after we write decrypted data
+    let original = fetch_plaintext(
+        &state.storage,
+        secret,
+        &identities,
+        state.large_secret_threshold,
+    )
+    .await?;
+
+    let (updated, elapsed) = match no_tempfile {
+        true => run_editor_piped(editor, &original).await?,
+        false => {
+            let temp_file = NamedTempFile::new().map_err(EditSecretError::CreatingTempFile)?;
+            let temp_file_path = temp_file.path();
+            tokio::fs::write(temp_file_path, &original)
+                .await
+                .map_err(EditSecretError::OpeningTempFile)?;
+            log::debug!("secret written to {}", temp_file_path.to_string_lossy());
+
+            let elapsed = run_editor(editor, temp_file_path).await?;
+
+            let updated = tokio::fs::read(temp_file_path)
+                .await
+                .map_err(EditSecretError::OpeningTempFile)?;
+            (updated, elapsed)
+        }
+    };
+    // Re-uploading identical content would only churn the stored object
+    if updated == original {
+        eprintln!("{secret_name}: no changes, not uploading");
+        // Filters are expected to finish quickly
+        if !no_tempfile {
+            hint_quick_exit(elapsed);
+        }
+        return Ok(exit_status(0));
+    }
+    check_edit(secret_name, &original, &updated, allow_empty)?;
+
+    if let Confirm::Ask { diff } = confirmation {
+        eprintln!(
+            "{}",
+            summarize_change(secret_name, &original, &updated, diff)
+        );
+        let prompt = format!("Upload changes to {secret_name}?");
+        if !confirm(&prompt).await.map_err(EditSecretError::Prompting)? {
+            eprintln!("{secret_name}: not uploading");
+            return Ok(exit_status(1));
+        }
+    }
+
+    let encrypted_data = encrypt_bytes(Cursor::new(updated), &secret.encryption_keys).await?;
+    write_secret(
+        &state.storage,
+        secret,
+        encrypted_data.into(),
+        &secret.encryption_keys,
+        signing_key,
+        state.keep_previous(),
+    )
+    .await
+    .map_err(|e| EditSecretError::WritingToStore(Box::new(e)))?;
+
+    Ok(exit_status(0))
+}
+
+/// Edits every secret whose name matches the given pattern in one editor
+/// session, uploading only those that changed.
+pub async fn edit_matching<S, E>(
+    state: &State<S, E>,
+    editor: &str,
+    pattern: &str,
+    signing_key: Option<&Path>,
+    confirmation: Confirm,
+    allow_empty: bool,
+) -> Result<ExitStatus, EditSecretError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let matcher = Glob::new(pattern)
+        .map_err(EditSecretError::InvalidPattern)?
+        .compile_matcher();
+    let mut secrets = state
+        .secrets
+        .values()
+        .filter(|s| matcher.is_match(&s.name))
+        .collect::<Vec<_>>();
+    secrets.sort_by(|a, b| a.name.cmp(&b.name));
+    if secrets.is_empty() {
+        return Err(EditSecretError::NoMatchingSecrets(pattern.to_string()));
+    }
+
+    // Each secret becomes a file named after it, so names must be usable as
+    // file names
+    if let Some(secret) = secrets
+        .iter()
+        .find(|s| s.name.contains('/') || s.name == "." || s.name == "..")
     {
-        let mut temp_file_handle = File::create(temp_file_path)
-            .await
-            .map_err(EditSecretError::OpeningTempFile)?;
-        let mut reader = decrypt_bytes(reader, &identities).await?;
-        tokio::io::copy(&mut reader, &mut temp_file_handle)
+        return Err(EditSecretError::UnsupportedName(secret.name.clone()));
+    }
+
+    ensure_terminal()?;
+    let identities = get_identities(&state.private_key_paths)?;
+    let temp_dir = tempfile::tempdir().map_err(EditSecretError::CreatingTempFile)?;
+    let mut originals = Vec::new();
+    for secret in secrets.iter() {
+        let plaintext = fetch_plaintext(
+            &state.storage,
+            secret,
+            &identities,
+            state.large_secret_threshold,
+        )
+        .await?;
+        tokio::fs::write(temp_dir.path().join(&secret.name), &plaintext)
             .await
             .map_err(EditSecretError::OpeningTempFile)?;
+        originals.push(plaintext);
     }
-    log::debug!("secret written to {}", temp_file_path.to_string_lossy());
-
     log::debug!(
-        "executing `{} {}`",
-        editor,
-        temp_file_path.to_string_lossy()
+        "{} secret(s) written to {}",
+        secrets.len(),
+        temp_dir.path().to_string_lossy()
     );
-    let editor_result = Command::new(editor)
-        .arg(temp_file_path)
-        .status()
+
+    let elapsed = run_editor(editor, temp_dir.path()).await?;
+
+    let mut changed = Vec::new();
+    for (secret, original) in secrets.iter().zip(originals.iter()) {
+        let updated = match tokio::fs::read(temp_dir.path().join(&secret.name)).await {
+            Ok(u) => u,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn!("{} was removed, leaving it unchanged", secret.name);
+                continue;
+            }
+            Err(e) => return Err(EditSecretError::OpeningTempFile(e)),
+        };
+
+        if &updated != original {
+            changed.push((secret, original, updated));
+        }
+    }
+
+    if changed.is_empty() {
+        eprintln!("no changes, not uploading");
+        hint_quick_exit(elapsed);
+        return Ok(exit_status(0));
+    }
+    for (secret, original, updated) in changed.iter() {
+        check_edit(&secret.name, original, updated, allow_empty)?;
+    }
+
+    if let Confirm::Ask { diff } = confirmation {
+        for (secret, original, updated) in changed.iter() {
+            eprintln!(
+                "{}",
+                summarize_change(&secret.name, original, updated, diff)
+            );
+        }
+        let prompt = format!("Upload changes to {} secret(s)?", changed.len());
+        if !confirm(&prompt).await.map_err(EditSecretError::Prompting)? {
+            eprintln!("not uploading");
+            return Ok(exit_status(1));
+        }
+    }
+
+    // Encrypt everything before writing anything, so a bad recipient can't
+    // leave a coordinated change half-applied
+    let mut encrypted = Vec::new();
+    for (secret, _, updated) in changed {
+        let data = encrypt_bytes(Cursor::new(updated), &secret.encryption_keys).await?;
+        encrypted.push((secret, data));
+    }
+
+    for (secret, data) in encrypted {
+        write_secret(
+            &state.storage,
+            secret,
+            data.into(),
+            &secret.encryption_keys,
+            signing_key,
+            state.keep_previous(),
+        )
+        .await
+        .map_err(|e| EditSecretError::WritingToStore(Box::new(e)))?;
+        eprintln!("{}: uploaded", secret.name);
+    }
+
+    Ok(exit_status(0))
+}
+
+/// How many times appending is attempted when the secret keeps changing
+/// underneath us.
+const APPEND_ATTEMPTS: usize = 3;
+
+/// Reads a secret's ciphertext in full, with its digest and storage version
+/// (if the storage has them).
+async fn fetch_ciphertext<S>(
+    storage: &S,
+    secret: &Secret,
+    threshold: u64,
+) -> Result<(Vec<u8>, CiphertextPin, Option<String>), AppendSecretError>
+where
+    S: SecretStorage + Sync,
+    <S as SecretStorage>::Error: 'static,
+{
+    let read = read_secret_if_changed(storage, secret, None, threshold)
         .await
-        .map_err(EditSecretError::InvokingEditor)?;
+        .map_err(|e| AppendSecretError::FetchingFromStore(Box::new(e)))?;
+    let ConditionalRead::Changed {
+        mut reader,
+        version,
+    } = read
+    else {
+        unreachable!("reads without a version always see a change");
+    };
+    let mut ciphertext = Vec::new();
+    reader
+        .read_to_end(&mut ciphertext)
+        .await
+        .map_err(AppendSecretError::ReadingSecret)?;
+    let digest = CiphertextPin::sha256(&ciphertext);
 
-    log::debug!("editor exited with status {}", editor_result);
-    if !editor_result.success() {
-        return Err(EditSecretError::EditorBadExit(editor_result));
+    Ok((ciphertext, digest, version))
+}
+
+/// Appends stdin to a secret, on a new line, retrying if anyone else changed
+/// it in the meantime so that concurrent appends aren't lost.
+///
+/// On storage with conditional writes, the upload only replaces the version
+/// that was read. Elsewhere, the secret is only checked for changes just
+/// before uploading, so a change made between that check and the upload can
+/// still be lost.
+pub async fn append<S, E>(
+    state: &State<S, E>,
+    secret_name: &str,
+    signing_key: Option<&Path>,
+) -> Result<ExitStatus, AppendSecretError>
+where
+    S: SecretStorage + Sync,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let secret = state
+        .secrets
+        .get(secret_name)
+        .ok_or_else(|| AppendSecretError::NoSuchSecret(secret_name.to_string()))?;
+    let mut addition = Vec::new();
+    tokio::io::stdin()
+        .read_to_end(&mut addition)
+        .await
+        .map_err(AppendSecretError::ReadingInput)?;
+    if addition.is_empty() {
+        eprintln!("{secret_name}: nothing to append");
+        return Ok(exit_status(0));
     }
 
-    let temp_file_handle = File::open(temp_file_path)
+    let identities = get_identities(&state.private_key_paths)?;
+    for attempt in 1..=APPEND_ATTEMPTS {
+        let (ciphertext, digest, version) =
+            fetch_ciphertext(&state.storage, secret, state.large_secret_threshold).await?;
+        let mut plaintext = Vec::new();
+        decrypt_secret(Cursor::new(ciphertext), secret, &identities)
+            .await?
+            .read_to_end(&mut plaintext)
+            .await
+            .map_err(AppendSecretError::ReadingSecret)?;
+
+        if !plaintext.is_empty() && !plaintext.ends_with(b"\n") {
+            plaintext.push(b'\n');
+        }
+        plaintext.extend_from_slice(&addition);
+        let encrypted_data = encrypt_bytes(Cursor::new(plaintext), &secret.encryption_keys).await?;
+
+        let version = version.filter(|_| state.storage.conditional_writes());
+        if version.is_none() {
+            let (_, current, _) =
+                fetch_ciphertext(&state.storage, secret, state.large_secret_threshold).await?;
+            if current != digest {
+                log::warn!("{secret_name} changed while appending (attempt {attempt}), retrying");
+                continue;
+            }
+        }
+
+        let res = write_secret_if_unchanged(
+            &state.storage,
+            secret,
+            encrypted_data.into(),
+            &secret.encryption_keys,
+            signing_key,
+            state.keep_previous(),
+            version.as_deref(),
+        )
+        .await;
+        match res {
+            Err(WriteSecretError::Changed(_)) => {
+                log::warn!("{secret_name} changed while appending (attempt {attempt}), retrying");
+            }
+            res => {
+                res.map_err(|e| AppendSecretError::WritingToStore(Box::new(e)))?;
+                eprintln!("{secret_name}: appended {} bytes", addition.len());
+                return Ok(exit_status(0));
+            }
+        }
+    }
+
+    Err(AppendSecretError::KeptChanging(secret_name.to_string()))
+}
+
+/// Encrypts plaintext on stdin to the named secret's recipients, writing the
+/// ciphertext to stdout (ASCII-armored, if `armor` is set).
+pub async fn encrypt_stream<S, E>(
+    state: &State<S, E>,
+    secret_name: &str,
+    armor: bool,
+) -> Result<ExitStatus, PipeSecretError>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let secret = state
+        .secrets
+        .get(secret_name)
+        .ok_or_else(|| PipeSecretError::NoSuchSecret(secret_name.to_string()))?;
+    let ciphertext = match armor {
+        true => encrypt_bytes_armored(tokio::io::stdin(), &secret.encryption_keys).await?,
+        false => encrypt_bytes(tokio::io::stdin(), &secret.encryption_keys).await?,
+    };
+
+    let mut stdout = tokio::io::stdout();
+    stdout
+        .write_all(&ciphertext)
         .await
-        .map_err(EditSecretError::OpeningTempFile)?;
-    let encrypted_data = encrypt_bytes(temp_file_handle, &secret.encryption_keys).await?;
-    state
-        .storage
-        .write(&secret.path, encrypted_data.as_slice())
+        .map_err(PipeSecretError::WritingOutput)?;
+    stdout
+        .flush()
         .await
-        .map_err(|e| EditSecretError::WritingToStore(Box::new(e)))?;
+        .map_err(PipeSecretError::WritingOutput)?;
 
-    Ok(ExitStatus::from_raw(0))
+    Ok(exit_status(0))
+}
+
+/// Decrypts the named secret's ciphertext from stdin with its identities (or
+/// ours, if it doesn't name any), writing the plaintext to stdout.
+pub async fn decrypt_stream<S, E>(
+    state: &State<S, E>,
+    secret_name: &str,
+) -> Result<ExitStatus, PipeSecretError>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let secret = state
+        .secrets
+        .get(secret_name)
+        .ok_or_else(|| PipeSecretError::NoSuchSecret(secret_name.to_string()))?;
+    let identities = get_identities(&state.private_key_paths)?;
+    let mut reader = decrypt_secret(tokio::io::stdin(), secret, &identities).await?;
+
+    let mut stdout = tokio::io::stdout();
+    tokio::io::copy(&mut reader, &mut stdout)
+        .await
+        .map_err(PipeSecretError::WritingOutput)?;
+    stdout
+        .flush()
+        .await
+        .map_err(PipeSecretError::WritingOutput)?;
+
+    Ok(exit_status(0))
+}
+
+pub async fn verify<S, E>(
+    state: &State<S, E>,
+    secret_names: &[String],
+) -> Result<ExitStatus, VerifySecretsError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let secrets =
+        select_secrets(&state.secrets, secret_names).map_err(VerifySecretsError::NoSuchSecret)?;
+
+    let mut mismatched = 0;
+    for secret in secrets {
+        // There's nowhere to record the recipients of inline values
+        if secret.value.is_some() {
+            println!("{}: inline, recipients not recorded", secret.name);
+            continue;
+        }
+        let record = match read_recipients(&state.storage, secret).await {
+            Ok(r) => r,
+            Err(e) => {
+                mismatched += 1;
+                println!("{}: unknown recipients ({e})", secret.name);
+                continue;
+            }
+        };
+
+        let diff = record.diff(&secret.encryption_keys);
+        if diff.is_empty() {
+            println!("{}: ok", secret.name);
+            continue;
+        }
+
+        mismatched += 1;
+        for key in diff.missing {
+            println!("{}: not encrypted to configured key {key}", secret.name);
+        }
+        for key in diff.extra {
+            println!("{}: still encrypted to unconfigured key {key}", secret.name);
+        }
+    }
+
+    if mismatched > 0 {
+        log::warn!("{mismatched} secret(s) don't match their configured recipients");
+        return Ok(exit_status(1));
+    }
+
+    Ok(exit_status(0))
+}
+
+/// How rekeying changes each secret's recipients.
+pub enum RecipientChange<'a> {
+    /// Adds and removes keys from the recipients it's currently encrypted to
+    Modify {
+        add: &'a [String],
+        remove: &'a [String],
+    },
+    /// Replaces the recipients with the configured keys
+    Sync,
+}
+
+pub async fn rekey<S, E>(
+    state: &State<S, E>,
+    secret_names: &[String],
+    change: RecipientChange<'_>,
+    signing_key: Option<&Path>,
+) -> Result<ExitStatus, RekeyError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let (add, remove, sync): (&[String], &[String], bool) = match change {
+        RecipientChange::Modify { add, remove } => (add, remove, false),
+        RecipientChange::Sync => (&[], &[], true),
+    };
+    if !sync && add.is_empty() && remove.is_empty() {
+        return Err(RekeyError::NoChanges);
+    }
+
+    // encrypt_bytes skips keys it can't parse, check them up-front so we
+    // don't silently record a recipient we didn't encrypt to
+    if let Some(key) = add.iter().find(|k| parse_recipient(k).is_err()) {
+        return Err(RekeyError::InvalidRecipient(key.to_string()));
+    }
+
+    if let Some(break_glass) = &state.break_glass {
+        let mandatory = RecipientsRecord::new(&break_glass.encryption_keys);
+        if let Some(key) = remove.iter().find(|k| mandatory.contains(k)) {
+            return Err(RekeyError::RemovingBreakGlassKey(key.to_string()));
+        }
+    }
+
+    let secrets = select_secrets(&state.secrets, secret_names).map_err(RekeyError::NoSuchSecret)?;
+    let identities = get_identities(&state.private_key_paths)?;
+    let remove = RecipientsRecord::new(remove);
+
+    for secret in secrets {
+        if secret.value.is_some() {
+            log::warn!(
+                "{} is inline in config, re-encrypt it with `credible secret encrypt --armor` instead",
+                secret.name
+            );
+            continue;
+        }
+        let current = match read_recipients(&state.storage, secret).await {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!(
+                    "couldn't read recipients for {} ({e}), assuming configured keys",
+                    secret.name
+                );
+                RecipientsRecord::new(&secret.encryption_keys)
+            }
+        };
+
+        if sync {
+            if let Some(key) = secret
+                .encryption_keys
+                .iter()
+                .find(|k| parse_recipient(k).is_err())
+            {
+                return Err(RekeyError::InvalidRecipient(key.to_string()));
+            }
+        }
+
+        // Syncing is adding every configured key to an empty set
+        let (mut recipients, add) = match sync {
+            true => (Vec::new(), secret.encryption_keys.as_slice()),
+            false => {
+                let kept = current
+                    .recipients
+                    .iter()
+                    .filter(|r| !remove.contains(r))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                (kept, add)
+            }
+        };
+        for key in add.iter().map(|k| normalize_recipient(k)) {
+            if !recipients.contains(&key) {
+                recipients.push(key);
+            }
+        }
+
+        if RecipientsRecord::new(&recipients) == current {
+            log::info!("{} is already encrypted to the requested keys", secret.name);
+            continue;
+        }
+        if recipients.is_empty() {
+            return Err(RekeyError::NoRecipientsLeft(secret.name.clone()));
+        }
+        if secret.pin.is_some() {
+            log::warn!("{} is pinned, its pin must be updated", secret.name);
+        }
+
+        let threshold = state.large_secret_threshold;
+        let reader = read_secret(&state.storage, secret, threshold)
+            .await
+            .map_err(|e| RekeyError::FetchingFromStore(Box::new(e)))?;
+        let plaintext = decrypt_secret(reader, secret, &identities).await?;
+        let encrypted_data =
+            Spooled::encrypt(plaintext, &recipients, &secret.name, threshold).await?;
+        write_secret(
+            &state.storage,
+            secret,
+            encrypted_data,
+            &recipients,
+            signing_key,
+            state.keep_previous(),
+        )
+        .await
+        .map_err(|e| RekeyError::WritingToStore(Box::new(e)))?;
+
+        log::info!(
+            "rekeyed {} to {} recipient(s)",
+            secret.name,
+            recipients.len()
+        );
+    }
+
+    Ok(exit_status(0))
+}
+
+/// Swaps a secret's objects with the ones kept from before it was last
+/// overwritten.
+pub async fn restore<S, E>(
+    state: &State<S, E>,
+    secret_name: &str,
+) -> Result<ExitStatus, RestoreSecretError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let secret = state
+        .secrets
+        .get(secret_name)
+        .ok_or_else(|| RestoreSecretError::NoSuchSecret(secret_name.to_string()))?;
+
+    restore_previous(&state.storage, secret, state.large_secret_threshold)
+        .await
+        .map_err(|e| RestoreSecretError::Restoring(Box::new(e)))?;
+    eprintln!("{secret_name}: restored previous version");
+
+    Ok(exit_status(0))
+}
+
+/// Removes a secret's ciphertext, and anything stored alongside it. The secret
+/// is left in config, for whoever runs this to remove.
+pub async fn remove<S, E>(
+    state: &State<S, E>,
+    secret_name: &str,
+) -> Result<ExitStatus, RemoveSecretError>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let secret = state
+        .secrets
+        .get(secret_name)
+        .ok_or_else(|| RemoveSecretError::NoSuchSecret(secret_name.to_string()))?;
+    if secret.value.is_some() {
+        return Err(RemoveSecretError::Inline(secret.name.clone()));
+    }
+
+    // Ciphertext first, so that a partial failure never leaves a secret that
+    // looks usable but has lost its signature or recipients record
+    let paths = std::iter::once(secret.path.clone()).chain(
+        SIDECAR_SUFFIXES
+            .iter()
+            .map(|s| sidecar_path(&secret.path, s)),
+    );
+    let delete = async {
+        for path in paths {
+            state
+                .storage
+                .delete(&path)
+                .await
+                .map_err(|e| RemoveSecretError::DeletingFromStore(path, Box::new(e)))?;
+        }
+        Ok(())
+    };
+    let (res, signal) = shielded(delete)
+        .await
+        .map_err(RemoveSecretError::ListeningForSignals)?;
+    res?;
+    if let Some(signal) = signal {
+        return Err(RemoveSecretError::Interrupted(secret.name.clone(), signal));
+    }
+
+    match &secret.defined_in {
+        Some(file) => eprintln!(
+            "{}: removed (its definition in {} can now be deleted)",
+            secret.name,
+            file.to_string_lossy()
+        ),
+        None => eprintln!("{}: removed", secret.name),
+    }
+
+    Ok(exit_status(0))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RemoveSecretError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("{0} is inline in config, delete its definition instead")]
+    Inline(String),
+    #[error("error deleting {0} from store: {1}")]
+    DeletingFromStore(PathBuf, Box<dyn std::error::Error>),
+    #[error("error listening for signals: {0}")]
+    ListeningForSignals(std::io::Error),
+    #[error("received signal {1} while removing {0} (which was finished first)")]
+    Interrupted(String, i32),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AppendSecretError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("error reading input: {0}")]
+    ReadingInput(std::io::Error),
+    #[error("error fetching existing secret from store: {0}")]
+    FetchingFromStore(Box<dyn std::error::Error>),
+    #[error("error reading existing secret: {0}")]
+    ReadingSecret(std::io::Error),
+    #[error("error decrypting existing secret: {0}")]
+    DecryptingSecret(#[from] DecryptionError),
+    #[error("error encrypting updated secret: {0}")]
+    EncryptingSecret(#[from] EncryptionError),
+    #[error("error uploading updated secret: {0}")]
+    WritingToStore(Box<dyn std::error::Error>),
+    #[error("{0} kept changing while appending to it, giving up")]
+    KeptChanging(String),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RestoreSecretError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("{0}")]
+    Restoring(Box<dyn std::error::Error>),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -119,6 +1235,16 @@ pub enum CreateUpdateSecretError {
     WritingToStore(Box<dyn std::error::Error>),
     #[error("error encrypting secret: {0}")]
     EncryptingSecret(#[from] EncryptionError),
+    #[error("{0}")]
+    Suspicious(#[from] SuspiciousUpload),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SuspiciousUpload {
+    #[error("refusing to upload empty plaintext for {0} (pass --allow-empty to do so anyway)")]
+    Empty(String),
+    #[error("refusing to replace {0} ({2} bytes) with {1} bytes, which looks truncated (pass --allow-empty to do so anyway)")]
+    Truncated(String, u64, u64),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -139,10 +1265,20 @@ pub enum UploadSecretError {
 pub enum EditSecretError {
     #[error("no secret named {0}")]
     NoSuchSecret(String),
+    #[error("invalid pattern: {0}")]
+    InvalidPattern(globset::Error),
+    #[error("no secrets match {0}")]
+    NoMatchingSecrets(String),
+    #[error("secret name {0} can't be used as a file name")]
+    UnsupportedName(String),
     #[error("error creating tempfile: {0}")]
     CreatingTempFile(std::io::Error),
     #[error("error opening tempfile: {0}")]
     OpeningTempFile(std::io::Error),
+    #[error("error reading existing secret: {0}")]
+    ReadingSecret(std::io::Error),
+    #[error("error reading confirmation: {0}")]
+    Prompting(std::io::Error),
     #[error("error creating pipe: {0}")]
     CreatingPipe(std::io::Error),
     #[error("error fetching existing secret from store: {0}")]
@@ -153,8 +1289,66 @@ pub enum EditSecretError {
     EncryptingSecret(#[from] EncryptionError),
     #[error("error uploading updated secret: {0}")]
     WritingToStore(Box<dyn std::error::Error>),
-    #[error("error invoking editor: {0}")]
-    InvokingEditor(std::io::Error),
+    #[error("invalid editor {0:?}: {1}")]
+    InvalidEditor(String, String),
+    #[error("error invoking editor {0}: {1}")]
+    InvokingEditor(String, std::io::Error),
+    #[error("error passing secret through editor: {0}")]
+    PipingToEditor(std::io::Error),
+    #[error("editing secrets needs a terminal (use `secret upload` to replace a secret non-interactively)")]
+    NotATerminal,
     #[error("editor exited with non-success status: {0}")]
     EditorBadExit(ExitStatus),
+    #[error("{0}")]
+    Suspicious(#[from] SuspiciousUpload),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PipeSecretError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("error encrypting input: {0}")]
+    Encrypting(#[from] EncryptionError),
+    #[error("error decrypting input: {0}")]
+    Decrypting(#[from] DecryptionError),
+    #[error("error writing output: {0}")]
+    WritingOutput(std::io::Error),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum VerifySecretsError {
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RekeyError {
+    #[error("no recipients to add or remove")]
+    NoChanges,
+    #[error("invalid recipient: {0}")]
+    InvalidRecipient(String),
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("refusing to remove every recipient of {0}")]
+    NoRecipientsLeft(String),
+    #[error("refusing to remove break-glass key {0}")]
+    RemovingBreakGlassKey(String),
+    #[error("error fetching existing secret from store: {0}")]
+    FetchingFromStore(Box<dyn std::error::Error>),
+    #[error("error decrypting existing secret: {0}")]
+    DecryptingSecret(#[from] DecryptionError),
+    #[error("error encrypting secret: {0}")]
+    EncryptingSecret(#[from] EncryptionError),
+    #[error("error uploading rekeyed secret: {0}")]
+    WritingToStore(Box<dyn std::error::Error>),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UploadDirError {
+    #[error("error reading directory {0}: {1}")]
+    ReadingDirectory(PathBuf, std::io::Error),
+    #[error("--generate-config requires at least one --encryption-key")]
+    NoEncryptionKeys,
+    #[error("error generating config: {0}")]
+    GeneratingConfig(serde_yaml::Error),
 }
@@ -0,0 +1,507 @@
+//! Local query service, for processes that request secrets on demand rather
+//! than reading them from files or the environment.
+//!
+//! Clients connect to a unix socket and send the name of a secret, followed by
+//! a newline. The reply is `OK\n` followed by the plaintext, or `ERR
+//! <message>\n`, after which the connection is closed. Who's asking is taken
+//! from the socket's peer credentials, rather than anything the client sends.
+//!
+//! Optionally, secrets are also served over HTTP, the way cloud metadata
+//! services hand out credentials, for programs that can only fetch them from
+//! a URL.
+
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::str::FromStr;
+use std::time::Duration;
+
+use age::Identity;
+use futures::future::{pending, FutureExt, LocalBoxFuture};
+use futures::stream::{FuturesUnordered, StreamExt};
+use nix::unistd::{Gid, Group, Uid, User};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+
+use super::State;
+use crate::age::{get_identities, DecryptionError};
+use crate::secret::{decrypt_secret, read_secret};
+use crate::signals::SignalListener;
+use crate::util::exit_status;
+use crate::{Secret, SecretError, SecretStorage};
+
+/// How long clients have to send their request before being disconnected.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request line (or header) we'll read from a client.
+const MAX_REQUEST_LEN: u64 = 4096;
+
+/// Most headers we'll read in an HTTP request.
+const MAX_HEADERS: usize = 64;
+
+/// Header HTTP clients must send, so that requests can't be made on their
+/// behalf by something that only lets them choose the URL (e.g. SSRF).
+const HTTP_REQUIRED_HEADER: (&str, &str) = ("metadata-flavor", "credible");
+
+/// Path secrets are served under over HTTP.
+const HTTP_PATH_PREFIX: &str = "/secrets/";
+
+/// Where the HTTP endpoint listens: `unix:<path>` for a unix socket, or an
+/// `address:port`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HttpAddress {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for HttpAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|_| format!("{s} is neither unix:<path> nor address:port"))
+    }
+}
+
+/// The HTTP endpoint to serve, alongside the unix socket.
+pub struct HttpEndpoint<'a> {
+    pub address: &'a HttpAddress,
+    /// Secrets served over HTTP. Over TCP, there's no telling who's asking,
+    /// so anyone who can connect may read these (and only these).
+    pub secrets: &'a [String],
+}
+
+enum HttpListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// Who a request came from.
+#[derive(Debug, Clone, Copy)]
+enum Client {
+    /// A local process, identified by its socket's peer credentials
+    Local {
+        uid: u32,
+        gid: u32,
+        pid: Option<i32>,
+    },
+    /// Anything that could reach the HTTP endpoint's address
+    Remote(SocketAddr),
+}
+
+impl std::fmt::Display for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Local {
+                uid,
+                pid: Some(pid),
+                ..
+            } => write!(f, "uid {uid} (pid {pid})"),
+            Self::Local { uid, pid: None, .. } => write!(f, "uid {uid}"),
+            Self::Remote(addr) => write!(f, "{addr}"),
+        }
+    }
+}
+
+/// Serves secrets over a unix socket (and optionally HTTP) until asked to shut
+/// down.
+pub async fn serve<S, E>(
+    state: &State<S, E>,
+    socket: &Path,
+    http: Option<HttpEndpoint<'_>>,
+) -> Result<ExitStatus, ServeError>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    let identities = get_identities(&state.private_key_paths)?;
+    if let Some(endpoint) = &http {
+        if let Some(name) = endpoint
+            .secrets
+            .iter()
+            .find(|n| !state.secrets.contains_key(*n))
+        {
+            return Err(ServeError::NoSuchSecret(name.clone()));
+        }
+        if matches!(endpoint.address, HttpAddress::Tcp(_)) && endpoint.secrets.is_empty() {
+            return Err(ServeError::NoHttpSecrets);
+        }
+    }
+
+    let listener = bind_unix(socket)?;
+    let http_listener = match &http {
+        Some(endpoint) => Some(match endpoint.address {
+            HttpAddress::Unix(path) => HttpListener::Unix(bind_unix(path)?),
+            HttpAddress::Tcp(addr) => {
+                HttpListener::Tcp(TcpListener::bind(addr).await.map_err(ServeError::Binding)?)
+            }
+        }),
+        None => None,
+    };
+
+    let server = Server {
+        state,
+        identities: &identities,
+        http_secrets: http.as_ref().map(|e| e.secrets).unwrap_or_default(),
+    };
+    let res = server.accept_loop(&listener, http_listener.as_ref()).await;
+    remove_socket(socket);
+    if let Some(HttpAddress::Unix(path)) = http.as_ref().map(|e| e.address) {
+        remove_socket(path);
+    }
+    res?;
+
+    Ok(exit_status(0))
+}
+
+fn bind_unix(socket: &Path) -> Result<UnixListener, ServeError> {
+    // A socket left behind by a previous run would stop us binding
+    if let Ok(meta) = std::fs::symlink_metadata(socket) {
+        use std::os::unix::fs::FileTypeExt;
+        if !meta.file_type().is_socket() {
+            return Err(ServeError::NotASocket(socket.to_owned()));
+        }
+        std::fs::remove_file(socket).map_err(ServeError::Binding)?;
+    }
+    let listener = UnixListener::bind(socket).map_err(ServeError::Binding)?;
+    // Anyone may connect, what they can read is decided per secret
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o666))
+        .map_err(ServeError::Binding)?;
+
+    Ok(listener)
+}
+
+fn remove_socket(socket: &Path) {
+    if let Err(e) = std::fs::remove_file(socket) {
+        log::warn!("couldn't remove {}: {e}", socket.to_string_lossy());
+    }
+}
+
+fn local_client(stream: &UnixStream) -> Result<Client, RequestError> {
+    let peer = stream.peer_cred().map_err(RequestError::ReadingRequest)?;
+    Ok(Client::Local {
+        uid: peer.uid(),
+        gid: peer.gid(),
+        pid: peer.pid(),
+    })
+}
+
+struct Server<'a, S, E>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+{
+    state: &'a State<S, E>,
+    identities: &'a [Box<dyn Identity>],
+    http_secrets: &'a [String],
+}
+
+impl<'a, S, E> Server<'a, S, E>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    async fn accept_loop(
+        &self,
+        listener: &UnixListener,
+        http: Option<&HttpListener>,
+    ) -> Result<(), ServeError> {
+        let mut signals = SignalListener::new().map_err(ServeError::ListeningForSignals)?;
+        let mut connections = FuturesUnordered::<LocalBoxFuture<()>>::new();
+        log::info!("serving {} secrets", self.state.secrets.len());
+
+        loop {
+            let accept_http = async {
+                match http {
+                    Some(HttpListener::Unix(l)) => {
+                        let (stream, _) = l.accept().await?;
+                        let client = local_client(&stream);
+                        Ok(self.handle_http(stream, client).boxed_local())
+                    }
+                    Some(HttpListener::Tcp(l)) => {
+                        let (stream, addr) = l.accept().await?;
+                        let client = Ok(Client::Remote(addr));
+                        Ok(self.handle_http(stream, client).boxed_local())
+                    }
+                    None => pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = signals.shutdown() => break,
+                Some(()) = connections.next() => (),
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => connections.push(self.handle(stream).boxed_local()),
+                    Err(e) => log::warn!("couldn't accept connection: {e}"),
+                },
+                accepted = accept_http => match accepted {
+                    Ok(connection) => connections.push(connection),
+                    Err::<_, std::io::Error>(e) => log::warn!("couldn't accept HTTP connection: {e}"),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answers a single request, logging (rather than returning) any errors so
+    /// that one bad client doesn't affect any others.
+    async fn handle(&self, mut stream: UnixStream) {
+        let res = match local_client(&stream) {
+            Ok(client) => self.respond(&mut stream, client).await,
+            Err(e) => Err(e),
+        };
+        let reply = match res {
+            Ok(plaintext) => {
+                let mut reply = b"OK\n".to_vec();
+                reply.extend_from_slice(&plaintext);
+                reply
+            }
+            Err(e) => {
+                log::warn!("{e}");
+                format!("ERR {e}\n").into_bytes()
+            }
+        };
+
+        if let Err(e) = stream.write_all(&reply).await {
+            log::debug!("couldn't reply to client: {e}");
+        }
+        let _ = stream.shutdown().await;
+    }
+
+    async fn respond(
+        &self,
+        stream: &mut UnixStream,
+        client: Client,
+    ) -> Result<Vec<u8>, RequestError> {
+        let mut reader = BufReader::new(&mut *stream);
+        let line = read_line(&mut reader).await?;
+
+        self.fetch(line.trim(), client, None).await
+    }
+
+    /// Answers a single HTTP request, like [Self::handle].
+    async fn handle_http<T>(&self, mut stream: T, client: Result<Client, RequestError>)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let res = match client {
+            Ok(client) => self.respond_http(&mut stream, client).await,
+            Err(e) => Err(e),
+        };
+        let (status, body) = match res {
+            Ok(plaintext) => ("200 OK", plaintext),
+            Err(e) => {
+                log::warn!("{e}");
+                (e.http_status(), format!("{e}\n").into_bytes())
+            }
+        };
+
+        let head = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        let res = async {
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(&body).await?;
+            stream.shutdown().await
+        };
+        if let Err(e) = res.await {
+            log::debug!("couldn't reply to client: {e}");
+        }
+    }
+
+    async fn respond_http<T>(&self, stream: &mut T, client: Client) -> Result<Vec<u8>, RequestError>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let mut reader = BufReader::new(stream);
+        let request = read_line(&mut reader).await?;
+        let mut parts = request.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+                (method, target)
+            }
+            _ => return Err(RequestError::Malformed),
+        };
+
+        let mut flavored = false;
+        for _ in 0..MAX_HEADERS {
+            let line = read_line(&mut reader).await?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(RequestError::Malformed);
+            };
+            if name.trim().eq_ignore_ascii_case(HTTP_REQUIRED_HEADER.0)
+                && value.trim() == HTTP_REQUIRED_HEADER.1
+            {
+                flavored = true;
+            }
+        }
+
+        if method != "GET" {
+            return Err(RequestError::MethodNotAllowed(method.to_string()));
+        }
+        if !flavored {
+            return Err(RequestError::MissingHeader);
+        }
+        let name = target
+            .strip_prefix(HTTP_PATH_PREFIX)
+            .ok_or_else(|| RequestError::NotFound(target.to_string()))?;
+
+        self.fetch(name, client, Some(self.http_secrets)).await
+    }
+
+    /// Reads and decrypts a secret, if the client may read it. Secrets served
+    /// over HTTP are limited to the given list, where one was given.
+    async fn fetch(
+        &self,
+        name: &str,
+        client: Client,
+        listed: Option<&[String]>,
+    ) -> Result<Vec<u8>, RequestError> {
+        let forbidden = || RequestError::Forbidden(name.to_string(), client.to_string());
+        // Unknown secrets are reported the same way as forbidden ones, so that
+        // clients can't find out what's configured
+        let secret = self.state.secrets.get(name).ok_or_else(forbidden)?;
+        let is_listed = match listed {
+            Some(listed) => listed.is_empty() || listed.iter().any(|n| n == name),
+            None => true,
+        };
+        let allowed = match client {
+            Client::Local { uid, gid, .. } => is_listed && may_read(secret, uid, gid),
+            // Only secrets that were explicitly listed are served to clients
+            // we can't identify
+            Client::Remote(_) => is_listed && listed.is_some_and(|l| !l.is_empty()),
+        };
+        if !allowed {
+            return Err(forbidden());
+        }
+        log::info!("serving {name} to {client}");
+
+        let reading = |e: String| RequestError::Reading(name.to_string(), e);
+        let threshold = self.state.large_secret_threshold;
+        let reader = read_secret(&self.state.storage, secret, threshold)
+            .await
+            .map_err(|e| reading(e.to_string()))?;
+        let mut plaintext = decrypt_secret(reader, secret, self.identities)
+            .await
+            .map_err(|e| reading(e.to_string()))?;
+        let mut data = Vec::new();
+        plaintext
+            .read_to_end(&mut data)
+            .await
+            .map_err(|e| reading(e.to_string()))?;
+
+        Ok(data)
+    }
+}
+
+/// Reads a line of a request, giving up on clients that are too slow or send
+/// too much.
+async fn read_line<R>(reader: &mut R) -> Result<String, RequestError>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut line = String::new();
+    let mut limited = reader.take(MAX_REQUEST_LEN);
+    tokio::time::timeout(REQUEST_TIMEOUT, limited.read_line(&mut line))
+        .await
+        .map_err(|_| RequestError::TimedOut)?
+        .map_err(RequestError::ReadingRequest)?;
+
+    Ok(line)
+}
+
+/// Whether a client may read a secret: root and whoever we're running as may
+/// read anything, and otherwise only the secret's owner (or members of its
+/// owning group), and the users and groups it allows.
+fn may_read(secret: &Secret, uid: u32, gid: u32) -> bool {
+    if uid == 0 || uid == nix::unistd::geteuid().as_raw() {
+        return true;
+    }
+
+    let owner = secret.owner_user.as_ref().map(|u| u.as_ref().uid.as_raw());
+    if owner == Some(uid) || secret.allowed_uids.contains(&uid) {
+        return true;
+    }
+
+    let group = secret.owner_group.as_ref().map(|g| g.as_ref().gid.as_raw());
+    let mut groups = group.iter().chain(&secret.allowed_gids);
+    if groups.clone().any(|&g| g == gid) {
+        return true;
+    }
+    // Peer credentials only carry the primary group, so supplementary
+    // membership comes from the group database
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => groups.any(|&g| is_member(&user, g)),
+        _ => false,
+    }
+}
+
+fn is_member(user: &User, gid: u32) -> bool {
+    match Group::from_gid(Gid::from_raw(gid)) {
+        Ok(Some(group)) => group.mem.contains(&user.name),
+        _ => false,
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum RequestError {
+    #[error("error reading request: {0}")]
+    ReadingRequest(std::io::Error),
+    #[error("timed out waiting for request")]
+    TimedOut,
+    #[error("malformed request")]
+    Malformed,
+    #[error("method {0} not allowed")]
+    MethodNotAllowed(String),
+    #[error("missing {}: {} header", HTTP_REQUIRED_HEADER.0, HTTP_REQUIRED_HEADER.1)]
+    MissingHeader,
+    #[error("no such path {0}")]
+    NotFound(String),
+    #[error("{1} may not read {0:?}")]
+    Forbidden(String, String),
+    #[error("error reading {0}: {1}")]
+    Reading(String, String),
+}
+
+impl RequestError {
+    fn http_status(&self) -> &'static str {
+        match self {
+            Self::ReadingRequest(_) | Self::Malformed | Self::MissingHeader => "400 Bad Request",
+            Self::TimedOut => "408 Request Timeout",
+            Self::MethodNotAllowed(_) => "405 Method Not Allowed",
+            Self::NotFound(_) => "404 Not Found",
+            Self::Forbidden(..) => "403 Forbidden",
+            Self::Reading(..) => "500 Internal Server Error",
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ServeError {
+    #[error("error reading identities: {0}")]
+    ReadingIdentities(#[from] DecryptionError),
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("secrets to serve over HTTP must be listed with --http-secret")]
+    NoHttpSecrets,
+    #[error("{0} exists and isn't a socket")]
+    NotASocket(PathBuf),
+    #[error("error binding socket: {0}")]
+    Binding(std::io::Error),
+    #[error("error listening for signals: {0}")]
+    ListeningForSignals(std::io::Error),
+}
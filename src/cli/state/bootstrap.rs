@@ -0,0 +1,99 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use tokio::io::AsyncReadExt;
+
+use crate::age::{dearmor_ciphertext, decrypt_bytes, get_identities, DecryptionError, HeaderError};
+use crate::secret::decrypt_secret;
+use crate::util::{parse_credentials, BoxedAsyncReader};
+use crate::{BootstrapSource, Secret};
+
+#[derive(thiserror::Error, Debug)]
+pub enum BootstrapError {
+    #[error("loading identities: {0}")]
+    LoadingIdentities(DecryptionError),
+    #[error("no secret named {0}")]
+    NoSuchSecret(String),
+    #[error("secret {0} isn't inline, so it can't be read before storage is set up")]
+    NotInline(String),
+    #[error("error reading {0}: {1}")]
+    ReadingFile(PathBuf, std::io::Error),
+    #[error("{0} isn't age ciphertext: {1}")]
+    InvalidCiphertext(BootstrapSource, HeaderError),
+    #[error("error decrypting {0}: {1}")]
+    Decrypting(BootstrapSource, DecryptionError),
+    #[error("error reading {0}: {1}")]
+    ReadingPlaintext(BootstrapSource, std::io::Error),
+    #[error("{0} isn't text")]
+    NotText(BootstrapSource),
+}
+
+/// Decrypts each bootstrap source, and sets the credentials in it (`KEY=VALUE`
+/// lines, like a credentials file) as environment variables, where storage
+/// picks them up when it's built.
+pub async fn load_credentials(
+    sources: &[BootstrapSource],
+    secrets: &[Secret],
+    identity_paths: &[PathBuf],
+) -> Result<(), BootstrapError> {
+    let identities = get_identities(identity_paths).map_err(BootstrapError::LoadingIdentities)?;
+    for source in sources {
+        let plaintext = match source {
+            BootstrapSource::Secret { name } => {
+                let secret = secrets
+                    .iter()
+                    .find(|s| &s.name == name)
+                    .ok_or_else(|| BootstrapError::NoSuchSecret(name.clone()))?;
+                let value = secret
+                    .value
+                    .as_deref()
+                    .ok_or_else(|| BootstrapError::NotInline(name.clone()))?;
+                let ciphertext = dearmor_ciphertext(value)
+                    .map_err(|e| BootstrapError::InvalidCiphertext(source.clone(), e))?;
+                decrypt_secret(Cursor::new(ciphertext), secret, &identities)
+                    .await
+                    .map_err(|e| BootstrapError::Decrypting(source.clone(), e))?
+            }
+            BootstrapSource::File { path } => {
+                // Not a secret, so there are no per-secret identities to use
+                let ciphertext = read_ciphertext(source, path).await?;
+                decrypt_bytes(Cursor::new(ciphertext), &identities)
+                    .await
+                    .map_err(|e| BootstrapError::Decrypting(source.clone(), e))?
+            }
+        };
+        let credentials = read_text(source, plaintext).await?;
+
+        for (key, value) in parse_credentials(&credentials) {
+            log::debug!("setting {key} from {source}");
+            std::env::set_var(key, value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a local file of ciphertext, which may be ASCII-armored.
+async fn read_ciphertext(source: &BootstrapSource, path: &Path) -> Result<Vec<u8>, BootstrapError> {
+    let data = tokio::fs::read(path)
+        .await
+        .map_err(|e| BootstrapError::ReadingFile(path.to_owned(), e))?;
+    match dearmor_ciphertext(&String::from_utf8_lossy(&data)) {
+        Ok(ciphertext) => Ok(ciphertext),
+        Err(HeaderError::NotArmored) => Ok(data),
+        Err(e) => Err(BootstrapError::InvalidCiphertext(source.clone(), e)),
+    }
+}
+
+async fn read_text(
+    source: &BootstrapSource,
+    mut plaintext: BoxedAsyncReader,
+) -> Result<String, BootstrapError> {
+    let mut data = Vec::new();
+    plaintext
+        .read_to_end(&mut data)
+        .await
+        .map_err(|e| BootstrapError::ReadingPlaintext(source.clone(), e))?;
+
+    String::from_utf8(data).map_err(|_| BootstrapError::NotText(source.clone()))
+}
@@ -1,11 +1,39 @@
-use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::default;
+use std::fmt::Display;
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
+use super::bootstrap::{load_credentials, BootstrapError};
 use super::State;
-use crate::secret::{EnvExposeArgs, FileExposeArgs};
-use crate::{Exposures, IntoSecretStorage, Secret, SecretError, SecretStorage};
+use crate::age::{dearmor_ciphertext, identity_exists, HeaderError};
+use crate::process::DEFAULT_SECRETS_DIR_ENV;
+use crate::secret::{
+    normalize_recipient,
+    template_secrets,
+    uses_key_groups,
+    ByteSize,
+    EnvExposeArgs,
+    FileExposeArgs,
+    TemplateExposeArgs,
+    DEFAULT_LARGE_SECRET_THRESHOLD,
+    KEY_GROUP_PREFIX,
+};
+use crate::util::partition_specs;
+use crate::{
+    ApprovalsConfig,
+    BootstrapSource,
+    BreakGlassConfig,
+    DiskBackedTmpdir,
+    Exposures,
+    IntoSecretStorage,
+    Secret,
+    SecretError,
+    SecretStorage,
+    StorageFallback,
+    TenantConfig,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum StateBuilderError {
@@ -14,10 +42,20 @@ pub enum StateBuilderError {
     #[error("invalid config file: {0}")]
     ParsingConfigFile(#[from] serde_yaml::Error),
 
-    #[error("duplicate secret path specified: {0}")]
-    DuplicatePath(PathBuf),
-    #[error("dupliecate environment variable name specified: {0}")]
-    DuplicateEnvName(String),
+    #[error("duplicate secret path specified: {0} (in {1} and {2})")]
+    DuplicatePath(PathBuf, ExposureSource, ExposureSource),
+    #[error("duplicate environment variable name specified: {0} (in {1} and {2})")]
+    DuplicateEnvName(String, ExposureSource, ExposureSource),
+    #[error("exposure in {1} refers to unknown secret {0}")]
+    UnknownSecret(String, ExposureSource),
+    #[error("template name {0} in {1} must be a plain file name")]
+    InvalidTemplateName(String, ExposureSource),
+    #[error("duplicate file name in secrets dir: {0} (in {1} and {2})")]
+    DuplicateTemplateName(String, ExposureSource, ExposureSource),
+    #[error("invalid template {0}: {1}")]
+    InvalidTemplate(PathBuf, String),
+    #[error("invalid configuration:\n{0}")]
+    InvalidConfig(ConfigErrors),
 
     #[error("build() called without a storage configuration provided")]
     StorageUnset,
@@ -25,8 +63,142 @@ pub enum StateBuilderError {
     #[error("multiple storage configurations provided")]
     DuplicateStorageConfig,
 
+    #[error("multiple break-glass configurations provided")]
+    DuplicateBreakGlassConfig,
+
+    #[error("multiple approvals configurations provided")]
+    DuplicateApprovalsConfig,
+
+    #[error("no tenant named {0}")]
+    UnknownTenant(String),
+    #[error("{2} refers to secret {0}, which belongs to tenant {1}")]
+    CrossTenantReference(String, String, ExposureSource),
+    #[error(
+        "secret {0} of tenant {2} is encrypted to key group {1}, which isn't one of its key groups"
+    )]
+    ForeignKeyGroup(String, String, String),
+    #[error("path of secret {0} must stay within the prefix of tenant {1}")]
+    PathOutsideTenant(String, String),
+
+    #[error("secret {0} is replaced by unknown secret {1}")]
+    UnknownReplacement(String, String),
+    #[error("secret {0} has neither a path nor an inline value")]
+    NoPath(String),
+    #[error("secret {0} has an inline value, so it can't have a path too")]
+    InlineWithPath(String),
+    #[error("inline value of secret {0} isn't armored age ciphertext: {1}")]
+    InvalidInlineValue(String, HeaderError),
+
+    #[error("exposure {0} is after {1}, which isn't exposed as a file or template")]
+    UnknownDependency(String, String),
+    #[error("exposures are after each other in a cycle: {}", .0.join(" after "))]
+    DependencyCycle(Vec<String>),
+
+    #[error("secret {0} is encrypted to a key group, but keyGroupsFrom isn't configured")]
+    KeyGroupsUnconfigured(String),
+
     #[error("error configuring storage: {0}")]
     SettingUpStorage(Box<dyn std::error::Error>),
+
+    #[error("error bootstrapping storage credentials: {0}")]
+    Bootstrapping(#[from] BootstrapError),
+}
+
+/// Where an exposure was configured, for error reporting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExposureSource {
+    ConfigFile(PathBuf),
+    /// A tenant's exposures, in the given config file (the name is boxed to
+    /// keep errors holding several sources small)
+    Tenant(PathBuf, Box<str>),
+    CommandLine,
+}
+
+impl ExposureSource {
+    /// Config file the exposure was defined in, if any.
+    pub fn config_file(&self) -> Option<&Path> {
+        match self {
+            Self::ConfigFile(p) | Self::Tenant(p, _) => Some(p),
+            Self::CommandLine => None,
+        }
+    }
+}
+
+impl Display for ExposureSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConfigFile(p) => write!(f, "config file {}", p.to_string_lossy()),
+            Self::Tenant(p, t) => write!(f, "tenant {t} in config file {}", p.to_string_lossy()),
+            Self::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// A secret used by an exposure, and where that exposure was configured.
+#[derive(Clone, Debug)]
+pub struct SecretReference {
+    pub secret_name: String,
+    /// The exposure, as `env:<name>`, `file[:<path>]` or `template:<name>`
+    pub exposure: String,
+    pub source: ExposureSource,
+}
+
+impl SecretReference {
+    pub fn file(exposure: &FileExposeArgs, source: &ExposureSource) -> Self {
+        Self {
+            secret_name: exposure.secret_name.clone(),
+            exposure: match &exposure.vanity_path {
+                Some(p) => format!("file:{}", p.to_string_lossy()),
+                None => "file".to_string(),
+            },
+            source: source.clone(),
+        }
+    }
+
+    pub fn env(exposure: &EnvExposeArgs, source: &ExposureSource) -> Self {
+        Self {
+            secret_name: exposure.secret_name.clone(),
+            exposure: format!("env:{}", exposure.name),
+            source: source.clone(),
+        }
+    }
+
+    /// References to each secret a template uses, reading the template to
+    /// find them.
+    pub async fn template(
+        exposure: &TemplateExposeArgs,
+        source: &ExposureSource,
+    ) -> Result<Vec<Self>, String> {
+        let template = tokio::fs::read_to_string(&exposure.template)
+            .await
+            .map_err(|e| e.to_string())?;
+        let names = template_secrets(&template).map_err(|e| e.to_string())?;
+
+        Ok(names
+            .into_iter()
+            .map(|secret_name| Self {
+                secret_name,
+                exposure: format!("template:{}", exposure.name),
+                source: source.clone(),
+            })
+            .collect())
+    }
+}
+
+/// Every problem found while validating the configuration, so that they can
+/// all be fixed at once.
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<StateBuilderError>);
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self
+            .0
+            .iter()
+            .map(|e| format!("  - {e}"))
+            .collect::<Vec<_>>();
+        write!(f, "{}", lines.join("\n"))
+    }
 }
 
 enum SetState<E> {
@@ -34,6 +206,7 @@ enum SetState<E> {
     Set(E),
 }
 
+#[allow(clippy::derivable_impls)]
 impl<E> Default for SetState<E> {
     fn default() -> Self {
         Self::Unset
@@ -46,14 +219,42 @@ pub struct StateBuilder<E, I> {
     secrets: Vec<Secret>,
     storage: SetState<I>,
     private_key_paths: Option<Vec<PathBuf>>,
-
-    seen_env_vars: HashSet<String>,
-    seen_file_paths: HashSet<PathBuf>,
-    seen_secret_names: HashSet<String>,
+    fallback: StorageFallback,
+    break_glass: Option<BreakGlassConfig>,
+    key_groups_from: Option<PathBuf>,
+    approvals: Option<ApprovalsConfig>,
+    secrets_dir_env: Option<Vec<String>>,
+    secrets_tmpdir: Option<PathBuf>,
+    disk_backed_tmpdir: DiskBackedTmpdir,
+    large_secret_threshold: Option<ByteSize>,
+    keep_large_previous: bool,
+    #[cfg(unix)]
+    mount_dirs: crate::system::MountDirs,
+    read_only: bool,
+    force_adopt: bool,
+    bootstrap: Vec<BootstrapSource>,
+    storage_type: Option<&'static str>,
+
+    seen_env_vars: HashMap<String, ExposureSource>,
+    seen_file_paths: HashMap<PathBuf, ExposureSource>,
+    template_sources: HashMap<String, ExposureSource>,
+    referenced_secrets: Vec<SecretReference>,
+    tenants: Tenants,
+    problems: Vec<StateBuilderError>,
 
     _data1: PhantomData<E>,
 }
 
+/// Which tenant is selected, and what's known about the others.
+#[derive(Default)]
+struct Tenants {
+    selected: Option<String>,
+    known: HashSet<String>,
+    /// Tenants and storage paths of secrets belonging to tenants that aren't
+    /// selected, which aren't loaded
+    other_secrets: HashMap<String, (String, PathBuf)>,
+}
+
 impl<E, I> Default for StateBuilder<E, I> {
     fn default() -> Self {
         Self {
@@ -61,10 +262,28 @@ impl<E, I> Default for StateBuilder<E, I> {
             secrets: Default::default(),
             storage: SetState::Unset,
             private_key_paths: Default::default(),
+            fallback: Default::default(),
+            break_glass: Default::default(),
+            key_groups_from: Default::default(),
+            approvals: Default::default(),
+            secrets_dir_env: Default::default(),
+            secrets_tmpdir: Default::default(),
+            disk_backed_tmpdir: Default::default(),
+            large_secret_threshold: Default::default(),
+            keep_large_previous: Default::default(),
+            #[cfg(unix)]
+            mount_dirs: Default::default(),
+            read_only: Default::default(),
+            force_adopt: Default::default(),
+            bootstrap: Default::default(),
+            storage_type: Default::default(),
 
             seen_env_vars: Default::default(),
             seen_file_paths: Default::default(),
-            seen_secret_names: Default::default(),
+            template_sources: Default::default(),
+            referenced_secrets: Default::default(),
+            tenants: Default::default(),
+            problems: Default::default(),
 
             _data1: Default::default(),
         }
@@ -92,6 +311,11 @@ impl<E, J> StateBuilder<E, J> {
         <S as IntoSecretStorage>::Impl: 'static,
         // ProcessRunningError: From<<S as IntoSecretStorage>::Error>,
     {
+        // Storage may need credentials that only the bootstrap sources have
+        if !self.bootstrap.is_empty() {
+            let identity_paths = identity_paths(self.private_key_paths.clone());
+            load_credentials(&self.bootstrap, &self.secrets, &identity_paths).await?;
+        }
         let storage = into_storage.build().await;
 
         Ok(StateBuilder {
@@ -99,19 +323,270 @@ impl<E, J> StateBuilder<E, J> {
             secrets: self.secrets,
             storage: SetState::Set(storage),
             private_key_paths: self.private_key_paths,
+            fallback: self.fallback,
+            break_glass: self.break_glass,
+            key_groups_from: self.key_groups_from,
+            approvals: self.approvals,
+            secrets_dir_env: self.secrets_dir_env,
+            secrets_tmpdir: self.secrets_tmpdir,
+            disk_backed_tmpdir: self.disk_backed_tmpdir,
+            large_secret_threshold: self.large_secret_threshold,
+            keep_large_previous: self.keep_large_previous,
+            #[cfg(unix)]
+            mount_dirs: self.mount_dirs,
+            read_only: self.read_only,
+            force_adopt: self.force_adopt,
+            bootstrap: self.bootstrap,
+            storage_type: self.storage_type,
 
             seen_env_vars: self.seen_env_vars,
             seen_file_paths: self.seen_file_paths,
-            seen_secret_names: self.seen_secret_names,
+            template_sources: self.template_sources,
+            referenced_secrets: self.referenced_secrets,
+            tenants: self.tenants,
+            problems: self.problems,
 
             _data1: default::Default::default(),
         })
     }
 
+    pub fn set_fallback(&mut self, fallback: StorageFallback) {
+        self.fallback = fallback;
+    }
+
+    pub fn set_break_glass(&mut self, config: BreakGlassConfig) -> Result<(), StateBuilderError> {
+        if self.break_glass.is_some() {
+            return Err(StateBuilderError::DuplicateBreakGlassConfig);
+        }
+
+        self.break_glass = Some(config);
+        Ok(())
+    }
+
+    /// Storage path of the recipients registry to resolve key groups from.
+    pub fn set_key_groups_from(&mut self, path: PathBuf) {
+        self.key_groups_from = Some(path);
+    }
+
+    pub fn set_approvals(&mut self, config: ApprovalsConfig) -> Result<(), StateBuilderError> {
+        if self.approvals.is_some() {
+            return Err(StateBuilderError::DuplicateApprovalsConfig);
+        }
+
+        self.approvals = Some(config);
+        Ok(())
+    }
+
+    pub fn set_exposure_root(&mut self, root: PathBuf) {
+        self.exposures.root = Some(root);
+    }
+
+    pub fn set_secrets_dir_env(&mut self, names: Vec<String>) {
+        self.secrets_dir_env = Some(names);
+    }
+
+    pub fn set_secrets_tmpdir(&mut self, dir: PathBuf) {
+        self.secrets_tmpdir = Some(dir);
+    }
+
+    pub fn set_disk_backed_tmpdir(&mut self, policy: DiskBackedTmpdir) {
+        self.disk_backed_tmpdir = policy;
+    }
+
+    /// Sets the size above which secrets are treated as large.
+    pub fn set_large_secret_threshold(&mut self, threshold: ByteSize) {
+        self.large_secret_threshold = Some(threshold);
+    }
+
+    /// Sets whether large secrets' ciphertext is kept before it's overwritten.
+    pub fn set_keep_large_previous(&mut self, keep: bool) {
+        self.keep_large_previous = keep;
+    }
+
+    #[cfg(unix)]
+    pub fn set_mount_dirs(&mut self, dirs: crate::system::MountDirs) {
+        self.mount_dirs = dirs;
+    }
+
+    /// Disables commands that modify stored secrets. Once set by any config
+    /// (or the command line), this can't be unset.
+    pub fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    /// Moves regular files in the way of any exposure's symlinked vanity path
+    /// aside, rather than only those of exposures that ask for it.
+    pub fn set_force_adopt(&mut self) {
+        self.force_adopt = true;
+    }
+
+    /// Adds sources of credentials to set up storage with, which are read when
+    /// storage is set.
+    pub fn add_bootstrap<I: IntoIterator<Item = BootstrapSource>>(&mut self, items: I) {
+        self.bootstrap.extend(items);
+    }
+
+    /// Records the type of storage that's configured, which is only used for
+    /// introspection (as storage itself is generic).
+    pub fn set_storage_type(&mut self, name: &'static str) {
+        self.storage_type = Some(name);
+    }
+
     pub fn add_secrets<I: IntoIterator<Item = Secret>>(&mut self, items: I) {
         self.secrets.extend(items);
     }
 
+    /// Stops checking ciphertext against the pins and signing keys of the
+    /// secrets added so far, for storage whose ciphertext can never match
+    /// them (e.g. dev values, which are encrypted on the fly).
+    pub fn skip_verification(&mut self) {
+        for secret in self.secrets.iter_mut() {
+            secret.pin = None;
+            secret.signing_keys.clear();
+        }
+    }
+
+    /// Only loads the given tenant's secrets and exposures (along with those
+    /// that don't belong to a tenant). Must be set before tenants are added.
+    pub fn set_tenant(&mut self, name: String) {
+        self.tenants.selected = Some(name);
+    }
+
+    /// Adds a tenant's secrets and exposures, unless another tenant is
+    /// selected. Secrets are stored under the tenant's prefix, and may only be
+    /// encrypted to its key groups.
+    pub fn add_tenant(&mut self, config_file: &Path, name: String, tenant: TenantConfig) {
+        let mut secrets = tenant.secrets;
+        for secret in secrets.iter_mut() {
+            secret.tenant = Some(name.clone());
+            secret.defined_in = Some(config_file.to_owned());
+
+            let escapes = secret
+                .path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+            if escapes {
+                self.problems.push(StateBuilderError::PathOutsideTenant(
+                    secret.name.clone(),
+                    name.clone(),
+                ));
+            }
+            if let Some(prefix) = tenant.prefix.as_ref().filter(|_| secret.value.is_none()) {
+                secret.path = prefix.join(&secret.path);
+            }
+
+            let foreign_groups = secret
+                .encryption_keys
+                .iter()
+                .filter_map(|k| k.strip_prefix(KEY_GROUP_PREFIX))
+                .filter(|g| !tenant.key_groups.iter().any(|t| t == g));
+            for group in foreign_groups {
+                self.problems.push(StateBuilderError::ForeignKeyGroup(
+                    secret.name.clone(),
+                    group.to_string(),
+                    name.clone(),
+                ));
+            }
+        }
+
+        self.tenants.known.insert(name.clone());
+        let selected = match &self.tenants.selected {
+            Some(s) => *s == name,
+            None => true,
+        };
+        if !selected {
+            let others = secrets
+                .into_iter()
+                .map(|s| (s.name, (name.clone(), s.path)));
+            self.tenants.other_secrets.extend(others);
+            return;
+        }
+
+        let source = ExposureSource::Tenant(config_file.to_owned(), name.into());
+        let (files, envs, templates) = partition_specs(tenant.exposures);
+        self.add_file_exposures(&source, files);
+        self.add_env_exposures(&source, envs);
+        self.add_template_exposures(&source, templates);
+        self.add_secrets(secrets);
+    }
+
+    /// Storage paths of every secret, by name, including those belonging to
+    /// tenants that aren't selected.
+    pub fn secret_paths(&self) -> HashMap<String, PathBuf> {
+        let others = self
+            .tenants
+            .other_secrets
+            .iter()
+            .map(|(name, (_, path))| (name.clone(), path.clone()));
+
+        self.secrets
+            .iter()
+            .map(|s| (s.name.clone(), s.path.clone()))
+            .chain(others)
+            .collect()
+    }
+
+    /// Adds secrets, replacing any already defined with the same name (for
+    /// local overlays).
+    pub fn override_secrets<I: IntoIterator<Item = Secret>>(&mut self, items: I) {
+        for secret in items {
+            self.secrets.retain(|s| s.name != secret.name);
+            self.secrets.push(secret);
+        }
+    }
+
+    /// Adds exposures from a local overlay, replacing (rather than conflicting
+    /// with) existing exposures of the same type for the same secrets, and
+    /// templates with the same name.
+    pub fn override_exposures(
+        &mut self,
+        source: &ExposureSource,
+        files: Vec<FileExposeArgs>,
+        envs: Vec<EnvExposeArgs>,
+        templates: Vec<TemplateExposeArgs>,
+    ) {
+        for file in files.iter() {
+            for old in self
+                .exposures
+                .files
+                .remove(&file.secret_name)
+                .unwrap_or_default()
+            {
+                if let Some(p) = &old.vanity_path {
+                    self.seen_file_paths.remove(p);
+                }
+            }
+        }
+
+        for env in envs.iter() {
+            for old in self
+                .exposures
+                .envs
+                .remove(&env.secret_name)
+                .unwrap_or_default()
+            {
+                self.seen_env_vars.remove(&old.name);
+            }
+        }
+
+        for template in templates.iter() {
+            let (old, kept) = std::mem::take(&mut self.exposures.templates)
+                .into_iter()
+                .partition(|t| t.name == template.name);
+            self.exposures.templates = kept;
+            for old in old {
+                self.template_sources.remove(&old.name);
+                if let Some(p) = &old.vanity_path {
+                    self.seen_file_paths.remove(p);
+                }
+            }
+        }
+
+        self.add_file_exposures(source, files);
+        self.add_env_exposures(source, envs);
+        self.add_template_exposures(source, templates);
+    }
+
     // pub async fn add_config_file(self, p: &Path) -> Result<(), StateBuilderError> {
     //     let data = fs::read(p)
     //         .await
@@ -140,43 +615,126 @@ impl<E, J> StateBuilder<E, J> {
     //     Ok(())
     // }
 
-    pub fn add_file_exposures<I>(&mut self, args: I) -> Result<(), StateBuilderError>
+    /// Records a vanity path as used, returning false (and recording the
+    /// conflict) if it's already in use.
+    fn claim_vanity_path(&mut self, path: Option<&Path>, source: &ExposureSource) -> bool {
+        let Some(p) = path else {
+            return true;
+        };
+
+        match self.seen_file_paths.entry(p.to_owned()) {
+            Entry::Occupied(e) => {
+                self.problems.push(StateBuilderError::DuplicatePath(
+                    p.to_owned(),
+                    e.get().clone(),
+                    source.clone(),
+                ));
+                false
+            }
+            Entry::Vacant(e) => {
+                e.insert(source.clone());
+                true
+            }
+        }
+    }
+
+    /// Adds template exposures from the given source. Relative template paths
+    /// are resolved against the directory of the config file they came from.
+    pub fn add_template_exposures<I>(&mut self, source: &ExposureSource, args: I)
     where
-        I: IntoIterator<Item = FileExposeArgs>,
+        I: IntoIterator<Item = TemplateExposeArgs>,
     {
         let mut items = Vec::new();
         for mut exposure in args.into_iter() {
-            if let Some(p) = &exposure.vanity_path {
-                let is_new = self.seen_file_paths.insert(p.to_owned());
-                if !is_new {
-                    return Err(StateBuilderError::DuplicatePath(
-                        exposure.vanity_path.take().unwrap(),
+            let is_file_name = Path::new(&exposure.name).file_name()
+                == Some(exposure.name.as_ref())
+                && !exposure.name.contains(std::path::MAIN_SEPARATOR);
+            if !is_file_name {
+                self.problems.push(StateBuilderError::InvalidTemplateName(
+                    exposure.name,
+                    source.clone(),
+                ));
+                continue;
+            }
+
+            match self.template_sources.entry(exposure.name.clone()) {
+                Entry::Occupied(e) => {
+                    self.problems.push(StateBuilderError::DuplicateTemplateName(
+                        exposure.name,
+                        e.get().clone(),
+                        source.clone(),
                     ));
+                    continue;
                 }
+                Entry::Vacant(e) => {
+                    e.insert(source.clone());
+                }
+            }
+
+            if !self.claim_vanity_path(exposure.vanity_path.as_deref(), source) {
+                continue;
+            }
+
+            if let Some(config) = source.config_file() {
+                if exposure.template.is_relative() {
+                    let dir = config.parent().unwrap_or(Path::new("."));
+                    exposure.template = dir.join(&exposure.template);
+                }
+            }
+
+            items.push(exposure);
+        }
+
+        self.exposures.add_templates(items);
+    }
+
+    /// Adds file exposures from the given source. Conflicts are recorded, and
+    /// reported together by [StateBuilder::build].
+    pub fn add_file_exposures<I>(&mut self, source: &ExposureSource, args: I)
+    where
+        I: IntoIterator<Item = FileExposeArgs>,
+    {
+        let mut items = Vec::new();
+        for exposure in args.into_iter() {
+            if !self.claim_vanity_path(exposure.vanity_path.as_deref(), source) {
+                continue;
             }
 
+            self.referenced_secrets
+                .push(SecretReference::file(&exposure, source));
             items.push(exposure);
         }
 
         self.exposures.add_files(items);
-        Ok(())
     }
 
-    pub fn add_env_exposures<I>(&mut self, args: I) -> Result<(), StateBuilderError>
+    /// Adds environment variable exposures from the given source. Conflicts
+    /// are recorded, and reported together by [StateBuilder::build].
+    pub fn add_env_exposures<I>(&mut self, source: &ExposureSource, args: I)
     where
         I: IntoIterator<Item = EnvExposeArgs>,
     {
         let mut items = Vec::new();
         for exposure in args.into_iter() {
-            let is_new = self.seen_env_vars.insert(exposure.name.clone());
-            if !is_new {
-                return Err(StateBuilderError::DuplicateEnvName(exposure.name));
+            match self.seen_env_vars.entry(exposure.name.clone()) {
+                Entry::Occupied(e) => {
+                    self.problems.push(StateBuilderError::DuplicateEnvName(
+                        exposure.name,
+                        e.get().clone(),
+                        source.clone(),
+                    ));
+                    continue;
+                }
+                Entry::Vacant(e) => {
+                    e.insert(source.clone());
+                }
             }
 
+            self.referenced_secrets
+                .push(SecretReference::env(&exposure, source));
             items.push(exposure);
         }
         self.exposures.add_envs(items);
-        Ok(())
     }
 }
 
@@ -185,37 +743,199 @@ where
     E: SecretError + 'static + Sized,
     J: SecretStorage<Error = E>,
 {
-    pub async fn build(self) -> Result<State<J, E>, StateBuilderError> {
-        let private_key_paths = self
-            .private_key_paths
-            .unwrap_or_else(|| {
-                let home = match std::env::var("HOME") {
-                    Ok(homedir) => homedir,
-                    Err(_) => return Vec::new(),
-                };
-
-                let mut ssh_dir = PathBuf::new();
-                ssh_dir.push(home);
-                ssh_dir.push(".ssh");
-
-                let rsa_path = ssh_dir.join("id_rsa");
-                let ed25519_path = ssh_dir.join("id_ed25519");
-                vec![rsa_path, ed25519_path]
-            })
-            .into_iter()
-            .filter(|p| p.exists())
-            .collect();
+    pub async fn build(mut self) -> Result<State<J, E>, StateBuilderError> {
+        // Check every exposure refers to a real secret up-front, rather than
+        // failing partway through exposing them
+        let secret_names = self
+            .secrets
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<HashSet<_>>();
+        let mut problems = self.problems;
+        let mut referenced_secrets = self.referenced_secrets;
+        for template in self.exposures.templates.iter() {
+            let source = &self.template_sources[&template.name];
+            if self.exposures.files.contains_key(&template.name) {
+                problems.push(StateBuilderError::DuplicateTemplateName(
+                    template.name.clone(),
+                    source.clone(),
+                    source.clone(),
+                ));
+            }
+
+            match SecretReference::template(template, source).await {
+                Ok(references) => referenced_secrets.extend(references),
+                Err(e) => problems.push(StateBuilderError::InvalidTemplate(
+                    template.template.clone(),
+                    e,
+                )),
+            }
+        }
+        if let Some(tenant) = &self.tenants.selected {
+            if !self.tenants.known.contains(tenant) {
+                problems.push(StateBuilderError::UnknownTenant(tenant.clone()));
+            }
+        }
+        let secret_tenants = self
+            .secrets
+            .iter()
+            .filter_map(|s| Some((s.name.as_str(), s.tenant.as_deref()?)))
+            .chain(
+                self.tenants
+                    .other_secrets
+                    .iter()
+                    .map(|(name, (tenant, _))| (name.as_str(), tenant.as_str())),
+            )
+            .collect::<HashMap<_, _>>();
+        for reference in referenced_secrets.iter() {
+            let name = reference.secret_name.as_str();
+            let referrer = match &reference.source {
+                ExposureSource::Tenant(_, t) => Some(&**t),
+                _ => None,
+            };
+            // Tenants' secrets can only be exposed by that tenant, or outside
+            // of any tenant (unless that tenant isn't loaded)
+            let owner = secret_tenants.get(name).copied();
+            let foreign = match (owner, referrer) {
+                (Some(owner), Some(referrer)) => owner != referrer,
+                (Some(_), None) => !secret_names.contains(name),
+                (None, _) => false,
+            };
+            if foreign {
+                problems.push(StateBuilderError::CrossTenantReference(
+                    reference.secret_name.clone(),
+                    owner.unwrap_or_default().to_string(),
+                    reference.source.clone(),
+                ));
+            } else if !secret_names.contains(name) {
+                problems.push(StateBuilderError::UnknownSecret(
+                    reference.secret_name.clone(),
+                    reference.source.clone(),
+                ));
+            }
+        }
+        for secret in self.secrets.iter() {
+            match (&secret.value, secret.path.as_os_str().is_empty()) {
+                (Some(_), false) => {
+                    problems.push(StateBuilderError::InlineWithPath(secret.name.clone()))
+                }
+                (Some(value), true) => {
+                    if let Err(e) = dearmor_ciphertext(value) {
+                        problems.push(StateBuilderError::InvalidInlineValue(
+                            secret.name.clone(),
+                            e,
+                        ))
+                    }
+                }
+                (None, true) => problems.push(StateBuilderError::NoPath(secret.name.clone())),
+                _ => (),
+            }
+            match &secret.replaced_by {
+                Some(r) if !secret_names.contains(r.as_str()) => problems.push(
+                    StateBuilderError::UnknownReplacement(secret.name.clone(), r.clone()),
+                ),
+                _ => (),
+            }
+        }
+        let dependencies = self.exposures.dependencies();
+        for (name, after) in dependencies.iter() {
+            for dep in after.iter().filter(|d| !dependencies.contains_key(*d)) {
+                problems.push(StateBuilderError::UnknownDependency(
+                    name.to_string(),
+                    dep.to_string(),
+                ));
+            }
+        }
+        if let Some(cycle) = self.exposures.dependency_cycle() {
+            problems.push(StateBuilderError::DependencyCycle(cycle));
+        }
+        if self.key_groups_from.is_none() {
+            for secret in self.secrets.iter() {
+                if uses_key_groups(&secret.encryption_keys) {
+                    problems.push(StateBuilderError::KeyGroupsUnconfigured(
+                        secret.name.clone(),
+                    ));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            return Err(StateBuilderError::InvalidConfig(ConfigErrors(problems)));
+        }
+
+        let private_key_paths = identity_paths(self.private_key_paths);
+
+        // Break-glass keys are mandatory recipients of every secret
+        let mut secrets = self.secrets;
+        if let Some(break_glass) = &self.break_glass {
+            for secret in secrets.iter_mut() {
+                for key in break_glass.encryption_keys.iter() {
+                    let present = secret
+                        .encryption_keys
+                        .iter()
+                        .any(|k| normalize_recipient(k) == normalize_recipient(key));
+                    if !present {
+                        secret.encryption_keys.push(key.clone());
+                    }
+                }
+            }
+        }
 
         let backing = match self.storage {
             SetState::Set(b) => b,
             SetState::Unset => return Err(StateBuilderError::StorageUnset),
         };
+        if self.force_adopt {
+            self.exposures.force_adopt();
+        }
 
-        Ok(State::new(
-            self.secrets,
-            self.exposures,
+        Ok(State {
+            secrets: secrets.into_iter().map(|s| (s.name.clone(), s)).collect(),
+            exposures: self.exposures,
+            references: referenced_secrets,
             private_key_paths,
-            backing,
-        ))
+            storage: backing,
+            storage_type: self.storage_type,
+            fallback: self.fallback,
+            break_glass: self.break_glass,
+            key_groups_from: self.key_groups_from,
+            approvals: self.approvals,
+            secrets_dir_env: self
+                .secrets_dir_env
+                .unwrap_or_else(|| vec![DEFAULT_SECRETS_DIR_ENV.to_string()]),
+            secrets_tmpdir: self.secrets_tmpdir,
+            disk_backed_tmpdir: self.disk_backed_tmpdir,
+            large_secret_threshold: self
+                .large_secret_threshold
+                .map_or(DEFAULT_LARGE_SECRET_THRESHOLD, |t| t.0),
+            keep_large_previous: self.keep_large_previous,
+            #[cfg(unix)]
+            mount_dirs: self.mount_dirs,
+            read_only: self.read_only,
+
+            _data1: Default::default(),
+        })
     }
 }
+
+/// The given identities (or the default SSH keys, if none were given) that
+/// exist.
+fn identity_paths(private_key_paths: Option<Vec<PathBuf>>) -> Vec<PathBuf> {
+    private_key_paths
+        .unwrap_or_else(|| {
+            let home = match std::env::var("HOME") {
+                Ok(homedir) => homedir,
+                Err(_) => return Vec::new(),
+            };
+
+            let mut ssh_dir = PathBuf::new();
+            ssh_dir.push(home);
+            ssh_dir.push(".ssh");
+
+            let rsa_path = ssh_dir.join("id_rsa");
+            let ed25519_path = ssh_dir.join("id_ed25519");
+            vec![rsa_path, ed25519_path]
+        })
+        .into_iter()
+        .filter(|p| identity_exists(p))
+        .collect()
+}
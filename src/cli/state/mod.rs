@@ -1,11 +1,32 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
 use std::path::PathBuf;
 
-use crate::{Exposures, Secret, SecretError, SecretStorage};
+use crate::secret::{uses_key_groups, KeepPrevious, RecipientsRegistry, UnknownKeyGroup};
+use crate::{
+    ApprovalsConfig,
+    BreakGlassConfig,
+    DiskBackedTmpdir,
+    Exposures,
+    Secret,
+    SecretError,
+    SecretStorage,
+    StorageFallback,
+};
 
+mod bootstrap;
+pub use bootstrap::BootstrapError;
 mod builder;
-pub use builder::{StateBuilder, StateBuilderError};
+pub use builder::{ConfigErrors, ExposureSource, SecretReference, StateBuilder, StateBuilderError};
+mod snapshot;
+pub use snapshot::{
+    EnvExposureSnapshot,
+    ExposuresSnapshot,
+    FileExposureSnapshot,
+    SecretSnapshot,
+    StateSnapshot,
+    TemplateExposureSnapshot,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ExposureLoadingError {
@@ -15,6 +36,14 @@ pub enum ExposureLoadingError {
     DecodingMountConfigFiles(serde_yaml::Error),
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum KeyGroupsError {
+    #[error("{0}")]
+    ReadingRegistry(Box<dyn std::error::Error>),
+    #[error("recipients of {0}: {1}")]
+    UnknownGroup(String, UnknownKeyGroup),
+}
+
 pub struct State<S, E>
 where
     S: SecretStorage,
@@ -22,32 +51,128 @@ where
 {
     pub secrets: HashMap<String, Secret>,
     pub exposures: Exposures,
+    /// Secrets used by exposures (including those used by templates), and
+    /// where each exposure was configured
+    pub references: Vec<SecretReference>,
     pub private_key_paths: Vec<PathBuf>,
 
     pub storage: S,
+    /// Type of the configured storage (as in its `type`), or `Dev` when dev
+    /// values are used
+    pub storage_type: Option<&'static str>,
+    pub fallback: StorageFallback,
+    pub break_glass: Option<BreakGlassConfig>,
+    /// Storage path of the recipients registry that key groups are defined in
+    pub key_groups_from: Option<PathBuf>,
+    /// Keyholders who must approve each other's destructive operations
+    pub approvals: Option<ApprovalsConfig>,
+    pub secrets_dir_env: Vec<String>,
+    /// Where run-command creates its secret file directory, if not the
+    /// system temp directory
+    pub secrets_tmpdir: Option<PathBuf>,
+    /// What run-command does if its secret file directory isn't
+    /// memory-backed
+    pub disk_backed_tmpdir: DiskBackedTmpdir,
+    /// Size (in bytes) above which secrets are streamed or spooled to disk,
+    /// rather than held in memory
+    pub large_secret_threshold: u64,
+    /// Whether the previous version of a large secret is kept when it's
+    /// overwritten
+    pub keep_large_previous: bool,
+    /// Mode and ownership of the directories system mount creates
+    #[cfg(unix)]
+    pub mount_dirs: crate::system::MountDirs,
+    /// Whether commands that modify stored secrets are disabled
+    pub read_only: bool,
 
     _data1: PhantomData<E>,
 }
 
 impl<S, E> State<S, E>
 where
-    S: SecretStorage<Error = E>,
-    E: SecretError + 'static + Sized,
+    S: SecretStorage,
+    E: SecretError,
 {
-    pub fn new(
-        secrets: Vec<Secret>,
-        exposures: Exposures,
-        private_key_paths: Vec<PathBuf>,
-        storage: S,
-    ) -> Self {
-        let secrets = secrets.into_iter().map(|s| (s.name.clone(), s)).collect();
-        Self {
-            secrets,
-            exposures,
-            private_key_paths,
-            storage,
-
-            _data1: Default::default(),
+    /// Where each of the given secret's exposures was configured, without
+    /// repeats.
+    pub fn used_by(&self, secret_name: &str) -> Vec<&ExposureSource> {
+        let mut sources = Vec::new();
+        for reference in self.references.iter() {
+            if reference.secret_name == secret_name && !sources.contains(&&reference.source) {
+                sources.push(&reference.source);
+            }
+        }
+
+        sources
+    }
+
+    /// How secrets' current objects are kept before they're overwritten.
+    pub fn keep_previous(&self) -> KeepPrevious {
+        KeepPrevious {
+            threshold: self.large_secret_threshold,
+            large: self.keep_large_previous,
+        }
+    }
+
+    /// Logs a warning for each deprecated secret that's about to be exposed.
+    pub fn warn_deprecated(&self) {
+        let mut warned = HashSet::new();
+        for name in self.references.iter().map(|r| &r.secret_name) {
+            let secret = match self.secrets.get(name) {
+                Some(s) if s.deprecated => s,
+                _ => continue,
+            };
+            if !warned.insert(name) {
+                continue;
+            }
+
+            let used_by = self
+                .used_by(name)
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            match &secret.replaced_by {
+                Some(r) => log::warn!("{name} is deprecated, use {r} instead (used by {used_by})"),
+                None => log::warn!("{name} is deprecated (used by {used_by})"),
+            }
         }
     }
 }
+
+impl<S, E> State<S, E>
+where
+    S: SecretStorage,
+    E: SecretError,
+    <S as SecretStorage>::Error: 'static,
+{
+    /// Expands key groups in secrets' recipients to the current members of
+    /// each group, read from the recipients registry in storage.
+    ///
+    /// This isn't done when building state, so that commands that only
+    /// decrypt don't depend on the registry being readable.
+    pub async fn resolve_key_groups(&mut self) -> Result<(), KeyGroupsError> {
+        let path = match &self.key_groups_from {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if !self
+            .secrets
+            .values()
+            .any(|s| uses_key_groups(&s.encryption_keys))
+        {
+            return Ok(());
+        }
+
+        let registry = RecipientsRegistry::read(&self.storage, path)
+            .await
+            .map_err(|e| KeyGroupsError::ReadingRegistry(Box::new(e)))?;
+        for secret in self.secrets.values_mut() {
+            secret.encryption_keys = registry
+                .expand(&secret.encryption_keys)
+                .map_err(|e| KeyGroupsError::UnknownGroup(secret.name.clone(), e))?;
+        }
+
+        Ok(())
+    }
+}
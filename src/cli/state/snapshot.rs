@@ -0,0 +1,206 @@
+//! A read-only view of the fully loaded (merged and validated) state, for
+//! other tools to consume, e.g. to detect drift between hosts' configs. It
+//! never includes plaintext, nor the ciphertext of inline secrets.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Serializer};
+
+use super::State;
+use crate::secret::{AclEntry, EnvExposeArgs, FileExposeArgs, LinkMode, TemplateExposeArgs};
+use crate::{Secret, SecretError, SecretStorage, StorageFallback};
+
+#[derive(Serialize, Debug)]
+pub struct StateSnapshot<'a> {
+    /// Type of the configured storage (as in its `type`)
+    pub storage: Option<&'a str>,
+    pub fallback: StorageFallback,
+    pub read_only: bool,
+    /// Identity files secrets are decrypted with, unless a secret has its own
+    pub identity_paths: &'a [PathBuf],
+    pub key_groups_from: Option<&'a Path>,
+    pub break_glass_keys: &'a [String],
+    pub approval_keys: &'a [String],
+    pub secrets_dir_env: &'a [String],
+    pub secrets: BTreeMap<&'a str, SecretSnapshot<'a>>,
+    pub exposures: ExposuresSnapshot<'a>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SecretSnapshot<'a> {
+    /// Storage path, which inline secrets don't have
+    pub path: Option<&'a Path>,
+    pub inline: bool,
+    pub encryption_keys: &'a [String],
+    pub signing_keys: &'a [String],
+    pub identities: &'a [PathBuf],
+    pub pin: Option<String>,
+    pub tenant: Option<&'a str>,
+    pub defined_in: Option<&'a Path>,
+    pub metadata: &'a BTreeMap<String, String>,
+    pub deprecated: bool,
+    pub replaced_by: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExposuresSnapshot<'a> {
+    /// Directory relative vanity paths are resolved against, if configured
+    pub root: Option<&'a Path>,
+    pub files: Vec<FileExposureSnapshot<'a>>,
+    pub envs: Vec<EnvExposureSnapshot<'a>>,
+    pub templates: Vec<TemplateExposureSnapshot<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileExposureSnapshot<'a> {
+    pub secret: &'a str,
+    pub path: Option<&'a Path>,
+    #[serde(serialize_with = "octal")]
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub link_mode: LinkMode,
+    pub acl: Vec<String>,
+    pub optional: bool,
+    pub after: &'a [String],
+}
+
+#[derive(Serialize, Debug)]
+pub struct EnvExposureSnapshot<'a> {
+    pub secret: &'a str,
+    pub name: &'a str,
+    pub overwrite: bool,
+    pub optional: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TemplateExposureSnapshot<'a> {
+    pub name: &'a str,
+    pub template: &'a Path,
+    pub path: Option<&'a Path>,
+    #[serde(serialize_with = "octal")]
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub link_mode: LinkMode,
+    pub acl: Vec<String>,
+    pub vars: &'a BTreeMap<String, String>,
+    pub optional: bool,
+    pub after: &'a [String],
+}
+
+fn octal<S: Serializer>(mode: &Option<u32>, s: S) -> Result<S::Ok, S::Error> {
+    match mode {
+        Some(mode) => s.serialize_str(&format!("{mode:04o}")),
+        None => s.serialize_none(),
+    }
+}
+
+fn acl(entries: &[AclEntry]) -> Vec<String> {
+    entries.iter().map(ToString::to_string).collect()
+}
+
+impl<'a> From<&'a Secret> for SecretSnapshot<'a> {
+    fn from(s: &'a Secret) -> Self {
+        Self {
+            path: Some(s.path.as_path()).filter(|_| s.value.is_none()),
+            inline: s.value.is_some(),
+            encryption_keys: &s.encryption_keys,
+            signing_keys: &s.signing_keys,
+            identities: &s.identities,
+            pin: s.pin.as_ref().map(ToString::to_string),
+            tenant: s.tenant.as_deref(),
+            defined_in: s.defined_in.as_deref(),
+            metadata: &s.metadata,
+            deprecated: s.deprecated,
+            replaced_by: s.replaced_by.as_deref(),
+        }
+    }
+}
+
+impl<'a> From<&'a FileExposeArgs> for FileExposureSnapshot<'a> {
+    fn from(e: &'a FileExposeArgs) -> Self {
+        Self {
+            secret: &e.secret_name,
+            path: e.vanity_path.as_deref(),
+            mode: e.mode,
+            owner: e.owner.as_ref().map(ToString::to_string),
+            group: e.group.as_ref().map(ToString::to_string),
+            link_mode: e.link_mode,
+            acl: acl(&e.acl),
+            optional: e.optional,
+            after: &e.after,
+        }
+    }
+}
+
+impl<'a> From<&'a EnvExposeArgs> for EnvExposureSnapshot<'a> {
+    fn from(e: &'a EnvExposeArgs) -> Self {
+        Self {
+            secret: &e.secret_name,
+            name: &e.name,
+            overwrite: e.overwrite,
+            optional: e.optional,
+        }
+    }
+}
+
+impl<'a> From<&'a TemplateExposeArgs> for TemplateExposureSnapshot<'a> {
+    fn from(t: &'a TemplateExposeArgs) -> Self {
+        Self {
+            name: &t.name,
+            template: &t.template,
+            path: t.vanity_path.as_deref(),
+            mode: t.mode,
+            owner: t.owner.as_ref().map(ToString::to_string),
+            group: t.group.as_ref().map(ToString::to_string),
+            link_mode: t.link_mode,
+            acl: acl(&t.acl),
+            vars: &t.vars,
+            optional: t.optional,
+            after: &t.after,
+        }
+    }
+}
+
+impl<S, E> State<S, E>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    /// Everything loaded from config (after merging and validation), without
+    /// any plaintext.
+    pub fn snapshot(&self) -> StateSnapshot<'_> {
+        let exposures = &self.exposures;
+        StateSnapshot {
+            storage: self.storage_type,
+            fallback: self.fallback,
+            read_only: self.read_only,
+            identity_paths: &self.private_key_paths,
+            key_groups_from: self.key_groups_from.as_deref(),
+            break_glass_keys: self
+                .break_glass
+                .as_ref()
+                .map(|b| b.encryption_keys.as_slice())
+                .unwrap_or_default(),
+            approval_keys: self
+                .approvals
+                .as_ref()
+                .map(|a| a.keys.as_slice())
+                .unwrap_or_default(),
+            secrets_dir_env: &self.secrets_dir_env,
+            secrets: self
+                .secrets
+                .iter()
+                .map(|(name, s)| (name.as_str(), s.into()))
+                .collect(),
+            exposures: ExposuresSnapshot {
+                root: exposures.root.as_deref(),
+                files: exposures.files.values().flatten().map(Into::into).collect(),
+                envs: exposures.envs.values().flatten().map(Into::into).collect(),
+                templates: exposures.templates.iter().map(Into::into).collect(),
+            },
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Checking and moving stored secrets between storage backends.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use tokio::io::AsyncReadExt;
+
+use super::State;
+use crate::secret::{sidecar_path, MigratingSecretStorage, SIDECAR_SUFFIXES};
+use crate::util::exit_status;
+use crate::{SecretError, SecretStorage};
+
+/// Every object that makes up the configured secrets (and the recipients
+/// registry, if key groups are used), whether or not it exists.
+fn stored_objects<S, E>(state: &State<S, E>) -> Vec<PathBuf>
+where
+    S: SecretStorage,
+    E: SecretError,
+{
+    let mut secrets = state.secrets.values().collect::<Vec<_>>();
+    secrets.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut objects = Vec::new();
+    for secret in secrets.into_iter().filter(|s| s.value.is_none()) {
+        objects.push(secret.path.clone());
+        let sidecars = SIDECAR_SUFFIXES
+            .iter()
+            .map(|s| sidecar_path(&secret.path, s));
+        objects.extend(sidecars);
+    }
+    objects.extend(state.key_groups_from.iter().cloned());
+
+    objects
+}
+
+/// Reads an object that's still only in the old storage, or nothing if it's
+/// already in the new storage (or in neither).
+async fn unmigrated<S>(to: &S, from: &S, p: &Path) -> Result<Option<Vec<u8>>, MigrateError>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let storage_error = |e: S::Error| MigrateError::Storage(p.to_owned(), Box::new(e));
+    match to.metadata(p).await {
+        Ok(_) => return Ok(None),
+        Err(e) if e.is_not_found() => (),
+        Err(e) => return Err(storage_error(e)),
+    }
+
+    let mut reader = match from.read(p).await {
+        Ok(reader) => reader,
+        Err(e) if e.is_not_found() => return Ok(None),
+        Err(e) => return Err(storage_error(e)),
+    };
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .await
+        .map_err(|e| MigrateError::Reading(p.to_owned(), e))?;
+
+    Ok(Some(data))
+}
+
+/// Lists the objects that are still only in the storage secrets are being
+/// moved from, or copies them to the new storage if `copy_all` is set.
+/// Objects already in the new storage are never overwritten.
+pub async fn migrate<S, E>(
+    state: &State<MigratingSecretStorage<S>, E>,
+    copy_all: bool,
+) -> Result<ExitStatus, MigrateError>
+where
+    S: SecretStorage + Sync + Send,
+    E: SecretError,
+    <S as SecretStorage>::Error: Send + 'static,
+{
+    let from = state.storage.from().ok_or(MigrateError::NotMigrating)?;
+    let to = state.storage.to();
+
+    let mut remaining = 0;
+    for p in stored_objects(state) {
+        let data = match unmigrated(to, from, &p).await? {
+            Some(data) => data,
+            None => continue,
+        };
+        if !copy_all {
+            println!("{}", p.to_string_lossy());
+            remaining += 1;
+            continue;
+        }
+
+        to.write(&p, data.as_slice())
+            .await
+            .map_err(|e| MigrateError::Storage(p.clone(), Box::new(e)))?;
+        let written = to
+            .metadata(&p)
+            .await
+            .map_err(|e| MigrateError::Storage(p.clone(), Box::new(e)))?;
+        if written.size != data.len() as u64 {
+            return Err(MigrateError::SizeMismatch(
+                p,
+                data.len() as u64,
+                written.size,
+            ));
+        }
+        eprintln!("copied {}", p.to_string_lossy());
+    }
+
+    if remaining > 0 {
+        log::warn!("{remaining} object(s) still need copying (pass --copy-all to copy them)");
+        return Ok(exit_status(1));
+    }
+
+    Ok(exit_status(0))
+}
+
+/// What a probe object written by `storage check` contains.
+const PROBE_CONTENT: &[u8] = b"written by credible storage check, safe to delete\n";
+
+/// Prints the outcome of a probe, with a hint for well-known failures.
+fn report_probe<T, E: SecretError>(name: &str, res: &Result<T, E>) -> bool {
+    match res {
+        Ok(_) => {
+            println!("ok    {name}");
+            true
+        }
+        Err(e) => {
+            println!("FAIL  {name}: {e}");
+            if let Some(hint) = e.hint() {
+                println!("      hint: {hint}");
+            }
+            false
+        }
+    }
+}
+
+/// Probes one storage backend with a throwaway object, returning whether
+/// every probe passed. Write probes are skipped unless `writable` is set.
+async fn probe<S: SecretStorage>(storage: &S, writable: bool) -> bool {
+    let p = PathBuf::from(format!(".credible/check-{}", std::process::id()));
+
+    // A missing object is only reported as missing (rather than forbidden)
+    // when objects can be listed, so this tests both
+    let res = match storage.metadata(&p).await {
+        Ok(_) => {
+            log::warn!("{} already exists", p.to_string_lossy());
+            Ok(())
+        }
+        Err(e) if e.is_not_found() => Ok(()),
+        Err(e) => Err(e),
+    };
+    if !report_probe("list and read", &res) {
+        return false;
+    }
+    if !writable {
+        println!("skip  write and delete (storage is read-only)");
+        return true;
+    }
+
+    if !report_probe("write", &storage.write(&p, PROBE_CONTENT).await) {
+        return false;
+    }
+    let read_back = match storage.read(&p).await {
+        Ok(mut reader) => {
+            let mut data = Vec::new();
+            match reader.read_to_end(&mut data).await {
+                Ok(_) => Some(data),
+                Err(e) => {
+                    println!("FAIL  read back: {e}");
+                    None
+                }
+            }
+        }
+        Err(e) => {
+            report_probe::<(), _>("read back", &Err(e));
+            None
+        }
+    };
+    let mut ok = match read_back {
+        Some(data) if data == PROBE_CONTENT => {
+            println!("ok    read back");
+            true
+        }
+        Some(data) => {
+            println!(
+                "FAIL  read back: wrote {} bytes, read {} different bytes",
+                PROBE_CONTENT.len(),
+                data.len()
+            );
+            false
+        }
+        None => false,
+    };
+
+    // Always try to clean up, even if reading back failed
+    if !report_probe("delete", &storage.delete(&p).await) {
+        log::warn!("{} may need removing by hand", p.to_string_lossy());
+        return false;
+    }
+    match storage.metadata(&p).await {
+        Err(e) if e.is_not_found() => println!("ok    deleted"),
+        Err(e) => ok &= report_probe::<(), _>("deleted", &Err(e)),
+        Ok(_) => {
+            println!("FAIL  deleted: object still exists after being deleted");
+            ok = false;
+        }
+    }
+
+    ok
+}
+
+/// Checks that storage is reachable, and that the credentials in use can
+/// list, read, write and delete objects, using a throwaway object under
+/// `.credible/`. When storage is being migrated, the old storage is only
+/// checked for reading.
+pub async fn check<S, E>(state: &State<MigratingSecretStorage<S>, E>) -> ExitStatus
+where
+    S: SecretStorage + Sync + Send,
+    E: SecretError,
+    <S as SecretStorage>::Error: Send,
+{
+    let mut ok = match state.storage.from() {
+        Some(from) => {
+            println!("old storage:");
+            let from_ok = probe(from, false).await;
+            println!("new storage:");
+            from_ok
+        }
+        None => true,
+    };
+    ok &= probe(state.storage.to(), !state.read_only).await;
+
+    match ok {
+        true => exit_status(0),
+        false => exit_status(1),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MigrateError {
+    #[error("storage isn't being migrated (its type isn't Migration)")]
+    NotMigrating,
+    #[error("error accessing {0}: {1}")]
+    Storage(PathBuf, Box<dyn std::error::Error>),
+    #[error("error reading {0}: {1}")]
+    Reading(PathBuf, std::io::Error),
+    #[error("wrote {1} bytes to {0}, but storage has {2}")]
+    SizeMismatch(PathBuf, u64, u64),
+}
@@ -1,21 +1,44 @@
-use std::os::unix::process::ExitStatusExt;
+use std::collections::BTreeSet;
 use std::path::Path;
 use std::process::ExitStatus;
 
+use age::Identity;
 pub use system::UnmountSecretsError;
+use tokio::process::Command;
 
 use super::{ExposureLoadingError, State};
 use crate::age::{get_identities, DecryptionError};
-use crate::{system, SecretError, SecretStorage};
+use crate::hooks::NoHooks;
+use crate::secret::{read_template_secrets, TemplateExposureError};
+use crate::signals::SignalListener;
+use crate::system::{ContainerBind, GenerationChange, GenerationFile, Host};
+use crate::util::exit_status;
+#[cfg(target_os = "linux")]
+use crate::watch::InotifyNotifier;
+use crate::watch::{ChangeHint, ChangeNotifier, DigestTracker, PollNotifier, PollSchedule};
+use crate::{
+    system,
+    CacheMode,
+    CachedSecretStorage,
+    MultiError,
+    Secret,
+    SecretError,
+    SecretStorage,
+    StorageFallback,
+};
 
 pub async fn mount<S, E>(
     state: &State<S, E>,
     mount_point: &Path,
     secret_dir: &Path,
+    offline: bool,
+    cache_dir: &Path,
+    binds: &[ContainerBind],
+    watch: Option<PollSchedule>,
 ) -> Result<ExitStatus, MountSecretsError>
 where
-    S: SecretStorage<Error = E>,
-    E: SecretError,
+    S: SecretStorage<Error = E> + Sync,
+    E: SecretError + Send,
     <S as SecretStorage>::Error: 'static,
 {
     let identities = get_identities(&state.private_key_paths)?;
@@ -23,27 +46,330 @@ where
     if !state.exposures.envs.is_empty() {
         panic!("env exposures on system mount");
     }
+    state.warn_deprecated();
+
+    let cache_mode = match (offline, state.fallback) {
+        (true, _) => Some(CacheMode::Offline),
+        (false, StorageFallback::Cache) => Some(CacheMode::Fallback),
+        (false, StorageFallback::None) => None,
+    };
+
+    let paths = (mount_point, secret_dir);
+    match cache_mode {
+        Some(mode) => {
+            let threshold = state.large_secret_threshold;
+            let storage =
+                CachedSecretStorage::new(&state.storage, cache_dir.to_owned(), mode, threshold);
+            mount_with(state, &storage, paths, binds, &identities, watch).await?
+        }
+        None => mount_with(state, &state.storage, paths, binds, &identities, watch).await?,
+    };
+
+    Ok(exit_status(0))
+}
+
+async fn mount_with<S, E, T>(
+    state: &State<S, E>,
+    storage: &T,
+    (mount_point, secret_dir): (&Path, &Path),
+    binds: &[ContainerBind],
+    identities: &[Box<dyn Identity>],
+    watch: Option<PollSchedule>,
+) -> Result<(), MountSecretsError>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+    T: SecretStorage + Sync,
+    <T as SecretStorage>::Error: 'static,
+{
+    // Digests are taken before mounting, so that a change landing part-way
+    // through is picked up by the first check afterwards
+    let mut tracker = DigestTracker::new(state.large_secret_threshold);
+    let watched = match watch {
+        Some(schedule) => {
+            let secrets = watched_secrets(state).await?;
+            let changes = tracker
+                .check(storage, &secrets)
+                .await
+                .map_err(checking_error)?;
+            tracker.record(&changes);
+            Some((secrets, schedule))
+        }
+        None => None,
+    };
 
     system::mount(
         mount_point,
         secret_dir,
         &state.secrets,
-        &state.exposures.files,
+        &state.exposures,
+        &state.mount_dirs,
+        identities,
+        storage,
+        state.large_secret_threshold,
+        &NoHooks,
+        &Host,
+    )
+    .await?;
+
+    // Refreshes happen within the generation, so binds only change here
+    if !binds.is_empty() {
+        let generation = tokio::fs::read_link(secret_dir)
+            .await
+            .map_err(system::MountSecretsError::ResolvingSecretDir)?;
+        for bind in binds {
+            bind.write(&generation, secret_dir).await?;
+        }
+    }
+
+    match watched {
+        Some((secrets, schedule)) => {
+            let watcher = Watcher {
+                secret_dir,
+                identities,
+                secrets: &secrets,
+            };
+            #[cfg(target_os = "linux")]
+            if let Some(root) = storage.local_root() {
+                match InotifyNotifier::new(root, &secrets, schedule) {
+                    Ok(notifier) => return watcher.run(state, storage, tracker, notifier).await,
+                    Err(e) => log::warn!("{e}, polling for changes instead"),
+                }
+            }
+            let notifier = PollNotifier::new(schedule);
+            watcher.run(state, storage, tracker, notifier).await
+        }
+        None => Ok(()),
+    }
+}
+
+fn checking_error<E: std::error::Error + 'static>(errors: MultiError<E>) -> MountSecretsError {
+    let mut boxed = MultiError::default();
+    boxed.append(errors);
+    MountSecretsError::CheckingForChanges(boxed)
+}
+
+/// Secrets used by the configured exposures.
+async fn watched_secrets<S, E>(state: &State<S, E>) -> Result<Vec<&Secret>, MountSecretsError>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError,
+{
+    let mut names = state
+        .exposures
+        .files
+        .keys()
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    for template in state.exposures.templates.iter() {
+        let used = read_template_secrets(&template.template)
+            .await
+            .map_err(MountSecretsError::ReadingTemplate)?;
+        names.extend(used);
+    }
+
+    Ok(names.iter().filter_map(|n| state.secrets.get(n)).collect())
+}
+
+struct Watcher<'a> {
+    secret_dir: &'a Path,
+    identities: &'a [Box<dyn Identity>],
+    secrets: &'a [&'a Secret],
+}
+
+impl Watcher<'_> {
+    /// Checks storage for changes to the watched secrets whenever notified,
+    /// until we're asked to shut down, refreshing only the exposures of those
+    /// that changed.
+    async fn run<S, E, T, N>(
+        &self,
+        state: &State<S, E>,
+        storage: &T,
+        mut tracker: DigestTracker,
+        mut notifier: N,
+    ) -> Result<(), MountSecretsError>
+    where
+        S: SecretStorage<Error = E>,
+        E: SecretError,
+        T: SecretStorage + Sync,
+        <T as SecretStorage>::Error: 'static,
+        N: ChangeNotifier,
+    {
+        let mut listener = SignalListener::new().map_err(MountSecretsError::ListeningForSignals)?;
+        log::info!("watching {} secrets for changes", self.secrets.len());
+
+        loop {
+            let hint = tokio::select! {
+                _ = listener.shutdown() => break,
+                hint = notifier.wait() => hint,
+            };
+            let hint = match hint {
+                Ok(hint) => hint,
+                Err(e) => {
+                    log::warn!("{e}, checking all secrets");
+                    ChangeHint::Any
+                }
+            };
+
+            let secrets = hint.filter(self.secrets);
+            let res = self.check(state, storage, &mut tracker, &secrets).await;
+            if let Err(e) = &res {
+                log::warn!("{e}");
+            }
+            notifier.checked(res.is_ok());
+        }
+
+        Ok(())
+    }
+
+    async fn check<S, E, T>(
+        &self,
+        state: &State<S, E>,
+        storage: &T,
+        tracker: &mut DigestTracker,
+        secrets: &[&Secret],
+    ) -> Result<(), MountSecretsError>
+    where
+        S: SecretStorage<Error = E>,
+        E: SecretError,
+        T: SecretStorage + Sync,
+        <T as SecretStorage>::Error: 'static,
+    {
+        let changes = tracker
+            .check(storage, secrets)
+            .await
+            .map_err(checking_error)?;
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        // Changes are only recorded once applied, so that failures are
+        // retried on the next check
+        let changed = changes.iter().map(|c| c.secret).collect::<Vec<_>>();
+        system::refresh(
+            self.secret_dir,
+            &changed,
+            &state.secrets,
+            &state.exposures,
+            self.identities,
+            storage,
+            state.large_secret_threshold,
+            &NoHooks,
+            &Host,
+        )
+        .await
+        .map_err(MountSecretsError::RefreshingSecrets)?;
+        tracker.record(&changes);
+
+        for secret in changed {
+            run_on_change(secret).await;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs a secret's `on_change` command, after its exposures were refreshed.
+async fn run_on_change(secret: &Secret) {
+    let Some(command) = &secret.on_change else {
+        return;
+    };
+
+    log::info!("running on_change for {}: {command}", secret.name);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("CREDIBLE_SECRET_NAME", &secret.name)
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => (),
+        Ok(status) => log::warn!("on_change for {} exited with {status}", secret.name),
+        Err(e) => log::warn!("couldn't run on_change for {}: {e}", secret.name),
+    }
+}
+
+/// Prints how a new generation would differ from the one currently mounted at
+/// `secret_dir` (or that every file would be added, if nothing is mounted).
+/// Exits with 1 if anything would change, like diff(1).
+pub async fn diff<S, E>(
+    state: &State<S, E>,
+    secret_dir: &Path,
+    json: bool,
+) -> Result<ExitStatus, system::DiffGenerationError>
+where
+    S: SecretStorage<Error = E>,
+    E: SecretError + 'static,
+{
+    let identities = get_identities(&state.private_key_paths)?;
+    let current = system::current_generation(secret_dir).await?;
+    let before = match &current {
+        Some(generation) => system::read_generation(generation).await?,
+        None => {
+            log::info!("nothing is mounted at {}", secret_dir.display());
+            Default::default()
+        }
+    };
+    let after = system::plan_generation(
+        current.as_deref().unwrap_or(secret_dir),
+        &state.secrets,
+        &state.exposures,
         &identities,
         &state.storage,
+        state.large_secret_threshold,
     )
     .await?;
+    let changes = system::diff_generations(before, after);
+
+    if json {
+        println!("{}", serde_json::to_string(&changes).expect("serializable"));
+    } else {
+        for change in changes.iter() {
+            println!("{}", describe_change(change));
+        }
+    }
+    if changes.is_empty() {
+        log::info!("no changes");
+    }
 
-    Ok(ExitStatus::from_raw(0))
+    Ok(exit_status(i32::from(!changes.is_empty())))
+}
+
+/// A line describing a change, like `~ db-password: content, mode 0400 -> 0440`.
+fn describe_change(change: &GenerationChange) -> String {
+    let owner = |f: &GenerationFile| format!("{}:{}", f.uid, f.gid);
+    match change {
+        GenerationChange::Added { path, after } => {
+            format!("+ {path} (mode {:04o}, owner {})", after.mode, owner(after))
+        }
+        GenerationChange::Removed { path, .. } => format!("- {path}"),
+        GenerationChange::Changed {
+            path,
+            before,
+            after,
+        } => {
+            let mut differences = Vec::new();
+            if before.sha256 != after.sha256 {
+                differences.push("content".to_string());
+            }
+            if before.mode != after.mode {
+                differences.push(format!("mode {:04o} -> {:04o}", before.mode, after.mode));
+            }
+            if owner(before) != owner(after) {
+                differences.push(format!("owner {} -> {}", owner(before), owner(after)));
+            }
+            format!("~ {path}: {}", differences.join(", "))
+        }
+    }
 }
 
 pub async fn unmount(
     mount_point: &Path,
     secret_dir: &Path,
 ) -> Result<ExitStatus, UnmountSecretsError> {
-    system::unmount(mount_point, Some(secret_dir), None).await?;
+    system::unmount(mount_point, Some(secret_dir), None, &NoHooks, &Host).await?;
 
-    Ok(ExitStatus::from_raw(0))
+    Ok(exit_status(0))
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -54,4 +380,12 @@ pub enum MountSecretsError {
     ReadingIdentities(#[from] DecryptionError),
     #[error("error loading exposures: {0}")]
     LoadingExposures(#[from] ExposureLoadingError),
+    #[error("error reading template: {0}")]
+    ReadingTemplate(TemplateExposureError),
+    #[error("error checking for changed secrets: {0}")]
+    CheckingForChanges(MultiError<Box<dyn std::error::Error>>),
+    #[error("error refreshing changed secrets: {0}")]
+    RefreshingSecrets(system::MountSecretsError),
+    #[error("error listening for signals: {0}")]
+    ListeningForSignals(std::io::Error),
 }
@@ -0,0 +1,116 @@
+//! Machine-readable lifecycle events, written as newline-delimited JSON for
+//! wrappers that want to track our progress without parsing logs.
+
+#[cfg(unix)]
+use std::fs::File;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+#[cfg(unix)]
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use serde::Serialize;
+
+static SINK: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExposureKind {
+    File,
+    Env,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    FetchStart {
+        secret: &'a str,
+    },
+    FetchDone {
+        secret: &'a str,
+    },
+    ExposeDone {
+        secret: &'a str,
+        kind: ExposureKind,
+        target: &'a str,
+    },
+    ChildSpawned {
+        pid: u32,
+    },
+    ChildExited {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EventsError {
+    #[error("an event sink is already configured")]
+    AlreadyInitialised,
+    #[cfg(unix)]
+    #[error("file descriptor {0} is not usable: {1}")]
+    InvalidFd(i32, nix::errno::Errno),
+    #[cfg(not(unix))]
+    #[error("writing events to a file descriptor isn't supported on this platform")]
+    Unsupported,
+}
+
+/// Sends all future events to the given writer.
+pub fn init(sink: Box<dyn Write + Send>) -> Result<(), EventsError> {
+    SINK.set(Mutex::new(sink))
+        .map_err(|_| EventsError::AlreadyInitialised)
+}
+
+/// Sends all future events to an inherited file descriptor.
+#[cfg(unix)]
+pub fn init_fd(fd: i32) -> Result<(), EventsError> {
+    // Make sure the descriptor is actually open before we take ownership of
+    // it, and don't leak it into the processes we spawn
+    fcntl(fd, FcntlArg::F_GETFD).map_err(|e| EventsError::InvalidFd(fd, e))?;
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(|e| EventsError::InvalidFd(fd, e))?;
+
+    // SAFETY: we've checked the descriptor is open, and nothing else in this
+    // process refers to it
+    let file = unsafe { File::from_raw_fd(fd) };
+    init(Box::new(file))
+}
+
+#[cfg(not(unix))]
+pub fn init_fd(_fd: i32) -> Result<(), EventsError> {
+    Err(EventsError::Unsupported)
+}
+
+/// Writes an event to the configured sink, if there is one.
+pub fn emit(event: Event) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+
+    let record = Record {
+        time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        event,
+    };
+    let mut line = match serde_json::to_vec(&record) {
+        Ok(l) => l,
+        Err(e) => {
+            log::warn!("couldn't encode event: {e}");
+            return;
+        }
+    };
+    line.push(b'\n');
+
+    // A reader going away shouldn't interrupt what we're doing
+    let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = sink.write_all(&line).and_then(|_| sink.flush()) {
+        log::debug!("couldn't write event: {e}");
+    }
+}
@@ -0,0 +1,38 @@
+//! Callbacks into the secret lifecycle, for embedding credible as a library.
+//!
+//! Every method has a no-op default, so implementations only need to handle
+//! what they're interested in.
+
+use std::path::Path;
+
+use crate::Secret;
+
+/// Where a secret was exposed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExposureTarget<'a> {
+    File(&'a Path),
+    Env(&'a str),
+}
+
+pub trait Hooks: Send + Sync {
+    /// Called before a secret's ciphertext is fetched from storage.
+    fn on_fetch(&self, _secret: &Secret) {}
+
+    /// Called after a secret has been fetched and decrypted.
+    fn on_decrypt(&self, _secret: &Secret) {}
+
+    /// Called after a secret has been exposed to its consumer.
+    fn on_expose(&self, _secret: &Secret, _target: ExposureTarget<'_>) {}
+
+    /// Called after a secret's exposures have been refreshed, because its
+    /// ciphertext changed while being watched.
+    fn on_refresh(&self, _secret: &Secret) {}
+
+    /// Called after a decrypted secret (or link to one) has been removed.
+    fn on_cleanup(&self, _path: &Path) {}
+}
+
+/// [Hooks] that do nothing.
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
@@ -1,19 +1,97 @@
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 
 use serde::Deserialize;
 
+#[cfg(unix)]
 pub mod system;
+#[cfg(unix)]
 pub use system::{MountSecretsError, UnmountSecretsError};
 mod secret;
-use secret::S3Config;
-pub use secret::{ExposureSpec, Exposures, Secret, SecretError, SecretStorage};
+pub use secret::{
+    read_exposure_tag,
+    restore_previous,
+    AzureBlobSecretStorage,
+    AzureBlobStorageError,
+    BackendConfig,
+    BackendStorage,
+    BackendStorageError,
+    ByteSize,
+    CacheMode,
+    CachedSecretStorage,
+    ChunkedSecretStorage,
+    ChunkedStorageConfig,
+    ChunkedStorageError,
+    ConditionalRead,
+    CredentialsConfig,
+    DevStorage,
+    DevStorageError,
+    ExposureSpec,
+    ExposureTag,
+    Exposures,
+    FileSecretStorage,
+    FileStorageConfig,
+    FileStorageError,
+    GitCommitter,
+    GitSecretStorage,
+    GitStorageConfig,
+    GitStorageError,
+    KeepPrevious,
+    LimitedSecretStorage,
+    LimitedStorageConfig,
+    MigratingSecretStorage,
+    MigratingStorageConfig,
+    MigrationConfig,
+    PreviousVersionError,
+    ProfileCredentialsConfig,
+    RateLimiter,
+    RateLimits,
+    RecordMode,
+    RecordingError,
+    RecordingSecretStorage,
+    RecordingStorageConfig,
+    RequestPermit,
+    Secret,
+    SecretError,
+    SecretStorage,
+    StorageFallback,
+    DEFAULT_LARGE_SECRET_THRESHOLD,
+    STORAGE_LOG_TARGET,
+};
+use secret::{AzureBlobConfig, S3Config};
 
 mod process_utils;
 
 mod age;
 
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(all(unix, feature = "test-env"))]
+pub mod test_env;
+
+pub mod events;
+
+pub mod hooks;
+
+pub mod signals;
+
+mod overrides;
+pub use overrides::{ConfigOverrides, CONFIG_OVERRIDE_PREFIX};
+
+pub mod watch;
+
 mod process;
-pub use process::ProcessRunningError;
+pub use process::{
+    run_process,
+    CommandRunner,
+    Container,
+    DiskBackedTmpdir,
+    EnvPolicy,
+    ProcessRunningError,
+    RunRecord,
+    RunRecordError,
+};
 
 pub mod cli;
 
@@ -22,6 +100,9 @@ pub use wrappers::{GroupWrapper, UserWrapper};
 
 pub mod util;
 
+mod multi_error;
+pub use multi_error::MultiError;
+
 #[derive(Deserialize, Debug)]
 pub struct RuntimeKey {
     pub private_key_path: PathBuf,
@@ -33,6 +114,174 @@ pub struct SecretManagerConfig {
     pub exposures: Option<Vec<ExposureSpec>>,
     pub secrets: Option<Vec<Secret>>,
     pub storage: Option<StorageConfig>,
+    /// Additional storage backends, selectable by name with `--storage`
+    pub storages: Option<HashMap<String, StorageConfig>>,
+    pub fallback: Option<StorageFallback>,
+    #[serde(alias = "breakGlass")]
+    pub break_glass: Option<BreakGlassConfig>,
+    /// Where to read key groups from, as `store:<path>` for a recipients
+    /// registry in storage
+    #[serde(alias = "keyGroupsFrom")]
+    pub key_groups_from: Option<String>,
+    /// Require a second keyholder's approval for destructive operations
+    pub approvals: Option<ApprovalsConfig>,
+    /// Limits on requests to storage
+    #[serde(alias = "rateLimits")]
+    pub rate_limits: Option<RateLimits>,
+    /// Size above which secrets are streamed rather than held in memory, and
+    /// can't be exposed as environment variables (default: 64MiB)
+    #[serde(alias = "largeSecretThreshold")]
+    pub large_secret_threshold: Option<ByteSize>,
+    /// Keep the previous version of secrets larger than
+    /// `large_secret_threshold` when they're overwritten (default: false)
+    #[serde(alias = "keepLargePrevious")]
+    pub keep_large_previous: Option<bool>,
+    /// Split objects bigger than this across several objects when writing,
+    /// for backends that limit object size
+    #[serde(alias = "chunkSize")]
+    pub chunk_size: Option<ByteSize>,
+    /// Environment variables to export the secret file directory under for
+    /// run-command (default: `SECRETS_FILE_DIR`)
+    #[serde(alias = "secretsDirEnv")]
+    pub secrets_dir_env: Option<Vec<String>>,
+    /// Directory run-command creates its secret file directory in (default:
+    /// the system temp directory)
+    #[serde(alias = "secretsTmpdir")]
+    pub secrets_tmpdir: Option<PathBuf>,
+    /// What run-command does if its secret file directory isn't
+    /// memory-backed: `warn` (the default), `refuse` or `allow`
+    #[serde(alias = "diskBackedTmpdir")]
+    pub disk_backed_tmpdir: Option<DiskBackedTmpdir>,
+    /// Mode and ownership of the directories `system mount` creates
+    #[cfg(unix)]
+    #[serde(alias = "mountDirs")]
+    pub mount_dirs: Option<system::MountDirs>,
+    /// Directory to resolve relative vanity paths against (default: the
+    /// working directory for run-command, and the secret dir for mounts)
+    #[serde(alias = "exposureRoot")]
+    pub exposure_root: Option<PathBuf>,
+    /// Refuse to run commands that modify stored secrets (e.g. on production
+    /// hosts)
+    #[serde(default, alias = "readOnly")]
+    pub read_only: bool,
+    /// Plaintext values for secrets (by name), used instead of storage when
+    /// running with `--env dev`
+    #[serde(default, alias = "devValues")]
+    pub dev_values: HashMap<String, String>,
+    /// Teams' secrets and exposures, by tenant name, kept apart from each
+    /// other's in a shared config
+    pub tenants: Option<BTreeMap<String, TenantConfig>>,
+    /// Encrypted credentials for storage, which are decrypted and set as
+    /// environment variables before storage is set up
+    #[serde(default)]
+    pub bootstrap: Vec<BootstrapSource>,
+}
+
+impl SecretManagerConfig {
+    /// Parses a config file, which may hold several YAML documents (separated
+    /// by `---`), each of which is read as if it were a separate config file.
+    /// Merge keys (`<<: *anchor`) are applied first, then any overrides.
+    pub fn from_documents(
+        data: &[u8],
+        overrides: &mut ConfigOverrides,
+    ) -> Result<Vec<Self>, serde_yaml::Error> {
+        let values = serde_yaml::Deserializer::from_slice(data).map(serde_yaml::Value::deserialize);
+        let documents = serde_yaml::Deserializer::from_slice(data);
+
+        let mut configs = Vec::new();
+        for (value, document) in values.zip(documents) {
+            let mut value = value?;
+            // e.g. after a trailing `---`
+            if value.is_null() {
+                continue;
+            }
+            let merged = has_merge_keys(&value);
+            if merged {
+                value.apply_merge()?;
+            }
+            let overridden = overrides.apply(&mut value);
+            // Parsing the document itself keeps line numbers in errors, which
+            // are lost once it's been changed
+            let config = match merged || overridden {
+                false => Self::deserialize(document)?,
+                true => serde_yaml::from_value(value)?,
+            };
+            configs.push(config);
+        }
+
+        Ok(configs)
+    }
+}
+
+/// Whether a YAML document uses merge keys anywhere.
+fn has_merge_keys(value: &serde_yaml::Value) -> bool {
+    use serde_yaml::Value;
+
+    match value {
+        Value::Mapping(m) => m.iter().any(|(k, v)| k == "<<" || has_merge_keys(v)),
+        Value::Sequence(s) => s.iter().any(has_merge_keys),
+        Value::Tagged(t) => has_merge_keys(&t.value),
+        _ => false,
+    }
+}
+
+/// A team's secrets and exposures. Its secrets are stored under its prefix,
+/// may only be encrypted to its key groups, and can't be exposed by other
+/// tenants.
+#[derive(Deserialize, Debug, Default)]
+pub struct TenantConfig {
+    /// Storage prefix that this tenant's secrets' paths are relative to
+    pub prefix: Option<PathBuf>,
+    /// Key groups this tenant's secrets may be encrypted to
+    #[serde(default, alias = "keyGroups")]
+    pub key_groups: Vec<String>,
+    #[serde(default)]
+    pub secrets: Vec<Secret>,
+    #[serde(default)]
+    pub exposures: Vec<ExposureSpec>,
+}
+
+/// Somewhere to read storage credentials from without storage: `KEY=VALUE`
+/// lines, like a credentials file, encrypted for our identities.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum BootstrapSource {
+    /// A secret with an inline value
+    #[serde(alias = "secret")]
+    Secret { name: String },
+    /// A local file of age ciphertext (which may be armored)
+    #[serde(alias = "file")]
+    File { path: PathBuf },
+}
+
+impl std::fmt::Display for BootstrapSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Secret { name } => write!(f, "secret {name}"),
+            Self::File { path } => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+fn default_audit_prefix() -> PathBuf {
+    PathBuf::from(".credible/break-glass")
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BreakGlassConfig {
+    /// Public keys that every secret is additionally encrypted to
+    #[serde(alias = "encryptionKeys")]
+    pub encryption_keys: Vec<String>,
+    /// Storage prefix to write audit records for break-glass access under
+    #[serde(default = "default_audit_prefix", alias = "auditPrefix")]
+    pub audit_prefix: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApprovalsConfig {
+    /// SSH public keys of the keyholders who can request and approve
+    /// destructive operations
+    pub keys: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -40,6 +289,27 @@ pub struct SecretManagerConfig {
 #[non_exhaustive]
 pub enum StorageConfig {
     S3(S3Config),
+    AzureBlob(AzureBlobConfig),
+    /// A local (or network-mounted) directory
+    File(FileStorageConfig),
+    /// A branch of a git repository, which writes are pushed to
+    Git(GitStorageConfig),
+    /// Reads from an old backend whatever isn't in the new one yet, while
+    /// secrets are moved between them
+    Migration(MigrationConfig),
+}
+
+impl StorageConfig {
+    /// The storage's `type`, as written in config.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::S3(_) => "S3",
+            Self::AzureBlob(_) => "AzureBlob",
+            Self::File(_) => "File",
+            Self::Git(_) => "Git",
+            Self::Migration(_) => "Migration",
+        }
+    }
 }
 
 #[async_trait::async_trait]
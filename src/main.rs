@@ -1,19 +1,48 @@
+use std::collections::HashMap;
+use std::io::Write;
 use std::path::PathBuf;
 use std::process::ExitStatus;
-use std::unimplemented;
 
 use clap::Parser;
 use credible::cli::Actions;
-use credible::util::partition_specs;
-use credible::StorageConfig::S3;
-use credible::{cli, SecretManagerConfig};
+use credible::events::EventsError;
+use credible::util::{exit_code, parse_credentials, partition_specs};
+use credible::StorageConfig::{AzureBlob, File, Git, Migration, S3};
+use credible::{
+    cli,
+    events,
+    BackendConfig,
+    ChunkedStorageConfig,
+    ConfigOverrides,
+    DevStorage,
+    DevStorageError,
+    LimitedStorageConfig,
+    MigratingStorageConfig,
+    ProcessRunningError,
+    RecordMode,
+    RecordingStorageConfig,
+    SecretError,
+    SecretManagerConfig,
+    SecretStorage,
+    StorageConfig,
+    DEFAULT_LARGE_SECRET_THRESHOLD,
+    STORAGE_LOG_TARGET,
+};
 use log::SetLoggerError;
-use simplelog::{ConfigBuilder, LevelFilter};
+use simplelog::{ConfigBuilder, LevelFilter, SharedLogger};
 use thiserror::Error;
 use tokio::fs;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::runtime::Runtime;
 
-use crate::cli::{CliParams, StateBuilderError};
+use crate::cli::{
+    CliParams,
+    ExposureSource,
+    KeyGroupsError,
+    RunEnvironment,
+    RuntimeFlavor,
+    StateBuilder,
+    StateBuilderError,
+};
 
 /*
 * credible system mount
@@ -23,10 +52,14 @@ use crate::cli::{CliParams, StateBuilderError};
 * credible run-command ...
 */
 
+const LOCAL_OVERLAY_FILE_NAME: &str = "credible.local.yaml";
+
 #[derive(Debug, Error)]
 enum MainError {
     #[error("{0}")]
     ParsingCliArgs(#[from] clap::Error),
+    #[error("error starting runtime: {0}")]
+    BuildingRuntime(std::io::Error),
     #[error("no config file given, and no credible.yaml found")]
     NoConfigFile,
     #[error("couldn't read credentials file at {0}: {1}")]
@@ -35,10 +68,30 @@ enum MainError {
     ReadingConfigFile(PathBuf, std::io::Error),
     #[error("invalid config file: {0}")]
     ParsingConfigFile(#[from] serde_yaml::Error),
+    #[error("invalid config from CREDIBLE__ environment variables: {0}")]
+    InvalidConfigOverride(serde_yaml::Error),
+    #[error("no storage named {0}, and it isn't a valid storage spec: {1}")]
+    InvalidStorageSpec(String, serde_yaml::Error),
     #[error("bad command line arguments: {0}")]
     SettingUpState(#[from] StateBuilderError),
     #[error("couldn't configure logger: {0}")]
     SettingLogger(#[from] SetLoggerError),
+    #[error("couldn't configure event stream: {0}")]
+    SettingUpEvents(#[from] EventsError),
+    #[error("keyGroupsFrom must be store:<path>, got {0}")]
+    InvalidKeyGroupsSource(String),
+    #[error("couldn't resolve key groups: {0}")]
+    ResolvingKeyGroups(#[from] KeyGroupsError),
+    #[error("dev value given for unknown secret {0}")]
+    UnknownDevValue(String),
+    #[error("couldn't set up dev values: {0}")]
+    SettingUpDevStorage(DevStorageError),
+    #[error("couldn't write dev identity: {0}")]
+    WritingDevIdentity(std::io::Error),
+    #[error("migration storage can't be nested")]
+    NestedMigration,
+    #[error("storage commands can't be used with --env dev")]
+    DevStorageCommand,
     #[error("error: {0}")]
     Executing(#[from] cli::Error),
 }
@@ -59,6 +112,23 @@ fn find_config_file() -> Option<PathBuf> {
     }
 }
 
+/// Finds a developer's local overrides, which live alongside the last config
+/// file and are applied after every other config file.
+fn find_local_overlay(config_files: &[PathBuf]) -> Option<PathBuf> {
+    let dir = config_files.last()?.parent()?;
+    let candidate = dir.join(LOCAL_OVERLAY_FILE_NAME);
+    if !candidate.is_file() || config_files.contains(&candidate) {
+        return None;
+    }
+
+    log::info!(
+        "applying local overrides from {}",
+        candidate.to_string_lossy()
+    );
+    Some(candidate)
+}
+
+#[allow(clippy::join_absolute_paths)]
 fn find_credentials_file() -> Option<PathBuf> {
     let home = std::env::var("$HOME").ok().map(PathBuf::from)?;
     // TODO: XDG etc?
@@ -70,22 +140,109 @@ fn find_credentials_file() -> Option<PathBuf> {
     }
 }
 
-fn init_logger(level: LevelFilter) -> Result<(), SetLoggerError> {
+/// Resolves a `--storage` argument, either by name from the configured
+/// `storages`, or as an inline storage config.
+fn resolve_storage(
+    spec: &str,
+    mut named: HashMap<String, StorageConfig>,
+) -> Result<StorageConfig, MainError> {
+    match named.remove(spec) {
+        Some(storage) => Ok(storage),
+        None => serde_yaml::from_str(spec)
+            .map_err(|e| MainError::InvalidStorageSpec(spec.to_string(), e)),
+    }
+}
+
+fn init_logger(level: LevelFilter, debug_storage: bool) -> Result<(), SetLoggerError> {
     let config = ConfigBuilder::default()
         .add_filter_allow_str("credible")
         .build();
-
-    simplelog::TermLogger::init(
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![simplelog::TermLogger::new(
         level,
         config,
         simplelog::TerminalMode::Stderr,
         simplelog::ColorChoice::Auto,
-    )
+    )];
+
+    // Requests to storage are logged separately, so that they can be shown
+    // without turning up the log level for everything else
+    if debug_storage {
+        let config = ConfigBuilder::default()
+            .add_filter_allow_str(STORAGE_LOG_TARGET)
+            .add_filter_allow_str("aws_smithy_client::retry")
+            .build();
+        loggers.push(simplelog::TermLogger::new(
+            LevelFilter::Debug,
+            config,
+            simplelog::TerminalMode::Stderr,
+            simplelog::ColorChoice::Auto,
+        ));
+    }
+
+    simplelog::CombinedLogger::init(loggers)
+}
+
+/// Builds the runtime asked for on the command line. Nothing in credible
+/// needs more than one thread.
+fn build_runtime(flavor: RuntimeFlavor, worker_threads: Option<u16>) -> std::io::Result<Runtime> {
+    let mut builder = match flavor {
+        RuntimeFlavor::CurrentThread => {
+            if worker_threads.is_some() {
+                log::warn!(
+                    "ignoring --worker-threads, which only applies to --runtime multi-thread"
+                );
+            }
+            tokio::runtime::Builder::new_current_thread()
+        }
+        RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(n) = worker_threads {
+                builder.worker_threads(n.into());
+            }
+            builder
+        }
+    };
+
+    builder.enable_all().build()
 }
 
-async fn real_main() -> Result<ExitStatus, MainError> {
+fn start() -> Result<ExitStatus, MainError> {
     let args = CliParams::try_parse()?;
-    init_logger(args.log_level)?;
+    init_logger(
+        match args.quiet {
+            true => LevelFilter::Off,
+            false => args.log_level,
+        },
+        args.debug_storage && !args.quiet,
+    )?;
+    let runtime =
+        build_runtime(args.runtime, args.worker_threads).map_err(MainError::BuildingRuntime)?;
+
+    runtime.block_on(real_main(args))
+}
+
+async fn real_main(args: CliParams) -> Result<ExitStatus, MainError> {
+    match (args.events_fd, args.events_json) {
+        (Some(fd), _) => events::init_fd(fd)?,
+        (None, true) => events::init(Box::new(std::io::stderr()))?,
+        (None, false) => (),
+    }
+    // Cleaning up doesn't need any config, and shouldn't be prevented by it
+    // being broken
+    if let Actions::Clean(a) = args.action {
+        return Ok(cli::clean(a).await?);
+    }
+    // Nor does approving an operation, which is checked by whoever performs it
+    if let Actions::Approve(a) = args.action {
+        return Ok(cli::approve(a).await?);
+    }
+    // Neither does generating a key, unless it's being registered in storage
+    if let Actions::Keygen(a) = &args.action {
+        if a.register.is_none() {
+            return Ok(cli::keygen(a).await?);
+        }
+    }
+
     let config_file = match args.config_file.is_empty() {
         false => args.config_file,
         true => find_config_file()
@@ -95,68 +252,328 @@ async fn real_main() -> Result<ExitStatus, MainError> {
     log::trace!("config loaded");
 
     if let Some(f) = args.credentials_file.or_else(find_credentials_file) {
-        let mut lines = match fs::File::open(&f).await {
-            Ok(r) => BufReader::new(r).lines(),
+        let credentials = match fs::read_to_string(&f).await {
+            Ok(c) => c,
             Err(e) => return Err(MainError::ReadingCredentialsFile(f, e)),
         };
-
-        loop {
-            match lines.next_line().await {
-                Ok(Some(line)) => match line.split_once('=') {
-                    Some((k, v)) => std::env::set_var(k, v),
-                    None => continue,
-                },
-                Ok(None) => break,
-                Err(e) => return Err(MainError::ReadingCredentialsFile(f, e)),
-            }
+        for (k, v) in parse_credentials(&credentials) {
+            std::env::set_var(k, v);
         }
     }
 
-    let mut builder = cli::StateBuilder::default();
-    for file in config_file {
+    let overlay = match args.no_local_config {
+        true => None,
+        false => find_local_overlay(&config_file),
+    };
+    let config_files = config_file
+        .into_iter()
+        .map(|f| (f, false))
+        .chain(overlay.map(|f| (f, true)));
+
+    // Storage (and so the builder's storage types) is only known once all
+    // config has been read
+    let mut builder = StateBuilder::<(), ()>::default();
+    if let Some(tenant) = args.tenant {
+        builder.set_tenant(tenant);
+    }
+    let mut storage = None;
+    let mut named_storages = HashMap::new();
+    let mut rate_limits = None;
+    let mut chunk_size = None;
+    let mut large_secret_threshold = DEFAULT_LARGE_SECRET_THRESHOLD;
+    let mut dev_values = HashMap::new();
+    let mut bootstrap = Vec::new();
+    let mut overrides = ConfigOverrides::from_env();
+    let mut configs = Vec::new();
+    for (file, is_overlay) in config_files {
         let data = fs::read(&file)
             .await
             .map_err(|e| MainError::ReadingConfigFile(file.to_path_buf(), e))?;
-        let config: SecretManagerConfig = serde_yaml::from_slice(&data)?;
-
+        // Each document in a file is read as if it were a file of its own
+        for config in SecretManagerConfig::from_documents(&data, &mut overrides)? {
+            configs.push((file.clone(), is_overlay, config));
+        }
+    }
+    if let Some(config) = overrides
+        .top_level()
+        .map_err(MainError::InvalidConfigOverride)?
+    {
+        configs.push((PathBuf::from("environment"), false, config));
+    }
+    for var in overrides.unapplied() {
+        log::warn!("{var} didn't override anything, as no config file has anywhere to put it");
+    }
+    for (file, is_overlay, config) in configs {
         if let Some(c) = config.exposures {
-            let (files, envs) = partition_specs(c);
-            builder.add_file_exposures(files)?;
-            builder.add_env_exposures(envs)?;
+            let (files, envs, templates) = partition_specs(c);
+            let source = ExposureSource::ConfigFile(file.clone());
+            match is_overlay {
+                true => builder.override_exposures(&source, files, envs, templates),
+                false => {
+                    builder.add_file_exposures(&source, files);
+                    builder.add_env_exposures(&source, envs);
+                    builder.add_template_exposures(&source, templates);
+                }
+            }
+        }
+
+        if let Some(mut secrets) = config.secrets {
+            for secret in secrets.iter_mut() {
+                secret.defined_in = Some(file.clone());
+            }
+            match is_overlay {
+                true => builder.override_secrets(secrets),
+                false => builder.add_secrets(secrets),
+            }
+        }
+
+        for (name, tenant) in config.tenants.into_iter().flatten() {
+            builder.add_tenant(&file, name, tenant);
+        }
+
+        if let Some(fallback) = config.fallback {
+            builder.set_fallback(fallback);
+        }
+
+        if let Some(root) = config.exposure_root {
+            builder.set_exposure_root(root);
+        }
+
+        if config.read_only {
+            builder.set_read_only();
         }
 
-        if let Some(secrets) = config.secrets {
-            builder.add_secrets(secrets);
+        if let Some(names) = config.secrets_dir_env {
+            builder.set_secrets_dir_env(names);
         }
 
-        if let Some(storage) = config.storage {
-            builder = match storage {
-                S3(s) => builder.set_secret_storage(s).await?,
-                _ => unimplemented!(),
-            };
+        if let Some(dir) = config.secrets_tmpdir {
+            builder.set_secrets_tmpdir(dir);
         }
+
+        if let Some(policy) = config.disk_backed_tmpdir {
+            builder.set_disk_backed_tmpdir(policy);
+        }
+
+        #[cfg(unix)]
+        if let Some(dirs) = config.mount_dirs {
+            builder.set_mount_dirs(dirs);
+        }
+
+        if let Some(break_glass) = config.break_glass {
+            builder.set_break_glass(break_glass)?;
+        }
+
+        if let Some(approvals) = config.approvals {
+            builder.set_approvals(approvals)?;
+        }
+
+        if let Some(source) = config.key_groups_from {
+            match source.strip_prefix("store:") {
+                Some(path) => builder.set_key_groups_from(PathBuf::from(path)),
+                None => return Err(MainError::InvalidKeyGroupsSource(source)),
+            }
+        }
+
+        if let Some(s) = config.storage {
+            storage = Some(s);
+        }
+
+        if let Some(s) = config.storages {
+            named_storages.extend(s);
+        }
+
+        if let Some(limits) = config.rate_limits {
+            rate_limits = Some(limits);
+        }
+
+        if let Some(size) = config.chunk_size {
+            chunk_size = Some(size.0);
+        }
+
+        if let Some(threshold) = config.large_secret_threshold {
+            large_secret_threshold = threshold.0;
+            builder.set_large_secret_threshold(threshold);
+        }
+
+        if let Some(keep) = config.keep_large_previous {
+            builder.set_keep_large_previous(keep);
+        }
+
+        dev_values.extend(config.dev_values);
+        bootstrap.extend(config.bootstrap);
+    }
+
+    let storage_override = match &args.action {
+        Actions::Secret(a) => a.storage.as_deref(),
+        _ => None,
+    };
+    if let Some(spec) = storage_override {
+        log::info!("using storage override {spec}");
+        storage = Some(resolve_storage(spec, named_storages)?);
+    }
+    if let Some(s) = &storage {
+        builder.set_storage_type(s.type_name());
     }
+    if args.force_adopt {
+        builder.set_force_adopt();
+    }
+
+    let (files, envs, templates) = partition_specs(args.exposure);
+    builder.add_file_exposures(&ExposureSource::CommandLine, files);
+    builder.add_env_exposures(&ExposureSource::CommandLine, envs);
+    builder.add_template_exposures(&ExposureSource::CommandLine, templates);
 
-    let (files, envs) = partition_specs(args.exposure);
-    builder.add_file_exposures(files)?;
-    builder.add_env_exposures(envs)?;
+    if let Some(root) = args.exposure_root {
+        builder.set_exposure_root(root);
+    }
+
+    if args.read_only {
+        builder.set_read_only();
+    }
+
+    if args.env == RunEnvironment::Dev {
+        let secret_paths = builder.secret_paths();
+        let values = dev_values
+            .into_iter()
+            .map(|(name, value)| match secret_paths.get(&name) {
+                Some(path) => Ok((path.clone(), value)),
+                None => Err(MainError::UnknownDevValue(name)),
+            })
+            .collect::<Result<_, _>>()?;
+        let storage = DevStorage::new(values).map_err(MainError::SettingUpDevStorage)?;
+
+        // Only the throwaway identity is used, so that dev runs never touch
+        // real keys
+        let mut identity_file =
+            tempfile::NamedTempFile::new().map_err(MainError::WritingDevIdentity)?;
+        identity_file
+            .write_all(storage.identity().as_bytes())
+            .map_err(MainError::WritingDevIdentity)?;
+        builder.set_identities([identity_file.path().to_owned()]);
+        // Dev values only exist in config, so there's nothing to write to
+        builder.set_read_only();
+        builder.skip_verification();
+        builder.set_storage_type("Dev");
+
+        log::info!("using dev values instead of storage");
+        let builder = builder.set_secret_storage(storage).await?;
+        return execute(builder, args.action).await;
+    }
 
     if let Some(paths) = args.private_key_paths {
         builder.set_identities(paths);
     }
-    let state = builder.build().await?;
-    let code = match args.action {
+    // Dev storage doesn't need credentials, so this is only done for real
+    // storage
+    builder.add_bootstrap(bootstrap);
+
+    let recording = match (args.record, args.replay) {
+        (Some(dir), _) => Some((dir, RecordMode::Record)),
+        (None, Some(dir)) => Some((dir, RecordMode::Replay)),
+        (None, None) => None,
+    };
+
+    // Storage is always wrapped in a limiter and chunking, which do nothing
+    // without any limits or chunk size configured (though chunked objects
+    // can always be read). Each chunk counts towards the limits. Around
+    // those, it's wrapped for migration, which does nothing unless there's
+    // an old backend to read from.
+    let limits = rate_limits.unwrap_or_default();
+    let backend = |s| {
+        let inner = match s {
+            S3(c) => BackendConfig::S3(c),
+            AzureBlob(c) => BackendConfig::AzureBlob(c),
+            File(c) => BackendConfig::File(c),
+            Git(c) => BackendConfig::Git(c),
+            Migration(_) => return Err(MainError::NestedMigration),
+            _ => unimplemented!(),
+        };
+        Ok(ChunkedStorageConfig {
+            inner: LimitedStorageConfig { inner, limits },
+            chunk_size,
+            large_secret_threshold,
+        })
+    };
+    let storage = match storage {
+        Some(Migration(m)) => Some(MigratingStorageConfig {
+            to: backend(*m.to)?,
+            from: Some(backend(*m.from)?),
+        }),
+        Some(s) => Some(MigratingStorageConfig {
+            to: backend(s)?,
+            from: None,
+        }),
+        None => None,
+    };
+
+    // Migrating needs to see both backends, without recording in the way
+    if let Actions::Storage(action) = args.action {
+        let storage = storage.ok_or(StateBuilderError::StorageUnset)?;
+        let state = builder.set_secret_storage(storage).await?.build().await?;
+        return Ok(cli::storage(&state, action).await?);
+    }
+
+    match (storage, recording) {
+        (Some(inner), Some((dir, mode))) => {
+            match mode {
+                RecordMode::Record => log::info!("recording secrets to {}", dir.to_string_lossy()),
+                RecordMode::Replay => {
+                    log::info!("replaying secrets from {}", dir.to_string_lossy())
+                }
+            }
+            let config = RecordingStorageConfig { inner, dir, mode };
+            execute(builder.set_secret_storage(config).await?, args.action).await
+        }
+        (Some(s), None) => execute(builder.set_secret_storage(s).await?, args.action).await,
+        (None, _) => Err(StateBuilderError::StorageUnset.into()),
+    }
+}
+
+async fn execute<E, J>(
+    builder: StateBuilder<E, J>,
+    action: Actions,
+) -> Result<ExitStatus, MainError>
+where
+    E: SecretError + Send + 'static,
+    J: SecretStorage<Error = E> + Sync + 'static,
+    ProcessRunningError: From<E>,
+{
+    let mut state = builder.build().await?;
+    // Only commands that encrypt, or compare against recipients, need key
+    // groups resolved
+    if matches!(action, Actions::Secret(_) | Actions::Rekey(_)) {
+        state.resolve_key_groups().await?;
+    }
+    let code = match action {
         Actions::RunCommand(args) => cli::process(&state, args).await?,
+        Actions::RunScript(args) => cli::run_script(&state, args).await?,
+        #[cfg(unix)]
+        Actions::DockerRun(args) => cli::docker_run(&state, args).await?,
+        #[cfg(unix)]
+        Actions::BuildkitSecret(args) => cli::buildkit_secret(&state, args).await?,
+        #[cfg(unix)]
         Actions::System(cmd) => cli::system(&state, cmd).await?,
         Actions::Secret(cmd) => cli::secret(&state, cmd).await?,
+        Actions::Rekey(args) => cli::rekey(&state, args).await?,
+        Actions::Backup(cmd) => cli::backup(&state, cmd).await?,
+        Actions::BreakGlass(cmd) => cli::break_glass(&state, cmd).await?,
+        Actions::Keygen(args) => cli::register_key(&state, args).await?,
+        Actions::Prefetch(args) => cli::prefetch(&state, args).await?,
+        Actions::MaskList(args) => cli::mask_list(&state, args).await?,
+        Actions::Report(cmd) => cli::report(&state, cmd).await?,
+        Actions::Inspect(cmd) => cli::inspect(&state, cmd).await?,
+        #[cfg(unix)]
+        Actions::Serve(args) => cli::serve(&state, args).await?,
+        Actions::Storage(_) => return Err(MainError::DevStorageCommand),
+        Actions::Clean(_) | Actions::Approve(_) => unreachable!("handled before loading config"),
     };
     Ok(code)
 }
 
-#[tokio::main]
-async fn main() {
-    let code = match real_main().await {
-        Ok(status) => status.code().unwrap_or_default(),
+fn main() {
+    let code = match start() {
+        Ok(status) => exit_code(status),
         Err(MainError::ParsingCliArgs(e)) => {
             eprintln!("{e}");
             1
@@ -0,0 +1,66 @@
+use std::fmt::Display;
+
+/// A collection of errors, each with the item (e.g. secret name) it relates
+/// to.
+///
+/// Used where continuing past a failure is safe, so that every problem can be
+/// reported (and fixed) at once rather than one per run.
+#[derive(Debug)]
+pub struct MultiError<E> {
+    errors: Vec<(String, E)>,
+}
+
+impl<E> Default for MultiError<E> {
+    fn default() -> Self {
+        Self { errors: Vec::new() }
+    }
+}
+
+impl<E> MultiError<E> {
+    pub fn push<C: Into<String>>(&mut self, context: C, error: E) {
+        self.errors.push((context.into(), error));
+    }
+
+    /// Adds all errors from another collection to this one.
+    pub fn append<F: Into<E>>(&mut self, other: MultiError<F>) {
+        self.errors
+            .extend(other.errors.into_iter().map(|(c, e)| (c, e.into())));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &E)> {
+        self.errors.iter().map(|(c, e)| (c.as_str(), e))
+    }
+
+    /// Succeeds if no errors have been collected.
+    pub fn into_result(self) -> Result<(), Self> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
+}
+
+impl<E: Display> Display for MultiError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let [(context, error)] = &self.errors[..] {
+            return write!(f, "{context}: {error}");
+        }
+
+        write!(f, "{} errors:", self.errors.len())?;
+        for (context, error) in self.errors.iter() {
+            write!(f, "\n  - {context}: {error}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<E: std::fmt::Debug + Display> std::error::Error for MultiError<E> {}
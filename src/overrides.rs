@@ -0,0 +1,156 @@
+//! Overriding single config values with environment variables, e.g.
+//! `CREDIBLE__STORAGE__BUCKET=other-bucket`, without another config file.
+
+use serde_yaml::{Mapping, Value};
+
+use crate::SecretManagerConfig;
+
+/// Prefix of environment variables that override config values, with `__`
+/// between nested keys.
+pub const CONFIG_OVERRIDE_PREFIX: &str = "CREDIBLE__";
+
+#[derive(Debug)]
+struct ConfigOverride {
+    /// Environment variable this came from
+    var: String,
+    /// Keys (or sequence indices) leading to the value, lowercased
+    path: Vec<String>,
+    value: Value,
+    /// Whether any config document had somewhere to put this
+    applied: bool,
+}
+
+/// Config values set by environment variables.
+///
+/// Nested keys (e.g. `CREDIBLE__STORAGE__BUCKET`) are set in every config
+/// document that has the mapping they belong in (here, `storage`). Top-level
+/// keys (e.g. `CREDIBLE__READ_ONLY`) are read as one more config file, after
+/// every other one.
+#[derive(Debug, Default)]
+pub struct ConfigOverrides {
+    overrides: Vec<ConfigOverride>,
+}
+
+impl ConfigOverrides {
+    /// Reads overrides from `CREDIBLE__*` environment variables. Values are
+    /// parsed as YAML, so e.g. `true`, `8` and `[a, b]` aren't strings.
+    pub fn from_env() -> Self {
+        let mut overrides = std::env::vars()
+            .filter_map(|(var, value)| {
+                let path = var
+                    .strip_prefix(CONFIG_OVERRIDE_PREFIX)?
+                    .split("__")
+                    .map(str::to_lowercase)
+                    .collect::<Vec<_>>();
+                if path.iter().any(String::is_empty) {
+                    log::warn!("ignoring {var}, which has an empty key");
+                    return None;
+                }
+                let value = serde_yaml::from_str(&value).unwrap_or(Value::String(value));
+
+                Some(ConfigOverride {
+                    var,
+                    path,
+                    value,
+                    applied: false,
+                })
+            })
+            .collect::<Vec<_>>();
+        // Applied in a stable order, so that nested overrides of a key that's
+        // itself overridden always end up the same way
+        overrides.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Self { overrides }
+    }
+
+    /// Sets nested overrides in a config document, returning whether it was
+    /// changed.
+    pub(crate) fn apply(&mut self, document: &mut Value) -> bool {
+        let mut changed = false;
+        for o in self.overrides.iter_mut().filter(|o| o.path.len() > 1) {
+            if set(document, &o.path, &o.value) {
+                o.applied = true;
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Config made of the top-level overrides, if there are any.
+    pub fn top_level(&mut self) -> Result<Option<SecretManagerConfig>, serde_yaml::Error> {
+        let mut document = Mapping::new();
+        for o in self.overrides.iter_mut().filter(|o| o.path.len() == 1) {
+            document.insert(Value::String(o.path[0].clone()), o.value.clone());
+            o.applied = true;
+        }
+
+        match document.is_empty() {
+            true => Ok(None),
+            false => serde_yaml::from_value(Value::Mapping(document)).map(Some),
+        }
+    }
+
+    /// Environment variables that didn't override anything, as no config had
+    /// anywhere to put them.
+    pub fn unapplied(&self) -> impl Iterator<Item = &str> {
+        self.overrides
+            .iter()
+            .filter(|o| !o.applied)
+            .map(|o| o.var.as_str())
+    }
+}
+
+/// Keys are matched ignoring case and underscores, so that environment
+/// variables can refer to camelCase keys (e.g. `ENCRYPTION_KEYS` to
+/// `encryptionKeys`).
+fn same_key(key: &Value, name: &str) -> bool {
+    let normalize = |s: &str| s.replace('_', "").to_lowercase();
+    match key.as_str() {
+        Some(key) => normalize(key) == normalize(name),
+        None => false,
+    }
+}
+
+fn child_mut<'a>(node: &'a mut Value, segment: &str) -> Option<&'a mut Value> {
+    match node {
+        Value::Mapping(m) => m
+            .iter_mut()
+            .find(|(k, _)| same_key(k, segment))
+            .map(|(_, v)| v),
+        Value::Sequence(s) => segment.parse::<usize>().ok().and_then(|i| s.get_mut(i)),
+        Value::Tagged(t) => child_mut(&mut t.value, segment),
+        _ => None,
+    }
+}
+
+/// Sets the value at the given path, if everything above it exists.
+fn set(document: &mut Value, path: &[String], value: &Value) -> bool {
+    let (key, parents) = path.split_last().expect("override paths aren't empty");
+    let mut node = document;
+    for segment in parents {
+        node = match child_mut(node, segment) {
+            Some(child) => child,
+            None => return false,
+        };
+    }
+
+    match node {
+        Value::Mapping(m) => {
+            let existing = m.keys().find(|k| same_key(k, key)).cloned();
+            m.insert(
+                existing.unwrap_or_else(|| Value::String(key.clone())),
+                value.clone(),
+            );
+            true
+        }
+        Value::Sequence(s) => match key.parse::<usize>().ok().and_then(|i| s.get_mut(i)) {
+            Some(item) => {
+                *item = value.clone();
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use tokio_pipe::PipeWrite;
+
+use crate::secret::{EnvExposureError, EnvTarget};
+use crate::Exposures;
+
+/// Runs a container runtime (`docker`, or something compatible like
+/// `podman`) instead of a command, with secrets exposed to what it runs or
+/// builds.
+#[derive(Debug, Clone, Copy)]
+pub enum Container<'a> {
+    /// Runs a container (`run`), with secrets exposed inside it
+    Run {
+        /// Container runtime to run (e.g. `docker`)
+        runtime: &'a str,
+        /// Where the secrets directory is mounted in the container
+        secrets_dir: &'a Path,
+    },
+    /// Builds an image (`build`), with each file exposure (or rendered
+    /// template) passed as a build secret named after its file, and each env
+    /// exposure as one named after its variable
+    Build {
+        /// Container runtime to run (e.g. `docker`)
+        runtime: &'a str,
+    },
+}
+
+impl Container<'_> {
+    /// Whether env exposures are passed to the runtime in an env file,
+    /// rather than in its own environment.
+    pub(crate) fn uses_env_file(&self) -> bool {
+        matches!(self, Self::Run { .. })
+    }
+
+    /// The runtime and its arguments that expose secrets, before those
+    /// given to the runner.
+    pub(crate) fn args(
+        &self,
+        tmpdir: &Path,
+        exposures: &Exposures,
+        env_file: Option<&str>,
+        secrets_dir_env: &[String],
+    ) -> Vec<String> {
+        match self {
+            Self::Run {
+                runtime,
+                secrets_dir,
+            } => {
+                let mut args = vec![
+                    runtime.to_string(),
+                    "run".to_string(),
+                    "--mount".to_string(),
+                    format!(
+                        "type=bind,source={},target={},readonly",
+                        tmpdir.display(),
+                        secrets_dir.display()
+                    ),
+                ];
+                if let Some(env_file) = env_file {
+                    args.push("--env-file".to_string());
+                    args.push(env_file.to_string());
+                }
+                for name in secrets_dir_env {
+                    args.push("--env".to_string());
+                    args.push(format!("{name}={}", secrets_dir.display()));
+                }
+
+                args
+            }
+            Self::Build { runtime } => {
+                let mut args = vec![runtime.to_string(), "build".to_string()];
+                let templates = exposures.templates.iter().map(|t| &t.name);
+                for name in exposures.files.keys().chain(templates) {
+                    args.push("--secret".to_string());
+                    args.push(format!("id={name},src={}", tmpdir.join(name).display()));
+                }
+                for name in exposures.envs.values().flatten().map(|e| &e.name) {
+                    args.push("--secret".to_string());
+                    args.push(format!("id={name},env={name}"));
+                }
+
+                args
+            }
+        }
+    }
+}
+
+/// Env exposures for a container, in `--env-file`'s format (one `NAME=value`
+/// per line, without quoting).
+#[derive(Default)]
+pub(crate) struct EnvFile(String);
+
+impl EnvFile {
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl EnvTarget for EnvFile {
+    // The container's environment comes from its image, not from us
+    fn inherits_env(&self) -> bool {
+        false
+    }
+
+    fn set_env(&mut self, name: &str, value: &str) -> Result<(), EnvExposureError> {
+        if value.contains(['\n', '\r']) {
+            return Err(EnvExposureError::ContainsNewline);
+        }
+        writeln!(self.0, "{name}={value}").expect("writing to a string can't fail");
+        Ok(())
+    }
+}
+
+/// Pipe that a container runtime reads its env file from, so that it's never
+/// written to disk.
+pub(crate) struct EnvFilePipe {
+    read: OwnedFd,
+    write: PipeWrite,
+}
+
+impl EnvFilePipe {
+    pub fn new() -> Result<Self, std::io::Error> {
+        let (read, write) = nix::unistd::pipe()?;
+        // SAFETY: both ends were just created, and aren't owned by anything
+        // else
+        let read = unsafe { OwnedFd::from_raw_fd(read) };
+        // Only the read end is inherited. If the runtime held the write end
+        // open too, it would never see the end of the file.
+        fcntl(write, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        let write = PipeWrite::from_raw_fd_checked(write)?;
+
+        Ok(Self { read, write })
+    }
+
+    /// Path the runtime can open the env file at.
+    pub fn path(&self) -> String {
+        format!("/dev/fd/{}", self.read.as_raw_fd())
+    }
+
+    /// Closes our copy of the read end, once the runtime has been started
+    /// with its own, and returns the end to write the env file to.
+    pub fn into_writer(self) -> PipeWrite {
+        self.write
+    }
+}
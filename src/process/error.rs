@@ -1,5 +1,5 @@
 use crate::age::DecryptionError;
-use crate::secret::{EnvExposureError, FileExposureError};
+use crate::MultiError;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ProcessRunningError {
@@ -13,6 +13,9 @@ pub enum ProcessRunningError {
     EmptyCommand,
     #[error("couldn't create tempdir: {0}")]
     CreatingTempDir(std::io::Error),
+    #[error("refusing to write secrets to {0}, which is not memory-backed")]
+    DiskBackedTmpdir(std::path::PathBuf),
+    #[cfg(unix)]
     #[error("setting permissions on tempdir: {0}")]
     ChmoddingTempDir(nix::errno::Errno),
     #[error("couldn't create temp file: {0}")]
@@ -39,8 +42,8 @@ pub enum ProcessRunningError {
     CreatingSignalHandlers(std::io::Error),
     #[error("forwarding signal to child process: {0}")]
     SignallingChildProcess(std::io::Error),
-    #[error("exposing secret files: {0}")]
-    ExposingSecretFiles(#[from] FileExposureError),
-    #[error("exposing secret envs: {0}")]
-    ExposingSecretEnvs(#[from] EnvExposureError),
+    #[error("exposing secrets: {0}")]
+    ExposingSecrets(MultiError<Box<dyn std::error::Error>>),
+    #[error("process was killed after running for {}", humantime::format_duration(*.0))]
+    TimedOut(std::time::Duration),
 }
@@ -2,117 +2,99 @@ use std::collections::HashMap;
 use std::process::ExitStatus;
 
 use age::Identity;
-use nix::sys::stat::FchmodatFlags::FollowSymlink;
-use nix::sys::stat::Mode;
-use signal_hook_tokio::Signals;
-use tokio::process::Command;
-use tokio_stream::StreamExt;
-
-use crate::process::signals::SIGNALS;
-use crate::secret::{clean_files, expose_env, expose_files, S3SecretStorageError};
-use crate::util::map_secrets;
+
+use crate::hooks::Hooks;
+use crate::secret::{
+    clean_files,
+    AzureBlobStorageError,
+    BackendStorageError,
+    ChunkedStorageError,
+    DevStorageError,
+    FileStorageError,
+    GitStorageError,
+    LinkMode,
+    RecordingError,
+    S3SecretStorageError,
+    SecretError,
+};
 use crate::{Exposures, Secret, SecretStorage};
 
 mod error;
 pub use error::*;
 
 mod signals;
-use signals::kill;
+
+mod runs;
+pub use runs::*;
+
+#[cfg(unix)]
+mod container;
+#[cfg(unix)]
+pub use container::Container;
+
+mod runner;
+pub use runner::{CommandRunner, EnvPolicy};
+
+mod tmpdir;
+pub(crate) use tmpdir::is_memory_backed;
+pub use tmpdir::DiskBackedTmpdir;
+
+/// Environment variable the secret file directory is exported under, unless
+/// configured otherwise.
+pub const DEFAULT_SECRETS_DIR_ENV: &str = "SECRETS_FILE_DIR";
 
 pub async fn run_process<B>(
     argv: &[String],
+    secrets_dir_env: &[String],
     secrets: &HashMap<String, Secret>,
     exposures: &Exposures,
     identities: &[Box<dyn Identity>],
     store: &B,
+    hooks: &dyn Hooks,
 ) -> Result<ExitStatus, ProcessRunningError>
 where
     B: SecretStorage,
     <B as SecretStorage>::Error: 'static,
     ProcessRunningError: From<<B as SecretStorage>::Error>,
 {
-    let first = argv.first().ok_or(ProcessRunningError::EmptyCommand)?;
-    let mut cmd = Command::new(first);
-    for arg in argv[1..].iter() {
-        cmd.arg(arg);
-    }
+    CommandRunner::new(argv.iter().cloned(), store)
+        .secrets_dir_env(secrets_dir_env.iter().cloned())
+        .secrets(secrets)
+        .exposures(exposures)
+        .identities(identities)
+        .hooks(hooks)
+        .run()
+        .await
+}
 
-    let tmpdir = tempfile::tempdir().map_err(ProcessRunningError::CreatingTempDir)?;
-    cmd.env(
-        "SECRETS_FILE_DIR",
-        tmpdir
-            .path()
-            .to_str()
-            .expect("we should be able to represent all paths as os strs"),
-    );
-
-    nix::sys::stat::fchmodat(
-        None,
-        tmpdir.path(),
-        Mode::from_bits(0o0700).unwrap(),
-        FollowSymlink,
-    )
-    .map_err(ProcessRunningError::ChmoddingTempDir)?;
-
-    // Signal interception done before setting up secrets. This lets us avoid
-    // edge cases where we may leave secrets around without cleaning up
-    let mut signals = Signals::new(SIGNALS).map_err(ProcessRunningError::CreatingSignalHandlers)?;
-
-    // Create files to expose to the process
-    let env_pairs =
-        map_secrets(secrets, exposures.envs.iter()).map_err(ProcessRunningError::NoSuchSecret)?;
-    let file_pairs =
-        map_secrets(secrets, exposures.files.iter()).map_err(ProcessRunningError::NoSuchSecret)?;
-
-    // Write env vars first, to decrease the likelihood of leaving unencrypted
-    // files on-disk in case of crash
-    expose_env(&mut cmd, store, &env_pairs, identities).await?;
-    expose_files(tmpdir.as_ref(), store, &file_pairs, identities).await?;
-    log::debug!("files exposed");
-
-    // Spawn the process, and wait for it to finish
-    let mut process_handle = cmd.spawn().map_err(ProcessRunningError::ForkingProcess)?;
-    let pid = process_handle.id().expect("spawned process has no PID");
-    log::debug!("process running with id {}", pid);
-    let process_fut = process_handle.wait();
-    tokio::pin!(process_fut);
-
-    let result = loop {
-        tokio::select! {
-            // TODO: Something about this is causing us to lose our task and
-            // exit early?
-            finished_process = &mut process_fut => {
-                break finished_process.map_err(ProcessRunningError::JoiningProcess)?;
-            },
-            signal = signals.next() => {
-                // NOTE: we should always be able to receive signals through the life of our process
-                let signal = signal.expect("signal iterator ended prematurely");
-                log::debug!("received signal {}", signal);
-                if let Err(e) = kill(pid, signal).await {
-                    // NOTE: If this is due to the process finishing, we can
-                    // just exit the next loop.
-                    log::warn!("{e}");
-                }
-            },
+async fn remove_record(record: Option<&std::path::Path>) {
+    if let Some(p) = record {
+        if let Err(e) = RunRecord::remove(p).await {
+            log::warn!("{e}");
         }
-    };
-
-    drop(tmpdir);
-
-    // Clean up dangling symlinks
-    let paths = exposures
-        .files
-        .values()
-        .flat_map(|e| e.iter().map(|p| p.vanity_path.as_ref()))
-        .filter_map(|e| e.map(|p| p.as_path()));
-    for e in clean_files(paths).await {
-        // Failure to delete these isn't worth returning an error, because
-        // these are just vanity symlinks that were pointing to our
-        // now-deleted temp dir
+    }
+}
+
+async fn clean_vanity_paths(exposures: &Exposures, hooks: &dyn Hooks) {
+    let (links, copies): (Vec<_>, Vec<_>) = exposures
+        .vanity_paths()
+        .partition(|(_, mode)| *mode == LinkMode::Symlink);
+    // Anything but a symlink where one should be isn't ours (e.g. a file we
+    // refused to replace)
+    let links = links.into_iter().filter(|(p, _)| p.is_symlink());
+
+    // Failure to delete these isn't worth returning an error, because the
+    // process has already finished
+    for e in clean_files(links.map(|(p, _)| p), hooks).await {
+        // Symlinks are just left dangling
         log::warn!("{e}");
     }
 
-    Ok(result)
+    for e in clean_files(copies.into_iter().map(|(p, _)| p), hooks).await {
+        // Hard links and copies still hold the secret, so make sure someone
+        // notices
+        log::error!("{e}, secret may still be readable there");
+    }
 }
 
 impl From<S3SecretStorageError> for ProcessRunningError {
@@ -120,3 +102,45 @@ impl From<S3SecretStorageError> for ProcessRunningError {
         ProcessRunningError::FetchingSecretsErr(Box::new(value))
     }
 }
+
+impl From<AzureBlobStorageError> for ProcessRunningError {
+    fn from(value: AzureBlobStorageError) -> Self {
+        ProcessRunningError::FetchingSecretsErr(Box::new(value))
+    }
+}
+
+impl From<FileStorageError> for ProcessRunningError {
+    fn from(value: FileStorageError) -> Self {
+        ProcessRunningError::FetchingSecretsErr(Box::new(value))
+    }
+}
+
+impl From<GitStorageError> for ProcessRunningError {
+    fn from(value: GitStorageError) -> Self {
+        ProcessRunningError::FetchingSecretsErr(Box::new(value))
+    }
+}
+
+impl From<BackendStorageError> for ProcessRunningError {
+    fn from(value: BackendStorageError) -> Self {
+        ProcessRunningError::FetchingSecretsErr(Box::new(value))
+    }
+}
+
+impl From<DevStorageError> for ProcessRunningError {
+    fn from(value: DevStorageError) -> Self {
+        ProcessRunningError::FetchingSecretsErr(Box::new(value))
+    }
+}
+
+impl<E: SecretError + 'static> From<RecordingError<E>> for ProcessRunningError {
+    fn from(value: RecordingError<E>) -> Self {
+        ProcessRunningError::FetchingSecretsErr(Box::new(value))
+    }
+}
+
+impl<E: SecretError + 'static> From<ChunkedStorageError<E>> for ProcessRunningError {
+    fn from(value: ChunkedStorageError<E>) -> Self {
+        ProcessRunningError::FetchingSecretsErr(Box::new(value))
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use age::Identity;
+#[cfg(unix)]
+use nix::sys::stat::FchmodatFlags::FollowSymlink;
+#[cfg(unix)]
+use nix::sys::stat::Mode;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::process::Command;
+
+#[cfg(unix)]
+use super::container::{Container, EnvFile, EnvFilePipe};
+use super::signals::SignalForwarder;
+use super::tmpdir::{check_memory_backed, DiskBackedTmpdir};
+use super::{clean_vanity_paths, remove_record, ProcessRunningError, RunRecord, TEMPDIR_PREFIX};
+use crate::events::{self, Event};
+use crate::hooks::{Hooks, NoHooks};
+use crate::process::DEFAULT_SECRETS_DIR_ENV;
+use crate::secret::{expose_env, expose_files, expose_templates, DEFAULT_LARGE_SECRET_THRESHOLD};
+use crate::util::map_secrets;
+use crate::{Exposures, MultiError, Secret, SecretStorage};
+
+/// Which of our own environment variables the command inherits.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum EnvPolicy {
+    /// Everything
+    #[default]
+    Inherit,
+    /// Nothing (only exposed secrets and explicitly-set variables)
+    Clear,
+    /// Only the named variables
+    Allow(Vec<String>),
+}
+
+/// Runs a command with secrets exposed to it, cleaning them up once it exits.
+/// This is what `credible run-command` does.
+///
+/// ```no_run
+/// # async fn example<S>(storage: &S, secrets: &std::collections::HashMap<String, credible::Secret>, exposures: &credible::Exposures) -> Result<(), credible::ProcessRunningError>
+/// # where S: credible::SecretStorage, S::Error: 'static, credible::ProcessRunningError: From<S::Error> {
+/// use std::time::Duration;
+///
+/// use credible::{CommandRunner, EnvPolicy};
+///
+/// let status = CommandRunner::new(["./deploy.sh", "--prod"], storage)
+///     .secrets(secrets)
+///     .exposures(exposures)
+///     .env_policy(EnvPolicy::Allow(vec!["PATH".into()]))
+///     .timeout(Duration::from_secs(600))
+///     .run()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct CommandRunner<'a, S> {
+    argv: Vec<String>,
+    storage: &'a S,
+    secrets: Option<&'a HashMap<String, Secret>>,
+    exposures: Option<&'a Exposures>,
+    identities: &'a [Box<dyn Identity>],
+    secrets_dir_env: Vec<String>,
+    secrets_tmpdir: Option<&'a Path>,
+    disk_backed_tmpdir: DiskBackedTmpdir,
+    large_secret_threshold: u64,
+    env_policy: EnvPolicy,
+    envs: Vec<(String, String)>,
+    timeout: Option<Duration>,
+    stdin: Option<&'a [u8]>,
+    #[cfg(unix)]
+    container: Option<Container<'a>>,
+    hooks: &'a dyn Hooks,
+}
+
+/// Writes input to one of a command's pipes (e.g. its stdin), then closes it
+/// so that the command sees the end of its input.
+async fn feed<W: AsyncWrite + Unpin>(pipe: Option<W>, input: &[u8], what: &str) {
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    // Commands that exit without reading all of their input close the pipe,
+    // which is up to them
+    if let Err(e) = pipe.write_all(input).await {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            log::warn!("couldn't write to the command's {what}: {e}");
+        }
+    }
+}
+
+impl<'a, S> CommandRunner<'a, S>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+    ProcessRunningError: From<<S as SecretStorage>::Error>,
+{
+    /// Runs `argv` (program first), fetching secrets from `storage`.
+    pub fn new<I, A>(argv: I, storage: &'a S) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        Self {
+            argv: argv.into_iter().map(Into::into).collect(),
+            storage,
+            secrets: None,
+            exposures: None,
+            identities: &[],
+            secrets_dir_env: vec![DEFAULT_SECRETS_DIR_ENV.to_string()],
+            secrets_tmpdir: None,
+            disk_backed_tmpdir: DiskBackedTmpdir::default(),
+            large_secret_threshold: DEFAULT_LARGE_SECRET_THRESHOLD,
+            env_policy: EnvPolicy::default(),
+            envs: Vec::new(),
+            timeout: None,
+            stdin: None,
+            #[cfg(unix)]
+            container: None,
+            hooks: &NoHooks,
+        }
+    }
+
+    /// Secret definitions that exposures refer to by name.
+    pub fn secrets(mut self, secrets: &'a HashMap<String, Secret>) -> Self {
+        self.secrets = Some(secrets);
+        self
+    }
+
+    /// Secrets to expose. Relative vanity paths should already be resolved
+    /// (see [Exposures::with_root]).
+    pub fn exposures(mut self, exposures: &'a Exposures) -> Self {
+        self.exposures = Some(exposures);
+        self
+    }
+
+    /// Identities to decrypt secrets with.
+    pub fn identities(mut self, identities: &'a [Box<dyn Identity>]) -> Self {
+        self.identities = identities;
+        self
+    }
+
+    /// Environment variables to export the secret file directory under
+    /// (default: `SECRETS_FILE_DIR`).
+    pub fn secrets_dir_env<I: IntoIterator<Item = String>>(mut self, names: I) -> Self {
+        self.secrets_dir_env = names.into_iter().collect();
+        self
+    }
+
+    /// Directory to create the secrets directory in (default: the system temp
+    /// directory), e.g. an existing tmpfs like `/dev/shm`.
+    pub fn secrets_tmpdir(mut self, dir: &'a Path) -> Self {
+        self.secrets_tmpdir = Some(dir);
+        self
+    }
+
+    /// What to do if the secrets directory turns out not to be
+    /// memory-backed.
+    pub fn disk_backed_tmpdir(mut self, policy: DiskBackedTmpdir) -> Self {
+        self.disk_backed_tmpdir = policy;
+        self
+    }
+
+    /// Size (in bytes) above which secrets are streamed into their files,
+    /// and refused as environment variables or in templates.
+    pub fn large_secret_threshold(mut self, bytes: u64) -> Self {
+        self.large_secret_threshold = bytes;
+        self
+    }
+
+    pub fn env_policy(mut self, policy: EnvPolicy) -> Self {
+        self.env_policy = policy;
+        self
+    }
+
+    /// Sets an environment variable for the command, regardless of
+    /// [EnvPolicy].
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Kills the command if it's still running after this long, returning
+    /// [ProcessRunningError::TimedOut]. Secrets are cleaned up either way.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Passes this to the command on stdin, instead of letting it inherit
+    /// ours.
+    pub fn stdin(mut self, input: &'a [u8]) -> Self {
+        self.stdin = Some(input);
+        self
+    }
+
+    /// Treats `argv` as the arguments to a container runtime's `run` (the
+    /// image, then its command) or `build`, and exposes secrets to the
+    /// container or build instead. For `run`, the secrets directory is
+    /// bind-mounted into it, and env exposures are passed in an env file on
+    /// a pipe. For `build`, they're passed as build secrets.
+    #[cfg(unix)]
+    pub fn container(mut self, container: Container<'a>) -> Self {
+        self.container = Some(container);
+        self
+    }
+
+    pub fn hooks(mut self, hooks: &'a dyn Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Builds the command, after `prefix` (e.g. a container runtime and its
+    /// arguments) if there is one.
+    fn command(&self, prefix: &[String]) -> Result<Command, ProcessRunningError> {
+        let mut argv = prefix.iter().chain(&self.argv);
+        let first = argv.next().ok_or(ProcessRunningError::EmptyCommand)?;
+        let mut cmd = Command::new(first);
+        cmd.args(argv);
+        if self.stdin.is_some() {
+            cmd.stdin(Stdio::piped());
+        }
+
+        match &self.env_policy {
+            EnvPolicy::Inherit => (),
+            EnvPolicy::Clear => {
+                cmd.env_clear();
+            }
+            EnvPolicy::Allow(names) => {
+                cmd.env_clear();
+                for name in names {
+                    if let Some(value) = std::env::var_os(name) {
+                        cmd.env(name, value);
+                    }
+                }
+            }
+        }
+        cmd.envs(self.envs.iter().map(|(k, v)| (k, v)));
+
+        Ok(cmd)
+    }
+
+    /// Exposes secrets, runs the command, and cleans up, returning the
+    /// command's exit status.
+    pub async fn run(self) -> Result<ExitStatus, ProcessRunningError> {
+        let no_secrets = HashMap::new();
+        let no_exposures = Exposures::default();
+        let secrets = self.secrets.unwrap_or(&no_secrets);
+        let exposures = self.exposures.unwrap_or(&no_exposures);
+        let (store, identities, hooks) = (self.storage, self.identities, self.hooks);
+        let threshold = self.large_secret_threshold;
+
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(TEMPDIR_PREFIX);
+        let tmpdir = match self.secrets_tmpdir {
+            Some(dir) => builder.tempdir_in(dir),
+            None => builder.tempdir(),
+        };
+        let tmpdir = tmpdir.map_err(ProcessRunningError::CreatingTempDir)?;
+
+        #[cfg(unix)]
+        let (mut cmd, mut env_file) = match self.container {
+            Some(container) => {
+                if self.argv.is_empty() {
+                    return Err(ProcessRunningError::EmptyCommand);
+                }
+                if exposures.vanity_paths().next().is_some() {
+                    match container {
+                        Container::Run { secrets_dir, .. } => log::warn!(
+                            "vanity paths are created on this host, not in the container (use {} there instead)",
+                            secrets_dir.display()
+                        ),
+                        Container::Build { .. } => log::warn!(
+                            "vanity paths are created on this host, not in the build (mount secrets by id there instead)"
+                        ),
+                    }
+                }
+                let pipe = match container.uses_env_file() {
+                    true => {
+                        Some(EnvFilePipe::new().map_err(ProcessRunningError::CreatingDataPipe)?)
+                    }
+                    false => None,
+                };
+                let prefix = container.args(
+                    tmpdir.path(),
+                    exposures,
+                    pipe.as_ref().map(|p| p.path()).as_deref(),
+                    &self.secrets_dir_env,
+                );
+                let cmd = self.command(&prefix)?;
+                (cmd, pipe.map(|p| (p, EnvFile::default())))
+            }
+            None => (self.command(&[])?, None),
+        };
+        #[cfg(not(unix))]
+        let mut cmd = self.command(&[])?;
+
+        let tmpdir_str = tmpdir
+            .path()
+            .to_str()
+            .expect("we should be able to represent all paths as os strs");
+        for name in &self.secrets_dir_env {
+            cmd.env(name, tmpdir_str);
+        }
+
+        // On Windows, the tempdir is created under the user's profile, which
+        // is already only accessible to them
+        #[cfg(unix)]
+        nix::sys::stat::fchmodat(
+            None,
+            tmpdir.path(),
+            Mode::from_bits(0o0700).unwrap(),
+            FollowSymlink,
+        )
+        .map_err(ProcessRunningError::ChmoddingTempDir)?;
+
+        check_memory_backed(tmpdir.path(), self.disk_backed_tmpdir)?;
+
+        // Record what we're about to create before creating it, so that it
+        // can be cleaned up if we don't get the chance to
+        let record = match RunRecord::new(tmpdir.path(), exposures).write().await {
+            Ok(p) => Some(p),
+            Err(e) => {
+                log::warn!("{e}, leftovers won't be cleaned up if we crash");
+                None
+            }
+        };
+
+        // Signal interception done before setting up secrets. This lets us
+        // avoid edge cases where we may leave secrets around without cleaning
+        // up
+        let mut signals =
+            SignalForwarder::new().map_err(ProcessRunningError::CreatingSignalHandlers)?;
+
+        // Create files to expose to the process
+        let env_pairs = map_secrets(secrets, exposures.envs.iter())
+            .map_err(ProcessRunningError::NoSuchSecret)?;
+        let stages = exposures.stages();
+        let file_pairs = stages
+            .iter()
+            .map(|stage| map_secrets(secrets, stage.files.iter()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(ProcessRunningError::NoSuchSecret)?;
+
+        // Write env vars first, to decrease the likelihood of leaving
+        // unencrypted files on-disk in case of crash. Everything is attempted
+        // either way, so that all failures are reported together.
+        let mut errors = MultiError::<Box<dyn std::error::Error>>::default();
+        #[cfg(unix)]
+        let res = match &mut env_file {
+            Some((_, env_file)) => {
+                expose_env(env_file, store, &env_pairs, identities, hooks, threshold).await
+            }
+            None => expose_env(&mut cmd, store, &env_pairs, identities, hooks, threshold).await,
+        };
+        #[cfg(not(unix))]
+        let res = expose_env(&mut cmd, store, &env_pairs, identities, hooks, threshold).await;
+        if let Err(e) = res {
+            errors.append(e);
+        }
+        // Each stage is exposed once everything it's after has been
+        for (stage, file_pairs) in stages.iter().zip(file_pairs.iter()) {
+            let tmpdir = tmpdir.as_ref();
+            let res = expose_files(tmpdir, store, file_pairs, identities, hooks, threshold);
+            if let Err(e) = res.await {
+                errors.append(e);
+            }
+            let templates = &stage.templates;
+            let res = expose_templates(
+                tmpdir, store, secrets, templates, identities, hooks, threshold,
+            );
+            if let Err(e) = res.await {
+                errors.append(e);
+            }
+        }
+        if !errors.is_empty() {
+            clean_vanity_paths(exposures, hooks).await;
+            remove_record(record.as_deref()).await;
+            return Err(ProcessRunningError::ExposingSecrets(errors));
+        }
+        log::debug!("files exposed");
+
+        // Spawn the process, and wait for it to finish
+        let mut process_handle = cmd.spawn().map_err(ProcessRunningError::ForkingProcess)?;
+        let pid = process_handle.id().expect("spawned process has no PID");
+        log::debug!("process running with id {}", pid);
+        events::emit(Event::ChildSpawned { pid });
+
+        // Input is written while waiting, so that a command that doesn't read
+        // it all can't block us
+        let stdin = process_handle.stdin.take();
+        #[cfg(unix)]
+        let (env_pipe, env_file) = match env_file {
+            Some((pipe, env_file)) => (Some(pipe.into_writer()), env_file),
+            None => (None, EnvFile::default()),
+        };
+        let waiting = async {
+            let input = self.stdin.unwrap_or_default();
+            #[cfg(unix)]
+            let (_, _, result) = tokio::join!(
+                feed(stdin, input, "stdin"),
+                feed(env_pipe, env_file.as_bytes(), "env file"),
+                signals.wait(&mut process_handle)
+            );
+            #[cfg(not(unix))]
+            let (_, result) = tokio::join!(
+                feed(stdin, input, "stdin"),
+                signals.wait(&mut process_handle)
+            );
+            result
+        };
+        let mut timed_out = None;
+        let result = match self.timeout {
+            None => waiting.await,
+            Some(timeout) => match tokio::time::timeout(timeout, waiting).await {
+                Ok(result) => result,
+                Err(_) => {
+                    log::warn!("process {pid} timed out, killing it");
+                    timed_out = Some(timeout);
+                    match process_handle.kill().await {
+                        Ok(()) => process_handle.wait().await,
+                        Err(e) => Err(e),
+                    }
+                }
+            },
+        };
+        let result = result.map_err(ProcessRunningError::JoiningProcess)?;
+
+        #[cfg(unix)]
+        let signal = result.signal();
+        #[cfg(not(unix))]
+        let signal = None;
+        events::emit(Event::ChildExited {
+            code: result.code(),
+            signal,
+        });
+
+        let tmpdir_path = tmpdir.path().to_owned();
+        match tmpdir.close() {
+            Ok(()) => hooks.on_cleanup(&tmpdir_path),
+            Err(e) => log::error!(
+                "couldn't remove secrets dir {}: {e}",
+                tmpdir_path.to_string_lossy()
+            ),
+        }
+
+        clean_vanity_paths(exposures, hooks).await;
+        remove_record(record.as_deref()).await;
+
+        match timed_out {
+            Some(timeout) => Err(ProcessRunningError::TimedOut(timeout)),
+            None => Ok(result),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::hooks::Hooks;
+use crate::secret::{read_exposure_tag, LinkMode};
+use crate::util::create_new_with_mode;
+use crate::{Exposures, MultiError};
+
+#[cfg(unix)]
+const RUNTIME_DIR_PERMISSIONS: u32 = 0o0700;
+const RECORD_PERMISSIONS: u32 = 0o0600;
+
+/// Prefix of the secret file directories run-command creates, so that they
+/// can be told apart from other temp directories.
+pub const TEMPDIR_PREFIX: &str = ".credible-";
+
+#[derive(thiserror::Error, Debug)]
+pub enum RunRecordError {
+    #[error("error creating runtime dir {0}: {1}")]
+    CreatingRuntimeDir(PathBuf, std::io::Error),
+    #[error("refusing to use runtime dir {0}, which is {1}")]
+    UntrustedRuntimeDir(PathBuf, &'static str),
+    #[error("error encoding run record: {0}")]
+    Encoding(serde_yaml::Error),
+    #[error("error writing run record to {0}: {1}")]
+    Writing(PathBuf, std::io::Error),
+    #[error("error reading run record {0}: {1}")]
+    Reading(PathBuf, std::io::Error),
+    #[error("error decoding run record {0}: {1}")]
+    Decoding(PathBuf, serde_yaml::Error),
+    #[error("error removing run record {0}: {1}")]
+    Removing(PathBuf, std::io::Error),
+}
+
+/// Directory for state that only lives as long as the user's session.
+pub fn runtime_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        return PathBuf::from(dir).join("credible");
+    }
+
+    // The temp dir is shared between users, so keep ours separate
+    #[cfg(unix)]
+    let name = format!("credible-{}", nix::unistd::getuid());
+    #[cfg(not(unix))]
+    let name = String::from("credible");
+    std::env::temp_dir().join(name)
+}
+
+/// Directory run records are kept in.
+pub fn runs_dir() -> PathBuf {
+    runtime_dir().join("runs")
+}
+
+/// Creates the given directory if it doesn't exist, and checks it's private
+/// to us. Without `XDG_RUNTIME_DIR`, the runtime dir is at a predictable path
+/// in a shared directory, so someone else may have created it first.
+async fn create_private_dir(dir: &Path) -> Result<(), RunRecordError> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    builder.mode(RUNTIME_DIR_PERMISSIONS);
+    match builder.create(dir) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(RunRecordError::CreatingRuntimeDir(dir.to_owned(), e)),
+    }
+
+    check_private_dir(dir).await
+}
+
+/// Checks that the given path is a real directory (not a symlink), owned by
+/// us, that nobody else can access.
+#[cfg(unix)]
+pub(crate) async fn check_private_dir(dir: &Path) -> Result<(), RunRecordError> {
+    let metadata = fs::symlink_metadata(dir)
+        .await
+        .map_err(|e| RunRecordError::Reading(dir.to_owned(), e))?;
+    let problem = if !metadata.is_dir() {
+        "not a directory"
+    } else if metadata.uid() != nix::unistd::geteuid().as_raw() {
+        "owned by someone else"
+    } else if metadata.mode() & 0o777 != RUNTIME_DIR_PERMISSIONS {
+        "accessible by others"
+    } else {
+        return Ok(());
+    };
+
+    Err(RunRecordError::UntrustedRuntimeDir(dir.to_owned(), problem))
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn check_private_dir(_dir: &Path) -> Result<(), RunRecordError> {
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VanityPathRecord {
+    pub path: PathBuf,
+    pub link_mode: LinkMode,
+}
+
+/// Everything a `run-command` leaves on disk while its child is running, so
+/// that it can be cleaned up by someone else if we crash before doing it
+/// ourselves.
+///
+/// Each run has its own record (named after its PID), so concurrent runs
+/// never contend over the same file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RunRecord {
+    pub pid: u32,
+    /// Seconds since the unix epoch
+    pub started: u64,
+    pub secrets_dir: PathBuf,
+    pub vanity_paths: Vec<VanityPathRecord>,
+}
+
+impl RunRecord {
+    pub fn new(secrets_dir: &Path, exposures: &Exposures) -> Self {
+        let vanity_paths = exposures
+            .vanity_paths()
+            .map(|(path, link_mode)| VanityPathRecord {
+                path: path.to_owned(),
+                link_mode,
+            })
+            .collect();
+
+        Self {
+            pid: std::process::id(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("system clock is before the unix epoch")
+                .as_secs(),
+            secrets_dir: secrets_dir.to_owned(),
+            vanity_paths,
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        runs_dir().join(format!("{}.yaml", self.pid))
+    }
+
+    /// Writes this record, returning where it was written to.
+    pub async fn write(&self) -> Result<PathBuf, RunRecordError> {
+        create_private_dir(&runtime_dir()).await?;
+        create_private_dir(&runs_dir()).await?;
+
+        let data = serde_yaml::to_string(self).map_err(RunRecordError::Encoding)?;
+
+        // Write-then-rename, so a reader never sees a partial record
+        let dest = self.path();
+        let temp = dest.with_extension("tmp");
+        // Left behind by an earlier run that had the same PID
+        match fs::remove_file(&temp).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(RunRecordError::Writing(temp.clone(), e))
+            }
+            _ => (),
+        }
+        let mut file = create_new_with_mode(RECORD_PERMISSIONS)
+            .open(&temp)
+            .await
+            .map_err(|e| RunRecordError::Writing(temp.clone(), e))?;
+        file.write_all(data.as_bytes())
+            .await
+            .map_err(|e| RunRecordError::Writing(temp.clone(), e))?;
+        fs::rename(&temp, &dest)
+            .await
+            .map_err(|e| RunRecordError::Writing(dest.clone(), e))?;
+
+        Ok(dest)
+    }
+
+    pub async fn read(p: &Path) -> Result<Self, RunRecordError> {
+        let data = fs::read(p)
+            .await
+            .map_err(|e| RunRecordError::Reading(p.to_owned(), e))?;
+        serde_yaml::from_slice(&data).map_err(|e| RunRecordError::Decoding(p.to_owned(), e))
+    }
+
+    /// Lists the paths of all run records.
+    pub async fn list() -> Result<Vec<PathBuf>, RunRecordError> {
+        let dir = runs_dir();
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(e) => e,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(RunRecordError::Reading(dir, e)),
+        };
+        // Records say what to delete, so only trust them from our own dir
+        check_private_dir(&runtime_dir()).await?;
+        check_private_dir(&dir).await?;
+
+        let mut paths = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| RunRecordError::Reading(dir.clone(), e))?
+        {
+            let p = entry.path();
+            if p.extension().map(|e| e == "yaml").unwrap_or(false) {
+                paths.push(p);
+            }
+        }
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    /// Whether the process that wrote this record is still running.
+    #[cfg(unix)]
+    pub fn is_running(&self) -> bool {
+        use nix::errno::Errno;
+        use nix::sys::signal::kill;
+        use nix::unistd::Pid;
+
+        if self.pid == std::process::id() {
+            return false;
+        }
+
+        // Signal 0 only checks whether the process exists
+        !matches!(
+            kill(Pid::from_raw(self.pid as i32), None),
+            Err(Errno::ESRCH)
+        )
+    }
+
+    /// Whether the process that wrote this record is still running. We can't
+    /// tell here, so assume it is rather than pulling files out from under
+    /// it.
+    #[cfg(not(unix))]
+    pub fn is_running(&self) -> bool {
+        self.pid != std::process::id()
+    }
+
+    /// Whether the file at the given path was tagged as exposed by this run.
+    /// Where tags can't be read at all, we can't tell, so it isn't.
+    fn tagged_by_us(&self, p: &Path) -> bool {
+        let generation = self.secrets_dir.file_name().unwrap_or_default();
+        match read_exposure_tag(p) {
+            Ok(Some(tag)) => generation == tag.generation.as_str(),
+            Ok(None) => false,
+            Err(e) => {
+                log::debug!("couldn't read tags of {}: {e}", p.to_string_lossy());
+                false
+            }
+        }
+    }
+
+    /// Checks that the run's secret file directory is one run-command would
+    /// have created: named like ours, directly in `tmpdir`, and owned by us.
+    /// Returns whether it still exists.
+    async fn check_secrets_dir(&self, tmpdir: &Path) -> Result<bool, std::io::Error> {
+        let dir = &self.secrets_dir;
+        let named = dir
+            .file_name()
+            .map(|n| n.to_string_lossy().starts_with(TEMPDIR_PREFIX))
+            .unwrap_or(false);
+        if !named || dir.parent() != Some(tmpdir) {
+            return Err(std::io::Error::other(format!(
+                "not a secrets directory created by run-command in {}",
+                tmpdir.to_string_lossy()
+            )));
+        }
+
+        let metadata = match fs::symlink_metadata(dir).await {
+            Ok(m) => m,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        #[cfg(unix)]
+        let owned = metadata.uid() == nix::unistd::geteuid().as_raw();
+        #[cfg(not(unix))]
+        let owned = true;
+        match metadata.is_dir() && owned {
+            true => Ok(true),
+            false => Err(std::io::Error::other("not a directory of ours")),
+        }
+    }
+
+    /// Removes everything the run left behind. Vanity paths that no longer
+    /// look like ours (e.g. a symlink that's been re-pointed somewhere else)
+    /// are left alone.
+    /// The secret file directory is only removed if it's in `tmpdir` (where
+    /// run-command creates them), so that a forged record can't have anything
+    /// else removed.
+    pub async fn clean_up(
+        &self,
+        tmpdir: &Path,
+        hooks: &dyn Hooks,
+    ) -> Result<(), MultiError<std::io::Error>> {
+        let mut errors = MultiError::default();
+        for vanity in self.vanity_paths.iter() {
+            let p = &vanity.path;
+            let ours = match vanity.link_mode {
+                LinkMode::Symlink => fs::read_link(p)
+                    .await
+                    .map(|target| target.starts_with(&self.secrets_dir))
+                    .unwrap_or(false),
+                LinkMode::Hardlink | LinkMode::Copy => {
+                    !p.is_symlink() && p.is_file() && self.tagged_by_us(p)
+                }
+            };
+            if !ours {
+                log::debug!("not removing {}, it isn't ours", p.to_string_lossy());
+                continue;
+            }
+
+            match fs::remove_file(p).await {
+                Ok(()) => hooks.on_cleanup(p),
+                Err(e) => errors.push(p.to_string_lossy(), e),
+            }
+        }
+
+        let res = match self.check_secrets_dir(tmpdir).await {
+            Ok(true) => fs::remove_dir_all(&self.secrets_dir).await,
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => hooks.on_cleanup(&self.secrets_dir),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => errors.push(self.secrets_dir.to_string_lossy(), e),
+        }
+
+        errors.into_result()
+    }
+
+    pub async fn remove(p: &Path) -> Result<(), RunRecordError> {
+        fs::remove_file(p)
+            .await
+            .map_err(|e| RunRecordError::Removing(p.to_owned(), e))
+    }
+}
@@ -1,16 +1,68 @@
-use signal_hook::consts::*;
-use tokio::process::Command;
+use std::process::ExitStatus;
 
-pub const SIGNALS: [i32; 9] = [
-    SIGHUP, SIGINT, SIGQUIT, SIGABRT, SIGTERM, SIGTSTP, SIGCONT, SIGUSR1, SIGUSR2,
-];
+use tokio::process::Child;
 
-pub async fn kill(pid: u32, signal: i32) -> Result<(), std::io::Error> {
-    Command::new("kill")
-        .arg(signal.to_string())
-        .arg(pid.to_string())
-        .status()
-        .await?;
+use crate::signals::{self, SignalListener};
 
-    Ok(())
+/// Intercepts signals sent to us, so that they can be passed on to our child
+/// process instead of killing us before we've cleaned up.
+pub struct SignalForwarder {
+    listener: SignalListener,
+}
+
+impl SignalForwarder {
+    pub fn new() -> Result<Self, std::io::Error> {
+        Ok(Self {
+            listener: SignalListener::new()?,
+        })
+    }
+
+    /// Waits for the child process to exit, forwarding any signals we receive
+    /// to it in the meantime.
+    ///
+    /// Job-control stops (e.g. Ctrl-Z) stop the child and then us, so that
+    /// the shell sees the whole job as stopped, and `fg`/`bg` (SIGCONT)
+    /// resume both. Any signal the terminal already sent to the child as
+    /// well is harmless, since continuing discards pending stops.
+    #[cfg(unix)]
+    pub async fn wait(&mut self, child: &mut Child) -> Result<ExitStatus, std::io::Error> {
+        let pid = child.id().expect("spawned process has no PID");
+        let process_fut = child.wait();
+        tokio::pin!(process_fut);
+
+        loop {
+            tokio::select! {
+                finished_process = &mut process_fut => {
+                    break finished_process;
+                },
+                signal = self.listener.next() => {
+                    log::debug!("received signal {}", signal);
+                    if let Err(e) = signals::send_to_child(pid, signal) {
+                        // NOTE: If this is due to the process finishing, we can
+                        // just exit the next loop.
+                        log::warn!("couldn't forward signal {signal} to process {pid}: {e}");
+                    }
+
+                    if signals::is_stop(signal) {
+                        log::debug!("stopping along with process {pid}");
+                        if let Err(e) = signals::suspend() {
+                            log::warn!("couldn't stop: {e}");
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Waits for the child process to exit. Console processes share our
+    /// Ctrl-C events, so we only need to outlive the child to clean up.
+    #[cfg(windows)]
+    pub async fn wait(&mut self, child: &mut Child) -> Result<ExitStatus, std::io::Error> {
+        loop {
+            tokio::select! {
+                finished_process = child.wait() => break finished_process,
+                _ = self.listener.next() => log::debug!("received ctrl-c, waiting for child"),
+            }
+        }
+    }
 }
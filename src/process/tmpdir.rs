@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::ProcessRunningError;
+
+#[cfg(target_os = "linux")]
+const RAMFS_MAGIC: nix::sys::statfs::FsType = nix::sys::statfs::FsType(0x858458f6);
+
+/// What to do when the directory secret files are written to isn't
+/// memory-backed, i.e. when plaintext would end up on disk.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskBackedTmpdir {
+    /// Carry on, but log a warning
+    #[default]
+    Warn,
+    /// Refuse to expose any secrets
+    Refuse,
+    /// Carry on silently
+    Allow,
+}
+
+/// Whether the given directory is on a memory-backed filesystem (tmpfs or
+/// ramfs), if that can be told on this platform.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_memory_backed(dir: &Path) -> Option<bool> {
+    use nix::sys::statfs::{statfs, TMPFS_MAGIC};
+
+    match statfs(dir) {
+        Ok(fs) => Some([TMPFS_MAGIC, RAMFS_MAGIC].contains(&fs.filesystem_type())),
+        Err(e) => {
+            log::warn!(
+                "couldn't check filesystem of {}: {e}",
+                dir.to_string_lossy()
+            );
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn is_memory_backed(dir: &Path) -> Option<bool> {
+    match nix::sys::statfs::statfs(dir) {
+        Ok(fs) => Some(fs.filesystem_type_name() == "tmpfs"),
+        Err(e) => {
+            log::warn!(
+                "couldn't check filesystem of {}: {e}",
+                dir.to_string_lossy()
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn is_memory_backed(_dir: &Path) -> Option<bool> {
+    None
+}
+
+/// Checks that secrets written to the given directory won't touch disk,
+/// applying the policy if they would.
+pub(super) fn check_memory_backed(
+    dir: &Path,
+    policy: DiskBackedTmpdir,
+) -> Result<(), ProcessRunningError> {
+    if policy == DiskBackedTmpdir::Allow {
+        return Ok(());
+    }
+
+    match (is_memory_backed(dir), policy) {
+        (Some(true), _) => Ok(()),
+        (None, _) => {
+            log::debug!(
+                "can't tell whether {} is memory-backed on this platform",
+                dir.to_string_lossy()
+            );
+            Ok(())
+        }
+        (Some(false), DiskBackedTmpdir::Refuse) => {
+            Err(ProcessRunningError::DiskBackedTmpdir(dir.to_owned()))
+        }
+        (Some(false), _) => {
+            log::warn!(
+                "{} is not memory-backed, secrets will be written to disk (set secrets_tmpdir to a tmpfs to avoid this)",
+                dir.to_string_lossy()
+            );
+            Ok(())
+        }
+    }
+}
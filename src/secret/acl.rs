@@ -0,0 +1,163 @@
+//! POSIX ACL entries for exposed files, for secrets read by several services
+//! that shouldn't share a group.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde_with::DeserializeFromStr;
+
+use crate::wrappers::{GroupWrapper, UserWrapper};
+
+/// Who an ACL entry grants access to.
+#[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AclSubject {
+    User(UserWrapper),
+    Group(GroupWrapper),
+}
+
+/// An extra user or group allowed to access an exposed file, e.g.
+/// `{user: grafana, perms: r}`.
+#[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct AclEntry {
+    #[serde(flatten)]
+    pub subject: AclSubject,
+    pub perms: AclPerms,
+}
+
+/// Permissions granted by an ACL entry, written like `r`, `rw` or `rwx`.
+#[derive(DeserializeFromStr, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AclPerms(u16);
+
+impl FromStr for AclPerms {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bits = 0;
+        for c in s.chars() {
+            bits |= match c {
+                'r' => 0o4,
+                'w' => 0o2,
+                'x' => 0o1,
+                '-' => 0,
+                c => return Err(format!("invalid ACL permission {c:?} (expected r, w or x)")),
+            };
+        }
+
+        Ok(Self(bits))
+    }
+}
+
+impl Display for AclPerms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (bit, c) in [(0o4, 'r'), (0o2, 'w'), (0o1, 'x')] {
+            let c = match self.0 & bit {
+                0 => '-',
+                _ => c,
+            };
+            write!(f, "{c}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Written as `setfacl` does, e.g. `user:grafana:r--`.
+impl Display for AclEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.subject {
+            AclSubject::User(u) => write!(f, "user:{u}:{}", self.perms),
+            AclSubject::Group(g) => write!(f, "group:{g}:{}", self.perms),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+
+    const XATTR_NAME: &str = "system.posix_acl_access";
+    const VERSION: u32 = 2;
+    const UNDEFINED_ID: u32 = u32::MAX;
+
+    const USER_OBJ: u16 = 0x01;
+    const USER: u16 = 0x02;
+    const GROUP_OBJ: u16 = 0x04;
+    const GROUP: u16 = 0x08;
+    const MASK: u16 = 0x10;
+    const OTHER: u16 = 0x20;
+
+    /// Encodes an access ACL for a file with the given mode, in the format
+    /// the kernel expects in `system.posix_acl_access`.
+    fn encode(mode: u32, entries: &[AclEntry]) -> Vec<u8> {
+        let mut users = Vec::new();
+        let mut groups = Vec::new();
+        for entry in entries {
+            match &entry.subject {
+                AclSubject::User(u) => users.push((u.as_ref().uid.as_raw(), entry.perms.0)),
+                AclSubject::Group(g) => groups.push((g.as_ref().gid.as_raw(), entry.perms.0)),
+            }
+        }
+        // Entries must be sorted by tag, then by id
+        users.sort();
+        groups.sort();
+
+        let group_obj = ((mode >> 3) & 0o7) as u16;
+        let mask = users
+            .iter()
+            .chain(groups.iter())
+            .fold(group_obj, |mask, (_, perms)| mask | perms);
+
+        let mut all = vec![(USER_OBJ, ((mode >> 6) & 0o7) as u16, UNDEFINED_ID)];
+        all.extend(users.into_iter().map(|(id, perms)| (USER, perms, id)));
+        all.push((GROUP_OBJ, group_obj, UNDEFINED_ID));
+        all.extend(groups.into_iter().map(|(id, perms)| (GROUP, perms, id)));
+        all.push((MASK, mask, UNDEFINED_ID));
+        all.push((OTHER, (mode & 0o7) as u16, UNDEFINED_ID));
+
+        let mut data = VERSION.to_le_bytes().to_vec();
+        for (tag, perms, id) in all {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&perms.to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+
+        data
+    }
+
+    pub(crate) async fn set_acl(
+        file: &tokio::fs::File,
+        mode: u32,
+        entries: &[AclEntry],
+    ) -> Result<(), std::io::Error> {
+        use xattr::FileExt;
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let data = encode(mode, entries);
+        let file = file.try_clone().await?.into_std().await;
+        tokio::task::spawn_blocking(move || file.set_xattr(XATTR_NAME, &data))
+            .await
+            .expect("setting ACL panicked")
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) use linux::set_acl;
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) async fn set_acl(
+    _file: &tokio::fs::File,
+    _mode: u32,
+    entries: &[AclEntry],
+) -> Result<(), std::io::Error> {
+    match entries.is_empty() {
+        true => Ok(()),
+        false => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "ACLs are only supported on Linux",
+        )),
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt::Display;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use ssh_key::{HashAlg, LineEnding, PublicKey, SshSig};
+
+use super::signature::read_signing_key;
+use crate::secret::{normalize_recipient, SigningError};
+
+/// Namespace for approval signatures, so that signatures over ciphertext (or
+/// anything else) can't be passed off as approvals.
+pub const APPROVAL_NAMESPACE: &str = "credible-approval";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApprovalError {
+    #[error("{0}")]
+    Signing(#[from] SigningError),
+    #[error("error encoding approval: {0}")]
+    Encoding(serde_yaml::Error),
+    #[error("approval signature is malformed: {0}")]
+    MalformedSignature(ssh_key::Error),
+    #[error("invalid approver key {0}: {1}")]
+    InvalidApproverKey(String, ssh_key::Error),
+    #[error("approval signature is invalid: {0}")]
+    InvalidSignature(ssh_key::Error),
+    #[error("approval is signed by {0}, which isn't an approver")]
+    UnknownApprover(String),
+    #[error("{0} isn't an approver, so can't request approved operations")]
+    UnknownRequester(String),
+    #[error("approval is signed by the requester, a second approver is needed")]
+    SelfApproved,
+    #[error("approval is for {0}, not {1}")]
+    WrongOperation(Operation, Operation),
+    #[error("approval expired")]
+    Expired,
+}
+
+/// A destructive operation that can require a second person's approval.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Operation {
+    /// Removing a secret's ciphertext from storage
+    RemoveSecret { name: String },
+    /// Rekeying secrets (all of them, if none are named) in a way that removes
+    /// recipients
+    Rekey {
+        secrets: Vec<String>,
+        remove_recipients: Vec<String>,
+        sync: bool,
+    },
+}
+
+impl Operation {
+    /// A rekey, in a canonical form, so that the same rekey is always the
+    /// same operation.
+    pub fn rekey(secrets: &[String], remove_recipients: &[String], sync: bool) -> Self {
+        let mut secrets = secrets.to_vec();
+        secrets.sort();
+        secrets.dedup();
+        let mut remove_recipients = remove_recipients
+            .iter()
+            .map(|k| normalize_recipient(k))
+            .collect::<Vec<_>>();
+        remove_recipients.sort();
+        remove_recipients.dedup();
+
+        Self::Rekey {
+            secrets,
+            remove_recipients,
+            sync,
+        }
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RemoveSecret { name } => write!(f, "removing secret {name}"),
+            Self::Rekey {
+                secrets,
+                remove_recipients,
+                sync,
+            } => {
+                match secrets.is_empty() {
+                    true => write!(f, "rekeying all secrets")?,
+                    false => write!(f, "rekeying {}", secrets.join(", "))?,
+                }
+                if !remove_recipients.is_empty() {
+                    write!(f, " to remove {}", remove_recipients.join(", "))?;
+                }
+                if *sync {
+                    write!(f, " to their configured recipients")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What an approval's signature covers.
+#[derive(Serialize)]
+struct SignedApproval<'a> {
+    operation: &'a Operation,
+    expires: u64,
+}
+
+/// A keyholder's signed approval for someone else to perform an operation.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Approval {
+    pub operation: Operation,
+    /// When the approval stops being valid, in seconds since the Unix epoch
+    pub expires: u64,
+    /// Armored SSH signature over the operation and expiry
+    pub signature: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the epoch")
+        .as_secs()
+}
+
+fn signed_data(operation: &Operation, expires: u64) -> Result<String, ApprovalError> {
+    serde_yaml::to_string(&SignedApproval { operation, expires }).map_err(ApprovalError::Encoding)
+}
+
+/// Reads the public half of an SSH private key, to identify whoever holds it.
+pub fn signing_public_key(key_path: &Path) -> Result<PublicKey, SigningError> {
+    Ok(read_signing_key(key_path)?.public_key().clone())
+}
+
+impl Approval {
+    /// Approves the operation with an OpenSSH private key, for the given
+    /// length of time.
+    pub fn sign(
+        key_path: &Path,
+        operation: Operation,
+        valid_for: Duration,
+    ) -> Result<Self, ApprovalError> {
+        let key = read_signing_key(key_path)?;
+        let expires = now() + valid_for.as_secs();
+        let data = signed_data(&operation, expires)?;
+        let signature = key
+            .sign(APPROVAL_NAMESPACE, HashAlg::Sha512, data.as_bytes())
+            .and_then(|sig| sig.to_pem(LineEnding::LF))
+            .map_err(SigningError::Signing)?;
+
+        Ok(Self {
+            operation,
+            expires,
+            signature,
+        })
+    }
+
+    /// Checks that this is a current approval of the operation, made by one of
+    /// the approvers other than the requester (who must also be an approver).
+    pub fn verify(
+        &self,
+        approvers: &[String],
+        operation: &Operation,
+        requester: &PublicKey,
+    ) -> Result<(), ApprovalError> {
+        if &self.operation != operation {
+            return Err(ApprovalError::WrongOperation(
+                self.operation.clone(),
+                operation.clone(),
+            ));
+        }
+        if self.expires < now() {
+            return Err(ApprovalError::Expired);
+        }
+
+        let approvers = approvers
+            .iter()
+            .map(|k| {
+                PublicKey::from_openssh(k)
+                    .map_err(|e| ApprovalError::InvalidApproverKey(k.to_string(), e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let is_approver =
+            |key: &PublicKey| approvers.iter().any(|a| a.key_data() == key.key_data());
+
+        if !is_approver(requester) {
+            return Err(ApprovalError::UnknownRequester(
+                requester.fingerprint(HashAlg::Sha256).to_string(),
+            ));
+        }
+
+        let signature =
+            SshSig::from_pem(&self.signature).map_err(ApprovalError::MalformedSignature)?;
+        let approver = PublicKey::from(signature.public_key().clone());
+        if !is_approver(&approver) {
+            return Err(ApprovalError::UnknownApprover(
+                approver.fingerprint(HashAlg::Sha256).to_string(),
+            ));
+        }
+        if approver.key_data() == requester.key_data() {
+            return Err(ApprovalError::SelfApproved);
+        }
+
+        let data = signed_data(&self.operation, self.expires)?;
+        approver
+            .verify(APPROVAL_NAMESPACE, data.as_bytes(), &signature)
+            .map_err(ApprovalError::InvalidSignature)
+    }
+}
@@ -0,0 +1,659 @@
+use std::io::Cursor;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use sha2::Sha256;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::Mutex;
+
+use crate::secret::{
+    ConditionalRead,
+    ObjectMetadata,
+    SecretError,
+    SecretStorage,
+    STORAGE_LOG_TARGET,
+};
+use crate::util::BoxedAsyncReader;
+use crate::IntoSecretStorage;
+
+/// Environment variable connection strings are read from, unless one is
+/// configured.
+const CONNECTION_STRING_ENV: &str = "AZURE_STORAGE_CONNECTION_STRING";
+
+/// Blob service API version requests are made with.
+const API_VERSION: &str = "2021-08-06";
+
+/// Resource managed identity tokens are requested for.
+const STORAGE_RESOURCE: &str = "https://storage.azure.com/";
+
+/// Azure's instance metadata service, which hands out tokens on VMs.
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+/// Well-known account and key of the local storage emulator (Azurite).
+const DEV_ACCOUNT: &str = "devstoreaccount1";
+const DEV_ACCOUNT_KEY: &str =
+    "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+/// How long a request may take, including reading its response, since
+/// nothing retries or times out underneath us.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long before they expire managed identity tokens are replaced.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// Characters escaped in blob names. Slashes are kept, as they separate
+/// "directories" in the URL just like in the name.
+const BLOB_NAME: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+#[derive(Deserialize, Debug)]
+pub struct AzureBlobConfig {
+    /// Container secrets are stored in
+    container: String,
+    /// Storage account (only needed with `auth: managed_identity`, since
+    /// connection strings name it)
+    #[serde(default)]
+    account: Option<String>,
+    #[serde(default)]
+    auth: AzureAuth,
+    /// Connection string to use, instead of `AZURE_STORAGE_CONNECTION_STRING`
+    #[serde(default, alias = "connectionString")]
+    connection_string: Option<String>,
+    /// Client ID of the user-assigned managed identity to use (default: the
+    /// system-assigned one)
+    #[serde(default, alias = "clientId")]
+    client_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AzureAuth {
+    /// The account key or SAS token in a connection string
+    #[default]
+    ConnectionString,
+    /// Tokens for the managed identity of the VM (or App Service, Container
+    /// App, ...) we're running on
+    ManagedIdentity,
+}
+
+/// How requests are authorized.
+enum Credential {
+    /// Signed with the account key
+    SharedKey { account: String, key: Vec<u8> },
+    /// A SAS token, added to every URL
+    Sas(String),
+    /// Bearer tokens for a managed identity
+    ManagedIdentity(ManagedIdentity),
+}
+
+/// Blob service endpoint (without a trailing slash), and how to authorize
+/// requests to it, from a connection string.
+fn parse_connection_string(s: &str) -> Result<(String, Credential), String> {
+    let mut fields = std::collections::HashMap::new();
+    for field in s.split(';').filter(|f| !f.trim().is_empty()) {
+        match field.split_once('=') {
+            Some((k, v)) => fields.insert(k.trim(), v.trim()),
+            None => return Err(format!("{field:?} isn't key=value")),
+        };
+    }
+
+    if fields.get("UseDevelopmentStorage") == Some(&"true") {
+        let endpoint = format!("http://127.0.0.1:10000/{DEV_ACCOUNT}");
+        let key = STANDARD
+            .decode(DEV_ACCOUNT_KEY)
+            .expect("emulator key is valid");
+        let account = DEV_ACCOUNT.to_string();
+        return Ok((endpoint, Credential::SharedKey { account, key }));
+    }
+
+    let account = fields.get("AccountName").map(|a| a.to_string());
+    let endpoint = match (fields.get("BlobEndpoint"), &account) {
+        (Some(endpoint), _) => endpoint.trim_end_matches('/').to_string(),
+        (None, Some(account)) => {
+            let protocol = fields.get("DefaultEndpointsProtocol").unwrap_or(&"https");
+            let suffix = fields.get("EndpointSuffix").unwrap_or(&"core.windows.net");
+            format!("{protocol}://{account}.blob.{suffix}")
+        }
+        (None, None) => return Err("it has neither AccountName nor BlobEndpoint".to_string()),
+    };
+
+    let credential = match (
+        fields.get("AccountKey"),
+        fields.get("SharedAccessSignature"),
+    ) {
+        (Some(key), _) => {
+            let account = account.ok_or("it has an AccountKey, but no AccountName")?;
+            let key = STANDARD
+                .decode(key)
+                .map_err(|e| format!("its AccountKey isn't valid base64: {e}"))?;
+            Credential::SharedKey { account, key }
+        }
+        (None, Some(sas)) => Credential::Sas(sas.trim_start_matches('?').to_string()),
+        (None, None) => {
+            return Err("it has neither AccountKey nor SharedAccessSignature".to_string())
+        }
+    };
+
+    Ok((endpoint, credential))
+}
+
+/// Fetches (and caches) tokens for a managed identity, from App Service's
+/// identity endpoint if we're running there, or the instance metadata
+/// service otherwise.
+struct ManagedIdentity {
+    client_id: Option<String>,
+    /// The current token, and when it expires
+    token: Mutex<Option<(String, SystemTime)>>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// Seconds since the epoch, which is a string in some API versions and a
+    /// number in others
+    expires_on: serde_json::Value,
+}
+
+impl ManagedIdentity {
+    async fn token(&self, client: &HttpClient) -> Result<String, AzureBlobStorageError> {
+        let mut cached = self.token.lock().await;
+        if let Some((token, expires)) = &*cached {
+            if SystemTime::now() + TOKEN_REFRESH_MARGIN < *expires {
+                return Ok(token.clone());
+            }
+        }
+
+        let encode = |s: &str| utf8_percent_encode(s, NON_ALPHANUMERIC).to_string();
+        let resource = encode(STORAGE_RESOURCE);
+        let (mut url, header) = match (
+            std::env::var("IDENTITY_ENDPOINT"),
+            std::env::var("IDENTITY_HEADER"),
+        ) {
+            (Ok(endpoint), Ok(secret)) => (
+                format!("{endpoint}?api-version=2019-08-01&resource={resource}"),
+                ("X-IDENTITY-HEADER", secret),
+            ),
+            _ => (
+                format!("{IMDS_TOKEN_URL}?api-version=2018-02-01&resource={resource}"),
+                ("Metadata", "true".to_string()),
+            ),
+        };
+        if let Some(client_id) = &self.client_id {
+            url = format!("{url}&client_id={}", encode(client_id));
+        }
+        let request = Request::get(url)
+            .header(header.0, header.1)
+            .body(Body::empty())
+            .map_err(|e| AzureBlobStorageError::GettingToken(e.to_string()))?;
+
+        let res = send(client, request).await?;
+        let status = res.status();
+        let body = read_body(res).await?;
+        if !status.is_success() {
+            let message = match String::from_utf8_lossy(&body).trim() {
+                "" => status.to_string(),
+                body => format!("{status}: {body}"),
+            };
+            return Err(AzureBlobStorageError::GettingToken(message));
+        }
+        let token: TokenResponse = serde_json::from_slice(&body)
+            .map_err(|e| AzureBlobStorageError::GettingToken(e.to_string()))?;
+        let expires_on = match &token.expires_on {
+            serde_json::Value::String(s) => s.parse().ok(),
+            v => v.as_u64(),
+        };
+        let expires = UNIX_EPOCH + Duration::from_secs(expires_on.unwrap_or_default());
+        log::debug!(target: STORAGE_LOG_TARGET, "got managed identity token");
+
+        *cached = Some((token.access_token.clone(), expires));
+        Ok(token.access_token)
+    }
+}
+
+type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+async fn send(
+    client: &HttpClient,
+    request: Request<Body>,
+) -> Result<Response<Body>, AzureBlobStorageError> {
+    match tokio::time::timeout(REQUEST_TIMEOUT, client.request(request)).await {
+        Ok(res) => Ok(res?),
+        Err(_) => Err(AzureBlobStorageError::TimedOut(REQUEST_TIMEOUT)),
+    }
+}
+
+async fn read_body(res: Response<Body>) -> Result<hyper::body::Bytes, AzureBlobStorageError> {
+    match tokio::time::timeout(REQUEST_TIMEOUT, hyper::body::to_bytes(res.into_body())).await {
+        Ok(body) => body.map_err(AzureBlobStorageError::ReadingData),
+        Err(_) => Err(AzureBlobStorageError::TimedOut(REQUEST_TIMEOUT)),
+    }
+}
+
+#[async_trait]
+impl IntoSecretStorage for AzureBlobConfig {
+    type Error = AzureBlobStorageError;
+    type Impl = AzureBlobSecretStorage;
+
+    async fn build(self) -> Self::Impl {
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = Client::builder().build(connector);
+
+        // Bad config is reported by every request, like missing credentials
+        // are for S3
+        let target = match self.auth {
+            AzureAuth::ConnectionString => {
+                let connection_string = match self.connection_string {
+                    Some(s) => Ok(s),
+                    None => std::env::var(CONNECTION_STRING_ENV)
+                        .map_err(|_| format!("{CONNECTION_STRING_ENV} isn't set")),
+                };
+                connection_string.and_then(|s| {
+                    parse_connection_string(&s)
+                        .map_err(|e| format!("invalid connection string: {e}"))
+                })
+            }
+            AzureAuth::ManagedIdentity => match self.account {
+                Some(account) => {
+                    let endpoint = format!("https://{account}.blob.core.windows.net");
+                    let identity = ManagedIdentity {
+                        client_id: self.client_id,
+                        token: Mutex::new(None),
+                    };
+                    Ok((endpoint, Credential::ManagedIdentity(identity)))
+                }
+                None => Err("`account` is required with `auth: managed_identity`".to_string()),
+            },
+        };
+
+        AzureBlobSecretStorage {
+            client,
+            container: self.container,
+            target,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AzureBlobStorageError {
+    #[error("{0}")]
+    Config(String),
+    #[error("error sending request to azure: {0}")]
+    SendingRequest(#[from] hyper::Error),
+    #[error("request to azure timed out after {}", humantime::format_duration(*.0))]
+    TimedOut(Duration),
+    #[error("error getting a managed identity token: {0}")]
+    GettingToken(String),
+    #[error("azure responded with {status}{}", .code.as_ref().map(|c| format!(" ({c})")).unwrap_or_default())]
+    Response {
+        status: StatusCode,
+        /// `x-ms-error-code` of the response, if any
+        code: Option<String>,
+    },
+    #[error("error reading data from azure: {0}")]
+    ReadingData(hyper::Error),
+    #[error("error copying data: {0}")]
+    CopyingData(#[from] std::io::Error),
+}
+
+impl SecretError for AzureBlobStorageError {
+    fn is_not_found(&self) -> bool {
+        // A missing container is a misconfiguration, not a missing secret
+        matches!(
+            self,
+            Self::Response { status: StatusCode::NOT_FOUND, code }
+                if code.as_deref() != Some("ContainerNotFound")
+        )
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Config(_) => Some(
+                "set AZURE_STORAGE_CONNECTION_STRING (or `connection_string`), or use \
+                 `auth: managed_identity` with `account`",
+            ),
+            Self::SendingRequest(_) | Self::TimedOut(_) => {
+                Some("Azure couldn't be reached: check network access, and the account name")
+            }
+            Self::GettingToken(_) => Some(
+                "managed identity tokens couldn't be fetched: check that the VM (or app) has a \
+                 managed identity, and `client_id` if it has several",
+            ),
+            Self::Response { status, code } => match code.as_deref() {
+                Some("ContainerNotFound") => Some("the container doesn't exist: check `container`"),
+                Some("AuthenticationFailed") => Some(
+                    "authentication failed: check the account key (or SAS token) and that this \
+                     host's clock is right",
+                ),
+                Some("AuthorizationPermissionMismatch" | "AuthorizationFailure") => Some(
+                    "access was denied: the identity needs the Storage Blob Data Reader role on \
+                     the container (or Contributor, to write)",
+                ),
+                _ if *status == StatusCode::FORBIDDEN => Some(
+                    "access was denied: check the identity's roles, and the account's network \
+                     rules",
+                ),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Error for an unsuccessful response. HEAD responses have no body, so the
+/// error code is only ever read from the header.
+fn response_error(res: &Response<Body>) -> AzureBlobStorageError {
+    AzureBlobStorageError::Response {
+        status: res.status(),
+        code: header(res, "x-ms-error-code").map(str::to_string),
+    }
+}
+
+fn header<'a>(res: &'a Response<Body>, name: &str) -> Option<&'a str> {
+    res.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Logs what Azure said about a request, for `--debug-storage`. Only the
+/// status, error code and request ID are logged, never headers (which hold
+/// signatures or tokens) or bodies.
+fn log_response(operation: &str, key: &str, res: &Result<Response<Body>, AzureBlobStorageError>) {
+    let (outcome, request_id) = match res {
+        Ok(r) => {
+            let outcome = match header(r, "x-ms-error-code") {
+                Some(code) => format!("{} {code}", r.status()),
+                None => r.status().to_string(),
+            };
+            (outcome, header(r, "x-ms-request-id"))
+        }
+        // Never sent, or no response
+        Err(e) => (e.to_string(), None),
+    };
+
+    log::debug!(
+        target: STORAGE_LOG_TARGET,
+        "{operation} {key}: {outcome} (request ID: {})",
+        request_id.unwrap_or("none"),
+    );
+}
+
+pub struct AzureBlobSecretStorage {
+    client: HttpClient,
+    container: String,
+    /// Blob service endpoint and credential, or why there aren't any
+    target: Result<(String, Credential), String>,
+}
+
+impl AzureBlobSecretStorage {
+    /// Sends a request for a blob. Responses are returned whatever their
+    /// status, for callers to handle the ones they expect.
+    async fn request(
+        &self,
+        method: Method,
+        key: &Path,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+        operation: &str,
+    ) -> Result<Response<Body>, AzureBlobStorageError> {
+        let key = key.to_str().expect("path not representable as str");
+        let res = self.send_request(method, key, headers, body).await;
+        log_response(operation, key, &res);
+
+        res
+    }
+
+    async fn send_request(
+        &self,
+        method: Method,
+        key: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Response<Body>, AzureBlobStorageError> {
+        let (endpoint, credential) = self
+            .target
+            .as_ref()
+            .map_err(|e| AzureBlobStorageError::Config(e.clone()))?;
+
+        let path = format!(
+            "/{}/{}",
+            utf8_percent_encode(&self.container, BLOB_NAME),
+            utf8_percent_encode(key, BLOB_NAME)
+        );
+        let mut url = format!("{endpoint}{path}");
+        if let Credential::Sas(sas) = credential {
+            url = format!("{url}?{sas}");
+        }
+
+        let date = httpdate::fmt_http_date(SystemTime::now());
+        let mut request = Request::builder()
+            .method(method.clone())
+            .uri(&url)
+            .header("x-ms-date", &date)
+            .header("x-ms-version", API_VERSION);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        if method == Method::PUT {
+            request = request.header("Content-Length", body.len());
+        }
+
+        match credential {
+            Credential::SharedKey { account, key } => {
+                // The endpoint's own path (e.g. the emulator's account name)
+                // is part of the signed resource too
+                let endpoint_path = endpoint
+                    .split_once("://")
+                    .and_then(|(_, rest)| rest.find('/').map(|i| &rest[i..]))
+                    .unwrap_or("");
+                let resource = format!("/{account}{endpoint_path}{path}");
+                let signature = sign(key, &method, &request, body.len(), &resource);
+                request =
+                    request.header("Authorization", format!("SharedKey {account}:{signature}"));
+            }
+            Credential::ManagedIdentity(identity) => {
+                let token = identity.token(&self.client).await?;
+                request = request.header("Authorization", format!("Bearer {token}"));
+            }
+            Credential::Sas(_) => (),
+        }
+
+        let request = request
+            .body(Body::from(body))
+            .map_err(|e| AzureBlobStorageError::Config(format!("invalid request: {e}")))?;
+        send(&self.client, request).await
+    }
+
+    /// Fetches a blob, unless it still has the given ETag.
+    async fn get_blob(
+        &self,
+        key: &Path,
+        if_none_match: Option<&str>,
+    ) -> Result<Response<Body>, AzureBlobStorageError> {
+        let headers = match if_none_match {
+            Some(etag) => vec![("If-None-Match", etag)],
+            None => vec![],
+        };
+
+        self.request(Method::GET, key, &headers, vec![], "GetBlob")
+            .await
+    }
+
+    /// Uploads a blob, only replacing one with the given ETag if `if_match` is
+    /// set. Returns whether it was written.
+    async fn put_blob<R: AsyncRead + Send + Unpin>(
+        &self,
+        key: &Path,
+        mut content: R,
+        if_match: Option<&str>,
+    ) -> Result<bool, AzureBlobStorageError> {
+        let mut buf = Vec::new();
+        content.read_to_end(&mut buf).await?;
+        let mut headers = vec![("x-ms-blob-type", "BlockBlob")];
+        headers.extend(if_match.map(|etag| ("If-Match", etag)));
+        let res = self
+            .request(Method::PUT, key, &headers, buf, "PutBlob")
+            .await?;
+        if if_match.is_some() && res.status() == StatusCode::PRECONDITION_FAILED {
+            return Ok(false);
+        }
+        if !res.status().is_success() {
+            return Err(response_error(&res));
+        }
+
+        Ok(true)
+    }
+}
+
+/// Signs a request with the account key, as described in "Authorize with
+/// Shared Key" in Azure's docs.
+fn sign(
+    key: &[u8],
+    method: &Method,
+    request: &hyper::http::request::Builder,
+    content_length: usize,
+    resource: &str,
+) -> String {
+    let headers = request.headers_ref().expect("request is valid");
+    let get = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+    };
+    // Zero lengths are left out since API version 2015-02-21
+    let content_length = match content_length {
+        0 => String::new(),
+        n => n.to_string(),
+    };
+
+    let mut ms_headers = headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+        .map(|(name, value)| format!("{}:{}\n", name, value.to_str().unwrap_or("").trim()))
+        .collect::<Vec<_>>();
+    ms_headers.sort();
+
+    let string_to_sign = format!(
+        "{method}\n{}\n{}\n{content_length}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}{resource}",
+        get("Content-Encoding"),
+        get("Content-Language"),
+        get("Content-MD5"),
+        get("Content-Type"),
+        get("Date"),
+        get("If-Modified-Since"),
+        get("If-Match"),
+        get("If-None-Match"),
+        get("If-Unmodified-Since"),
+        get("Range"),
+        ms_headers.concat(),
+    );
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(string_to_sign.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+#[async_trait]
+impl SecretStorage for AzureBlobSecretStorage {
+    type Error = AzureBlobStorageError;
+
+    async fn read(&self, key: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        let res = self.get_blob(key, None).await?;
+        if !res.status().is_success() {
+            return Err(response_error(&res));
+        }
+        let body = read_body(res).await?;
+
+        Ok(BoxedAsyncReader::from_async_read(Cursor::new(body)))
+    }
+
+    async fn read_if_changed(
+        &self,
+        key: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        let res = self.get_blob(key, version).await?;
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(ConditionalRead::Unchanged);
+        }
+        if !res.status().is_success() {
+            return Err(response_error(&res));
+        }
+        let version = header(&res, "ETag").map(str::to_string);
+        let body = read_body(res).await?;
+
+        Ok(ConditionalRead::Changed {
+            version,
+            reader: BoxedAsyncReader::from_async_read(Cursor::new(body)),
+        })
+    }
+
+    async fn metadata(&self, key: &Path) -> Result<ObjectMetadata, Self::Error> {
+        let res = self
+            .request(Method::HEAD, key, &[], vec![], "GetBlobProperties")
+            .await?;
+        if !res.status().is_success() {
+            return Err(response_error(&res));
+        }
+
+        Ok(ObjectMetadata {
+            size: header(&res, "Content-Length")
+                .and_then(|l| l.parse().ok())
+                .unwrap_or_default(),
+            last_modified: header(&res, "Last-Modified")
+                .and_then(|t| httpdate::parse_http_date(t).ok()),
+        })
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        key: &Path,
+        new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        self.put_blob(key, new_encrypted_content, None).await?;
+        Ok(())
+    }
+
+    fn write_if_unchanged<'a, R: AsyncRead + Send + Unpin + 'a>(
+        &'a self,
+        key: &'a Path,
+        new_encrypted_content: R,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Self::Error>> {
+        Box::pin(self.put_blob(key, new_encrypted_content, Some(version)))
+    }
+
+    fn conditional_writes(&self) -> bool {
+        true
+    }
+
+    async fn delete(&self, key: &Path) -> Result<(), Self::Error> {
+        let res = self
+            .request(Method::DELETE, key, &[], vec![], "DeleteBlob")
+            .await?;
+        let err = response_error(&res);
+        // Removing a blob that doesn't exist succeeds, like it does on S3
+        if !res.status().is_success() && !err.is_not_found() {
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use thiserror::Error;
+use tokio::io::AsyncRead;
+
+use crate::secret::{
+    AzureBlobConfig,
+    AzureBlobSecretStorage,
+    AzureBlobStorageError,
+    ConditionalRead,
+    FileSecretStorage,
+    FileStorageConfig,
+    FileStorageError,
+    GitSecretStorage,
+    GitStorageConfig,
+    GitStorageError,
+    ObjectMetadata,
+    S3Config,
+    S3SecretStorage,
+    S3SecretStorageError,
+    SecretError,
+    SecretStorage,
+};
+use crate::util::BoxedAsyncReader;
+use crate::IntoSecretStorage;
+
+/// Config for any of the services secrets can be stored in, so that the
+/// storage wrappers (chunking, limits, migration, ...) don't need to know
+/// which one is in use.
+#[derive(Debug)]
+pub enum BackendConfig {
+    S3(S3Config),
+    AzureBlob(AzureBlobConfig),
+    File(FileStorageConfig),
+    Git(GitStorageConfig),
+}
+
+pub enum BackendStorage {
+    S3(S3SecretStorage),
+    // Boxed, as it holds its own HTTP client (the S3 one is shared)
+    AzureBlob(Box<AzureBlobSecretStorage>),
+    File(FileSecretStorage),
+    Git(GitSecretStorage),
+}
+
+#[derive(Error, Debug)]
+pub enum BackendStorageError {
+    // Boxed, as the SDK's errors are large
+    #[error("{0}")]
+    S3(Box<S3SecretStorageError>),
+    #[error("{0}")]
+    AzureBlob(#[from] AzureBlobStorageError),
+    #[error("{0}")]
+    File(#[from] FileStorageError),
+    #[error("{0}")]
+    Git(#[from] GitStorageError),
+}
+
+impl From<S3SecretStorageError> for BackendStorageError {
+    fn from(value: S3SecretStorageError) -> Self {
+        Self::S3(Box::new(value))
+    }
+}
+
+impl SecretError for BackendStorageError {
+    fn is_not_found(&self) -> bool {
+        match self {
+            Self::S3(e) => e.is_not_found(),
+            Self::AzureBlob(e) => e.is_not_found(),
+            Self::File(e) => e.is_not_found(),
+            Self::Git(e) => e.is_not_found(),
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::S3(e) => e.hint(),
+            Self::AzureBlob(e) => e.hint(),
+            Self::File(e) => e.hint(),
+            Self::Git(e) => e.hint(),
+        }
+    }
+}
+
+#[async_trait]
+impl IntoSecretStorage for BackendConfig {
+    type Error = BackendStorageError;
+    type Impl = BackendStorage;
+
+    async fn build(self) -> Self::Impl {
+        match self {
+            Self::S3(c) => BackendStorage::S3(c.build().await),
+            Self::AzureBlob(c) => BackendStorage::AzureBlob(Box::new(c.build().await)),
+            Self::File(c) => BackendStorage::File(c.build().await),
+            Self::Git(c) => BackendStorage::Git(c.build().await),
+        }
+    }
+}
+
+#[async_trait]
+impl SecretStorage for BackendStorage {
+    type Error = BackendStorageError;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        match self {
+            Self::S3(s) => Ok(s.read(p).await?),
+            Self::AzureBlob(s) => Ok(s.read(p).await?),
+            Self::File(s) => Ok(s.read(p).await?),
+            Self::Git(s) => Ok(s.read(p).await?),
+        }
+    }
+
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        match self {
+            Self::S3(s) => Ok(s.read_if_changed(p, version).await?),
+            Self::AzureBlob(s) => Ok(s.read_if_changed(p, version).await?),
+            Self::File(s) => Ok(s.read_if_changed(p, version).await?),
+            Self::Git(s) => Ok(s.read_if_changed(p, version).await?),
+        }
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        match self {
+            Self::S3(s) => Ok(s.metadata(p).await?),
+            Self::AzureBlob(s) => Ok(s.metadata(p).await?),
+            Self::File(s) => Ok(s.metadata(p).await?),
+            Self::Git(s) => Ok(s.metadata(p).await?),
+        }
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        match self {
+            Self::S3(s) => Ok(s.write(p, new_encrypted_content).await?),
+            Self::AzureBlob(s) => Ok(s.write(p, new_encrypted_content).await?),
+            Self::File(s) => Ok(s.write(p, new_encrypted_content).await?),
+            Self::Git(s) => Ok(s.write(p, new_encrypted_content).await?),
+        }
+    }
+
+    fn write_if_unchanged<'a, R: AsyncRead + Send + Unpin + 'a>(
+        &'a self,
+        p: &'a Path,
+        new_encrypted_content: R,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            match self {
+                Self::S3(s) => Ok(s
+                    .write_if_unchanged(p, new_encrypted_content, version)
+                    .await?),
+                Self::AzureBlob(s) => Ok(s
+                    .write_if_unchanged(p, new_encrypted_content, version)
+                    .await?),
+                Self::File(s) => Ok(s
+                    .write_if_unchanged(p, new_encrypted_content, version)
+                    .await?),
+                Self::Git(s) => Ok(s
+                    .write_if_unchanged(p, new_encrypted_content, version)
+                    .await?),
+            }
+        })
+    }
+
+    fn conditional_writes(&self) -> bool {
+        match self {
+            Self::S3(s) => s.conditional_writes(),
+            Self::AzureBlob(s) => s.conditional_writes(),
+            Self::File(s) => s.conditional_writes(),
+            Self::Git(s) => s.conditional_writes(),
+        }
+    }
+
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error> {
+        match self {
+            Self::S3(s) => Ok(s.delete(p).await?),
+            Self::AzureBlob(s) => Ok(s.delete(p).await?),
+            Self::File(s) => Ok(s.delete(p).await?),
+            Self::Git(s) => Ok(s.delete(p).await?),
+        }
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        match self {
+            Self::File(s) => s.local_root(),
+            _ => None,
+        }
+    }
+}
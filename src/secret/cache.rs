@@ -0,0 +1,382 @@
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+
+use crate::secret::{ConditionalRead, ObjectMetadata, SecretError, SecretStorage, Spooled};
+use crate::util::BoxedAsyncReader;
+
+#[cfg(unix)]
+const CACHE_DIR_PERMISSIONS: u32 = 0o0700;
+
+/// What to do when the backing store can't be read from.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageFallback {
+    /// Fail the operation
+    #[default]
+    None,
+    /// Fall back to the last successfully-fetched ciphertext in the local
+    /// cache
+    Cache,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Read from the backing store, keeping the cache up-to-date and reading
+    /// from it only when the backing store fails
+    Fallback,
+    /// Never contact the backing store, only read from the cache
+    Offline,
+    /// Always read from the backing store, failing if the cache can't be
+    /// updated (for filling the cache ahead of time)
+    Refresh,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CachedStorageError<E: SecretError> {
+    #[error("{0}")]
+    Storage(E),
+    #[error("error reading cached ciphertext at {0}: {1}")]
+    ReadingCache(PathBuf, std::io::Error),
+    #[error("backing store unavailable ({0}), and no usable cache at {1}: {2}")]
+    NoFallback(E, PathBuf, std::io::Error),
+    #[error("error reading data from backing store: {0}")]
+    CopyingData(std::io::Error),
+    #[error("error updating cached ciphertext for {0}: {1}")]
+    UpdatingCache(PathBuf, std::io::Error),
+    #[error("writes are not possible while offline")]
+    WritingOffline,
+}
+
+impl<E: SecretError> SecretError for CachedStorageError<E> {
+    fn is_not_found(&self) -> bool {
+        match self {
+            Self::Storage(e) | Self::NoFallback(e, _, _) => e.is_not_found(),
+            Self::ReadingCache(_, e) => e.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Storage(e) | Self::NoFallback(e, _, _) => e.hint(),
+            _ => None,
+        }
+    }
+}
+
+/// Where to keep a local copy of the object at the given storage path.
+pub(super) fn flattened_path(dir: &Path, p: &Path) -> PathBuf {
+    // Flatten storage paths into a single file name, so that we never create
+    // (or escape) nested directories
+    let escaped = p.to_string_lossy().replace('%', "%25").replace('/', "%2F");
+    dir.join(format!("{escaped}.age"))
+}
+
+/// Wraps another [SecretStorage], keeping a local copy of every ciphertext it
+/// successfully reads so that it can be re-used if the backing store becomes
+/// unreachable.
+///
+/// Only ciphertext is ever written to the cache, decryption still happens on
+/// every read.
+pub struct CachedSecretStorage<'a, S> {
+    inner: &'a S,
+    cache_dir: PathBuf,
+    mode: CacheMode,
+    /// Size above which fetched objects are spooled to disk on their way
+    /// into the cache
+    threshold: u64,
+}
+
+impl<'a, S> CachedSecretStorage<'a, S>
+where
+    S: SecretStorage,
+{
+    pub fn new(inner: &'a S, cache_dir: PathBuf, mode: CacheMode, threshold: u64) -> Self {
+        Self {
+            inner,
+            cache_dir,
+            mode,
+            threshold,
+        }
+    }
+
+    fn cache_path(&self, p: &Path) -> PathBuf {
+        flattened_path(&self.cache_dir, p)
+    }
+
+    async fn read_cache(&self, p: &Path) -> Result<BoxedAsyncReader, std::io::Error> {
+        let file = fs::File::open(self.cache_path(p)).await?;
+        Ok(BoxedAsyncReader::from_async_read(file))
+    }
+
+    /// Where the version of the cached copy of an object is kept.
+    fn version_path(&self, p: &Path) -> PathBuf {
+        let mut path = self.cache_path(p).into_os_string();
+        path.push(".version");
+        PathBuf::from(path)
+    }
+
+    /// The version of the object we have a cached copy of, if we know it.
+    async fn cached_version(&self, p: &Path) -> Option<String> {
+        if !self.cache_path(p).exists() {
+            return None;
+        }
+        fs::read_to_string(self.version_path(p)).await.ok()
+    }
+
+    /// Reads the cached copy of an object, after failing to read it from the
+    /// backing store. Objects the backing store says don't exist were removed
+    /// (or revoked) on purpose, so their cached copy is dropped rather than
+    /// used.
+    async fn fall_back(
+        &self,
+        p: &Path,
+        e: S::Error,
+    ) -> Result<BoxedAsyncReader, CachedStorageError<S::Error>> {
+        if e.is_not_found() {
+            self.forget(p).await;
+            return Err(CachedStorageError::Storage(e));
+        }
+        if self.mode == CacheMode::Refresh {
+            return Err(CachedStorageError::Storage(e));
+        }
+        log::warn!(
+            "couldn't read {} from backing store, falling back to cache: {e}",
+            p.to_string_lossy()
+        );
+        self.read_cache(p)
+            .await
+            .map_err(|io| CachedStorageError::NoFallback(e, self.cache_path(p), io))
+    }
+
+    /// Removes the cached copy of an object, so that it's not used offline
+    /// either.
+    async fn forget(&self, p: &Path) {
+        for path in [self.cache_path(p), self.version_path(p)] {
+            match fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    log::warn!("couldn't remove {} from cache: {e}", path.to_string_lossy())
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Reads a freshly-fetched object, keeping a copy of it in the cache.
+    async fn keep(
+        &self,
+        p: &Path,
+        reader: BoxedAsyncReader,
+        version: Option<&str>,
+    ) -> Result<BoxedAsyncReader, CachedStorageError<S::Error>> {
+        let (mut data, _) = Spooled::read(reader, &p.to_string_lossy(), self.threshold)
+            .await
+            .map_err(CachedStorageError::CopyingData)?;
+
+        match self.update_cache(p, &mut data, version).await {
+            Ok(()) => (),
+            Err(e) if self.mode == CacheMode::Refresh => {
+                return Err(CachedStorageError::UpdatingCache(p.to_owned(), e))
+            }
+            // Not worth failing the read over, we have what we came for
+            Err(e) => log::warn!("couldn't update cache for {}: {e}", p.to_string_lossy()),
+        }
+
+        data.into_reader()
+            .await
+            .map_err(CachedStorageError::CopyingData)
+    }
+
+    async fn update_cache(
+        &self,
+        p: &Path,
+        data: &mut Spooled,
+        version: Option<&str>,
+    ) -> Result<(), std::io::Error> {
+        if !self.cache_dir.exists() {
+            fs::create_dir_all(&self.cache_dir).await?;
+            #[cfg(unix)]
+            {
+                let perms = std::fs::Permissions::from_mode(CACHE_DIR_PERMISSIONS);
+                fs::set_permissions(&self.cache_dir, perms).await?;
+            }
+        }
+
+        // Write-then-rename, so a crash never leaves a truncated entry behind
+        let dest = self.cache_path(p);
+        let temp = dest.with_extension("tmp");
+        let mut file = fs::File::create(&temp).await?;
+        data.copy_to(&mut file).await?;
+        file.flush().await?;
+        fs::rename(&temp, &dest).await?;
+
+        // Written after the data, so that a version never describes data it
+        // doesn't belong to
+        let version_path = self.version_path(p);
+        match version {
+            Some(v) => fs::write(version_path, v).await,
+            None => match fs::remove_file(version_path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl<'a, S> SecretStorage for CachedSecretStorage<'a, S>
+where
+    S: SecretStorage + Sync,
+    <S as SecretStorage>::Error: Send,
+{
+    type Error = CachedStorageError<S::Error>;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        if self.mode == CacheMode::Offline {
+            log::debug!("reading {} from cache", p.to_string_lossy());
+            return self
+                .read_cache(p)
+                .await
+                .map_err(|e| CachedStorageError::ReadingCache(self.cache_path(p), e));
+        }
+
+        // Only download the object if it's changed since we cached it
+        let cached_version = self.cached_version(p).await;
+        let read = self.inner.read_if_changed(p, cached_version.as_deref());
+        let (reader, version) = match read.await {
+            Ok(ConditionalRead::Unchanged) => match self.read_cache(p).await {
+                Ok(reader) => {
+                    log::debug!("{} is unchanged, using cached copy", p.to_string_lossy());
+                    return Ok(reader);
+                }
+                // Removed since we checked for it
+                Err(_) => match self.inner.read(p).await {
+                    Ok(reader) => (reader, None),
+                    Err(e) => return self.fall_back(p, e).await,
+                },
+            },
+            Ok(ConditionalRead::Changed { reader, version }) => (reader, version),
+            Err(e) => return self.fall_back(p, e).await,
+        };
+
+        self.keep(p, reader, version.as_deref()).await
+    }
+
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        if self.mode == CacheMode::Offline {
+            return Ok(ConditionalRead::Changed {
+                reader: self.read(p).await?,
+                version: None,
+            });
+        }
+
+        let (reader, version) = match self.inner.read_if_changed(p, version).await {
+            Ok(ConditionalRead::Unchanged) => return Ok(ConditionalRead::Unchanged),
+            Ok(ConditionalRead::Changed { reader, version }) => (reader, version),
+            Err(e) => {
+                return Ok(ConditionalRead::Changed {
+                    reader: self.fall_back(p, e).await?,
+                    version: None,
+                })
+            }
+        };
+
+        let reader = self.keep(p, reader, version.as_deref()).await?;
+        Ok(ConditionalRead::Changed { reader, version })
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        if self.mode == CacheMode::Offline {
+            let cache_path = self.cache_path(p);
+            let metadata = fs::metadata(&cache_path)
+                .await
+                .map_err(|e| CachedStorageError::ReadingCache(cache_path, e))?;
+            return Ok(ObjectMetadata {
+                size: metadata.len(),
+                last_modified: metadata.modified().ok(),
+            });
+        }
+
+        self.inner
+            .metadata(p)
+            .await
+            .map_err(CachedStorageError::Storage)
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        if self.mode == CacheMode::Offline {
+            return Err(CachedStorageError::WritingOffline);
+        }
+
+        self.inner
+            .write(p, new_encrypted_content)
+            .await
+            .map_err(CachedStorageError::Storage)
+    }
+
+    fn write_if_unchanged<'b, R: AsyncRead + Send + Unpin + 'b>(
+        &'b self,
+        p: &'b Path,
+        new_encrypted_content: R,
+        version: &'b str,
+    ) -> BoxFuture<'b, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            if self.mode == CacheMode::Offline {
+                return Err(CachedStorageError::WritingOffline);
+            }
+
+            self.inner
+                .write_if_unchanged(p, new_encrypted_content, version)
+                .await
+                .map_err(CachedStorageError::Storage)
+        })
+    }
+
+    fn conditional_writes(&self) -> bool {
+        self.inner.conditional_writes()
+    }
+
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error> {
+        if self.mode == CacheMode::Offline {
+            return Err(CachedStorageError::WritingOffline);
+        }
+
+        self.inner
+            .delete(p)
+            .await
+            .map_err(CachedStorageError::Storage)?;
+        match fs::remove_file(self.cache_path(p)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!(
+                    "couldn't remove cached copy of {}: {e}",
+                    p.to_string_lossy()
+                )
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        match self.mode {
+            CacheMode::Offline => None,
+            _ => self.inner.local_root(),
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::secret::{
+    sidecar_path,
+    CiphertextPin,
+    ConditionalRead,
+    ObjectMetadata,
+    SecretError,
+    SecretStorage,
+    Spool,
+};
+use crate::util::BoxedAsyncReader;
+use crate::IntoSecretStorage;
+
+/// What every manifest starts with. Nothing else we store (age ciphertext,
+/// signatures, recipients records) can.
+const MANIFEST_MAGIC: &[u8] = b"credible-chunked:";
+
+/// Largest manifest we'll read. Objects bigger than this are never
+/// manifests.
+const MAX_MANIFEST_SIZE: u64 = 1024;
+
+/// Stands in for an object that was too big for the backend, and was split
+/// into chunks stored alongside it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Manifest {
+    #[serde(rename = "credible-chunked")]
+    version: u32,
+    size: u64,
+    chunks: u64,
+    digest: CiphertextPin,
+}
+
+impl Manifest {
+    /// Where the given chunk of an object is stored. Chunks are named after
+    /// the content they're part of, so that rewriting an object never
+    /// changes chunks a reader of the previous version might still want.
+    fn chunk_path(&self, p: &Path, i: u64) -> PathBuf {
+        let CiphertextPin::Sha256(digest) = &self.digest;
+        sidecar_path(p, &format!("chunk.{}.{i}", &digest[..16]))
+    }
+
+    fn chunk_paths(&self, p: &Path) -> Vec<PathBuf> {
+        (0..self.chunks).map(|i| self.chunk_path(p, i)).collect()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ChunkedStorageError<E: SecretError> {
+    #[error("{0}")]
+    Storage(E),
+    #[error("error reading {0}: {1}")]
+    Reading(PathBuf, std::io::Error),
+    #[error("invalid chunk manifest at {0}: {1}")]
+    InvalidManifest(PathBuf, serde_yaml::Error),
+    #[error("chunk manifest at {0} lists {1} chunks for {2} bytes")]
+    InconsistentManifest(PathBuf, u64, u64),
+    #[error("error fetching chunk {0} of {1}: {2}")]
+    FetchingChunk(u64, PathBuf, E),
+    #[error("chunks of {0} don't match their manifest (expected {1}, got {2})")]
+    Mismatch(PathBuf, CiphertextPin, CiphertextPin),
+    #[error("error buffering {0} to split into chunks: {1}")]
+    Buffering(PathBuf, std::io::Error),
+}
+
+impl<E: SecretError> SecretError for ChunkedStorageError<E> {
+    fn is_not_found(&self) -> bool {
+        matches!(self, Self::Storage(e) if e.is_not_found())
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Storage(e) | Self::FetchingChunk(_, _, e) => e.hint(),
+            _ => None,
+        }
+    }
+}
+
+/// Wraps another [SecretStorage], splitting objects bigger than a chunk size
+/// across several objects (for backends with object size limits), and
+/// putting them back together on read. Chunked objects are read whether or
+/// not a chunk size is set.
+pub struct ChunkedSecretStorage<S> {
+    inner: S,
+    chunk_size: Option<u64>,
+    threshold: u64,
+}
+
+impl<S> ChunkedSecretStorage<S>
+where
+    S: SecretStorage + Sync + Send,
+    <S as SecretStorage>::Error: Send,
+{
+    pub fn new(inner: S, chunk_size: Option<u64>, threshold: u64) -> Self {
+        Self {
+            inner,
+            chunk_size,
+            threshold,
+        }
+    }
+
+    /// Passes ordinary objects through, and reassembles chunked ones.
+    async fn open(
+        &self,
+        p: &Path,
+        mut reader: BoxedAsyncReader,
+    ) -> Result<BoxedAsyncReader, ChunkedStorageError<S::Error>> {
+        let mut head = Vec::new();
+        (&mut reader)
+            .take(MANIFEST_MAGIC.len() as u64)
+            .read_to_end(&mut head)
+            .await
+            .map_err(|e| ChunkedStorageError::Reading(p.to_owned(), e))?;
+        if head != MANIFEST_MAGIC {
+            return Ok(BoxedAsyncReader::from_async_read(
+                Cursor::new(head).chain(reader),
+            ));
+        }
+
+        let manifest = self.read_manifest(p, head, reader).await?;
+        log::debug!(
+            "{} is stored in {} chunks",
+            p.to_string_lossy(),
+            manifest.chunks
+        );
+        let mut spool = Spool::new(&p.to_string_lossy(), self.threshold);
+        for (i, chunk) in manifest.chunk_paths(p).into_iter().enumerate() {
+            let reader = self
+                .inner
+                .read(&chunk)
+                .await
+                .map_err(|e| ChunkedStorageError::FetchingChunk(i as u64, p.to_owned(), e))?;
+            spool
+                .append(reader)
+                .await
+                .map_err(|e| ChunkedStorageError::Reading(chunk, e))?;
+        }
+
+        let size = spool.len();
+        let (data, digest) = spool.finish();
+        if size != manifest.size || digest != manifest.digest {
+            return Err(ChunkedStorageError::Mismatch(
+                p.to_owned(),
+                manifest.digest,
+                digest,
+            ));
+        }
+
+        data.into_reader()
+            .await
+            .map_err(|e| ChunkedStorageError::Reading(p.to_owned(), e))
+    }
+
+    async fn read_manifest(
+        &self,
+        p: &Path,
+        mut data: Vec<u8>,
+        reader: BoxedAsyncReader,
+    ) -> Result<Manifest, ChunkedStorageError<S::Error>> {
+        reader
+            .take(MAX_MANIFEST_SIZE)
+            .read_to_end(&mut data)
+            .await
+            .map_err(|e| ChunkedStorageError::Reading(p.to_owned(), e))?;
+        let manifest: Manifest = serde_yaml::from_slice(&data)
+            .map_err(|e| ChunkedStorageError::InvalidManifest(p.to_owned(), e))?;
+        // Objects are only chunked when they're bigger than a chunk, and
+        // every chunk holds at least a byte, so anything else didn't come
+        // from us
+        if manifest.chunks == 0 || manifest.chunks > manifest.size {
+            return Err(ChunkedStorageError::InconsistentManifest(
+                p.to_owned(),
+                manifest.chunks,
+                manifest.size,
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// The manifest currently stored at the given path, if it's chunked.
+    async fn current_manifest(&self, p: &Path) -> Option<Manifest> {
+        let mut reader = self.inner.read(p).await.ok()?;
+        let mut head = Vec::new();
+        (&mut reader)
+            .take(MANIFEST_MAGIC.len() as u64)
+            .read_to_end(&mut head)
+            .await
+            .ok()?;
+        match head == MANIFEST_MAGIC {
+            true => self.read_manifest(p, head, reader).await.ok(),
+            false => None,
+        }
+    }
+
+    /// Writes an object, in chunks if it's big enough. With a `version`, the
+    /// object's manifest (or the object itself, if it's small) is only
+    /// written if it's still at that version. Returns whether it was written.
+    async fn put<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        new_encrypted_content: R,
+        version: Option<&str>,
+    ) -> Result<bool, ChunkedStorageError<S::Error>> {
+        let Some(chunk_size) = self.chunk_size else {
+            return self
+                .write_object(p, new_encrypted_content, version)
+                .await
+                .map_err(ChunkedStorageError::Storage);
+        };
+
+        let buffering = |e| ChunkedStorageError::Buffering(p.to_owned(), e);
+        let mut spool = Spool::new(&p.to_string_lossy(), self.threshold);
+        spool
+            .append(new_encrypted_content)
+            .await
+            .map_err(buffering)?;
+        let size = spool.len();
+        let (data, digest) = spool.finish();
+        let mut reader = data.into_reader().await.map_err(buffering)?;
+        let old = self.current_manifest(p).await;
+
+        if size <= chunk_size {
+            let written = self
+                .write_object(p, reader, version)
+                .await
+                .map_err(ChunkedStorageError::Storage)?;
+            if written {
+                self.remove_chunks(p, old, None).await;
+            }
+            return Ok(written);
+        }
+
+        let manifest = Manifest {
+            version: 1,
+            size,
+            chunks: size.div_ceil(chunk_size),
+            digest,
+        };
+        log::debug!(
+            "writing {} in {} chunks",
+            p.to_string_lossy(),
+            manifest.chunks
+        );
+        // Chunks are written before the manifest, so that a manifest never
+        // refers to chunks that don't exist yet
+        for i in 0..manifest.chunks {
+            let mut chunk = Vec::new();
+            (&mut reader)
+                .take(chunk_size)
+                .read_to_end(&mut chunk)
+                .await
+                .map_err(buffering)?;
+            self.inner
+                .write(&manifest.chunk_path(p, i), Cursor::new(chunk))
+                .await
+                .map_err(ChunkedStorageError::Storage)?;
+        }
+
+        let data = serde_yaml::to_string(&manifest).expect("manifests are always serialisable");
+        let written = self
+            .write_object(p, data.as_bytes(), version)
+            .await
+            .map_err(ChunkedStorageError::Storage)?;
+        if !written {
+            // Nothing refers to the chunks we wrote, unless whoever changed
+            // the object wrote the same content
+            let current = self.current_manifest(p).await;
+            self.remove_chunks(p, Some(manifest), current.as_ref())
+                .await;
+            return Ok(false);
+        }
+        self.remove_chunks(p, old, Some(&manifest)).await;
+
+        Ok(true)
+    }
+
+    async fn write_object<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        content: R,
+        version: Option<&str>,
+    ) -> Result<bool, S::Error> {
+        match version {
+            Some(version) => self.inner.write_if_unchanged(p, content, version).await,
+            None => self.inner.write(p, content).await.map(|()| true),
+        }
+    }
+
+    /// Removes chunks that the object at the given path no longer uses.
+    async fn remove_chunks(&self, p: &Path, old: Option<Manifest>, new: Option<&Manifest>) {
+        let Some(old) = old else {
+            return;
+        };
+        if Some(&old.digest) == new.map(|m| &m.digest) {
+            return;
+        }
+
+        for chunk in old.chunk_paths(p) {
+            if let Err(e) = self.inner.delete(&chunk).await {
+                log::warn!("couldn't remove old chunk {}: {e}", chunk.to_string_lossy());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S> SecretStorage for ChunkedSecretStorage<S>
+where
+    S: SecretStorage + Sync + Send,
+    <S as SecretStorage>::Error: Send,
+{
+    type Error = ChunkedStorageError<S::Error>;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        let reader = self
+            .inner
+            .read(p)
+            .await
+            .map_err(ChunkedStorageError::Storage)?;
+        self.open(p, reader).await
+    }
+
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        // A manifest changes whenever its content does, so its version
+        // stands in for the whole object
+        match self.inner.read_if_changed(p, version).await {
+            Ok(ConditionalRead::Unchanged) => Ok(ConditionalRead::Unchanged),
+            Ok(ConditionalRead::Changed { reader, version }) => Ok(ConditionalRead::Changed {
+                reader: self.open(p, reader).await?,
+                version,
+            }),
+            Err(e) => Err(ChunkedStorageError::Storage(e)),
+        }
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        let mut metadata = self
+            .inner
+            .metadata(p)
+            .await
+            .map_err(ChunkedStorageError::Storage)?;
+        if metadata.size <= MAX_MANIFEST_SIZE {
+            if let Some(manifest) = self.current_manifest(p).await {
+                metadata.size = manifest.size;
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        self.put(p, new_encrypted_content, None).await?;
+        Ok(())
+    }
+
+    fn write_if_unchanged<'a, R: AsyncRead + Send + Unpin + 'a>(
+        &'a self,
+        p: &'a Path,
+        new_encrypted_content: R,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Self::Error>> {
+        Box::pin(self.put(p, new_encrypted_content, Some(version)))
+    }
+
+    fn conditional_writes(&self) -> bool {
+        // Only the manifest is written conditionally, which is enough since
+        // its version stands in for the whole object
+        self.inner.conditional_writes()
+    }
+
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error> {
+        let old = self.current_manifest(p).await;
+        // The manifest goes first, so that it never refers to missing chunks
+        self.inner
+            .delete(p)
+            .await
+            .map_err(ChunkedStorageError::Storage)?;
+        self.remove_chunks(p, old, None).await;
+
+        Ok(())
+    }
+
+    // Manifests are written last, so watching them is enough
+    fn local_root(&self) -> Option<&Path> {
+        self.inner.local_root()
+    }
+}
+
+/// Storage config with objects split into chunks of at most `chunk_size`
+/// bytes.
+pub struct ChunkedStorageConfig<C> {
+    pub inner: C,
+    pub chunk_size: Option<u64>,
+    /// Size above which objects are spooled to disk while being reassembled
+    /// or split
+    pub large_secret_threshold: u64,
+}
+
+#[async_trait]
+impl<C> IntoSecretStorage for ChunkedStorageConfig<C>
+where
+    C: IntoSecretStorage + Send,
+    C::Impl: Sync + Send,
+    C::Error: Send,
+{
+    type Error = ChunkedStorageError<C::Error>;
+    type Impl = ChunkedSecretStorage<C::Impl>;
+
+    async fn build(self) -> Self::Impl {
+        ChunkedSecretStorage::new(
+            self.inner.build().await,
+            self.chunk_size,
+            self.large_secret_threshold,
+        )
+    }
+}
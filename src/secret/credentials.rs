@@ -0,0 +1,158 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::SystemTime;
+
+use aws_config::environment::EnvironmentVariableCredentialsProvider;
+use aws_config::imds::credentials::ImdsCredentialsProvider;
+use aws_config::profile::profile_file::{ProfileFileKind, ProfileFiles};
+use aws_config::profile::ProfileFileCredentialsProvider;
+use aws_credential_types::provider::error::CredentialsError;
+use aws_credential_types::provider::{future, ProvideCredentials, SharedCredentialsProvider};
+use aws_credential_types::Credentials;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use serde::Deserialize;
+use tokio::process::Command;
+
+/// Where a storage backend gets its credentials from, instead of the default
+/// AWS provider chain.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum CredentialsConfig {
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+    Env,
+    /// A profile from the shared config and credentials files
+    Profile(ProfileCredentialsConfig),
+    /// The EC2 instance metadata service
+    Imds,
+    /// An external command (and its arguments), which prints credentials as
+    /// JSON in the same format as AWS's `credential_process`
+    Exec(Vec<String>),
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ProfileCredentialsConfig {
+    /// Profile to use (default: `AWS_PROFILE`, or `default`)
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Credentials file to read instead of the default config and credentials
+    /// files (`~/.aws/config` and `~/.aws/credentials`)
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+impl CredentialsConfig {
+    pub fn provider(self) -> SharedCredentialsProvider {
+        match self {
+            Self::Env => {
+                SharedCredentialsProvider::new(EnvironmentVariableCredentialsProvider::new())
+            }
+            Self::Profile(config) => {
+                let mut builder = ProfileFileCredentialsProvider::builder();
+                if let Some(name) = config.name {
+                    builder = builder.profile_name(name);
+                }
+                if let Some(file) = config.file {
+                    let files = ProfileFiles::builder()
+                        .with_file(ProfileFileKind::Credentials, file)
+                        .build();
+                    builder = builder.profile_files(files);
+                }
+                SharedCredentialsProvider::new(builder.build())
+            }
+            Self::Imds => {
+                SharedCredentialsProvider::new(ImdsCredentialsProvider::builder().build())
+            }
+            Self::Exec(command) => SharedCredentialsProvider::new(ExecCredentials { command }),
+        }
+    }
+}
+
+/// Credentials printed by a `credential_process` command.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ProcessCredentials {
+    version: u32,
+    access_key_id: String,
+    secret_access_key: String,
+    #[serde(default)]
+    session_token: Option<String>,
+    /// When the credentials expire (RFC 3339), after which the command is run
+    /// again
+    #[serde(default)]
+    expiration: Option<String>,
+}
+
+/// Gets credentials by running a command, for integrating with credential
+/// brokers.
+#[derive(Debug)]
+struct ExecCredentials {
+    command: Vec<String>,
+}
+
+impl ExecCredentials {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| CredentialsError::invalid_configuration("exec command is empty"))?;
+        // Errors from the command are passed straight through to stderr (which
+        // `output()` would capture)
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| CredentialsError::provider_error(format!("running {program}: {e}")))?
+            .wait_with_output()
+            .await
+            .map_err(|e| CredentialsError::provider_error(format!("running {program}: {e}")))?;
+        if !output.status.success() {
+            return Err(CredentialsError::provider_error(format!(
+                "{program} failed ({})",
+                output.status
+            )));
+        }
+
+        let creds: ProcessCredentials = serde_json::from_slice(&output.stdout).map_err(|e| {
+            CredentialsError::provider_error(format!("parsing output of {program}: {e}"))
+        })?;
+        if creds.version != 1 {
+            return Err(CredentialsError::provider_error(format!(
+                "{program} printed credentials with unsupported version {}",
+                creds.version
+            )));
+        }
+        let expiration = match creds.expiration {
+            Some(s) => {
+                let time = DateTime::from_str(&s, DateTimeFormat::DateTime)
+                    .ok()
+                    .and_then(|t| SystemTime::try_from(t).ok())
+                    .ok_or_else(|| {
+                        CredentialsError::provider_error(format!(
+                            "{program} printed an invalid expiration time: {s}"
+                        ))
+                    })?;
+                Some(time)
+            }
+            None => None,
+        };
+
+        Ok(Credentials::new(
+            creds.access_key_id,
+            creds.secret_access_key,
+            creds.session_token,
+            expiration,
+            "exec",
+        ))
+    }
+}
+
+impl ProvideCredentials for ExecCredentials {
+    fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        future::ProvideCredentials::new(self.credentials())
+    }
+}
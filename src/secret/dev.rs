@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+
+use age::secrecy::ExposeSecret;
+use age::x25519;
+use async_trait::async_trait;
+use tokio::io::AsyncRead;
+
+use super::{ObjectMetadata, SecretError, SecretStorage};
+use crate::util::BoxedAsyncReader;
+use crate::IntoSecretStorage;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DevStorageError {
+    #[error("no dev value for {0}")]
+    NotFound(PathBuf),
+    #[error("dev values can't be modified")]
+    ReadOnly,
+    #[error("error creating encryption stream: {0}")]
+    CreatingStream(age::EncryptError),
+    #[error("error encrypting dev value: {0}")]
+    Encrypting(std::io::Error),
+}
+
+impl SecretError for DevStorageError {
+    fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound(_))
+    }
+}
+
+/// Storage serving inline plaintext values from config, for development and
+/// tests that shouldn't need network access or real keys.
+///
+/// Values are encrypted to a throwaway identity when created, so that they
+/// go through the same decryption as real secrets.
+pub struct DevStorage {
+    objects: HashMap<PathBuf, Vec<u8>>,
+    identity: x25519::Identity,
+}
+
+impl DevStorage {
+    /// Creates storage holding the given plaintext values, keyed by the
+    /// storage path of the secret they stand in for.
+    pub fn new(values: HashMap<PathBuf, String>) -> Result<Self, DevStorageError> {
+        let identity = x25519::Identity::generate();
+        let mut objects = HashMap::new();
+        for (path, value) in values {
+            let encryptor = age::Encryptor::with_recipients(vec![Box::new(identity.to_public())])
+                .expect("a recipient is given");
+            let mut ciphertext = Vec::new();
+            let mut writer = encryptor
+                .wrap_output(&mut ciphertext)
+                .map_err(DevStorageError::CreatingStream)?;
+            writer
+                .write_all(value.as_bytes())
+                .map_err(DevStorageError::Encrypting)?;
+            writer.finish().map_err(DevStorageError::Encrypting)?;
+            objects.insert(path, ciphertext);
+        }
+
+        Ok(Self { objects, identity })
+    }
+
+    /// The identity that decrypts these values, in age's identity file
+    /// format.
+    pub fn identity(&self) -> String {
+        self.identity.to_string().expose_secret().clone()
+    }
+}
+
+#[async_trait]
+impl IntoSecretStorage for DevStorage {
+    type Error = DevStorageError;
+    type Impl = DevStorage;
+
+    async fn build(self) -> Self::Impl {
+        self
+    }
+}
+
+#[async_trait]
+impl SecretStorage for DevStorage {
+    type Error = DevStorageError;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        let data = self
+            .objects
+            .get(p)
+            .ok_or_else(|| DevStorageError::NotFound(p.to_owned()))?;
+        Ok(BoxedAsyncReader::from_async_read(Cursor::new(data.clone())))
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        let data = self
+            .objects
+            .get(p)
+            .ok_or_else(|| DevStorageError::NotFound(p.to_owned()))?;
+        Ok(ObjectMetadata {
+            size: data.len() as u64,
+            last_modified: None,
+        })
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        _p: &Path,
+        _new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        Err(DevStorageError::ReadOnly)
+    }
+
+    async fn delete(&self, _p: &Path) -> Result<(), Self::Error> {
+        Err(DevStorageError::ReadOnly)
+    }
+}
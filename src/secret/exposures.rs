@@ -1,8 +1,11 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, OneOrMany};
+
+use crate::secret::AclEntry;
 
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
 #[serde(tag = "type")]
@@ -11,6 +14,8 @@ pub enum ExposureSpec {
     File(Box<FileExposeArgs>),
     #[serde(alias = "env")]
     Env(EnvExposeArgs),
+    #[serde(alias = "template")]
+    Template(Box<TemplateExposeArgs>),
 }
 
 impl ExposureSpec {
@@ -25,12 +30,22 @@ impl ExposureSpec {
             mode,
             owner,
             group,
+            link_mode: LinkMode::default(),
+            acl: Vec::new(),
+            optional: false,
+            after: Vec::new(),
+            force_adopt: false,
         }))
     }
 
     pub fn env_from_str(secret_name: String, name: &str) -> Self {
         let name = name.parse().expect("infallible error");
-        Self::Env(EnvExposeArgs { secret_name, name })
+        Self::Env(EnvExposeArgs {
+            secret_name,
+            name,
+            overwrite: false,
+            optional: false,
+        })
     }
 }
 
@@ -48,6 +63,7 @@ impl FromStr for ExposureSpec {
     }
 }
 
+#[serde_as]
 #[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
 pub struct FileExposeArgs {
     pub secret_name: String,
@@ -56,21 +72,157 @@ pub struct FileExposeArgs {
     pub mode: Option<u32>,
     pub owner: Option<crate::UserWrapper>,
     pub group: Option<crate::GroupWrapper>,
+    #[serde(default, alias = "linkMode")]
+    pub link_mode: LinkMode,
+    /// Extra users and groups to grant access to, as POSIX ACL entries
+    #[serde(default)]
+    pub acl: Vec<AclEntry>,
+    /// Skip this exposure (with a warning) if the secret doesn't exist in
+    /// storage, instead of failing
+    #[serde(default)]
+    pub optional: bool,
+    /// Secrets (by name) or templates that must be exposed before this one
+    #[serde_as(as = "OneOrMany<_>")]
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Move a regular file that's in the way of a symlinked vanity path aside
+    /// (to `<path>.credible-backup`), instead of refusing to replace it
+    #[serde(default, alias = "forceAdopt")]
+    pub force_adopt: bool,
+}
+
+/// How a file exposure's vanity path refers to the decrypted secret.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// Symlink to the file in the secrets directory
+    #[default]
+    Symlink,
+    /// Hard-link to the file in the secrets directory, which must be on the
+    /// same filesystem as the vanity path
+    Hardlink,
+    /// Write a separate copy of the secret to the vanity path
+    Copy,
+}
+
+/// A file rendered from a template, which can refer to secrets, host facts
+/// and config values.
+#[serde_as]
+#[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct TemplateExposeArgs {
+    /// Name of the rendered file in the secrets directory
+    pub name: String,
+    /// Path to the template, relative to the config file it's defined in
+    pub template: PathBuf,
+    #[serde(alias = "path")]
+    pub vanity_path: Option<PathBuf>,
+    pub mode: Option<u32>,
+    pub owner: Option<crate::UserWrapper>,
+    pub group: Option<crate::GroupWrapper>,
+    #[serde(default, alias = "linkMode")]
+    pub link_mode: LinkMode,
+    /// Extra users and groups to grant access to, as POSIX ACL entries
+    #[serde(default)]
+    pub acl: Vec<AclEntry>,
+    /// Values available to the template as `vars.<name>`
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    /// Skip rendering this template (with a warning) if any secret it uses
+    /// doesn't exist in storage, instead of failing
+    #[serde(default)]
+    pub optional: bool,
+    /// Secrets (by name) or other templates that must be exposed before this
+    /// one is rendered
+    #[serde_as(as = "OneOrMany<_>")]
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Move a regular file that's in the way of a symlinked vanity path aside,
+    /// as with file exposures
+    #[serde(default, alias = "forceAdopt")]
+    pub force_adopt: bool,
+}
+
+impl TemplateExposeArgs {
+    /// How the rendered file is written, which is the same as any other file
+    /// exposure.
+    pub fn file_spec(&self) -> FileExposeArgs {
+        FileExposeArgs {
+            secret_name: self.name.clone(),
+            vanity_path: self.vanity_path.clone(),
+            mode: self.mode,
+            owner: self.owner.clone(),
+            group: self.group.clone(),
+            link_mode: self.link_mode,
+            acl: self.acl.clone(),
+            optional: self.optional,
+            after: self.after.clone(),
+            force_adopt: self.force_adopt,
+        }
+    }
 }
 
 #[derive(Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
 pub struct EnvExposeArgs {
     pub secret_name: String,
     pub name: String,
+    /// Replace the variable if it's already set in the calling environment,
+    /// instead of refusing to run
+    #[serde(default)]
+    pub overwrite: bool,
+    /// Skip this exposure (with a warning) if the secret doesn't exist in
+    /// storage, instead of failing
+    #[serde(default)]
+    pub optional: bool,
 }
 
+/// Exposures, keyed by secret name. These are ordered, so that secrets are
+/// always exposed (and logged, and fail) in the same order between runs.
 #[derive(Default)]
 pub struct Exposures {
-    pub files: HashMap<String, Vec<FileExposeArgs>>,
-    pub envs: HashMap<String, Vec<EnvExposeArgs>>,
+    pub files: BTreeMap<String, Vec<FileExposeArgs>>,
+    pub envs: BTreeMap<String, Vec<EnvExposeArgs>>,
+    pub templates: Vec<TemplateExposeArgs>,
+    /// Directory relative vanity paths are resolved against, if not the
+    /// default for the command being run
+    pub root: Option<PathBuf>,
+}
+
+/// Makes relative vanity paths absolute, by resolving them against the given
+/// root.
+pub fn resolve_vanity_paths(
+    files: &BTreeMap<String, Vec<FileExposeArgs>>,
+    root: &Path,
+) -> BTreeMap<String, Vec<FileExposeArgs>> {
+    files
+        .iter()
+        .map(|(name, specs)| {
+            let specs = specs
+                .iter()
+                .cloned()
+                .map(|mut spec| {
+                    if let Some(p) = spec.vanity_path.as_ref().filter(|p| p.is_relative()) {
+                        spec.vanity_path = Some(root.join(p));
+                    }
+                    spec
+                })
+                .collect();
+            (name.clone(), specs)
+        })
+        .collect()
 }
 
 impl Exposures {
+    /// Moves regular files in the way of every file and template exposure's
+    /// symlinked vanity path aside, instead of refusing to replace them.
+    pub fn force_adopt(&mut self) {
+        for spec in self.files.values_mut().flatten() {
+            spec.force_adopt = true;
+        }
+        for template in self.templates.iter_mut() {
+            template.force_adopt = true;
+        }
+    }
+
     pub fn add_files<I: IntoIterator<Item = FileExposeArgs>>(&mut self, specs: I) {
         for spec in specs {
             match self.files.get_mut(&spec.secret_name) {
@@ -82,6 +234,47 @@ impl Exposures {
         }
     }
 
+    /// Copies these exposures, with relative vanity paths resolved against the
+    /// configured root (or the given default, if there isn't one).
+    pub fn with_root(&self, default_root: &Path) -> Exposures {
+        let root = self.root.as_deref().unwrap_or(default_root);
+        let templates = self
+            .templates
+            .iter()
+            .cloned()
+            .map(|mut t| {
+                if let Some(p) = t.vanity_path.as_ref().filter(|p| p.is_relative()) {
+                    t.vanity_path = Some(root.join(p));
+                }
+                t
+            })
+            .collect();
+        Exposures {
+            files: resolve_vanity_paths(&self.files, root),
+            envs: self.envs.clone(),
+            templates,
+            root: Some(root.to_owned()),
+        }
+    }
+
+    /// Every vanity path these exposures create, and how.
+    pub fn vanity_paths(&self) -> impl Iterator<Item = (&Path, LinkMode)> {
+        let files = self
+            .files
+            .values()
+            .flatten()
+            .filter_map(|s| Some((s.vanity_path.as_deref()?, s.link_mode)));
+        let templates = self
+            .templates
+            .iter()
+            .filter_map(|t| Some((t.vanity_path.as_deref()?, t.link_mode)));
+        files.chain(templates)
+    }
+
+    pub fn add_templates<I: IntoIterator<Item = TemplateExposeArgs>>(&mut self, specs: I) {
+        self.templates.extend(specs);
+    }
+
     pub fn add_envs<I: IntoIterator<Item = EnvExposeArgs>>(&mut self, specs: I) {
         for spec in specs {
             match self.envs.get_mut(&spec.secret_name) {
@@ -92,4 +285,106 @@ impl Exposures {
             };
         }
     }
+
+    /// What each file exposure (by secret name) and template (by name) must
+    /// be exposed after.
+    pub fn dependencies(&self) -> BTreeMap<&str, BTreeSet<&str>> {
+        let files = self.files.iter().map(|(name, specs)| {
+            let after = specs.iter().flat_map(|s| s.after.iter());
+            (name.as_str(), after.map(String::as_str).collect())
+        });
+        let templates = self.templates.iter().map(|t| {
+            let after = t.after.iter().map(String::as_str);
+            (t.name.as_str(), after.collect())
+        });
+        files.chain(templates).collect()
+    }
+
+    /// Splits these exposures into stages to expose one after the other, so
+    /// that everything is exposed after what it's declared to be `after`.
+    /// Without any dependencies, there's a single stage. Anything in a cycle
+    /// (which is refused when loading config) is left to the last stage.
+    pub fn stages(&self) -> Vec<Exposures> {
+        let deps = self.dependencies();
+        let mut remaining = deps.keys().copied().collect::<BTreeSet<_>>();
+        let mut stages = Vec::new();
+        while !remaining.is_empty() {
+            // Dependencies on anything that isn't exposed are already met
+            let mut ready = remaining
+                .iter()
+                .copied()
+                .filter(|n| deps[*n].iter().all(|d| !remaining.contains(d)))
+                .collect::<HashSet<_>>();
+            if ready.is_empty() {
+                ready = remaining.clone().into_iter().collect();
+            }
+            remaining.retain(|n| !ready.contains(n));
+
+            stages.push(Exposures {
+                files: self
+                    .files
+                    .iter()
+                    .filter(|(name, _)| ready.contains(name.as_str()))
+                    .map(|(name, specs)| (name.clone(), specs.clone()))
+                    .collect(),
+                envs: Default::default(),
+                templates: self
+                    .templates
+                    .iter()
+                    .filter(|t| ready.contains(t.name.as_str()))
+                    .cloned()
+                    .collect(),
+                root: self.root.clone(),
+            });
+        }
+
+        // Environment variables are set before anything runs, so they're
+        // never waited on
+        match stages.first_mut() {
+            Some(first) => first.envs = self.envs.clone(),
+            None => stages.push(Exposures {
+                envs: self.envs.clone(),
+                root: self.root.clone(),
+                ..Default::default()
+            }),
+        }
+
+        stages
+    }
+
+    /// Exposures that (directly or not) are declared to come after
+    /// themselves, in order, starting and ending with the same one.
+    pub fn dependency_cycle(&self) -> Option<Vec<String>> {
+        let deps = self.dependencies();
+        let mut finished = HashSet::new();
+        deps.keys()
+            .find_map(|name| find_cycle(&deps, name, &mut Vec::new(), &mut finished))
+    }
+}
+
+/// Depth-first search for a cycle from `name`, along the path taken so far.
+fn find_cycle<'a>(
+    deps: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+    name: &'a str,
+    path: &mut Vec<&'a str>,
+    finished: &mut HashSet<&'a str>,
+) -> Option<Vec<String>> {
+    if let Some(start) = path.iter().position(|n| *n == name) {
+        let cycle = path[start..].iter().chain([&name]);
+        return Some(cycle.map(|n| n.to_string()).collect());
+    }
+    if finished.contains(name) {
+        return None;
+    }
+
+    path.push(name);
+    for dep in deps.get(name).into_iter().flatten() {
+        if let Some(cycle) = find_cycle(deps, dep, path, finished) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    finished.insert(name);
+
+    None
 }
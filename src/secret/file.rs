@@ -1,109 +1,555 @@
 use age::Identity;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use sha2::{Digest, Sha256};
+#[cfg(unix)]
+use tokio::fs::symlink;
+#[cfg(windows)]
+use tokio::fs::symlink_file as symlink;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
-use crate::age::{decrypt_bytes, DecryptionError};
+use crate::age::DecryptionError;
+use crate::events::{self, Event, ExposureKind};
+use crate::hooks::{ExposureTarget, Hooks};
 use crate::secret::exposures::*;
 use crate::secret::{Secret, SecretStorage, *};
+use crate::util::{create_new_with_mode, no_follow};
+use crate::MultiError;
 
-const FILE_PERMISSIONS: u32 = 0o0400;
+/// Mode of exposed files, unless their exposure gives one.
+pub const FILE_PERMISSIONS: u32 = 0o0400;
+
+/// Suffix regular files in the way of symlinked vanity paths are moved aside
+/// to, when adopting them.
+pub const ADOPTED_SUFFIX: &str = ".credible-backup";
+
+/// Decrypted content to write out for a file exposure.
+pub(super) enum Plaintext<'a> {
+    Bytes(&'a [u8]),
+    /// Streamed straight to the destination, for large secrets
+    Stream(&'a mut (dyn AsyncRead + Unpin + Send)),
+    /// Already written out to the given file (e.g. for an earlier exposure
+    /// of the same secret)
+    File(&'a Path),
+}
+
+impl Plaintext<'_> {
+    async fn write_to(&mut self, file: &mut tokio::fs::File) -> Result<(), std::io::Error> {
+        match self {
+            Self::Bytes(data) => file.write_all(data).await,
+            Self::Stream(reader) => tokio::io::copy(reader, file).await.map(|_| ()),
+            Self::File(path) => {
+                let mut source = no_follow(tokio::fs::OpenOptions::new().read(true))
+                    .open(path)
+                    .await?;
+                tokio::io::copy(&mut source, file).await.map(|_| ())
+            }
+        }?;
+        file.flush().await
+    }
+}
+
+/// Sets ownership through the open file, rather than its path, so that it
+/// can't be redirected elsewhere in the meantime.
+#[cfg(unix)]
+fn set_owner(
+    file: &tokio::fs::File,
+    _path: &Path,
+    spec: &FileExposeArgs,
+) -> Result<(), FileExposureError> {
+    use std::os::fd::AsRawFd;
+
+    let owner = spec.owner.as_ref().map(|o| o.as_ref().uid);
+    let group = spec.group.as_ref().map(|g| g.as_ref().gid);
+    nix::unistd::fchown(file.as_raw_fd(), owner, group)
+        .map_err(FileExposureError::SettingPermissions)
+}
+
+#[cfg(windows)]
+fn set_owner(
+    _file: &tokio::fs::File,
+    path: &Path,
+    spec: &FileExposeArgs,
+) -> Result<(), FileExposureError> {
+    if spec.owner.is_some() || spec.group.is_some() {
+        log::warn!(
+            "ignoring owner/group for {}, not supported on this platform",
+            path.to_string_lossy()
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks that the directory a vanity path goes in can only be changed by us
+/// (or root), so that nobody else can swap things out from under us while we
+/// create the vanity path.
+#[cfg(unix)]
+async fn check_vanity_parent(p: &Path) -> Result<(), FileExposureError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let parent = match p.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    let metadata = match tokio::fs::metadata(parent).await {
+        Ok(metadata) => metadata,
+        // Reported when creating the vanity path
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(FileExposureError::CreatingLink(e)),
+    };
+
+    let euid = nix::unistd::geteuid().as_raw();
+    if metadata.uid() != 0 && metadata.uid() != euid {
+        return Err(FileExposureError::UntrustedDirectory(
+            parent.to_owned(),
+            format!("owned by uid {}", metadata.uid()),
+        ));
+    }
+    // Shared directories like /tmp are fine, as long as the sticky bit stops
+    // others removing or renaming our files
+    let mode = metadata.mode();
+    if mode & 0o022 != 0 && mode & 0o1000 == 0 {
+        return Err(FileExposureError::UntrustedDirectory(
+            parent.to_owned(),
+            format!("writable by others (mode {:#o})", mode & 0o7777),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+async fn check_vanity_parent(_p: &Path) -> Result<(), FileExposureError> {
+    Ok(())
+}
+
+/// Removes whatever is at the given path, if anything. Symlinks are removed
+/// themselves, never what they point to.
+async fn remove_if_present(p: &Path) -> Result<(), std::io::Error> {
+    match tokio::fs::remove_file(p).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// What files written for an exposure are tagged with.
+fn exposure_tag(secret_dir: &Path, spec: &FileExposeArgs) -> ExposureTag {
+    ExposureTag {
+        secret: spec.secret_name.clone(),
+        generation: secret_dir
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+/// Creates the vanity path for an exposure, replacing whatever a previous
+/// exposure left there.
+async fn link_vanity_path(
+    dest_path: &Path,
+    p: &Path,
+    spec: &FileExposeArgs,
+    mode: u32,
+    tag: ExposureTag,
+) -> Result<(), FileExposureError> {
+    check_vanity_parent(p).await?;
+
+    match spec.link_mode {
+        LinkMode::Symlink => {
+            match tokio::fs::symlink_metadata(p).await {
+                Ok(m) if m.is_symlink() => {
+                    log::debug!("removing {}", p.to_string_lossy());
+                    tokio::fs::remove_file(p)
+                        .await
+                        .map_err(FileExposureError::CreatingSymlink)?;
+                }
+                Ok(m) if m.is_file() => adopt_vanity_path(p, spec).await?,
+                _ => (),
+            }
+            symlink(dest_path, p)
+                .await
+                .map_err(FileExposureError::CreatingSymlink)?;
+
+            log::debug!(
+                "symlinked {} to {}",
+                p.to_string_lossy(),
+                dest_path.to_string_lossy()
+            );
+        }
+        LinkMode::Hardlink | LinkMode::Copy => {
+            // Build the file next to the vanity path and rename it into place,
+            // so that readers never see a partially-written secret
+            let mut temp_name = p.file_name().unwrap_or_default().to_owned();
+            temp_name.push(".credible-tmp");
+            let temp_path = p.with_file_name(temp_name);
+            remove_if_present(&temp_path)
+                .await
+                .map_err(FileExposureError::CreatingLink)?;
+
+            if spec.link_mode == LinkMode::Hardlink {
+                tokio::fs::hard_link(dest_path, &temp_path)
+                    .await
+                    .map_err(FileExposureError::CreatingLink)?;
+            } else {
+                let mut file = create_new_with_mode(mode)
+                    .open(&temp_path)
+                    .await
+                    .map_err(FileExposureError::CreatingLink)?;
+                Plaintext::File(dest_path)
+                    .write_to(&mut file)
+                    .await
+                    .map_err(FileExposureError::WritingToFile)?;
+                set_owner(&file, &temp_path, spec)?;
+                set_acl(&file, mode, &spec.acl)
+                    .await
+                    .map_err(FileExposureError::SettingAcl)?;
+                tag_file(&file, tag).await;
+            }
+
+            tokio::fs::rename(&temp_path, p)
+                .await
+                .map_err(FileExposureError::CreatingLink)?;
+
+            log::debug!(
+                "{} {} to {}",
+                match spec.link_mode {
+                    LinkMode::Hardlink => "hard-linked",
+                    _ => "copied",
+                },
+                dest_path.to_string_lossy(),
+                p.to_string_lossy()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Deals with a regular file where a symlinked vanity path goes, which can't
+/// be one of ours: it's moved aside if the exposure allows it (e.g. with
+/// `--force-adopt`), and refused otherwise.
+async fn adopt_vanity_path(p: &Path, spec: &FileExposeArgs) -> Result<(), FileExposureError> {
+    if !spec.force_adopt {
+        return Err(FileExposureError::VanityPathOccupied(p.to_owned()));
+    }
+
+    let mut backup = p.as_os_str().to_owned();
+    backup.push(ADOPTED_SUFFIX);
+    let adopting = |e| FileExposureError::AdoptingVanityPath(p.to_owned(), e);
+    // Linked rather than renamed, so that an earlier backup is never replaced
+    tokio::fs::hard_link(p, &backup).await.map_err(adopting)?;
+    tokio::fs::remove_file(p).await.map_err(adopting)?;
+    log::warn!(
+        "moved {} aside to {}",
+        p.to_string_lossy(),
+        backup.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+/// Fetches a secret, returning a reader of its plaintext.
+async fn open_plaintext<S>(
+    storage: &S,
+    secret: &Secret,
+    identities: &[Box<dyn Identity>],
+    hooks: &dyn Hooks,
+    threshold: u64,
+) -> Result<BoxedAsyncReader, FileExposureError>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    events::emit(Event::FetchStart {
+        secret: &secret.name,
+    });
+    hooks.on_fetch(secret);
+    let reader =
+        read_secret(storage, secret, threshold)
+            .await
+            .map_err(|e| match e.is_not_found() {
+                true => FileExposureError::NotInStorage,
+                false => FileExposureError::FetchingSecret(Box::new(e)),
+            })?;
+
+    Ok(decrypt_secret(reader, secret, identities).await?)
+}
+
+/// Reads plaintext into the buffer, up to the large secret threshold.
+/// Returns whether there's more to read.
+async fn read_up_to_threshold(
+    reader: &mut BoxedAsyncReader,
+    buf: &mut Vec<u8>,
+    threshold: u64,
+) -> Result<bool, FileExposureError> {
+    reader
+        .take(threshold + 1)
+        .read_to_end(buf)
+        .await
+        .map_err(|e| FileExposureError::FetchingSecret(Box::new(e)))?;
+
+    Ok(buf.len() as u64 > threshold)
+}
+
+fn fetch_done(secret: &Secret, hooks: &dyn Hooks) {
+    events::emit(Event::FetchDone {
+        secret: &secret.name,
+    });
+    hooks.on_decrypt(secret);
+}
+
+/// SHA-256 digest (in hex) of a secret's plaintext, which is streamed rather
+/// than held in memory.
+pub async fn plaintext_digest<S>(
+    storage: &S,
+    secret: &Secret,
+    identities: &[Box<dyn Identity>],
+    hooks: &dyn Hooks,
+    threshold: u64,
+) -> Result<String, FileExposureError>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let mut reader = open_plaintext(storage, secret, identities, hooks, threshold).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .await
+            .map_err(|e| FileExposureError::FetchingSecret(Box::new(e)))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    fetch_done(secret, hooks);
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Fetches a secret's plaintext into the buffer. Large secrets are refused,
+/// rather than held in memory.
+pub(super) async fn fetch_plaintext<S>(
+    storage: &S,
+    secret: &Secret,
+    identities: &[Box<dyn Identity>],
+    hooks: &dyn Hooks,
+    buf: &mut Vec<u8>,
+    threshold: u64,
+) -> Result<(), FileExposureError>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let mut reader = open_plaintext(storage, secret, identities, hooks, threshold).await?;
+    if read_up_to_threshold(&mut reader, buf, threshold).await? {
+        return Err(FileExposureError::TooLarge(ByteSize(threshold)));
+    }
+    fetch_done(secret, hooks);
+
+    Ok(())
+}
+
+/// Writes a file exposure's content into the secrets directory (named after
+/// the exposure's secret), and creates its vanity path. Returns the path
+/// consumers should read it from.
+pub(super) async fn write_file(
+    secret_dir: &Path,
+    file_spec: &FileExposeArgs,
+    mut plaintext: Plaintext<'_>,
+) -> Result<PathBuf, FileExposureError> {
+    let mode = file_spec.mode.unwrap_or(FILE_PERMISSIONS);
+
+    let dest_path = secret_dir.join(&file_spec.secret_name);
+    // Written alongside and renamed into place, so that secrets refreshed
+    // while in use are never seen partially-written
+    let temp_path = secret_dir.join(format!("{}.credible-tmp", file_spec.secret_name));
+    remove_if_present(&temp_path)
+        .await
+        .map_err(FileExposureError::CreatingTempFile)?;
+    {
+        let mut file = create_new_with_mode(mode)
+            .open(&temp_path)
+            .await
+            .map_err(FileExposureError::CreatingTempFile)?;
+
+        plaintext
+            .write_to(&mut file)
+            .await
+            .map_err(FileExposureError::WritingToFile)?;
+        set_owner(&file, &temp_path, file_spec)?;
+        set_acl(&file, mode, &file_spec.acl)
+            .await
+            .map_err(FileExposureError::SettingAcl)?;
+        tag_file(&file, exposure_tag(secret_dir, file_spec)).await;
+    }
+
+    tokio::fs::rename(&temp_path, &dest_path)
+        .await
+        .map_err(FileExposureError::WritingToFile)?;
+
+    log::debug!(
+        "wrote {} to {} with permissions {:#o}",
+        file_spec.secret_name,
+        dest_path.as_path().to_string_lossy(),
+        mode,
+    );
+
+    match &file_spec.vanity_path {
+        Some(p) => {
+            let tag = exposure_tag(secret_dir, file_spec);
+            link_vanity_path(&dest_path, p, file_spec, mode, tag).await?;
+            Ok(p.to_owned())
+        }
+        None => Ok(dest_path),
+    }
+}
+
+async fn expose_file(
+    secret_dir: &Path,
+    secret: &Secret,
+    file_spec: &FileExposeArgs,
+    plaintext: Plaintext<'_>,
+    hooks: &dyn Hooks,
+) -> Result<PathBuf, FileExposureError> {
+    let target = write_file(secret_dir, file_spec, plaintext).await?;
+    events::emit(Event::ExposeDone {
+        secret: &secret.name,
+        kind: ExposureKind::File,
+        target: &target.to_string_lossy(),
+    });
+    hooks.on_expose(secret, ExposureTarget::File(&target));
+
+    Ok(target)
+}
+
+/// Exposes a large secret by streaming it into the first of its exposures,
+/// and copying that for the rest, so that it's never held in memory.
+async fn expose_large_file(
+    secret_dir: &Path,
+    secret: &Secret,
+    exposure_set: &[FileExposeArgs],
+    head: &[u8],
+    rest: BoxedAsyncReader,
+    hooks: &dyn Hooks,
+) -> Result<(), MultiError<FileExposureError>> {
+    let Some((first, others)) = exposure_set.split_first() else {
+        return Ok(());
+    };
+    let mut errors = MultiError::default();
+    let mut stream = ProgressReader::new(head.chain(rest), &secret.name);
+
+    let first = expose_file(
+        secret_dir,
+        secret,
+        first,
+        Plaintext::Stream(&mut stream),
+        hooks,
+    );
+    if let Err(e) = first.await {
+        errors.push(&secret.name, e);
+        return errors.into_result();
+    }
+    stream.finish();
+    fetch_done(secret, hooks);
+
+    // Every exposure writes the same file in the secrets directory
+    let written = secret_dir.join(&secret.name);
+    for file_spec in others {
+        let plaintext = Plaintext::File(&written);
+        if let Err(e) = expose_file(secret_dir, secret, file_spec, plaintext, hooks).await {
+            errors.push(&secret.name, e);
+        }
+    }
+
+    errors.into_result()
+}
 
 // TODO:
 // - metadata file (what points here, time set, etc)
 // - state locking
+/// Exposes secrets as files in the given directory. Failing secrets don't
+/// stop the others from being exposed, and are reported together. Secrets
+/// larger than `threshold` bytes are streamed into place.
 pub async fn expose_files<S>(
     secret_dir: &Path,
     storage: &S,
     exposures: &[(&Secret, &Vec<FileExposeArgs>)],
     identities: &[Box<dyn Identity>],
-) -> Result<(), FileExposureError>
+    hooks: &dyn Hooks,
+    threshold: u64,
+) -> Result<(), MultiError<FileExposureError>>
 where
     S: SecretStorage,
     <S as SecretStorage>::Error: 'static,
 {
+    let mut errors = MultiError::default();
     let mut buf = vec![];
     log::debug!("mounting {} exposures", exposures.len());
     for (secret, exposure_set) in exposures {
-        let reader = storage
-            .read(&secret.path)
-            .await
-            .map_err(|e| FileExposureError::FetchingSecret(Box::new(e)))?;
-
-        let mut reader = decrypt_bytes(reader, identities).await?;
-        reader
-            .read_to_end(&mut buf)
-            .await
-            .map_err(|e| FileExposureError::FetchingSecret(Box::new(e)))?;
-
-        for file_spec in exposure_set.iter() {
-            let owner = file_spec.owner.as_ref().map(|o| o.as_ref().uid);
-            let group = file_spec.group.as_ref().map(|g| g.as_ref().gid);
-            let mode = file_spec.mode.unwrap_or(FILE_PERMISSIONS);
-
-            let dest_path = secret_dir.join(&secret.name);
-            {
-                let mut file = OpenOptions::new()
-                    .mode(mode)
-                    .create(true)
-                    .truncate(true)
-                    .write(true)
-                    .open(&dest_path)
-                    .await
-                    .map_err(FileExposureError::CreatingTempFile)?;
-
-                file.write_all(&buf)
-                    .await
-                    .map_err(FileExposureError::WritingToFile)?;
-
-                log::debug!(
-                    "wrote {} to {} with permissions {:#o}",
-                    secret.name,
-                    dest_path.as_path().to_string_lossy(),
-                    mode,
-                );
+        let res = match open_plaintext(storage, secret, identities, hooks, threshold).await {
+            Ok(mut reader) => read_up_to_threshold(&mut reader, &mut buf, threshold)
+                .await
+                .map(|large| large.then_some(reader)),
+            Err(e) => Err(e),
+        };
+        let rest = match res {
+            Ok(rest) => rest,
+            Err(e) => {
+                match e {
+                    FileExposureError::NotInStorage if exposure_set.iter().all(|s| s.optional) => {
+                        log::warn!("skipping optional secret {}: {e}", secret.name)
+                    }
+                    e => errors.push(&secret.name, e),
+                }
+                buf.truncate(0);
+                continue;
             }
+        };
 
-            nix::unistd::chown(dest_path.as_path(), owner, group)
-                .map_err(FileExposureError::SettingPermissions)?;
-
-            if let Some(p) = &file_spec.vanity_path {
-                if p.is_symlink() {
-                    log::debug!("removing {}", p.to_string_lossy());
-                    tokio::fs::remove_file(p)
-                        .await
-                        .map_err(FileExposureError::CreatingSymlink)?;
-                }
-                tokio::fs::symlink(&dest_path, p)
-                    .await
-                    .map_err(FileExposureError::CreatingSymlink)?;
+        if let Some(rest) = rest {
+            log::info!(
+                "{} is larger than {}, streaming it",
+                secret.name,
+                ByteSize(threshold)
+            );
+            let res = expose_large_file(secret_dir, secret, exposure_set, &buf, rest, hooks);
+            if let Err(e) = res.await {
+                errors.append(e);
+            }
+            buf = vec![];
+            continue;
+        }
 
-                log::debug!(
-                    "symlinked {} to {}",
-                    p.to_string_lossy(),
-                    dest_path.to_string_lossy()
-                );
+        fetch_done(secret, hooks);
+        for file_spec in exposure_set.iter() {
+            let plaintext = Plaintext::Bytes(&buf);
+            if let Err(e) = expose_file(secret_dir, secret, file_spec, plaintext, hooks).await {
+                errors.push(&secret.name, e);
             }
         }
 
         buf.truncate(0);
     }
 
-    Ok(())
+    errors.into_result()
 }
 
-pub async fn clean_files<'a, I>(paths: I) -> Vec<FileCleanupError>
+pub async fn clean_files<'a, I>(paths: I, hooks: &dyn Hooks) -> Vec<FileCleanupError>
 where
     I: Iterator<Item = &'a Path>,
 {
     let mut errs = vec![];
 
     for p in paths {
-        if let Err(e) = tokio::fs::remove_file(p)
-            .await
-            .map_err(|e| FileCleanupError(p.to_owned(), e))
-        {
-            errs.push(e);
-        };
+        match tokio::fs::remove_file(p).await {
+            Ok(()) => hooks.on_cleanup(p),
+            // e.g. an optional exposure that was skipped
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => errs.push(FileCleanupError(p.to_owned(), e)),
+        }
     }
 
     errs
@@ -121,10 +567,28 @@ pub enum FileExposureError {
     WritingToFile(std::io::Error),
     #[error("error creating symlink to decrypted secret: {0}")]
     CreatingSymlink(std::io::Error),
+    #[error("error creating file at vanity path: {0}")]
+    CreatingLink(std::io::Error),
+    #[error(
+        "refusing to replace {0}, which is a regular file rather than a symlink (pass \
+         --force-adopt, or set force_adopt on its exposure, to move it aside)"
+    )]
+    VanityPathOccupied(PathBuf),
+    #[error("error moving {0} aside: {1}")]
+    AdoptingVanityPath(PathBuf, std::io::Error),
+    #[error("refusing to create vanity path in {0}, which is {1}")]
+    UntrustedDirectory(PathBuf, String),
+    #[cfg(unix)]
     #[error("error setting permissions on created file: {0}")]
     SettingPermissions(nix::errno::Errno),
+    #[error("error setting ACL on created file: {0}")]
+    SettingAcl(std::io::Error),
+    #[error("secret does not exist in storage")]
+    NotInStorage,
+    #[error("secret is larger than {0}, and can only be exposed as a file")]
+    TooLarge(ByteSize),
 }
 
 #[derive(thiserror::Error, Debug)]
-#[error("not able to clean up symlink at {0}: {1}")]
+#[error("not able to clean up vanity path at {0}: {1}")]
 pub struct FileCleanupError(PathBuf, std::io::Error);
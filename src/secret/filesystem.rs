@@ -0,0 +1,228 @@
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+
+use super::{ConditionalRead, ObjectMetadata, SecretError, SecretStorage};
+use crate::util::BoxedAsyncReader;
+use crate::IntoSecretStorage;
+
+/// Suffix of files that ciphertext is written to before being renamed into
+/// place.
+const TEMP_SUFFIX: &str = ".credible-tmp";
+
+/// Mode of written files. Temporary files are only readable by us, but
+/// ciphertext needs to be readable by whoever decrypts it (access is limited
+/// by the storage directory's permissions instead).
+#[cfg(unix)]
+const FILE_MODE: u32 = 0o644;
+
+#[derive(Deserialize, Debug)]
+pub struct FileStorageConfig {
+    /// Directory secrets are stored in (which may be a network mount)
+    root: PathBuf,
+}
+
+/// Storage in a local directory, for hosts without access to object storage.
+/// Each object is a file under the root, at its path.
+pub struct FileSecretStorage {
+    root: PathBuf,
+}
+
+#[derive(Error, Debug)]
+pub enum FileStorageError {
+    #[error("{0} is outside of the storage directory")]
+    InvalidPath(PathBuf),
+    #[error("{0} doesn't exist")]
+    NotFound(PathBuf),
+    #[error("error reading {0}: {1}")]
+    Reading(PathBuf, std::io::Error),
+    #[error("error writing {0}: {1}")]
+    Writing(PathBuf, std::io::Error),
+    #[error("error removing {0}: {1}")]
+    Removing(PathBuf, std::io::Error),
+}
+
+impl SecretError for FileStorageError {
+    fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound(_))
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        let e = match self {
+            Self::Reading(_, e) | Self::Writing(_, e) | Self::Removing(_, e) => e,
+            _ => return None,
+        };
+        match e.kind() {
+            ErrorKind::PermissionDenied => Some(
+                "check that this user can read (or, to upload, write to) the storage directory",
+            ),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl IntoSecretStorage for FileStorageConfig {
+    type Error = FileStorageError;
+    type Impl = FileSecretStorage;
+
+    async fn build(self) -> Self::Impl {
+        FileSecretStorage { root: self.root }
+    }
+}
+
+impl FileSecretStorage {
+    /// Where the object at the given path is stored, under the root.
+    fn path(&self, p: &Path) -> Result<PathBuf, FileStorageError> {
+        match relative_path(p) {
+            Some(relative) => Ok(self.root.join(relative)),
+            None => Err(FileStorageError::InvalidPath(p.to_owned())),
+        }
+    }
+
+    async fn open(&self, p: &Path) -> Result<(fs::File, std::fs::Metadata), FileStorageError> {
+        let path = self.path(p)?;
+        let reading = |e: std::io::Error| match e.kind() {
+            ErrorKind::NotFound => FileStorageError::NotFound(path.clone()),
+            _ => FileStorageError::Reading(path.clone(), e),
+        };
+        let file = fs::File::open(&path).await.map_err(reading)?;
+        // Taken from the open file, so that it describes what's read even if
+        // it's replaced meanwhile
+        let metadata = file.metadata().await.map_err(reading)?;
+
+        Ok((file, metadata))
+    }
+}
+
+/// An object path relative to the directory it's stored in, which it can't
+/// leave. Absolute paths are treated as relative, and `None` is returned for
+/// paths that would leave the directory (or are the directory itself).
+pub(crate) fn relative_path(p: &Path) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in p.components() {
+        match component {
+            Component::Normal(c) => path.push(c),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    match path.as_os_str().is_empty() {
+        true => None,
+        false => Some(path),
+    }
+}
+
+/// Version of a file, which changes whenever it's replaced (since writes
+/// rename a new file into place).
+fn file_version(metadata: &std::fs::Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    #[cfg(unix)]
+    let id = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let id = metadata.len();
+
+    Some(format!("{id}-{}", modified.as_nanos()))
+}
+
+#[async_trait]
+impl SecretStorage for FileSecretStorage {
+    type Error = FileStorageError;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        let (file, _) = self.open(p).await?;
+        Ok(BoxedAsyncReader::from_async_read(file))
+    }
+
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        let (file, metadata) = self.open(p).await?;
+        let current = file_version(&metadata);
+        if current.is_some() && current.as_deref() == version {
+            return Ok(ConditionalRead::Unchanged);
+        }
+
+        Ok(ConditionalRead::Changed {
+            reader: BoxedAsyncReader::from_async_read(file),
+            version: current,
+        })
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        let (_, metadata) = self.open(p).await?;
+        Ok(ObjectMetadata {
+            size: metadata.len(),
+            last_modified: metadata.modified().ok(),
+        })
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        mut new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        let path = self.path(p)?;
+        let writing = |e| FileStorageError::Writing(path.clone(), e);
+        let dir = path.parent().expect("objects are always under the root");
+        fs::create_dir_all(dir).await.map_err(writing)?;
+
+        // Written next to where it ends up, then renamed into place, so that
+        // readers (including other hosts, on a network mount) never see a
+        // partial file. It's removed if anything fails before then.
+        let name = path.file_name().expect("objects always have a name");
+        let temp = tempfile::Builder::new()
+            .prefix(&format!(".{}.", name.to_string_lossy()))
+            .suffix(TEMP_SUFFIX)
+            .tempfile_in(dir)
+            .map_err(writing)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::Permissions::from_mode(FILE_MODE);
+            temp.as_file().set_permissions(mode).map_err(writing)?;
+        }
+        let mut file = fs::File::from_std(temp.as_file().try_clone().map_err(writing)?);
+        tokio::io::copy(&mut new_encrypted_content, &mut file)
+            .await
+            .map_err(writing)?;
+        file.flush().await.map_err(writing)?;
+        file.sync_all().await.map_err(writing)?;
+        drop(file);
+        temp.persist(&path).map_err(|e| writing(e.error))?;
+
+        // Make the rename itself durable
+        #[cfg(unix)]
+        fs::File::open(dir)
+            .await
+            .map_err(writing)?
+            .sync_all()
+            .await
+            .map_err(writing)?;
+        log::debug!("wrote {}", path.display());
+
+        Ok(())
+    }
+
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error> {
+        let path = self.path(p)?;
+        match fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(FileStorageError::Removing(path, e)),
+        }
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
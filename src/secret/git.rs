@@ -0,0 +1,474 @@
+use std::ffi::{OsStr, OsString};
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+use super::filesystem::relative_path;
+use super::{ConditionalRead, ObjectMetadata, SecretError, SecretStorage};
+use crate::util::BoxedAsyncReader;
+use crate::IntoSecretStorage;
+
+/// How long the clone is trusted to be up to date for, so that a single
+/// command only fetches once, but `system mount --watch` still sees new
+/// commits.
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many times a change is committed and pushed, when someone else pushes
+/// to the branch first.
+const PUSH_ATTEMPTS: usize = 3;
+
+fn default_branch() -> String {
+    "main".to_string()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GitStorageConfig {
+    /// Repository to clone (anything `git clone` accepts)
+    url: String,
+    /// Branch secrets are read from and committed to
+    #[serde(default = "default_branch")]
+    branch: String,
+    /// Where to keep the clone (default: under ~/.cache/credible/git)
+    checkout: Option<PathBuf>,
+    /// Who commits are made by (default: git's `user.name` and `user.email`)
+    committer: Option<GitCommitter>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GitCommitter {
+    name: String,
+    email: String,
+}
+
+/// Storage in a git repository, where each object is a file at its path in
+/// the branch. Writes are committed and pushed straight away.
+pub struct GitSecretStorage {
+    url: String,
+    branch: String,
+    checkout: PathBuf,
+    committer: Option<GitCommitter>,
+    /// When the clone was last brought up to date with the remote. It's
+    /// locked for every operation, as they all share the clone's working
+    /// tree and index.
+    synced: Mutex<Option<Instant>>,
+}
+
+#[derive(Error, Debug)]
+pub enum GitStorageError {
+    #[error("{0} is outside of the repository")]
+    InvalidPath(PathBuf),
+    #[error("{0} doesn't exist")]
+    NotFound(PathBuf),
+    #[error("error running git: {0}")]
+    Running(std::io::Error),
+    #[error("git {command} failed: {stderr}")]
+    Git { command: String, stderr: String },
+    #[error("unexpected output from git {0}")]
+    UnexpectedOutput(String),
+    #[error("error writing {0}: {1}")]
+    Writing(PathBuf, std::io::Error),
+    #[error("error reading ciphertext to commit: {0}")]
+    ReadingContent(std::io::Error),
+    #[error("pushing was rejected {0} times, as the branch kept changing")]
+    PushRejected(usize),
+}
+
+impl SecretError for GitStorageError {
+    fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound(_))
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        const AUTH_ERRORS: [&str; 3] = [
+            "Permission denied",
+            "Authentication failed",
+            "could not read Username",
+        ];
+
+        match self {
+            Self::Running(e) if e.kind() == ErrorKind::NotFound => {
+                Some("git isn't installed (or isn't on PATH)")
+            }
+            Self::Git { stderr, .. } if stderr.contains("Please tell me who you are") => {
+                Some("set `committer` in the storage config, or git's user.name and user.email")
+            }
+            Self::Git { stderr, .. } if AUTH_ERRORS.iter().any(|e| stderr.contains(e)) => {
+                Some("check that this user can fetch from (or, to upload, push to) the repository")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Where a repository is cloned to by default, which is different for each
+/// repository.
+fn default_checkout(url: &str) -> PathBuf {
+    let base = match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".cache/credible/git"),
+        None => PathBuf::from("/var/cache/credible/git"),
+    };
+    let digest = format!("{:x}", Sha256::digest(url));
+
+    base.join(&digest[..16])
+}
+
+#[async_trait]
+impl IntoSecretStorage for GitStorageConfig {
+    type Error = GitStorageError;
+    type Impl = GitSecretStorage;
+
+    async fn build(self) -> Self::Impl {
+        GitSecretStorage {
+            checkout: self.checkout.unwrap_or_else(|| default_checkout(&self.url)),
+            url: self.url,
+            branch: self.branch,
+            committer: self.committer,
+            synced: Mutex::new(None),
+        }
+    }
+}
+
+fn failed(args: &[OsString], output: &Output) -> GitStorageError {
+    GitStorageError::Git {
+        command: args
+            .first()
+            .map(|a| a.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+    }
+}
+
+impl GitSecretStorage {
+    /// Git, set up to run in the clone.
+    fn command(&self) -> Command {
+        let mut command = Command::new("git");
+        command.arg("-C").arg(&self.checkout);
+        if let Some(committer) = &self.committer {
+            command
+                .arg("-c")
+                .arg(format!("user.name={}", committer.name))
+                .arg("-c")
+                .arg(format!("user.email={}", committer.email));
+        }
+        command
+            // Credentials can only come from helpers and keys, as there's
+            // nobody to prompt
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .kill_on_drop(true);
+
+        command
+    }
+
+    /// Runs git in the clone, whether or not it succeeds.
+    async fn run<I, S>(&self, args: I) -> Result<(Vec<OsString>, Output), GitStorageError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let args = args
+            .into_iter()
+            .map(|a| a.as_ref().to_owned())
+            .collect::<Vec<_>>();
+        log::debug!("running git {args:?}");
+        let output = self
+            .command()
+            .args(&args)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(GitStorageError::Running)?;
+
+        Ok((args, output))
+    }
+
+    /// Stores content as a blob in the clone's object database, returning its
+    /// id.
+    async fn hash_object(&self, content: &[u8]) -> Result<String, GitStorageError> {
+        let args = ["hash-object", "-w", "--stdin"];
+        log::debug!("running git {args:?}");
+        let mut child = self
+            .command()
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(GitStorageError::Running)?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // git only replies once it's read everything, so there's no need to
+        // read its output while writing
+        stdin
+            .write_all(content)
+            .await
+            .map_err(GitStorageError::Running)?;
+        drop(stdin);
+        let output = child
+            .wait_with_output()
+            .await
+            .map_err(GitStorageError::Running)?;
+        if !output.status.success() {
+            return Err(failed(&[OsString::from(args[0])], &output));
+        }
+
+        let blob = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        match blob.is_empty() {
+            true => Err(GitStorageError::UnexpectedOutput(args[0].to_string())),
+            false => Ok(blob),
+        }
+    }
+
+    /// Runs git in the clone, returning its output if it succeeds.
+    async fn git<I, S>(&self, args: I) -> Result<Vec<u8>, GitStorageError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let (args, output) = self.run(args).await?;
+        match output.status.success() {
+            true => Ok(output.stdout),
+            false => Err(failed(&args, &output)),
+        }
+    }
+
+    /// Brings the clone up to date with the branch (cloning it first, if
+    /// need be), unless that was recently done. Anything left behind by
+    /// failed changes is discarded.
+    async fn sync(&self, synced: &mut Option<Instant>, force: bool) -> Result<(), GitStorageError> {
+        if !force && synced.is_some_and(|t| t.elapsed() < SYNC_INTERVAL) {
+            return Ok(());
+        }
+
+        if self.checkout.join(".git").exists() {
+            self.git(["remote", "set-url", "origin", &self.url]).await?;
+        } else {
+            log::info!("cloning {} to {}", self.url, self.checkout.display());
+            fs::create_dir_all(&self.checkout)
+                .await
+                .map_err(|e| GitStorageError::Writing(self.checkout.clone(), e))?;
+            self.git(["init", "-q"]).await?;
+            self.git(["remote", "add", "origin", &self.url]).await?;
+        }
+
+        let (args, output) = self.run(["fetch", "-q", "origin", &self.branch]).await?;
+        if output.status.success() {
+            self.git(["checkout", "-q", "-f", "-B", &self.branch, "FETCH_HEAD"])
+                .await?;
+        } else if String::from_utf8_lossy(&output.stderr).contains("couldn't find remote ref") {
+            // Nothing's been pushed to the branch yet, so it's started afresh
+            // by the first write
+            log::info!("branch {} doesn't exist yet", self.branch);
+            let branch = format!("refs/heads/{}", self.branch);
+            self.git(["symbolic-ref", "HEAD", &branch]).await?;
+            self.git(["update-ref", "-d", &branch]).await?;
+            self.git(["read-tree", "--empty"]).await?;
+        } else {
+            return Err(failed(&args, &output));
+        }
+        self.git(["clean", "-q", "-f", "-d"]).await?;
+        *synced = Some(Instant::now());
+
+        Ok(())
+    }
+
+    fn relative(&self, p: &Path) -> Result<PathBuf, GitStorageError> {
+        relative_path(p).ok_or_else(|| GitStorageError::InvalidPath(p.to_owned()))
+    }
+
+    /// Id of the blob at the given path in the checked-out commit, if there
+    /// is one.
+    async fn blob(&self, relative: &Path) -> Result<Option<String>, GitStorageError> {
+        let spec = format!("HEAD:{}", relative.to_string_lossy());
+        let (args, output) = self.run(["rev-parse", "-q", "--verify", &spec]).await?;
+        match output.status.code() {
+            Some(0) => Ok(Some(
+                String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            )),
+            // Either the path or the branch doesn't exist
+            Some(1) => Ok(None),
+            _ => Err(failed(&args, &output)),
+        }
+    }
+
+    async fn read_blob(&self, p: &Path) -> Result<(String, Vec<u8>), GitStorageError> {
+        let blob = self
+            .blob(&self.relative(p)?)
+            .await?
+            .ok_or_else(|| GitStorageError::NotFound(p.to_owned()))?;
+        let data = self.git(["cat-file", "blob", &blob]).await?;
+
+        Ok((blob, data))
+    }
+
+    /// Commits what's staged, and pushes it. Returns whether the push was
+    /// accepted, which it isn't if someone else pushed first.
+    async fn commit_and_push(&self, message: &str) -> Result<bool, GitStorageError> {
+        let (_, unchanged) = self.run(["diff", "--cached", "--quiet"]).await?;
+        if unchanged.status.success() {
+            log::debug!("nothing to commit");
+            return Ok(true);
+        }
+        self.git(["commit", "-q", "-m", message]).await?;
+
+        let refspec = format!("HEAD:refs/heads/{}", self.branch);
+        let (args, output) = self.run(["push", "-q", "origin", &refspec]).await?;
+        if output.status.success() {
+            return Ok(true);
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.contains("[rejected]") || stderr.contains("non-fast-forward") {
+            true => {
+                log::info!("{} changed while committing, retrying", self.branch);
+                Ok(false)
+            }
+            false => Err(failed(&args, &output)),
+        }
+    }
+}
+
+#[async_trait]
+impl SecretStorage for GitSecretStorage {
+    type Error = GitStorageError;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        let mut synced = self.synced.lock().await;
+        self.sync(&mut synced, false).await?;
+        let (_, data) = self.read_blob(p).await?;
+
+        Ok(BoxedAsyncReader::from_async_read(Cursor::new(data)))
+    }
+
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        let mut synced = self.synced.lock().await;
+        self.sync(&mut synced, false).await?;
+        // Blob ids change exactly when the content does
+        let relative = self.relative(p)?;
+        if let Some(blob) = self.blob(&relative).await? {
+            if Some(blob.as_str()) == version {
+                return Ok(ConditionalRead::Unchanged);
+            }
+        }
+        let (blob, data) = self.read_blob(p).await?;
+
+        Ok(ConditionalRead::Changed {
+            reader: BoxedAsyncReader::from_async_read(Cursor::new(data)),
+            version: Some(blob),
+        })
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        let mut synced = self.synced.lock().await;
+        self.sync(&mut synced, false).await?;
+        let relative = self.relative(p)?;
+        let blob = self
+            .blob(&relative)
+            .await?
+            .ok_or_else(|| GitStorageError::NotFound(p.to_owned()))?;
+
+        let size = self.git(["cat-file", "-s", &blob]).await?;
+        let size = String::from_utf8_lossy(&size)
+            .trim()
+            .parse()
+            .map_err(|_| GitStorageError::UnexpectedOutput("cat-file".to_string()))?;
+        // When it was last committed
+        let time = self
+            .git([
+                OsStr::new("log"),
+                OsStr::new("-1"),
+                OsStr::new("--format=%ct"),
+                OsStr::new("HEAD"),
+                OsStr::new("--"),
+                relative.as_os_str(),
+            ])
+            .await?;
+        let last_modified = String::from_utf8_lossy(&time)
+            .trim()
+            .parse()
+            .ok()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+
+        Ok(ObjectMetadata {
+            size,
+            last_modified,
+        })
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        mut new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        let relative = self.relative(p)?;
+        // Kept, so that it can be committed again if pushing is rejected
+        let mut content = Vec::new();
+        new_encrypted_content
+            .read_to_end(&mut content)
+            .await
+            .map_err(GitStorageError::ReadingContent)?;
+
+        let mut synced = self.synced.lock().await;
+        let message = format!("Update {}", relative.to_string_lossy());
+        for _ in 0..PUSH_ATTEMPTS {
+            // Always committed on top of the latest commit, so that pushing
+            // only fails if someone else pushes meanwhile
+            self.sync(&mut synced, true).await?;
+            // Staged without going through the working tree, which anyone who
+            // can push could have filled with symlinks pointing out of it
+            let blob = self.hash_object(&content).await?;
+            self.git([
+                OsStr::new("update-index"),
+                OsStr::new("--add"),
+                OsStr::new("--cacheinfo"),
+                OsStr::new("100644"),
+                OsStr::new(&blob),
+                relative.as_os_str(),
+            ])
+            .await?;
+
+            if self.commit_and_push(&message).await? {
+                log::debug!("committed {}", relative.display());
+                return Ok(());
+            }
+        }
+
+        Err(GitStorageError::PushRejected(PUSH_ATTEMPTS))
+    }
+
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error> {
+        let relative = self.relative(p)?;
+        let mut synced = self.synced.lock().await;
+        let message = format!("Remove {}", relative.to_string_lossy());
+        for _ in 0..PUSH_ATTEMPTS {
+            self.sync(&mut synced, true).await?;
+            if self.blob(&relative).await?.is_none() {
+                return Ok(());
+            }
+            self.git([
+                OsStr::new("rm"),
+                OsStr::new("-q"),
+                OsStr::new("--"),
+                relative.as_os_str(),
+            ])
+            .await?;
+
+            if self.commit_and_push(&message).await? {
+                return Ok(());
+            }
+        }
+
+        Err(GitStorageError::PushRejected(PUSH_ATTEMPTS))
+    }
+}
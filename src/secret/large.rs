@@ -0,0 +1,337 @@
+//! Support for secrets too large to comfortably hold in memory (e.g. model
+//! bundles). Past a configurable size, ciphertext that has to be read in full
+//! is spooled to disk instead of memory, file exposures are streamed straight
+//! to their destination, and environment exposures are refused.
+
+use std::fmt::Display;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::age::{encrypt, EncryptionError};
+use crate::secret::CiphertextPin;
+use crate::util::BoxedAsyncReader;
+
+/// Size above which secrets are treated as large, unless configured
+/// otherwise.
+pub const DEFAULT_LARGE_SECRET_THRESHOLD: u64 = 64 << 20;
+
+/// How often to report progress on large secrets.
+const PROGRESS_INTERVAL: u64 = 256 << 20;
+
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// A size in bytes, written either as a plain number or with a binary unit
+/// (e.g. `512KiB`, `64MiB`, `2GiB`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number = number
+            .parse::<u64>()
+            .map_err(|_| format!("invalid size: {s}"))?;
+        let multiplier = match unit.trim() {
+            "" | "B" => 1,
+            "K" | "KiB" => 1 << 10,
+            "M" | "MiB" => 1 << 20,
+            "G" | "GiB" => 1 << 30,
+            unit => {
+                return Err(format!(
+                    "unknown size unit {unit} (expected KiB, MiB or GiB)"
+                ))
+            }
+        };
+
+        number
+            .checked_mul(multiplier)
+            .map(Self)
+            .ok_or_else(|| format!("size too large: {s}"))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(n) => Ok(Self(n)),
+            Raw::Text(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (unit, size) in [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)] {
+            if self.0 >= size {
+                return write!(f, "{:.1}{unit}", self.0 as f64 / size as f64);
+            }
+        }
+        write!(f, "{}B", self.0)
+    }
+}
+
+/// Logs how far through a large secret we are.
+pub(crate) struct Progress {
+    name: String,
+    done: u64,
+    next_report: u64,
+    started: Instant,
+}
+
+impl Progress {
+    pub(crate) fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            done: 0,
+            next_report: PROGRESS_INTERVAL,
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn advance(&mut self, n: usize) {
+        self.done += n as u64;
+        if self.done >= self.next_report {
+            log::info!("{}: {} so far", self.name, ByteSize(self.done));
+            self.next_report += PROGRESS_INTERVAL;
+        }
+    }
+
+    /// Reports the total, if it was large enough for progress to have been
+    /// reported along the way.
+    pub(crate) fn finish(&self) {
+        if self.done >= PROGRESS_INTERVAL {
+            log::info!(
+                "{}: {} in {:.1}s",
+                self.name,
+                ByteSize(self.done),
+                self.started.elapsed().as_secs_f64()
+            );
+        }
+    }
+}
+
+/// Reader that reports progress through what it reads.
+pub(crate) struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+}
+
+impl<R> ProgressReader<R> {
+    pub(crate) fn new(inner: R, name: &str) -> Self {
+        Self {
+            inner,
+            progress: Progress::new(name),
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        self.progress.finish()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.progress.advance(read);
+        res
+    }
+}
+
+/// Content that has to be read in full before it can be used (e.g. to check
+/// its digest), held in memory unless it's large, in which case it's spooled
+/// to an anonymous temp file. Only ever used for ciphertext.
+pub struct Spooled {
+    content: Content,
+    size: u64,
+    digest: CiphertextPin,
+}
+
+enum Content {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+/// Builds up [Spooled] content from one or more readers.
+pub(crate) struct Spool {
+    content: Content,
+    len: u64,
+    hasher: Sha256,
+    progress: Progress,
+    threshold: u64,
+}
+
+impl Spool {
+    /// Starts spooling, to disk once there's more than `threshold` bytes.
+    pub(crate) fn new(name: &str, threshold: u64) -> Self {
+        Self {
+            content: Content::Memory(Vec::new()),
+            len: 0,
+            hasher: Sha256::new(),
+            progress: Progress::new(name),
+            threshold,
+        }
+    }
+
+    /// Adds everything from the given reader.
+    pub(crate) async fn append<R>(&mut self, mut reader: R) -> Result<(), std::io::Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.hasher.update(&chunk[..n]);
+            self.progress.advance(n);
+            self.len += n as u64;
+
+            match &mut self.content {
+                Content::File(file) => file.write_all(&chunk[..n]).await?,
+                Content::Memory(buf) => {
+                    buf.extend_from_slice(&chunk[..n]);
+                    if buf.len() as u64 > self.threshold {
+                        log::debug!(
+                            "{} is larger than {}, spooling it to disk",
+                            self.progress.name,
+                            ByteSize(self.threshold)
+                        );
+                        let mut file = File::from_std(tempfile::tempfile()?);
+                        file.write_all(buf).await?;
+                        self.content = Content::File(file);
+                    }
+                }
+            }
+        }
+    }
+
+    /// How much has been spooled so far.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// The spooled content, along with its digest.
+    pub(crate) fn finish(self) -> (Spooled, CiphertextPin) {
+        self.progress.finish();
+        let digest = CiphertextPin::Sha256(format!("{:x}", self.hasher.finalize()));
+        let spooled = Spooled {
+            content: self.content,
+            size: self.len,
+            digest: digest.clone(),
+        };
+        (spooled, digest)
+    }
+}
+
+impl From<Vec<u8>> for Spooled {
+    fn from(buf: Vec<u8>) -> Self {
+        Self {
+            size: buf.len() as u64,
+            digest: CiphertextPin::sha256(&buf),
+            content: Content::Memory(buf),
+        }
+    }
+}
+
+impl Spooled {
+    /// Reads everything from the given reader (spooling it to disk past
+    /// `threshold` bytes), returning it along with its digest.
+    pub(crate) async fn read<R>(
+        reader: R,
+        name: &str,
+        threshold: u64,
+    ) -> Result<(Self, CiphertextPin), std::io::Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut spool = Spool::new(name, threshold);
+        spool.append(reader).await?;
+        Ok(spool.finish())
+    }
+
+    /// Encrypts plaintext to the given recipients as it's read, spooling the
+    /// ciphertext (to disk past `threshold` bytes), so that large secrets can
+    /// be uploaded without holding them in memory.
+    pub(crate) async fn encrypt<R>(
+        plaintext: R,
+        recipients: &[String],
+        name: &str,
+        threshold: u64,
+    ) -> Result<Self, EncryptionError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        // Either side finishing (or failing) closes its end of the pipe, so
+        // the other never waits on it forever
+        let (writer, reader) = tokio::io::duplex(CHUNK_SIZE);
+        let mut spool = Spool::new(name, threshold);
+        let (encrypted, spooled) =
+            tokio::join!(encrypt(plaintext, recipients, writer), spool.append(reader));
+        encrypted?;
+        spooled.map_err(EncryptionError::WritingOutput)?;
+
+        Ok(spool.finish().0)
+    }
+
+    /// Size of the content, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// SHA-256 digest of the content.
+    pub fn digest(&self) -> &CiphertextPin {
+        &self.digest
+    }
+
+    /// Copies the content to the given writer.
+    pub(crate) async fn copy_to<W>(&mut self, writer: &mut W) -> Result<(), std::io::Error>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match &mut self.content {
+            Content::Memory(buf) => writer.write_all(buf).await,
+            Content::File(file) => {
+                file.seek(SeekFrom::Start(0)).await?;
+                tokio::io::copy(file, writer).await.map(|_| ())
+            }
+        }
+    }
+
+    pub(crate) async fn into_reader(self) -> Result<BoxedAsyncReader, std::io::Error> {
+        match self.content {
+            Content::Memory(buf) => {
+                Ok(BoxedAsyncReader::from_async_read(std::io::Cursor::new(buf)))
+            }
+            Content::File(mut file) => {
+                file.seek(SeekFrom::Start(0)).await?;
+                Ok(BoxedAsyncReader::from_async_read(file))
+            }
+        }
+    }
+}
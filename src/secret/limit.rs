@@ -0,0 +1,254 @@
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::secret::{ConditionalRead, ObjectMetadata, SecretStorage};
+use crate::util::BoxedAsyncReader;
+use crate::IntoSecretStorage;
+
+/// Limits on how hard we hit the storage backend, so that many hosts
+/// (re)mounting at once don't get throttled.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimits {
+    /// Most requests in flight at once
+    #[serde(alias = "maxConcurrent")]
+    pub max_concurrent: Option<usize>,
+    /// Sustained requests per second
+    #[serde(alias = "requestsPerSecond")]
+    pub requests_per_second: Option<f64>,
+    /// Requests that can be made back-to-back before being held to
+    /// `requests_per_second` (default: one second's worth)
+    pub burst: Option<u32>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket for request rate.
+struct RateBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<Bucket>,
+}
+
+impl RateBucket {
+    fn new(rate: f64, burst: Option<u32>) -> Self {
+        let capacity = burst.map(f64::from).unwrap_or(rate).max(1.0);
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new(Bucket {
+                tokens: capacity,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token if one's available, otherwise returns how long until
+    /// one will be.
+    fn try_take(&self) -> Result<(), Duration> {
+        let mut bucket = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+
+    async fn take(&self) {
+        while let Err(wait) = self.try_take() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Enforces [RateLimits] across every request made through it. Can be shared
+/// between several storages (e.g. to give an application one budget for all
+/// of them).
+pub struct RateLimiter {
+    concurrency: Option<Arc<Semaphore>>,
+    rate: Option<RateBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            concurrency: limits
+                .max_concurrent
+                .map(|n| Arc::new(Semaphore::new(n.max(1)))),
+            rate: limits
+                .requests_per_second
+                .filter(|r| *r > 0.0)
+                .map(|r| RateBucket::new(r, limits.burst)),
+        }
+    }
+
+    /// Waits until a request is allowed. The request counts towards the
+    /// concurrency limit until the returned permit is dropped.
+    pub async fn acquire(&self) -> RequestPermit {
+        let permit = match &self.concurrency {
+            Some(semaphore) => Some(
+                semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("rate limiter semaphore is never closed"),
+            ),
+            None => None,
+        };
+        if let Some(bucket) = &self.rate {
+            bucket.take().await;
+        }
+
+        RequestPermit { _permit: permit }
+    }
+}
+
+/// Permission to make a request, from [RateLimiter::acquire].
+pub struct RequestPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Reader that holds its request's permit until it's dropped, so that
+/// downloads still in progress count as in flight.
+struct PermitReader {
+    inner: BoxedAsyncReader,
+    _permit: RequestPermit,
+}
+
+impl AsyncRead for PermitReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+/// Wraps another [SecretStorage], holding every request to a [RateLimiter].
+pub struct LimitedSecretStorage<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S: SecretStorage> LimitedSecretStorage<S> {
+    pub fn new(inner: S, limiter: Arc<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+#[async_trait]
+impl<S> SecretStorage for LimitedSecretStorage<S>
+where
+    S: SecretStorage + Sync + Send,
+    <S as SecretStorage>::Error: Send,
+{
+    type Error = S::Error;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        let permit = self.limiter.acquire().await;
+        let inner = self.inner.read(p).await?;
+        Ok(BoxedAsyncReader::from_async_read(PermitReader {
+            inner,
+            _permit: permit,
+        }))
+    }
+
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        let permit = self.limiter.acquire().await;
+        match self.inner.read_if_changed(p, version).await? {
+            ConditionalRead::Unchanged => Ok(ConditionalRead::Unchanged),
+            ConditionalRead::Changed { reader, version } => Ok(ConditionalRead::Changed {
+                reader: BoxedAsyncReader::from_async_read(PermitReader {
+                    inner: reader,
+                    _permit: permit,
+                }),
+                version,
+            }),
+        }
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        let _permit = self.limiter.acquire().await;
+        self.inner.metadata(p).await
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        let _permit = self.limiter.acquire().await;
+        self.inner.write(p, new_encrypted_content).await
+    }
+
+    fn write_if_unchanged<'a, R: AsyncRead + Send + Unpin + 'a>(
+        &'a self,
+        p: &'a Path,
+        new_encrypted_content: R,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            let _permit = self.limiter.acquire().await;
+            self.inner
+                .write_if_unchanged(p, new_encrypted_content, version)
+                .await
+        })
+    }
+
+    fn conditional_writes(&self) -> bool {
+        self.inner.conditional_writes()
+    }
+
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error> {
+        let _permit = self.limiter.acquire().await;
+        self.inner.delete(p).await
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        self.inner.local_root()
+    }
+}
+
+/// Storage config with requests held to [RateLimits].
+pub struct LimitedStorageConfig<C> {
+    pub inner: C,
+    pub limits: RateLimits,
+}
+
+#[async_trait]
+impl<C> IntoSecretStorage for LimitedStorageConfig<C>
+where
+    C: IntoSecretStorage + Send,
+    C::Impl: Sync + Send,
+    C::Error: Send,
+{
+    type Error = C::Error;
+    type Impl = LimitedSecretStorage<C::Impl>;
+
+    async fn build(self) -> Self::Impl {
+        let limiter = Arc::new(RateLimiter::new(self.limits));
+        LimitedSecretStorage::new(self.inner.build().await, limiter)
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{CiphertextPin, ConditionalRead, ObjectMetadata, SecretError, SecretStorage};
+use crate::util::BoxedAsyncReader;
+use crate::IntoSecretStorage;
+
+#[derive(thiserror::Error, Debug)]
+pub enum MemoryStorageError {
+    #[error("no object at {0}")]
+    NotFound(PathBuf),
+    #[error("error reading new content: {0}")]
+    ReadingContent(std::io::Error),
+}
+
+impl SecretError for MemoryStorageError {
+    fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound(_))
+    }
+}
+
+/// Storage holding ciphertext in memory, for tests. Objects' versions are
+/// their digests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores an object directly, without going through [SecretStorage].
+    pub fn insert(&self, p: PathBuf, ciphertext: Vec<u8>) {
+        self.objects.lock().unwrap().insert(p, ciphertext);
+    }
+
+    /// The object currently stored at the given path, if any.
+    pub fn get(&self, p: &Path) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(p).cloned()
+    }
+}
+
+#[async_trait]
+impl IntoSecretStorage for MemoryStorage {
+    type Error = MemoryStorageError;
+    type Impl = MemoryStorage;
+
+    async fn build(self) -> Self::Impl {
+        self
+    }
+}
+
+#[async_trait]
+impl SecretStorage for MemoryStorage {
+    type Error = MemoryStorageError;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        let data = self
+            .get(p)
+            .ok_or_else(|| MemoryStorageError::NotFound(p.to_owned()))?;
+        Ok(BoxedAsyncReader::from_async_read(Cursor::new(data)))
+    }
+
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        let data = self
+            .get(p)
+            .ok_or_else(|| MemoryStorageError::NotFound(p.to_owned()))?;
+        let current = CiphertextPin::sha256(&data).to_string();
+        if version == Some(&current) {
+            return Ok(ConditionalRead::Unchanged);
+        }
+
+        Ok(ConditionalRead::Changed {
+            reader: BoxedAsyncReader::from_async_read(Cursor::new(data)),
+            version: Some(current),
+        })
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        let data = self
+            .get(p)
+            .ok_or_else(|| MemoryStorageError::NotFound(p.to_owned()))?;
+        Ok(ObjectMetadata {
+            size: data.len() as u64,
+            last_modified: None,
+        })
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        mut new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        let mut data = Vec::new();
+        new_encrypted_content
+            .read_to_end(&mut data)
+            .await
+            .map_err(MemoryStorageError::ReadingContent)?;
+        self.insert(p.to_owned(), data);
+
+        Ok(())
+    }
+
+    fn write_if_unchanged<'a, R: AsyncRead + Send + Unpin + 'a>(
+        &'a self,
+        p: &'a Path,
+        mut new_encrypted_content: R,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            let mut data = Vec::new();
+            new_encrypted_content
+                .read_to_end(&mut data)
+                .await
+                .map_err(MemoryStorageError::ReadingContent)?;
+
+            let mut objects = self.objects.lock().unwrap();
+            let current = objects.get(p).map(|d| CiphertextPin::sha256(d).to_string());
+            if current.as_deref() != Some(version) {
+                return Ok(false);
+            }
+            objects.insert(p.to_owned(), data);
+
+            Ok(true)
+        })
+    }
+
+    fn conditional_writes(&self) -> bool {
+        true
+    }
+
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error> {
+        self.objects.lock().unwrap().remove(p);
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+
+use crate::secret::{ConditionalRead, ObjectMetadata, SecretError, SecretStorage};
+use crate::util::BoxedAsyncReader;
+use crate::{IntoSecretStorage, StorageConfig};
+
+/// Moving from one storage backend to another, without a flag day.
+#[derive(Deserialize, Debug)]
+pub struct MigrationConfig {
+    /// Backend secrets are being moved off of, which is only read from
+    pub from: Box<StorageConfig>,
+    /// Backend secrets are being moved to, which all writes go to
+    pub to: Box<StorageConfig>,
+}
+
+/// Wraps the storage secrets are being moved to, reading objects it doesn't
+/// have yet from the storage they're being moved from.
+///
+/// Writes only go to the new storage, and removals go to both (so that a
+/// removed secret doesn't come back from the old one). Without any old
+/// storage, everything is passed straight through.
+pub struct MigratingSecretStorage<S> {
+    to: S,
+    from: Option<S>,
+}
+
+impl<S> MigratingSecretStorage<S> {
+    pub fn new(to: S, from: Option<S>) -> Self {
+        Self { to, from }
+    }
+
+    /// Storage secrets are being moved to.
+    pub fn to(&self) -> &S {
+        &self.to
+    }
+
+    /// Storage secrets are being moved from, if they're being moved.
+    pub fn from(&self) -> Option<&S> {
+        self.from.as_ref()
+    }
+}
+
+#[async_trait]
+impl<S> SecretStorage for MigratingSecretStorage<S>
+where
+    S: SecretStorage + Sync + Send,
+    <S as SecretStorage>::Error: Send,
+{
+    type Error = S::Error;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        match (self.to.read(p).await, &self.from) {
+            (Err(e), Some(from)) if e.is_not_found() => {
+                log::debug!(
+                    "{} not migrated yet, reading old storage",
+                    p.to_string_lossy()
+                );
+                from.read(p).await
+            }
+            (res, _) => res,
+        }
+    }
+
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        // Versions from the old storage mean nothing to the new one, so a
+        // secret that's migrated in between is always seen as changed
+        match (self.to.read_if_changed(p, version).await, &self.from) {
+            (Err(e), Some(from)) if e.is_not_found() => from.read_if_changed(p, version).await,
+            (res, _) => res,
+        }
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        match (self.to.metadata(p).await, &self.from) {
+            (Err(e), Some(from)) if e.is_not_found() => from.metadata(p).await,
+            (res, _) => res,
+        }
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        self.to.write(p, new_encrypted_content).await
+    }
+
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error> {
+        self.to.delete(p).await?;
+        match &self.from {
+            Some(from) => from.delete(p).await,
+            None => Ok(()),
+        }
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        self.to.local_root()
+    }
+}
+
+/// Storage config for the backend secrets are being moved to, and the one
+/// they're being moved from, if any.
+pub struct MigratingStorageConfig<C> {
+    pub to: C,
+    pub from: Option<C>,
+}
+
+#[async_trait]
+impl<C> IntoSecretStorage for MigratingStorageConfig<C>
+where
+    C: IntoSecretStorage + Send,
+    C::Impl: Sync + Send,
+    C::Error: Send,
+{
+    type Error = C::Error;
+    type Impl = MigratingSecretStorage<C::Impl>;
+
+    async fn build(self) -> Self::Impl {
+        let from = match self.from {
+            Some(from) => Some(from.build().await),
+            None => None,
+        };
+
+        MigratingSecretStorage::new(self.to.build().await, from)
+    }
+}
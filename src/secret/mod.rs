@@ -1,32 +1,116 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::Cursor;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::TryFutureExt;
 use serde::Deserialize;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
+use crate::age::{dearmor_ciphertext, decrypt_bytes, get_identities, DecryptionError, HeaderError};
+use crate::signals::shielded;
 use crate::util::BoxedAsyncReader;
 use crate::wrappers::{GroupWrapper, UserWrapper};
 
 mod process;
 pub use process::*;
 
+mod cache;
+pub use cache::*;
+
+mod pin;
+pub use pin::*;
+
+mod signature;
+pub use signature::*;
+
+mod approval;
+pub use approval::*;
+
+mod recipients;
+pub use recipients::*;
+
+mod registry;
+pub use registry::*;
+
+mod previous;
+pub(crate) use previous::keep_previous;
+pub use previous::{restore_previous, KeepPrevious, PreviousVersionError, PREVIOUS_SUFFIX};
+
 mod file;
 pub use file::*;
 
+mod acl;
+pub use acl::*;
+
+mod tag;
+pub use tag::*;
+
+mod credentials;
+pub use credentials::*;
+
 mod s3;
 pub use s3::*;
 
+mod azure;
+pub use azure::*;
+
+mod filesystem;
+pub use filesystem::*;
+
+mod git;
+pub use git::*;
+
+mod backend;
+pub use backend::*;
+
+mod dev;
+pub use dev::*;
+
+mod record;
+pub use record::*;
+
+mod limit;
+pub use limit::*;
+
+mod large;
+pub use large::*;
+
+mod chunked;
+pub use chunked::*;
+
+mod migration;
+pub use migration::*;
+
+#[cfg(feature = "test-env")]
+mod memory;
+#[cfg(feature = "test-env")]
+pub use memory::*;
+
 mod exposures;
 pub use exposures::*;
 
-#[derive(Deserialize, Debug, Clone)]
+mod template;
+pub use template::*;
+
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct Secret {
     pub name: String,
+    /// Public keys to encrypt to, or `group:<name>` to encrypt to every
+    /// member of a key group in the recipients registry
     #[serde(alias = "encryptionKeys")]
     pub encryption_keys: Vec<String>,
 
     // TODO: Will this be fine for all providers?
+    #[serde(default)]
     pub path: PathBuf,
+    /// ASCII-armored ciphertext, used instead of reading it from storage, for
+    /// small secrets that are needed before storage can be reached (like its
+    /// own credentials). Secrets with a value have no path.
+    pub value: Option<String>,
     #[serde(alias = "mountPath")]
     pub mount_path: Option<PathBuf>,
 
@@ -34,6 +118,99 @@ pub struct Secret {
     pub owner_user: Option<UserWrapper>,
     #[serde(alias = "ownerGroup")]
     pub owner_group: Option<GroupWrapper>,
+
+    /// Expected digest of the ciphertext, verified on every read
+    pub pin: Option<CiphertextPin>,
+
+    /// SSH public keys trusted to sign this secret. If any are given, a valid
+    /// signature is required before the secret is used.
+    #[serde(default, alias = "signingKeys")]
+    pub signing_keys: Vec<String>,
+
+    /// Identity files to decrypt this secret with, instead of every identity
+    /// available. Relative paths are resolved against the config file this
+    /// secret is defined in.
+    #[serde(default)]
+    pub identities: Vec<PathBuf>,
+
+    /// Shell command to run after this secret's exposures are refreshed in
+    /// watch mode (e.g. to restart the service that uses it)
+    #[serde(alias = "onChange")]
+    pub on_change: Option<String>,
+
+    /// Users (by uid) that may read this secret from `credible serve`, in
+    /// addition to its owner
+    #[serde(default, alias = "allowedUids")]
+    pub allowed_uids: Vec<u32>,
+    /// Groups (by gid) whose members may read this secret from `credible
+    /// serve`, in addition to its owning group. Supplementary members are
+    /// looked up in the group database
+    #[serde(default, alias = "allowedGids")]
+    pub allowed_gids: Vec<u32>,
+
+    /// Free-form information about this secret (e.g. `description`, `owner`,
+    /// `runbook`), shown by `secret list`, `secret inspect` and `secret audit`
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+
+    /// Whether this secret is being phased out. Exposing it logs a warning,
+    /// and `secret audit` reports whatever still uses it.
+    #[serde(default)]
+    pub deprecated: bool,
+    /// Secret to use instead of this one, once it's deprecated
+    #[serde(alias = "replacedBy")]
+    pub replaced_by: Option<String>,
+
+    /// Config file this secret was loaded from, if any
+    #[serde(skip)]
+    pub defined_in: Option<PathBuf>,
+    /// Tenant this secret belongs to, if it was defined under `tenants`
+    #[serde(skip)]
+    pub tenant: Option<String>,
+}
+
+/// Log target for details of each request storage backends make, which are
+/// only shown with `--debug-storage`.
+pub const STORAGE_LOG_TARGET: &str = "storage";
+
+/// Objects that may be stored alongside a secret's ciphertext.
+pub const SIDECAR_SUFFIXES: [&str; 5] = [
+    SIGNATURE_SUFFIX,
+    RECIPIENTS_SUFFIX,
+    // Previous versions of the ciphertext and the objects above, kept when
+    // it's overwritten
+    PREVIOUS_SUFFIX,
+    "sig.bak",
+    "recipients.bak",
+];
+
+/// Path of an object stored alongside the ciphertext at the given path (e.g.
+/// `sample` -> `sample.sig`).
+pub fn sidecar_path(p: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(p.as_os_str());
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Information about a stored object, without its content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMetadata {
+    pub size: u64,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// Result of [SecretStorage::read_if_changed].
+pub enum ConditionalRead {
+    /// The object is still at the version that was given
+    Unchanged,
+    /// The object has changed (or its version couldn't be compared)
+    Changed {
+        reader: BoxedAsyncReader,
+        /// Opaque version of the object (e.g. an S3 ETag), if the storage
+        /// has them
+        version: Option<String>,
+    },
 }
 
 #[async_trait]
@@ -41,11 +218,392 @@ pub trait SecretStorage {
     type Error: SecretError;
 
     async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error>;
+    /// Reads the object at the given path, unless it's still at `version`
+    /// (from an earlier conditional read). Storage without versions always
+    /// reads it.
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        _version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error>
+    where
+        Self: Sync,
+    {
+        let reader = self.read(p).await?;
+        Ok(ConditionalRead::Changed {
+            reader,
+            version: None,
+        })
+    }
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error>;
     async fn write<R: AsyncRead + Send + Unpin>(
         &self,
         p: &Path,
         new_encrypted_content: R,
     ) -> Result<(), Self::Error>;
+    /// Writes the object at the given path, unless it's no longer at `version`
+    /// (from an earlier conditional read), returning whether it was written.
+    /// Only storage with [SecretStorage::conditional_writes] checks the
+    /// version; the rest always write it.
+    ///
+    /// Unlike the other methods, this returns a boxed future itself, so that
+    /// storage doesn't have to be `Sync` to write unconditionally.
+    fn write_if_unchanged<'a, R: AsyncRead + Send + Unpin + 'a>(
+        &'a self,
+        p: &'a Path,
+        new_encrypted_content: R,
+        _version: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Self::Error>> {
+        Box::pin(self.write(p, new_encrypted_content).map_ok(|()| true))
+    }
+    /// Whether [SecretStorage::write_if_unchanged] checks the version as part
+    /// of the write, so that changes made since it was read are never
+    /// overwritten.
+    fn conditional_writes(&self) -> bool {
+        false
+    }
+    /// Removes the object at the given path. Removing an object that doesn't
+    /// exist succeeds.
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error>;
+    /// Local directory that objects are stored in (at their paths), for
+    /// storage that can be watched for changes instead of polled.
+    fn local_root(&self) -> Option<&Path> {
+        None
+    }
+}
+
+pub trait SecretError: std::error::Error {
+    /// Whether this error means the object doesn't exist in storage (rather
+    /// than that it couldn't be fetched).
+    fn is_not_found(&self) -> bool {
+        false
+    }
+
+    /// How to fix this error, if it's one of the common ways storage is
+    /// misconfigured (e.g. missing credentials).
+    fn hint(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReadSecretError<E: SecretError> {
+    #[error("{0}")]
+    Storage(E),
+    #[error("error reading ciphertext: {0}")]
+    ReadingCiphertext(std::io::Error),
+    #[error("error decoding inline value of {0}: {1}")]
+    DecodingInline(String, HeaderError),
+    #[error("ciphertext for {0} does not match pin (expected {1}, got {2})")]
+    PinMismatch(String, CiphertextPin, CiphertextPin),
+    #[error("error fetching signature for {0}: {1}")]
+    FetchingSignature(String, E),
+    #[error("error reading signature for {0}: {1}")]
+    ReadingSignature(String, std::io::Error),
+    #[error("signature verification failed for {0}: {1}")]
+    VerifyingSignature(String, VerificationError),
+}
+
+impl<E: SecretError> ReadSecretError<E> {
+    /// Whether the secret doesn't exist in storage.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::Storage(e) if e.is_not_found())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WriteSecretError<E: SecretError> {
+    #[error("{0}")]
+    Storage(E),
+    #[error("secret {0} requires a signature, but no signing key was given")]
+    SignatureRequired(String),
+    #[error("{0} is inline in config, replace its value with the output of `credible secret encrypt --armor {0}` instead")]
+    Inline(String),
+    #[error("error signing {0}: {1}")]
+    Signing(String, SigningError),
+    #[error("error encoding recipients record: {0}")]
+    EncodingRecipients(serde_yaml::Error),
+    #[error("error keeping previous version: {0}")]
+    KeepingPrevious(PreviousVersionError<E>),
+    #[error("error reading spooled ciphertext: {0}")]
+    ReadingCiphertext(std::io::Error),
+    #[error("wrote {1} bytes of ciphertext for {0}, but storage has {2}")]
+    SizeMismatch(String, u64, u64),
+    #[error("error listening for signals: {0}")]
+    ListeningForSignals(std::io::Error),
+    #[error("received signal {1} while writing {0} (which was finished first)")]
+    Interrupted(String, i32),
+    #[error("{0} was changed by someone else while it was being written")]
+    Changed(String),
+}
+
+/// Decrypts a secret's ciphertext, with the identities it names if it has any,
+/// or otherwise any of the given ones.
+pub async fn decrypt_secret<R>(
+    ciphertext: R,
+    secret: &Secret,
+    identities: &[Box<dyn age::Identity>],
+) -> Result<BoxedAsyncReader, DecryptionError>
+where
+    R: AsyncRead + Unpin + Sized + Send + 'static,
+{
+    if secret.identities.is_empty() {
+        return decrypt_bytes(ciphertext, identities).await;
+    }
+
+    let base = secret.defined_in.as_deref().and_then(Path::parent);
+    let paths = secret
+        .identities
+        .iter()
+        .map(|p| base.map(|b| b.join(p)).unwrap_or_else(|| p.clone()))
+        .collect::<Vec<_>>();
+    log::debug!("decrypting {} with {} identities", secret.name, paths.len());
+    decrypt_bytes(ciphertext, &get_identities(&paths)?).await
+}
+
+/// Reads the ciphertext of a secret from storage, verifying it against the
+/// secret's pinned digest (if it has one). Inline values are trusted as much
+/// as the rest of config, so they aren't verified.
+///
+/// Ciphertext that has to be read in full to be verified is spooled to disk
+/// past `threshold` bytes.
+pub async fn read_secret<S: SecretStorage>(
+    storage: &S,
+    secret: &Secret,
+    threshold: u64,
+) -> Result<BoxedAsyncReader, ReadSecretError<S::Error>> {
+    if let Some(value) = &secret.value {
+        return inline_reader(secret, value);
+    }
+    let reader = storage
+        .read(&secret.path)
+        .await
+        .map_err(ReadSecretError::Storage)?;
+
+    verify_secret(storage, secret, reader, threshold).await
+}
+
+/// Like [read_secret], but skips fetching ciphertext that's still at the
+/// given version.
+pub async fn read_secret_if_changed<S: SecretStorage + Sync>(
+    storage: &S,
+    secret: &Secret,
+    version: Option<&str>,
+    threshold: u64,
+) -> Result<ConditionalRead, ReadSecretError<S::Error>> {
+    if let Some(value) = &secret.value {
+        let current = CiphertextPin::sha256(value.as_bytes()).to_string();
+        return Ok(match version == Some(&current) {
+            true => ConditionalRead::Unchanged,
+            false => ConditionalRead::Changed {
+                reader: inline_reader(secret, value)?,
+                version: Some(current),
+            },
+        });
+    }
+    let read = storage
+        .read_if_changed(&secret.path, version)
+        .await
+        .map_err(ReadSecretError::Storage)?;
+
+    match read {
+        ConditionalRead::Unchanged => Ok(ConditionalRead::Unchanged),
+        ConditionalRead::Changed { reader, version } => Ok(ConditionalRead::Changed {
+            reader: verify_secret(storage, secret, reader, threshold).await?,
+            version,
+        }),
+    }
 }
 
-pub trait SecretError: std::error::Error {}
+/// Reads an inline value's ciphertext.
+fn inline_reader<E: SecretError>(
+    secret: &Secret,
+    value: &str,
+) -> Result<BoxedAsyncReader, ReadSecretError<E>> {
+    let ciphertext = dearmor_ciphertext(value)
+        .map_err(|e| ReadSecretError::DecodingInline(secret.name.clone(), e))?;
+    Ok(BoxedAsyncReader::from_async_read(Cursor::new(ciphertext)))
+}
+
+/// Checks freshly-read ciphertext against the secret's pin and signing keys.
+async fn verify_secret<S: SecretStorage>(
+    storage: &S,
+    secret: &Secret,
+    reader: BoxedAsyncReader,
+    threshold: u64,
+) -> Result<BoxedAsyncReader, ReadSecretError<S::Error>> {
+    if secret.pin.is_none() && secret.signing_keys.is_empty() {
+        return Ok(reader);
+    }
+
+    // We need the full ciphertext before we can trust any of it
+    let (ciphertext, digest) = Spooled::read(reader, &secret.name, threshold)
+        .await
+        .map_err(ReadSecretError::ReadingCiphertext)?;
+
+    if let Some(pin) = &secret.pin {
+        if *pin != digest {
+            let name = secret.name.clone();
+            return Err(ReadSecretError::PinMismatch(name, pin.clone(), digest));
+        }
+        log::debug!("ciphertext for {} matches {}", secret.name, pin);
+    }
+
+    if !secret.signing_keys.is_empty() {
+        let mut sig = Vec::new();
+        storage
+            .read(&sidecar_path(&secret.path, SIGNATURE_SUFFIX))
+            .await
+            .map_err(|e| ReadSecretError::FetchingSignature(secret.name.clone(), e))?
+            .read_to_end(&mut sig)
+            .await
+            .map_err(|e| ReadSecretError::ReadingSignature(secret.name.clone(), e))?;
+        // Signatures cover the digest, which was computed as we spooled
+        verify_ciphertext(secret, &digest, &sig)
+            .map_err(|e| ReadSecretError::VerifyingSignature(secret.name.clone(), e))?;
+        log::debug!("signature for {} verified", secret.name);
+    }
+
+    ciphertext
+        .into_reader()
+        .await
+        .map_err(ReadSecretError::ReadingCiphertext)
+}
+
+/// Writes new ciphertext for a secret to storage, along with a detached
+/// signature if a signing key is given, and a record of the recipients it was
+/// encrypted to.
+///
+/// Termination signals received while writing are held off until every object
+/// has been written, so that storage isn't left with ciphertext that doesn't
+/// match its signature or recipients record, and are then returned as
+/// [`WriteSecretError::Interrupted`].
+pub async fn write_secret<S: SecretStorage>(
+    storage: &S,
+    secret: &Secret,
+    ciphertext: Spooled,
+    recipients: &[String],
+    signing_key: Option<&Path>,
+    keep: KeepPrevious,
+) -> Result<(), WriteSecretError<S::Error>> {
+    write_secret_if_unchanged(
+        storage,
+        secret,
+        ciphertext,
+        recipients,
+        signing_key,
+        keep,
+        None,
+    )
+    .await
+}
+
+/// Like [write_secret], but fails with [WriteSecretError::Changed] if the
+/// ciphertext in storage is no longer at `version` (from
+/// [read_secret_if_changed]). Storage without
+/// [SecretStorage::conditional_writes] can't check this, and writes it anyway.
+pub async fn write_secret_if_unchanged<S: SecretStorage>(
+    storage: &S,
+    secret: &Secret,
+    ciphertext: Spooled,
+    recipients: &[String],
+    signing_key: Option<&Path>,
+    keep: KeepPrevious,
+    version: Option<&str>,
+) -> Result<(), WriteSecretError<S::Error>> {
+    if secret.value.is_some() {
+        return Err(WriteSecretError::Inline(secret.name.clone()));
+    }
+
+    // Sign before writing anything, so we never leave behind ciphertext that
+    // can't be verified
+    let signature = match signing_key {
+        Some(key) => {
+            let signature = sign_ciphertext(key, secret, ciphertext.digest())
+                .map_err(|e| WriteSecretError::Signing(secret.name.clone(), e))?;
+            Some(signature)
+        }
+        None if !secret.signing_keys.is_empty() => {
+            return Err(WriteSecretError::SignatureRequired(secret.name.clone()))
+        }
+        None => None,
+    };
+
+    let write = write_objects(
+        storage, secret, ciphertext, recipients, signature, keep, version,
+    );
+    match shielded(write)
+        .await
+        .map_err(WriteSecretError::ListeningForSignals)?
+    {
+        (res, None) => res,
+        (res, Some(signal)) => {
+            res?;
+            Err(WriteSecretError::Interrupted(secret.name.clone(), signal))
+        }
+    }
+}
+
+/// Writes every object that makes up a secret, once it's been signed.
+async fn write_objects<S: SecretStorage>(
+    storage: &S,
+    secret: &Secret,
+    ciphertext: Spooled,
+    recipients: &[String],
+    signature: Option<String>,
+    keep: KeepPrevious,
+    version: Option<&str>,
+) -> Result<(), WriteSecretError<S::Error>> {
+    keep_previous(storage, secret, keep)
+        .await
+        .map_err(WriteSecretError::KeepingPrevious)?;
+    let (size, digest) = (ciphertext.size(), ciphertext.digest().clone());
+    let reader = ciphertext
+        .into_reader()
+        .await
+        .map_err(WriteSecretError::ReadingCiphertext)?;
+    let written = match version {
+        Some(version) => storage.write_if_unchanged(&secret.path, reader, version),
+        None => Box::pin(storage.write(&secret.path, reader).map_ok(|()| true)),
+    };
+    if !written.await.map_err(WriteSecretError::Storage)? {
+        return Err(WriteSecretError::Changed(secret.name.clone()));
+    }
+    // Catch writes that were cut short, before anything else refers to them
+    let written = storage
+        .metadata(&secret.path)
+        .await
+        .map_err(WriteSecretError::Storage)?;
+    if written.size != size {
+        return Err(WriteSecretError::SizeMismatch(
+            secret.name.clone(),
+            size,
+            written.size,
+        ));
+    }
+
+    if let Some(sig) = signature {
+        storage
+            .write(
+                &sidecar_path(&secret.path, SIGNATURE_SUFFIX),
+                sig.as_bytes(),
+            )
+            .await
+            .map_err(WriteSecretError::Storage)?;
+        log::debug!("wrote signature for {}", secret.name);
+    }
+
+    let record = serde_yaml::to_string(&RecipientsRecord::new(recipients))
+        .map_err(WriteSecretError::EncodingRecipients)?;
+    storage
+        .write(
+            &sidecar_path(&secret.path, RECIPIENTS_SUFFIX),
+            record.as_bytes(),
+        )
+        .await
+        .map_err(WriteSecretError::Storage)?;
+
+    log::info!("uploaded {} ({digest})", secret.name);
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use sha2::{Digest, Sha256};
+
+/// An expected digest of a secret's ciphertext, in the form `sha256:<hex>`.
+#[derive(DeserializeFromStr, SerializeDisplay, Clone, Debug, PartialEq, Eq)]
+pub enum CiphertextPin {
+    Sha256(String),
+}
+
+impl CiphertextPin {
+    pub fn sha256(data: &[u8]) -> Self {
+        Self::Sha256(format!("{:x}", Sha256::digest(data)))
+    }
+
+    /// Checks the given ciphertext against this pin, returning the actual
+    /// digest if they don't match.
+    pub fn verify(&self, data: &[u8]) -> Result<(), CiphertextPin> {
+        let actual = match self {
+            Self::Sha256(_) => Self::sha256(data),
+        };
+
+        match &actual == self {
+            true => Ok(()),
+            false => Err(actual),
+        }
+    }
+}
+
+impl FromStr for CiphertextPin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("sha256", digest))
+                if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                Ok(Self::Sha256(digest.to_ascii_lowercase()))
+            }
+            Some(("sha256", _)) => Err(format!("invalid sha256 digest in pin: {s}")),
+            _ => Err(format!(
+                "unsupported pin format (expected sha256:<hex>): {s}"
+            )),
+        }
+    }
+}
+
+impl Display for CiphertextPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sha256(digest) => write!(f, "sha256:{digest}"),
+        }
+    }
+}
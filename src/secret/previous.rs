@@ -0,0 +1,180 @@
+//! Copies of a secret's objects from before it was last overwritten, so that a
+//! bad upload or edit can be undone on storage without versioning.
+
+use std::path::{Path, PathBuf};
+
+use super::{
+    sidecar_path,
+    ByteSize,
+    Secret,
+    SecretError,
+    SecretStorage,
+    Spooled,
+    RECIPIENTS_SUFFIX,
+    SIGNATURE_SUFFIX,
+};
+
+/// Suffix of the copy of an object kept from before it was last overwritten.
+pub const PREVIOUS_SUFFIX: &str = "bak";
+
+/// How a secret's current objects are kept before it's overwritten.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepPrevious {
+    /// Size above which objects are spooled to disk while they're copied,
+    /// and above which ciphertext is only kept if `large` is set
+    pub threshold: u64,
+    pub large: bool,
+}
+
+/// Objects that make up a stored secret: its ciphertext, then everything
+/// stored alongside it.
+fn secret_objects(secret: &Secret) -> [PathBuf; 3] {
+    [
+        secret.path.clone(),
+        sidecar_path(&secret.path, SIGNATURE_SUFFIX),
+        sidecar_path(&secret.path, RECIPIENTS_SUFFIX),
+    ]
+}
+
+/// Reads an object in full (spooling it to disk past `threshold` bytes), if
+/// it exists.
+async fn read_optional<S: SecretStorage>(
+    storage: &S,
+    p: &Path,
+    threshold: u64,
+) -> Result<Option<Spooled>, PreviousVersionError<S::Error>> {
+    let reader = match storage.read(p).await {
+        Ok(reader) => reader,
+        Err(e) if e.is_not_found() => return Ok(None),
+        Err(e) => return Err(PreviousVersionError::Storage(p.to_owned(), e)),
+    };
+    let (data, _) = Spooled::read(reader, &p.to_string_lossy(), threshold)
+        .await
+        .map_err(|e| PreviousVersionError::Reading(p.to_owned(), e))?;
+
+    Ok(Some(data))
+}
+
+/// Writes an object, or removes it if there's nothing to write.
+async fn replace<S: SecretStorage>(
+    storage: &S,
+    p: &Path,
+    data: Option<Spooled>,
+) -> Result<(), PreviousVersionError<S::Error>> {
+    let storage_err = |e| PreviousVersionError::Storage(p.to_owned(), e);
+    let Some(data) = data else {
+        return match storage.delete(p).await {
+            Err(e) if e.is_not_found() => Ok(()),
+            res => res.map_err(storage_err),
+        };
+    };
+
+    let size = data.size();
+    let reader = data
+        .into_reader()
+        .await
+        .map_err(|e| PreviousVersionError::Reading(p.to_owned(), e))?;
+    storage.write(p, reader).await.map_err(storage_err)?;
+    // A copy that was cut short would be worse than none, since it'd be
+    // restored in place of the real thing
+    let written = storage.metadata(p).await.map_err(storage_err)?;
+    if written.size != size {
+        return Err(PreviousVersionError::SizeMismatch(
+            p.to_owned(),
+            size,
+            written.size,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Copies a secret's current objects to their backup paths, before they're
+/// overwritten. Backups of objects the secret doesn't have (e.g. a signature)
+/// are removed, so that they're never restored alongside the wrong ciphertext.
+///
+/// Ciphertext larger than the large secret threshold isn't kept unless
+/// `keep.large` is set, and any older backups are removed instead, since
+/// they'd no longer be the version before this one.
+pub(crate) async fn keep_previous<S: SecretStorage>(
+    storage: &S,
+    secret: &Secret,
+    keep: KeepPrevious,
+) -> Result<(), PreviousVersionError<S::Error>> {
+    let size = match storage.metadata(&secret.path).await {
+        Ok(metadata) => metadata.size,
+        // Nothing to keep for a new secret
+        Err(e) if e.is_not_found() => return Ok(()),
+        Err(e) => return Err(PreviousVersionError::Storage(secret.path.clone(), e)),
+    };
+    let objects = secret_objects(secret);
+    if size > keep.threshold && !keep.large {
+        log::info!(
+            "not keeping the previous version of {}, it's larger than {}",
+            secret.name,
+            ByteSize(keep.threshold)
+        );
+        for path in objects {
+            replace(storage, &sidecar_path(&path, PREVIOUS_SUFFIX), None).await?;
+        }
+        return Ok(());
+    }
+
+    let mut current = Vec::new();
+    for path in objects {
+        current.push((read_optional(storage, &path, keep.threshold).await?, path));
+    }
+    let read = current[0].0.as_ref().map_or(0, |data| data.size());
+    if read != size {
+        return Err(PreviousVersionError::SizeMismatch(
+            secret.path.clone(),
+            size,
+            read,
+        ));
+    }
+
+    for (data, path) in current {
+        replace(storage, &sidecar_path(&path, PREVIOUS_SUFFIX), data).await?;
+    }
+    log::debug!("kept previous version of {}", secret.name);
+
+    Ok(())
+}
+
+/// Swaps a secret's objects with the ones kept from before it was last
+/// overwritten, so that restoring twice undoes the restore.
+pub async fn restore_previous<S: SecretStorage>(
+    storage: &S,
+    secret: &Secret,
+    threshold: u64,
+) -> Result<(), PreviousVersionError<S::Error>> {
+    let mut objects = Vec::new();
+    for path in secret_objects(secret) {
+        let previous_path = sidecar_path(&path, PREVIOUS_SUFFIX);
+        let previous = read_optional(storage, &previous_path, threshold).await?;
+        let current = read_optional(storage, &path, threshold).await?;
+        objects.push((path, previous, previous_path, current));
+    }
+    if objects[0].1.is_none() {
+        return Err(PreviousVersionError::NoPreviousVersion(secret.name.clone()));
+    }
+
+    for (path, previous, previous_path, current) in objects {
+        replace(storage, &path, previous).await?;
+        replace(storage, &previous_path, current).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PreviousVersionError<E: SecretError> {
+    #[error("no previous version of {0} is stored")]
+    NoPreviousVersion(String),
+    #[error("error accessing {0}: {1}")]
+    Storage(PathBuf, E),
+    #[error("error reading {0}: {1}")]
+    Reading(PathBuf, std::io::Error),
+    #[error("copy of {0} was cut short (expected {1} bytes, got {2})")]
+    SizeMismatch(PathBuf, u64, u64),
+}
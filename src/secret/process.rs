@@ -2,41 +2,147 @@ use age::Identity;
 use tokio::io::AsyncReadExt;
 use tokio::process::Command;
 
-use super::EnvExposeArgs;
-use crate::age::{decrypt_bytes, DecryptionError};
-use crate::{Secret, SecretStorage};
+use super::{decrypt_secret, read_secret, ByteSize, EnvExposeArgs};
+use crate::age::DecryptionError;
+use crate::events::{self, Event, ExposureKind};
+use crate::hooks::{ExposureTarget, Hooks};
+use crate::{MultiError, Secret, SecretStorage};
 
-pub async fn expose_env<S>(
-    cmd: &mut Command,
+async fn fetch_plaintext<S>(
     storage: &S,
-    exposures: &[(&Secret, &Vec<EnvExposeArgs>)],
+    secret: &Secret,
     identities: &[Box<dyn Identity>],
+    hooks: &dyn Hooks,
+    buf: &mut String,
+    threshold: u64,
 ) -> Result<(), EnvExposureError>
 where
     S: SecretStorage,
     <S as SecretStorage>::Error: 'static,
+{
+    events::emit(Event::FetchStart {
+        secret: &secret.name,
+    });
+    hooks.on_fetch(secret);
+    let reader =
+        read_secret(storage, secret, threshold)
+            .await
+            .map_err(|e| match e.is_not_found() {
+                true => EnvExposureError::NotInStorage,
+                false => EnvExposureError::FetchingSecret(Box::new(e)),
+            })?;
+    let reader = decrypt_secret(reader, secret, identities).await?;
+    // Size is checked before encoding, so that large binary secrets are
+    // reported as such
+    let mut data = Vec::new();
+    reader
+        .take(threshold + 1)
+        .read_to_end(&mut data)
+        .await
+        .map_err(|e| EnvExposureError::FetchingSecret(Box::new(e)))?;
+    if data.len() as u64 > threshold {
+        return Err(EnvExposureError::TooLarge(ByteSize(threshold)));
+    }
+    *buf = String::from_utf8(data).map_err(|e| EnvExposureError::FetchingSecret(Box::new(e)))?;
+    events::emit(Event::FetchDone {
+        secret: &secret.name,
+    });
+    hooks.on_decrypt(secret);
+
+    Ok(())
+}
+
+/// Where env exposures are set: a command's environment, or somewhere that
+/// ends up in one.
+pub trait EnvTarget {
+    /// Whether the variables are set alongside our own environment, so that
+    /// exposures can collide with it.
+    fn inherits_env(&self) -> bool {
+        true
+    }
+
+    fn set_env(&mut self, name: &str, value: &str) -> Result<(), EnvExposureError>;
+}
+
+impl EnvTarget for Command {
+    fn set_env(&mut self, name: &str, value: &str) -> Result<(), EnvExposureError> {
+        self.env(name, value);
+        Ok(())
+    }
+}
+
+/// Exposes secrets as environment variables of the given command. Failing
+/// secrets don't stop the others from being exposed, and are reported
+/// together. Secrets larger than `threshold` bytes are refused.
+pub async fn expose_env<S, T>(
+    cmd: &mut T,
+    storage: &S,
+    exposures: &[(&Secret, &Vec<EnvExposeArgs>)],
+    identities: &[Box<dyn Identity>],
+    hooks: &dyn Hooks,
+    threshold: u64,
+) -> Result<(), MultiError<EnvExposureError>>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+    T: EnvTarget,
 {
     // Expose environment variables to the process
+    let mut errors = MultiError::default();
     let mut buf = String::new();
     for (secret, exposure_set) in exposures {
-        let reader = storage
-            .read(&secret.path)
-            .await
-            .map_err(|e| EnvExposureError::FetchingSecret(Box::new(e)))?;
-        let mut reader = decrypt_bytes(reader, identities).await?;
-        reader
-            .read_to_string(&mut buf)
-            .await
-            .map_err(|e| EnvExposureError::FetchingSecret(Box::new(e)))?;
+        // Checked before fetching, so that we don't decrypt anything we
+        // won't use
+        let mut collided = false;
+        for env_spec in exposure_set.iter() {
+            if !cmd.inherits_env() || std::env::var_os(&env_spec.name).is_none() {
+                continue;
+            }
+            match env_spec.overwrite {
+                true => log::debug!("overwriting {} from the environment", env_spec.name),
+                false => {
+                    errors.push(
+                        &secret.name,
+                        EnvExposureError::AlreadySet(env_spec.name.clone()),
+                    );
+                    collided = true;
+                }
+            }
+        }
+        if collided {
+            continue;
+        }
+
+        let res = fetch_plaintext(storage, secret, identities, hooks, &mut buf, threshold);
+        if let Err(e) = res.await {
+            match e {
+                EnvExposureError::NotInStorage if exposure_set.iter().all(|s| s.optional) => {
+                    log::warn!("skipping optional secret {}: {e}", secret.name)
+                }
+                e => errors.push(&secret.name, e),
+            }
+            buf.truncate(0);
+            continue;
+        }
+
         for env_spec in exposure_set.iter() {
             log::debug!("exposing {} as {}", secret.name, &env_spec.name);
-            cmd.env(&env_spec.name, &buf);
+            if let Err(e) = cmd.set_env(&env_spec.name, &buf) {
+                errors.push(&secret.name, e);
+                continue;
+            }
+            events::emit(Event::ExposeDone {
+                secret: &secret.name,
+                kind: ExposureKind::Env,
+                target: &env_spec.name,
+            });
+            hooks.on_expose(secret, ExposureTarget::Env(&env_spec.name));
         }
 
         buf.truncate(0);
     }
 
-    Ok(())
+    errors.into_result()
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -45,4 +151,12 @@ pub enum EnvExposureError {
     FetchingSecret(Box<dyn std::error::Error + 'static>),
     #[error("error decrypting secrets: {0}")]
     DecryptingSecret(#[from] DecryptionError),
+    #[error("{0} is already set in the environment (set `overwrite: true` to replace it)")]
+    AlreadySet(String),
+    #[error("secret is larger than {0}, and can only be exposed as a file")]
+    TooLarge(ByteSize),
+    #[error("secret does not exist in storage")]
+    NotInStorage,
+    #[error("secret contains a newline, which can't be passed in an env file (expose it as a file instead)")]
+    ContainsNewline,
 }
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use crate::secret::{sidecar_path, Secret, SecretError, SecretStorage};
+
+/// Suffix of the object recording which recipients a secret's current
+/// ciphertext is encrypted to.
+pub const RECIPIENTS_SUFFIX: &str = "recipients";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReadRecipientsError<E: SecretError> {
+    #[error("error fetching recipients record: {0}")]
+    Fetching(E),
+    #[error("error reading recipients record: {0}")]
+    Reading(std::io::Error),
+    #[error("error decoding recipients record: {0}")]
+    Decoding(serde_yaml::Error),
+    #[error("inline values don't have a recipients record")]
+    Inline,
+}
+
+/// Record of the recipients a secret's ciphertext was last encrypted to.
+///
+/// age doesn't reveal recipients in its header for all key types, so we keep
+/// track of them ourselves to detect partially-completed key rotations.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecipientsRecord {
+    pub recipients: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RecipientsDiff {
+    /// Configured recipients the ciphertext is not encrypted to
+    pub missing: Vec<String>,
+    /// Recipients the ciphertext is encrypted to that are no longer configured
+    pub extra: Vec<String>,
+}
+
+impl RecipientsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// Strips comments from SSH public keys, so that keys compare equal
+/// regardless of the comment they were copied with.
+pub fn normalize_recipient(key: &str) -> String {
+    key.split_whitespace().take(2).collect::<Vec<_>>().join(" ")
+}
+
+impl RecipientsRecord {
+    pub fn new(recipients: &[String]) -> Self {
+        let recipients = recipients.iter().map(|r| normalize_recipient(r)).collect();
+        Self { recipients }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        let key = normalize_recipient(key);
+        self.recipients.iter().any(|r| r == &key)
+    }
+
+    /// Compares these recipients with the given expected set.
+    pub fn diff(&self, expected: &[String]) -> RecipientsDiff {
+        let expected = RecipientsRecord::new(expected);
+        let missing = expected
+            .recipients
+            .iter()
+            .filter(|r| !self.contains(r))
+            .cloned()
+            .collect();
+        let extra = self
+            .recipients
+            .iter()
+            .filter(|r| !expected.contains(r))
+            .cloned()
+            .collect();
+
+        RecipientsDiff { missing, extra }
+    }
+}
+
+pub async fn read_recipients<S: SecretStorage>(
+    storage: &S,
+    secret: &Secret,
+) -> Result<RecipientsRecord, ReadRecipientsError<S::Error>> {
+    if secret.value.is_some() {
+        return Err(ReadRecipientsError::Inline);
+    }
+    let mut buf = Vec::new();
+    storage
+        .read(&sidecar_path(&secret.path, RECIPIENTS_SUFFIX))
+        .await
+        .map_err(ReadRecipientsError::Fetching)?
+        .read_to_end(&mut buf)
+        .await
+        .map_err(ReadRecipientsError::Reading)?;
+
+    serde_yaml::from_slice(&buf).map_err(ReadRecipientsError::Decoding)
+}
@@ -0,0 +1,242 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::cache::flattened_path;
+use crate::secret::{ConditionalRead, ObjectMetadata, SecretError, SecretStorage};
+use crate::util::BoxedAsyncReader;
+use crate::IntoSecretStorage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordMode {
+    /// Read from the backing store, saving a copy of everything read
+    Record,
+    /// Never contact the backing store, only serve previously-recorded reads
+    Replay,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RecordingError<E: SecretError> {
+    #[error("{0}")]
+    Storage(E),
+    #[error("error reading data from backing store: {0}")]
+    CopyingData(std::io::Error),
+    #[error("error recording ciphertext to {0}: {1}")]
+    Recording(PathBuf, std::io::Error),
+    #[error("error reading recorded ciphertext at {0}: {1}")]
+    Replaying(PathBuf, std::io::Error),
+    #[error("writes are not possible while replaying")]
+    WritingReplay,
+}
+
+impl<E: SecretError> SecretError for RecordingError<E> {
+    fn is_not_found(&self) -> bool {
+        match self {
+            Self::Storage(e) => e.is_not_found(),
+            Self::Replaying(_, e) => e.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Storage(e) => e.hint(),
+            _ => None,
+        }
+    }
+}
+
+/// Wraps another [SecretStorage], saving every ciphertext read through it to
+/// a directory, so that the same reads can be replayed later without access
+/// to the backing store (e.g. for deterministic integration tests in CI).
+///
+/// Like the cache, only ciphertext is recorded.
+pub struct RecordingSecretStorage<S> {
+    inner: S,
+    dir: PathBuf,
+    mode: RecordMode,
+}
+
+impl<S: SecretStorage> RecordingSecretStorage<S> {
+    pub fn new(inner: S, dir: PathBuf, mode: RecordMode) -> Self {
+        Self { inner, dir, mode }
+    }
+
+    fn recording_path(&self, p: &Path) -> PathBuf {
+        flattened_path(&self.dir, p)
+    }
+
+    async fn record(&self, p: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+        fs::create_dir_all(&self.dir).await?;
+        fs::write(self.recording_path(p), data).await
+    }
+}
+
+#[async_trait]
+impl<S> SecretStorage for RecordingSecretStorage<S>
+where
+    S: SecretStorage + Sync + Send,
+    <S as SecretStorage>::Error: Send,
+{
+    type Error = RecordingError<S::Error>;
+
+    async fn read(&self, p: &Path) -> Result<BoxedAsyncReader, Self::Error> {
+        let recording = self.recording_path(p);
+        if self.mode == RecordMode::Replay {
+            log::debug!(
+                "replaying {} from {}",
+                p.to_string_lossy(),
+                recording.to_string_lossy()
+            );
+            let data = fs::read(&recording)
+                .await
+                .map_err(|e| RecordingError::Replaying(recording, e))?;
+            return Ok(BoxedAsyncReader::from_async_read(Cursor::new(data)));
+        }
+
+        let mut data = Vec::new();
+        self.inner
+            .read(p)
+            .await
+            .map_err(RecordingError::Storage)?
+            .read_to_end(&mut data)
+            .await
+            .map_err(RecordingError::CopyingData)?;
+        self.record(p, &data)
+            .await
+            .map_err(|e| RecordingError::Recording(recording, e))?;
+
+        Ok(BoxedAsyncReader::from_async_read(Cursor::new(data)))
+    }
+
+    async fn read_if_changed(
+        &self,
+        p: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        // Versions aren't recorded, so replays always see a change
+        if self.mode == RecordMode::Replay {
+            return Ok(ConditionalRead::Changed {
+                reader: self.read(p).await?,
+                version: None,
+            });
+        }
+
+        let (mut reader, version) = match self.inner.read_if_changed(p, version).await {
+            Ok(ConditionalRead::Unchanged) => return Ok(ConditionalRead::Unchanged),
+            Ok(ConditionalRead::Changed { reader, version }) => (reader, version),
+            Err(e) => return Err(RecordingError::Storage(e)),
+        };
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .await
+            .map_err(RecordingError::CopyingData)?;
+        self.record(p, &data)
+            .await
+            .map_err(|e| RecordingError::Recording(self.recording_path(p), e))?;
+
+        Ok(ConditionalRead::Changed {
+            reader: BoxedAsyncReader::from_async_read(Cursor::new(data)),
+            version,
+        })
+    }
+
+    async fn metadata(&self, p: &Path) -> Result<ObjectMetadata, Self::Error> {
+        if self.mode == RecordMode::Replay {
+            let recording = self.recording_path(p);
+            let metadata = fs::metadata(&recording)
+                .await
+                .map_err(|e| RecordingError::Replaying(recording, e))?;
+            // Modification times aren't replayed, so that output is the same
+            // between runs
+            return Ok(ObjectMetadata {
+                size: metadata.len(),
+                last_modified: None,
+            });
+        }
+
+        self.inner
+            .metadata(p)
+            .await
+            .map_err(RecordingError::Storage)
+    }
+
+    async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        p: &Path,
+        new_encrypted_content: R,
+    ) -> Result<(), Self::Error> {
+        if self.mode == RecordMode::Replay {
+            return Err(RecordingError::WritingReplay);
+        }
+
+        self.inner
+            .write(p, new_encrypted_content)
+            .await
+            .map_err(RecordingError::Storage)
+    }
+
+    fn write_if_unchanged<'a, R: AsyncRead + Send + Unpin + 'a>(
+        &'a self,
+        p: &'a Path,
+        new_encrypted_content: R,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            if self.mode == RecordMode::Replay {
+                return Err(RecordingError::WritingReplay);
+            }
+
+            self.inner
+                .write_if_unchanged(p, new_encrypted_content, version)
+                .await
+                .map_err(RecordingError::Storage)
+        })
+    }
+
+    fn conditional_writes(&self) -> bool {
+        self.inner.conditional_writes()
+    }
+
+    async fn delete(&self, p: &Path) -> Result<(), Self::Error> {
+        if self.mode == RecordMode::Replay {
+            return Err(RecordingError::WritingReplay);
+        }
+
+        self.inner.delete(p).await.map_err(RecordingError::Storage)
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        match self.mode {
+            RecordMode::Replay => None,
+            RecordMode::Record => self.inner.local_root(),
+        }
+    }
+}
+
+/// Storage config wrapped with recording or replaying.
+pub struct RecordingStorageConfig<C> {
+    pub inner: C,
+    pub dir: PathBuf,
+    pub mode: RecordMode,
+}
+
+#[async_trait]
+impl<C> IntoSecretStorage for RecordingStorageConfig<C>
+where
+    C: IntoSecretStorage + Send,
+    C::Impl: Sync + Send,
+    C::Error: Send,
+{
+    type Error = RecordingError<C::Error>;
+    type Impl = RecordingSecretStorage<C::Impl>;
+
+    async fn build(self) -> Self::Impl {
+        RecordingSecretStorage::new(self.inner.build().await, self.dir, self.mode)
+    }
+}
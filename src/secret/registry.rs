@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use crate::secret::{normalize_recipient, SecretError, SecretStorage};
+
+#[derive(thiserror::Error, Debug)]
+pub enum RegistryError<E: SecretError> {
+    #[error("error fetching recipients registry: {0}")]
+    Fetching(E),
+    #[error("error reading recipients registry: {0}")]
+    Reading(std::io::Error),
+    #[error("error decoding recipients registry: {0}")]
+    Decoding(serde_yaml::Error),
+    #[error("error encoding recipients registry: {0}")]
+    Encoding(serde_yaml::Error),
+    #[error("error writing recipients registry: {0}")]
+    Writing(E),
+}
+
+/// Prefix of `encryption_keys` entries that refer to a key group in the
+/// registry, rather than being a public key.
+pub const KEY_GROUP_PREFIX: &str = "group:";
+
+#[derive(thiserror::Error, Debug)]
+#[error("no key group named {0}")]
+pub struct UnknownKeyGroup(pub String);
+
+/// Public keys of enrolled machines (and people), kept in storage alongside
+/// the secrets, so that enrolling a machine doesn't need a config change.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecipientsRegistry {
+    /// Public keys, by the name of the machine (or person) that holds them
+    #[serde(default)]
+    pub keys: BTreeMap<String, String>,
+    /// Named sets of recipients, which secrets can be encrypted to with
+    /// `group:<name>`. Members are names from `keys`, or public keys.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+/// Whether any of the given recipients refer to a key group.
+pub fn uses_key_groups(keys: &[String]) -> bool {
+    keys.iter().any(|k| k.starts_with(KEY_GROUP_PREFIX))
+}
+
+impl RecipientsRegistry {
+    /// Replaces `group:<name>` entries with the public keys of the group's
+    /// members. Other entries are kept as they are.
+    pub fn expand(&self, keys: &[String]) -> Result<Vec<String>, UnknownKeyGroup> {
+        let mut expanded: Vec<String> = Vec::new();
+        let mut push = |key: &str| {
+            let present = expanded
+                .iter()
+                .any(|k| normalize_recipient(k) == normalize_recipient(key));
+            if !present {
+                expanded.push(key.to_string());
+            }
+        };
+
+        for key in keys {
+            let group = match key.strip_prefix(KEY_GROUP_PREFIX) {
+                Some(g) => g,
+                None => {
+                    push(key);
+                    continue;
+                }
+            };
+            let members = self
+                .groups
+                .get(group)
+                .ok_or_else(|| UnknownKeyGroup(group.to_string()))?;
+            for member in members {
+                push(self.keys.get(member).unwrap_or(member));
+            }
+        }
+
+        Ok(expanded)
+    }
+
+    /// Reads the registry at the given storage path. A registry that doesn't
+    /// exist yet is empty.
+    pub async fn read<S: SecretStorage>(
+        storage: &S,
+        path: &Path,
+    ) -> Result<Self, RegistryError<S::Error>> {
+        let mut reader = match storage.read(path).await {
+            Ok(r) => r,
+            Err(e) if e.is_not_found() => return Ok(Self::default()),
+            Err(e) => return Err(RegistryError::Fetching(e)),
+        };
+        let mut buf = Vec::new();
+        reader
+            .read_to_end(&mut buf)
+            .await
+            .map_err(RegistryError::Reading)?;
+
+        serde_yaml::from_slice(&buf).map_err(RegistryError::Decoding)
+    }
+
+    pub async fn write<S: SecretStorage>(
+        &self,
+        storage: &S,
+        path: &Path,
+    ) -> Result<(), RegistryError<S::Error>> {
+        let data = serde_yaml::to_string(self).map_err(RegistryError::Encoding)?;
+        storage
+            .write(path, data.as_bytes())
+            .await
+            .map_err(RegistryError::Writing)
+    }
+}
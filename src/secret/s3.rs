@@ -1,17 +1,39 @@
+use std::convert::Infallible;
 use std::path::Path;
+use std::time::SystemTime;
 
 use async_trait::async_trait;
+use aws_credential_types::provider::error::CredentialsError;
+use aws_credential_types::provider::{future, ProvideCredentials};
+use aws_sdk_s3::client::customize::CustomizableOperation;
 use aws_sdk_s3::config::Region;
-use aws_sdk_s3::error::SdkError;
-use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::complete_multipart_upload::CompleteMultipartUploadError;
+use aws_sdk_s3::operation::create_multipart_upload::CreateMultipartUploadError;
+use aws_sdk_s3::operation::delete_object::DeleteObjectError;
+use aws_sdk_s3::operation::get_object::{GetObjectError, GetObjectOutput};
+use aws_sdk_s3::operation::head_object::HeadObjectError;
 use aws_sdk_s3::operation::put_object::PutObjectError;
+use aws_sdk_s3::operation::upload_part::UploadPartError;
+use aws_sdk_s3::operation::{RequestId, RequestIdExt};
 use aws_sdk_s3::primitives::{ByteStream, ByteStreamError};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
 use aws_sdk_s3::Client;
+use aws_sig_auth::signer::{OperationSigningConfig, SigningRequirements};
+use futures::future::BoxFuture;
+use hyper::header::{HeaderValue, IF_MATCH};
 use serde::Deserialize;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::secret::{SecretError, SecretStorage};
+use crate::secret::{
+    ConditionalRead,
+    CredentialsConfig,
+    ObjectMetadata,
+    SecretError,
+    SecretStorage,
+    STORAGE_LOG_TARGET,
+};
 use crate::util::BoxedAsyncReader;
 use crate::IntoSecretStorage;
 
@@ -21,6 +43,38 @@ pub struct S3Config {
     // Required, because AWS require you to specify the correct region for your
     // bucket.
     region: String,
+    #[serde(default)]
+    auth: S3Auth,
+    /// Where to get credentials from (default: the AWS provider chain)
+    #[serde(default)]
+    credentials: Option<CredentialsConfig>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum S3Auth {
+    /// Resolve credentials using the default AWS provider chain
+    #[default]
+    Default,
+    /// Send unsigned requests, for public (or VPC endpoint-restricted) buckets.
+    /// Only reads are supported in this mode.
+    None,
+}
+
+/// Credentials provider that never yields credentials, so that the default
+/// chain (env, profile, IMDS, ...) is never consulted.
+#[derive(Debug)]
+struct AnonymousCredentials;
+
+impl ProvideCredentials for AnonymousCredentials {
+    fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        future::ProvideCredentials::ready(Err(CredentialsError::not_loaded(
+            "anonymous access configured for bucket",
+        )))
+    }
 }
 
 #[async_trait]
@@ -30,10 +84,26 @@ impl IntoSecretStorage for S3Config {
 
     async fn build(self) -> Self::Impl {
         let region = Region::new(self.region);
-        let config = aws_config::from_env().region(region).load().await;
+        let loader = aws_config::from_env().region(region);
+        let loader = match (self.auth, self.credentials) {
+            (S3Auth::Default, None) => loader,
+            (S3Auth::Default, Some(credentials)) => {
+                loader.credentials_provider(credentials.provider())
+            }
+            (S3Auth::None, credentials) => {
+                if credentials.is_some() {
+                    log::warn!(
+                        "ignoring credentials for bucket {}, which uses `auth: none`",
+                        self.bucket
+                    );
+                }
+                loader.credentials_provider(AnonymousCredentials)
+            }
+        };
+        let config = loader.load().await;
         let client = Client::new(&config);
 
-        S3SecretStorage::new(client, self.bucket)
+        S3SecretStorage::new(client, self.bucket, self.auth)
     }
 }
 
@@ -41,28 +111,370 @@ impl IntoSecretStorage for S3Config {
 pub enum S3SecretStorageError {
     #[error("error getting object from s3: {0}")]
     GettingObject(#[from] SdkError<GetObjectError>),
+    #[error("error getting object metadata from s3: {0}")]
+    GettingMetadata(#[from] SdkError<HeadObjectError>),
     #[error("error writing object to s3: {0}")]
     UpdatingObject(#[from] SdkError<PutObjectError>),
+    #[error("error starting multipart upload to s3: {0}")]
+    StartingUpload(#[from] SdkError<CreateMultipartUploadError>),
+    #[error("error uploading part to s3: {0}")]
+    UploadingPart(#[from] SdkError<UploadPartError>),
+    #[error("error completing multipart upload to s3: {0}")]
+    CompletingUpload(#[from] SdkError<CompleteMultipartUploadError>),
+    #[error("error deleting object from s3: {0}")]
+    DeletingObject(#[from] SdkError<DeleteObjectError>),
     #[error("error reading data from s3: {0}")]
     ReadingData(#[from] ByteStreamError),
     #[error("error copying data: {0}")]
     CopyingData(#[from] std::io::Error),
+    #[error("{0:?} isn't an ETag")]
+    InvalidVersion(String),
+}
+
+impl SecretError for S3SecretStorageError {
+    fn is_not_found(&self) -> bool {
+        match self {
+            Self::GettingObject(SdkError::ServiceError(e)) => e.err().is_no_such_key(),
+            Self::GettingMetadata(SdkError::ServiceError(e)) => e.err().is_not_found(),
+            _ => false,
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::GettingObject(e) => sdk_hint(e),
+            Self::GettingMetadata(e) => sdk_hint(e),
+            Self::UpdatingObject(e) => sdk_hint(e),
+            Self::StartingUpload(e) => sdk_hint(e),
+            Self::UploadingPart(e) => sdk_hint(e),
+            Self::CompletingUpload(e) => sdk_hint(e),
+            Self::DeletingObject(e) => sdk_hint(e),
+            _ => None,
+        }
+    }
+}
+
+/// How to fix the common ways access to S3 is misconfigured, which the SDK's
+/// errors don't make obvious.
+fn sdk_hint<E>(e: &SdkError<E>) -> Option<&'static str>
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    match e {
+        // Credentials are loaded while the request is being built
+        SdkError::ConstructionFailure(_) => {
+            let mut source = std::error::Error::source(e);
+            while let Some(s) = source {
+                if s.to_string().contains("credentials") {
+                    return Some(
+                        "AWS credentials couldn't be loaded: set AWS_PROFILE (or \
+                         AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY), check `credentials` if \
+                         it's set, or use `auth: none` for public buckets (--debug-storage shows \
+                         why)",
+                    );
+                }
+                source = s.source();
+            }
+            return None;
+        }
+        SdkError::DispatchFailure(_) | SdkError::TimeoutError(_) => {
+            return Some(
+                "S3 couldn't be reached: check network access, and any proxy or VPC endpoint",
+            )
+        }
+        _ => (),
+    }
+
+    match e.code() {
+        Some("NoSuchBucket") => return Some("the bucket doesn't exist: check `bucket`"),
+        Some("AuthorizationHeaderMalformed" | "PermanentRedirect") => {
+            return Some("the bucket is in a different region: check `region`")
+        }
+        Some("InvalidAccessKeyId") => return Some("the AWS access key ID doesn't exist"),
+        Some("SignatureDoesNotMatch") => return Some("the AWS secret access key is wrong"),
+        Some("ExpiredToken") => return Some("the AWS credentials have expired: refresh them"),
+        _ => (),
+    }
+
+    // Responses to HEAD requests have no body, so only have a status
+    match e.raw_response().map(|r| r.http().status().as_u16()) {
+        Some(301) => Some("the bucket is in a different region: check `region`"),
+        Some(403) => Some(
+            "access was denied: check that both the IAM policy of the credentials in use and \
+             the bucket's policy allow this (reading a missing object also needs s3:ListBucket)",
+        ),
+        _ => None,
+    }
 }
 
-impl SecretError for S3SecretStorageError {}
+/// Logs what S3 said about a request, for `--debug-storage`. Only the status,
+/// error code and request IDs are logged, never headers (which hold request
+/// signatures) or bodies.
+fn log_response<T, E>(operation: &str, key: &str, res: &Result<T, SdkError<E>>)
+where
+    T: RequestId + RequestIdExt,
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    let (outcome, request_id, extended_request_id) = match res {
+        Ok(output) => (
+            "ok".to_string(),
+            output.request_id(),
+            output.extended_request_id(),
+        ),
+        Err(e) => {
+            let outcome = match (e.raw_response(), e.code()) {
+                (Some(r), Some(code)) => format!("{} {code}", r.http().status()),
+                (Some(r), None) => r.http().status().to_string(),
+                // Never sent, or no response (e.g. missing credentials, or
+                // network errors)
+                (None, _) => DisplayErrorContext(e).to_string(),
+            };
+            (outcome, e.request_id(), e.extended_request_id())
+        }
+    };
+
+    log::debug!(
+        target: STORAGE_LOG_TARGET,
+        "{operation} {key}: {outcome} (request ID: {}, extended request ID: {})",
+        request_id.unwrap_or("none"),
+        extended_request_id.unwrap_or("none"),
+    );
+}
 
 #[derive(Clone)]
 pub struct S3SecretStorage {
     client: Client,
     bucket: String,
+    auth: S3Auth,
 }
 
 impl S3SecretStorage {
-    pub fn new(client: Client, bucket: String) -> Self {
-        Self { client, bucket }
+    pub fn new(client: Client, bucket: String, auth: S3Auth) -> Self {
+        Self {
+            client,
+            bucket,
+            auth,
+        }
+    }
+}
+
+/// HTTP status S3 responds with when a conditional GET matches.
+const NOT_MODIFIED: u16 = 304;
+
+/// Whether a conditional GET failed because the object matched the given ETag.
+fn is_not_modified(e: &SdkError<GetObjectError>) -> bool {
+    e.raw_response()
+        .map(|r| r.http().status().as_u16() == NOT_MODIFIED)
+        .unwrap_or(false)
+}
+
+/// HTTP status S3 responds with when a conditional write's ETag doesn't match.
+const PRECONDITION_FAILED: u16 = 412;
+
+/// Whether a conditional write failed because the object has changed (or
+/// another conditional write to it was in progress).
+fn is_changed<E>(e: &SdkError<E>) -> bool
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    let status = e.raw_response().map(|r| r.http().status().as_u16());
+    status == Some(PRECONDITION_FAILED) || e.code() == Some("ConditionalRequestConflict")
+}
+
+/// Sends an operation without signing it, for `auth: none`.
+fn unsigned<O, Retry>(op: CustomizableOperation<O, Retry>) -> CustomizableOperation<O, Retry> {
+    let unsigned = op.map_operation(|mut op| {
+        if let Some(c) = op.properties_mut().get_mut::<OperationSigningConfig>() {
+            c.signing_requirements = SigningRequirements::Disabled;
+        }
+        Ok::<_, Infallible>(op)
+    });
+    match unsigned {
+        Ok(op) => op,
+        Err(never) => match never {},
     }
 }
 
+/// Only applies a write if the object still has the given ETag.
+fn if_match_etag<O, Retry>(
+    op: CustomizableOperation<O, Retry>,
+    etag: HeaderValue,
+) -> CustomizableOperation<O, Retry> {
+    op.mutate_request(|r| {
+        r.headers_mut().insert(IF_MATCH, etag);
+    })
+}
+
+impl S3SecretStorage {
+    /// Fetches an object, unless it still has the given ETag.
+    async fn get_object(
+        &self,
+        key: &Path,
+        if_none_match: Option<&str>,
+    ) -> Result<GetObjectOutput, SdkError<GetObjectError>> {
+        let path_str = key.to_str().expect("path not representable as str");
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(path_str)
+            .set_if_none_match(if_none_match.map(str::to_string));
+        let res = match self.auth {
+            S3Auth::Default => request.send().await,
+            S3Auth::None => unsigned(request.customize().await?).send().await,
+        };
+        log_response("GetObject", path_str, &res);
+
+        res
+    }
+
+    /// Uploads an object, only replacing one with the given ETag if `if_match`
+    /// is set. Returns whether it was written.
+    async fn put<R: AsyncRead + Send + Unpin>(
+        &self,
+        key: &Path,
+        mut content: R,
+        if_match: Option<HeaderValue>,
+    ) -> Result<bool, S3SecretStorageError> {
+        let path_str = key.to_str().expect("path not representable as str");
+        // Large ciphertext is streamed in parts, rather than held in memory
+        let first = read_part(&mut content).await?;
+        if first.len() == PART_SIZE {
+            return self
+                .write_multipart(path_str, first, content, if_match)
+                .await;
+        }
+
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(path_str)
+            .body(ByteStream::from(first));
+        let conditional = if_match.is_some();
+        let res = match if_match {
+            Some(etag) => if_match_etag(request.customize().await?, etag).send().await,
+            None => request.send().await,
+        };
+        log_response("PutObject", path_str, &res);
+        match res {
+            Err(e) if conditional && is_changed(&e) => Ok(false),
+            res => res.map(|_| true).map_err(Into::into),
+        }
+    }
+
+    /// Uploads an object in parts of [`PART_SIZE`], starting with `first`, so
+    /// that only one part is held in memory at a time. The upload is aborted
+    /// if any part fails (or the object changed, with `if_match`), so S3
+    /// doesn't keep (and bill for) the parts.
+    async fn write_multipart<R: AsyncRead + Send + Unpin>(
+        &self,
+        key: &str,
+        first: Vec<u8>,
+        rest: R,
+        if_match: Option<HeaderValue>,
+    ) -> Result<bool, S3SecretStorageError> {
+        let res = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        log_response("CreateMultipartUpload", key, &res);
+        let upload_id = res?.upload_id().unwrap_or_default().to_string();
+
+        let res = self
+            .upload_parts(key, &upload_id, first, rest, if_match)
+            .await;
+        if !matches!(res, Ok(true)) {
+            let aborted = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .send()
+                .await;
+            log_response("AbortMultipartUpload", key, &aborted);
+            if let Err(e) = aborted {
+                log::warn!(
+                    "couldn't abort multipart upload {upload_id} of {key}, its parts may be \
+                     left in the bucket: {}",
+                    DisplayErrorContext(e)
+                );
+            }
+        }
+
+        res
+    }
+
+    async fn upload_parts<R: AsyncRead + Send + Unpin>(
+        &self,
+        key: &str,
+        upload_id: &str,
+        first: Vec<u8>,
+        mut rest: R,
+        if_match: Option<HeaderValue>,
+    ) -> Result<bool, S3SecretStorageError> {
+        let mut parts = CompletedMultipartUpload::builder();
+        let mut part = first;
+        let mut part_number = 1;
+        while !part.is_empty() {
+            let res = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(part))
+                .send()
+                .await;
+            log_response("UploadPart", key, &res);
+            let uploaded = res?;
+            parts = parts.parts(
+                CompletedPart::builder()
+                    .set_e_tag(uploaded.e_tag().map(str::to_string))
+                    .part_number(part_number)
+                    .build(),
+            );
+
+            part_number += 1;
+            part = read_part(&mut rest).await?;
+        }
+
+        let request = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(parts.build());
+        let conditional = if_match.is_some();
+        let res = match if_match {
+            Some(etag) => if_match_etag(request.customize().await?, etag).send().await,
+            None => request.send().await,
+        };
+        log_response("CompleteMultipartUpload", key, &res);
+        match res {
+            Err(e) if conditional && is_changed(&e) => Ok(false),
+            res => res.map(|_| true).map_err(Into::into),
+        }
+    }
+}
+
+/// Objects larger than this are uploaded in parts of this size. S3 needs
+/// every part but the last to be at least 5MiB.
+const PART_SIZE: usize = 16 << 20;
+
+/// Reads up to [`PART_SIZE`] bytes, stopping early only at the end of input.
+async fn read_part<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, std::io::Error> {
+    let mut part = Vec::new();
+    reader.take(PART_SIZE as u64).read_to_end(&mut part).await?;
+    Ok(part)
+}
+
 #[async_trait]
 impl SecretStorage for S3SecretStorage {
     // TODO: We need to have better formatting/more specific error types for
@@ -71,36 +483,85 @@ impl SecretStorage for S3SecretStorage {
     type Error = S3SecretStorageError;
 
     async fn read(&self, key: &Path) -> Result<BoxedAsyncReader, Self::Error> {
-        let path_str = key.to_str().expect("path not representable as str");
-        let object = self
-            .client
-            .get_object()
-            .bucket(&self.bucket)
-            .key(path_str)
-            .send()
-            .await?;
+        let object = self.get_object(key, None).await?;
 
         Ok(BoxedAsyncReader::from_async_read(
             object.body.into_async_read(),
         ))
     }
 
+    async fn read_if_changed(
+        &self,
+        key: &Path,
+        version: Option<&str>,
+    ) -> Result<ConditionalRead, Self::Error> {
+        let object = match self.get_object(key, version).await {
+            Ok(o) => o,
+            Err(e) if is_not_modified(&e) => return Ok(ConditionalRead::Unchanged),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(ConditionalRead::Changed {
+            version: object.e_tag().map(str::to_string),
+            reader: BoxedAsyncReader::from_async_read(object.body.into_async_read()),
+        })
+    }
+
+    async fn metadata(&self, key: &Path) -> Result<ObjectMetadata, Self::Error> {
+        let path_str = key.to_str().expect("path not representable as str");
+        let request = self.client.head_object().bucket(&self.bucket).key(path_str);
+        let res = match self.auth {
+            S3Auth::Default => request.send().await,
+            S3Auth::None => unsigned(request.customize().await?).send().await,
+        };
+        log_response("HeadObject", path_str, &res);
+        let object = res?;
+
+        Ok(ObjectMetadata {
+            size: object.content_length().max(0) as u64,
+            last_modified: object
+                .last_modified()
+                .and_then(|t| SystemTime::try_from(*t).ok()),
+        })
+    }
+
     async fn write<R: AsyncRead + Send + Unpin>(
         &self,
         key: &Path,
-        mut new_encrypted_content: R,
+        new_encrypted_content: R,
     ) -> Result<(), Self::Error> {
+        self.put(key, new_encrypted_content, None).await?;
+        Ok(())
+    }
+
+    fn write_if_unchanged<'a, R: AsyncRead + Send + Unpin + 'a>(
+        &'a self,
+        key: &'a Path,
+        new_encrypted_content: R,
+        version: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            let etag = HeaderValue::from_str(version)
+                .map_err(|_| S3SecretStorageError::InvalidVersion(version.to_string()))?;
+            self.put(key, new_encrypted_content, Some(etag)).await
+        })
+    }
+
+    fn conditional_writes(&self) -> bool {
+        true
+    }
+
+    async fn delete(&self, key: &Path) -> Result<(), Self::Error> {
         let path_str = key.to_str().expect("path not representable as str");
-        let mut buf = Vec::new();
-        new_encrypted_content.read_to_end(&mut buf).await?;
-        let body = ByteStream::from(buf);
-        self.client
-            .put_object()
+        let res = self
+            .client
+            .delete_object()
             .bucket(&self.bucket)
             .key(path_str)
-            .body(body)
             .send()
-            .await?;
+            .await;
+        log_response("DeleteObject", path_str, &res);
+        res?;
 
         Ok(())
     }
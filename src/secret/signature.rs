@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+
+use ssh_key::{HashAlg, LineEnding, PrivateKey, PublicKey, SshSig};
+
+use super::{CiphertextPin, Secret};
+
+/// Namespace for SSH signatures, so that signatures made for other purposes
+/// (e.g. git commits) can't be replayed against secrets.
+pub const SIGNATURE_NAMESPACE: &str = "credible";
+
+/// Suffix of the object holding the signature over a secret's ciphertext.
+pub const SIGNATURE_SUFFIX: &str = "sig";
+
+#[derive(thiserror::Error, Debug)]
+pub enum SigningError {
+    #[error("error reading signing key at {0}: {1}")]
+    ReadingKey(PathBuf, ssh_key::Error),
+    #[error("signing key at {0} is passphrase-protected, which isn't supported")]
+    EncryptedKey(PathBuf),
+    #[error("error signing ciphertext: {0}")]
+    Signing(ssh_key::Error),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum VerificationError {
+    #[error("signature is malformed: {0}")]
+    MalformedSignature(ssh_key::Error),
+    #[error("invalid signing key {0}: {1}")]
+    InvalidSigningKey(String, ssh_key::Error),
+    #[error("signature does not match any trusted signing key")]
+    NoMatchingKey,
+}
+
+/// Reads an unencrypted OpenSSH private key.
+pub(super) fn read_signing_key(key_path: &Path) -> Result<PrivateKey, SigningError> {
+    let key = PrivateKey::read_openssh_file(key_path)
+        .map_err(|e| SigningError::ReadingKey(key_path.to_owned(), e))?;
+    if key.is_encrypted() {
+        return Err(SigningError::EncryptedKey(key_path.to_owned()));
+    }
+
+    Ok(key)
+}
+
+/// What's signed for a secret: its name and storage path along with the
+/// digest of its ciphertext, so that a signature (and the ciphertext it was
+/// made for) can't be served as a different secret.
+fn signed_message(secret: &Secret, digest: &CiphertextPin) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(secret.name.as_bytes());
+    message.push(0);
+    message.extend_from_slice(secret.path.to_string_lossy().as_bytes());
+    message.push(0);
+    message.extend_from_slice(digest.to_string().as_bytes());
+
+    message
+}
+
+/// Signs a secret's ciphertext (by its digest) with an OpenSSH private key,
+/// returning an armored SSH signature.
+pub fn sign_ciphertext(
+    key_path: &Path,
+    secret: &Secret,
+    digest: &CiphertextPin,
+) -> Result<String, SigningError> {
+    let key = read_signing_key(key_path)?;
+    let message = signed_message(secret, digest);
+    key.sign(SIGNATURE_NAMESPACE, HashAlg::Sha512, &message)
+        .and_then(|sig| sig.to_pem(LineEnding::LF))
+        .map_err(SigningError::Signing)
+}
+
+/// Verifies an armored SSH signature over a secret's ciphertext (by its
+/// digest), succeeding if any of the secret's trusted public keys produced it.
+pub fn verify_ciphertext(
+    secret: &Secret,
+    digest: &CiphertextPin,
+    signature: &[u8],
+) -> Result<(), VerificationError> {
+    let signature = SshSig::from_pem(signature).map_err(VerificationError::MalformedSignature)?;
+    let message = signed_message(secret, digest);
+
+    for key in &secret.signing_keys {
+        let public_key = PublicKey::from_openssh(key)
+            .map_err(|e| VerificationError::InvalidSigningKey(key.to_string(), e))?;
+        if public_key
+            .verify(SIGNATURE_NAMESPACE, &message, &signature)
+            .is_ok()
+        {
+            return Ok(());
+        }
+    }
+
+    Err(VerificationError::NoMatchingKey)
+}
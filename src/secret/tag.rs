@@ -0,0 +1,68 @@
+//! Extended attributes marking files as exposed by credible, so that they can
+//! be told apart from files users put in the same place.
+
+use std::path::Path;
+
+/// Name of the secret (or template) an exposed file holds.
+pub const SECRET_XATTR: &str = "user.credible.secret";
+/// Name of the secrets directory (the generation, for `system mount`) an
+/// exposed file was written to.
+pub const GENERATION_XATTR: &str = "user.credible.generation";
+
+/// What an exposed file was tagged with when it was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExposureTag {
+    pub secret: String,
+    pub generation: String,
+}
+
+/// Tags an exposed file with the secret it holds and the secrets directory it
+/// was written to. Not every filesystem supports user xattrs (e.g. ramfs),
+/// so this is best-effort.
+#[cfg(unix)]
+pub(crate) async fn tag_file(file: &tokio::fs::File, tag: ExposureTag) {
+    use xattr::FileExt;
+
+    let file = match file.try_clone().await {
+        Ok(file) => file.into_std().await,
+        Err(e) => {
+            log::debug!("not tagging {}: {e}", tag.secret);
+            return;
+        }
+    };
+    let res = tokio::task::spawn_blocking(move || {
+        file.set_xattr(SECRET_XATTR, tag.secret.as_bytes())?;
+        file.set_xattr(GENERATION_XATTR, tag.generation.as_bytes())
+    })
+    .await
+    .expect("tagging file panicked");
+    if let Err(e) = res {
+        log::debug!("couldn't tag exposed file: {e}");
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn tag_file(_file: &tokio::fs::File, _tag: ExposureTag) {}
+
+/// Reads the tag of an exposed file, without following symlinks. Returns
+/// `Ok(None)` for untagged files, and an error if tags can't be read at all
+/// (e.g. on filesystems without xattr support).
+#[cfg(unix)]
+pub fn read_exposure_tag(p: &Path) -> Result<Option<ExposureTag>, std::io::Error> {
+    let read = |name| -> Result<Option<String>, std::io::Error> {
+        Ok(xattr::get(p, name)?.map(|v| String::from_utf8_lossy(&v).into_owned()))
+    };
+
+    match (read(SECRET_XATTR)?, read(GENERATION_XATTR)?) {
+        (Some(secret), Some(generation)) => Ok(Some(ExposureTag { secret, generation })),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn read_exposure_tag(_p: &Path) -> Result<Option<ExposureTag>, std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "extended attributes aren't supported on this platform",
+    ))
+}
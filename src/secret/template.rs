@@ -0,0 +1,296 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use age::Identity;
+
+use super::file::{fetch_plaintext, write_file, Plaintext};
+use super::{FileExposureError, Secret, SecretStorage, TemplateExposeArgs};
+use crate::events::{self, Event, ExposureKind};
+use crate::hooks::{ExposureTarget, Hooks};
+use crate::util::{current_host, current_user};
+use crate::MultiError;
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+#[derive(thiserror::Error, Debug)]
+pub enum TemplateError {
+    #[error("unterminated placeholder at byte {0}")]
+    Unterminated(usize),
+    #[error("invalid placeholder {{{{ {0} }}}} (expected <secrets|host|env|vars>.<name>)")]
+    InvalidPlaceholder(String),
+    #[error("unknown host fact {0} (expected hostname, short_hostname, user, os or arch)")]
+    UnknownHostFact(String),
+    #[error("environment variable {0} is not set")]
+    MissingEnv(String),
+    #[error("template variable {0} is not defined")]
+    MissingVar(String),
+    #[error("secret {0} wasn't fetched before rendering")]
+    MissingSecret(String),
+    #[error("secret {0} is not valid UTF-8")]
+    SecretNotUtf8(String),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TemplateExposureError {
+    #[error("error reading template {0}: {1}")]
+    ReadingTemplate(PathBuf, std::io::Error),
+    #[error("error in template {0}: {1}")]
+    Parsing(PathBuf, TemplateError),
+    #[error("no such secret: {0}")]
+    NoSuchSecret(String),
+    #[error("error fetching secret {0}: {1}")]
+    FetchingSecret(String, FileExposureError),
+    #[error("error rendering template {0}: {1}")]
+    Rendering(PathBuf, TemplateError),
+    #[error("error writing rendered template: {0}")]
+    Writing(FileExposureError),
+}
+
+/// A reference to a value in a template, written as `{{ namespace.name }}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Placeholder {
+    /// Plaintext of a secret
+    Secret(String),
+    /// A fact about the host we're running on
+    Host(String),
+    /// An environment variable
+    Env(String),
+    /// A value from the exposure's `vars`
+    Var(String),
+}
+
+impl Placeholder {
+    fn parse(s: &str) -> Result<Self, TemplateError> {
+        let invalid = || TemplateError::InvalidPlaceholder(s.to_string());
+        let (namespace, name) = s.split_once('.').ok_or_else(invalid)?;
+        if name.is_empty() {
+            return Err(invalid());
+        }
+
+        let name = name.to_string();
+        Ok(match namespace {
+            "secrets" => Self::Secret(name),
+            "host" => Self::Host(name),
+            "env" => Self::Env(name),
+            "vars" => Self::Var(name),
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// A piece of a parsed template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment<'a> {
+    Text(&'a str),
+    Placeholder(Placeholder),
+}
+
+fn parse(template: &str) -> Result<Vec<Segment<'_>>, TemplateError> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(OPEN) {
+        let offset = template.len() - rest.len() + start;
+        segments.push(Segment::Text(&rest[..start]));
+        let after = &rest[start + OPEN.len()..];
+        let end = after
+            .find(CLOSE)
+            .ok_or(TemplateError::Unterminated(offset))?;
+        segments.push(Segment::Placeholder(Placeholder::parse(
+            after[..end].trim(),
+        )?));
+        rest = &after[end + CLOSE.len()..];
+    }
+    segments.push(Segment::Text(rest));
+
+    Ok(segments)
+}
+
+/// Names of the secrets a template refers to.
+pub fn template_secrets(template: &str) -> Result<Vec<String>, TemplateError> {
+    let mut names = parse(template)?
+        .into_iter()
+        .filter_map(|s| match s {
+            Segment::Placeholder(Placeholder::Secret(name)) => Some(name),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+
+    Ok(names)
+}
+
+/// Reads a template file, returning the names of the secrets it refers to.
+pub async fn read_template_secrets(path: &Path) -> Result<Vec<String>, TemplateExposureError> {
+    let template = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| TemplateExposureError::ReadingTemplate(path.to_owned(), e))?;
+    template_secrets(&template).map_err(|e| TemplateExposureError::Parsing(path.to_owned(), e))
+}
+
+fn host_fact(name: &str) -> Result<String, TemplateError> {
+    Ok(match name {
+        "hostname" => current_host(),
+        "short_hostname" => current_host()
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        "user" => current_user(),
+        "os" => std::env::consts::OS.to_string(),
+        "arch" => std::env::consts::ARCH.to_string(),
+        _ => return Err(TemplateError::UnknownHostFact(name.to_string())),
+    })
+}
+
+/// Renders a template with the given secret plaintexts and variables.
+pub fn render(
+    template: &str,
+    secrets: &HashMap<String, Vec<u8>>,
+    vars: &BTreeMap<String, String>,
+) -> Result<String, TemplateError> {
+    let mut out = String::with_capacity(template.len());
+    for segment in parse(template)? {
+        let placeholder = match segment {
+            Segment::Text(t) => {
+                out.push_str(t);
+                continue;
+            }
+            Segment::Placeholder(p) => p,
+        };
+
+        match placeholder {
+            Placeholder::Secret(name) => {
+                let Some(value) = secrets.get(&name) else {
+                    return Err(TemplateError::MissingSecret(name));
+                };
+                let value = std::str::from_utf8(value)
+                    .map_err(|_| TemplateError::SecretNotUtf8(name.clone()))?;
+                out.push_str(value);
+            }
+            Placeholder::Host(name) => out.push_str(&host_fact(&name)?),
+            Placeholder::Env(name) => {
+                let value = std::env::var(&name).map_err(|_| TemplateError::MissingEnv(name))?;
+                out.push_str(&value);
+            }
+            Placeholder::Var(name) => {
+                let value = vars.get(&name).ok_or(TemplateError::MissingVar(name))?;
+                out.push_str(value);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Fetches the secrets a template uses and renders it, returning what it
+/// renders to and the secrets it used.
+pub async fn render_template<'a, S>(
+    storage: &S,
+    secrets: &'a HashMap<String, Secret>,
+    spec: &TemplateExposeArgs,
+    identities: &[Box<dyn Identity>],
+    hooks: &dyn Hooks,
+    threshold: u64,
+) -> Result<(String, Vec<&'a Secret>), TemplateExposureError>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let template = tokio::fs::read_to_string(&spec.template)
+        .await
+        .map_err(|e| TemplateExposureError::ReadingTemplate(spec.template.clone(), e))?;
+    let names = template_secrets(&template)
+        .map_err(|e| TemplateExposureError::Parsing(spec.template.clone(), e))?;
+
+    let mut used = Vec::new();
+    let mut plaintexts = HashMap::new();
+    for name in names {
+        let secret = secrets
+            .get(&name)
+            .ok_or_else(|| TemplateExposureError::NoSuchSecret(name.clone()))?;
+        let mut buf = Vec::new();
+        fetch_plaintext(storage, secret, identities, hooks, &mut buf, threshold)
+            .await
+            .map_err(|e| TemplateExposureError::FetchingSecret(name.clone(), e))?;
+        plaintexts.insert(name, buf);
+        used.push(secret);
+    }
+
+    let rendered = render(&template, &plaintexts, &spec.vars)
+        .map_err(|e| TemplateExposureError::Rendering(spec.template.clone(), e))?;
+
+    Ok((rendered, used))
+}
+
+async fn expose_template<S>(
+    secret_dir: &Path,
+    storage: &S,
+    secrets: &HashMap<String, Secret>,
+    spec: &TemplateExposeArgs,
+    identities: &[Box<dyn Identity>],
+    hooks: &dyn Hooks,
+    threshold: u64,
+) -> Result<(), TemplateExposureError>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let (rendered, used) =
+        render_template(storage, secrets, spec, identities, hooks, threshold).await?;
+    let target = write_file(
+        secret_dir,
+        &spec.file_spec(),
+        Plaintext::Bytes(rendered.as_bytes()),
+    )
+    .await
+    .map_err(TemplateExposureError::Writing)?;
+
+    for secret in used {
+        events::emit(Event::ExposeDone {
+            secret: &secret.name,
+            kind: ExposureKind::File,
+            target: &target.to_string_lossy(),
+        });
+        hooks.on_expose(secret, ExposureTarget::File(&target));
+    }
+
+    Ok(())
+}
+
+/// Renders templates into the given directory. Failing templates don't stop
+/// the others from being rendered, and are reported together. Secrets larger
+/// than `threshold` bytes can't be used in templates.
+pub async fn expose_templates<S>(
+    secret_dir: &Path,
+    storage: &S,
+    secrets: &HashMap<String, Secret>,
+    templates: &[TemplateExposeArgs],
+    identities: &[Box<dyn Identity>],
+    hooks: &dyn Hooks,
+    threshold: u64,
+) -> Result<(), MultiError<TemplateExposureError>>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let mut errors = MultiError::default();
+    for spec in templates {
+        let res = expose_template(
+            secret_dir, storage, secrets, spec, identities, hooks, threshold,
+        );
+        let res = res.await;
+        match res {
+            Ok(()) => (),
+            Err(e @ TemplateExposureError::FetchingSecret(_, FileExposureError::NotInStorage))
+                if spec.optional =>
+            {
+                log::warn!("skipping optional template {}: {e}", spec.name)
+            }
+            Err(e) => errors.push(&spec.name, e),
+        }
+    }
+
+    errors.into_result()
+}
@@ -0,0 +1,194 @@
+//! Signal handling shared by everything that needs to clean up after itself
+//! before exiting: `run-command` forwards signals on to its child, and
+//! long-running processes use them to shut down gracefully.
+
+use std::future::Future;
+
+#[cfg(unix)]
+use signal_hook::consts::*;
+#[cfg(unix)]
+use signal_hook_tokio::Signals;
+#[cfg(unix)]
+use tokio_stream::StreamExt;
+
+/// Signals we intercept. Anything not listed keeps its default behaviour.
+#[cfg(unix)]
+pub const SIGNALS: [i32; 13] = [
+    SIGHUP, SIGINT, SIGQUIT, SIGABRT, SIGTERM, SIGTSTP, SIGTTIN, SIGTTOU, SIGCONT, SIGUSR1,
+    SIGUSR2, SIGWINCH, SIGALRM,
+];
+
+/// Signals that ask us to stop what we're doing.
+#[cfg(unix)]
+const TERMINATION_SIGNALS: [i32; 5] = [SIGHUP, SIGINT, SIGQUIT, SIGABRT, SIGTERM];
+
+/// Job-control signals that would stop us, if we weren't intercepting them
+/// (e.g. Ctrl-Z, or a background job touching the terminal).
+#[cfg(unix)]
+const STOP_SIGNALS: [i32; 3] = [SIGTSTP, SIGTTIN, SIGTTOU];
+
+/// Stand-in for Ctrl-C on platforms without unix signals.
+#[cfg(windows)]
+pub const CTRL_C: i32 = 2;
+
+/// Whether the given signal is a request to terminate, rather than e.g. a
+/// notification or job control.
+pub fn is_termination(signal: i32) -> bool {
+    #[cfg(unix)]
+    return TERMINATION_SIGNALS.contains(&signal);
+    #[cfg(windows)]
+    return signal == CTRL_C;
+}
+
+/// Whether the given signal is a job-control request to stop.
+#[cfg(unix)]
+pub fn is_stop(signal: i32) -> bool {
+    STOP_SIGNALS.contains(&signal)
+}
+
+/// Stops this process until it's continued (i.e. with SIGCONT), the way a
+/// stop signal would have if we weren't intercepting it. This lets the shell
+/// see the job as stopped.
+#[cfg(unix)]
+pub fn suspend() -> Result<(), std::io::Error> {
+    nix::sys::signal::raise(nix::sys::signal::Signal::SIGSTOP)?;
+
+    Ok(())
+}
+
+/// Sends a signal to a child process, or to its whole process group if it has
+/// its own (e.g. it's a shell with job control), so that everything it
+/// started is stopped and resumed together.
+#[cfg(unix)]
+pub fn send_to_child(pid: u32, signal: i32) -> Result<(), std::io::Error> {
+    use nix::unistd::{getpgid, getpgrp, Pid};
+
+    match getpgid(Some(Pid::from_raw(pid as i32))) {
+        Ok(pgid) if pgid != getpgrp() => send_group(pgid.as_raw() as u32, signal),
+        _ => send(pid, signal),
+    }
+}
+
+/// Sends a signal to every process in a process group.
+#[cfg(unix)]
+fn send_group(pgid: u32, signal: i32) -> Result<(), std::io::Error> {
+    use nix::sys::signal::{killpg, Signal};
+    use nix::unistd::Pid;
+
+    let signal = Signal::try_from(signal)?;
+    killpg(Pid::from_raw(pgid as i32), signal)?;
+
+    Ok(())
+}
+
+/// Sends a signal to another process.
+#[cfg(unix)]
+pub fn send(pid: u32, signal: i32) -> Result<(), std::io::Error> {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    let signal = Signal::try_from(signal)?;
+    kill(Pid::from_raw(pid as i32), signal)?;
+
+    Ok(())
+}
+
+/// Intercepts signals sent to us, so that they can be handled at a point
+/// where it's safe to do so, instead of killing us before we've cleaned up.
+///
+/// Signals are intercepted from the moment this is created until it's
+/// dropped.
+pub struct SignalListener {
+    #[cfg(unix)]
+    signals: Signals,
+    #[cfg(windows)]
+    ctrl_c: tokio::signal::windows::CtrlC,
+}
+
+impl SignalListener {
+    pub fn new() -> Result<Self, std::io::Error> {
+        Ok(Self {
+            #[cfg(unix)]
+            signals: Signals::new(SIGNALS)?,
+            #[cfg(windows)]
+            ctrl_c: tokio::signal::windows::ctrl_c()?,
+        })
+    }
+
+    /// Waits for the next signal.
+    pub async fn next(&mut self) -> i32 {
+        // NOTE: we should always be able to receive signals through the life
+        // of our process
+        #[cfg(unix)]
+        return self
+            .signals
+            .next()
+            .await
+            .expect("signal iterator ended prematurely");
+        #[cfg(windows)]
+        {
+            self.ctrl_c
+                .recv()
+                .await
+                .expect("ctrl-c listener ended prematurely");
+            CTRL_C
+        }
+    }
+
+    /// Waits until we're asked to terminate, ignoring any other signals
+    /// (apart from stopping when asked to).
+    pub async fn shutdown(&mut self) -> i32 {
+        loop {
+            let signal = self.next().await;
+            if is_termination(signal) {
+                log::info!("received signal {signal}, shutting down");
+                break signal;
+            }
+
+            #[cfg(unix)]
+            if is_stop(signal) {
+                log::debug!("received signal {signal}, stopping");
+                if let Err(e) = suspend() {
+                    log::warn!("couldn't stop: {e}");
+                }
+                continue;
+            }
+
+            log::debug!("ignoring signal {signal}");
+        }
+    }
+}
+
+/// Runs something that would leave a mess if it were cut short (e.g. writing
+/// several objects to storage), holding off signals until it's finished.
+///
+/// Returns the first termination signal received while it was running, if
+/// any, so that the caller can exit once it's safe to. As signals stay
+/// intercepted afterwards, the caller is responsible for exiting.
+pub async fn shielded<F: Future>(fut: F) -> Result<(F::Output, Option<i32>), std::io::Error> {
+    let mut signals = SignalListener::new()?;
+    let mut received = None;
+    tokio::pin!(fut);
+    loop {
+        tokio::select! {
+            output = &mut fut => return Ok((output, received)),
+            signal = signals.next() => {
+                if is_termination(signal) {
+                    log::warn!("received signal {signal}, exiting once storage is up to date");
+                    received.get_or_insert(signal);
+                    continue;
+                }
+
+                #[cfg(unix)]
+                if is_stop(signal) {
+                    if let Err(e) = suspend() {
+                        log::warn!("couldn't stop: {e}");
+                    }
+                    continue;
+                }
+
+                log::debug!("ignoring signal {signal}");
+            }
+        }
+    }
+}
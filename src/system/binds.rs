@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use tokio::fs;
+
+use super::MountSecretsError;
+
+/// How a container runtime is told to bind-mount secrets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindFormat {
+    /// A systemd-nspawn settings file, with a `BindReadOnly=` line in its
+    /// `[Files]` section
+    Nspawn,
+    /// systemd-nspawn arguments (`--bind-ro=...`)
+    NspawnArgs,
+    /// Podman (or Docker) arguments (`--volume=...:ro`)
+    PodmanArgs,
+}
+
+/// A file `system mount` writes for a container runtime, binding the mounted
+/// secrets into containers that are started with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerBind {
+    pub format: BindFormat,
+    /// File to write
+    pub path: PathBuf,
+    /// Where the secrets appear in the container (default: the secret dir)
+    pub target: Option<PathBuf>,
+}
+
+impl FromStr for ContainerBind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(':').collect::<Vec<_>>();
+        let (format, path, target) = match parts[..] {
+            [format, path] => (format, path, None),
+            [format, path, target] => (format, path, Some(PathBuf::from(target))),
+            _ => return Err(format!("{s} is not format:file or format:file:target")),
+        };
+        let format = match format {
+            "nspawn" => BindFormat::Nspawn,
+            "nspawn-args" => BindFormat::NspawnArgs,
+            "podman-args" => BindFormat::PodmanArgs,
+            _ => {
+                return Err(format!(
+                    "unknown bind format {format} (expected nspawn, nspawn-args or podman-args)"
+                ))
+            }
+        };
+
+        Ok(Self {
+            format,
+            path: PathBuf::from(path),
+            target,
+        })
+    }
+}
+
+impl ContainerBind {
+    fn render(&self, generation: &Path, secret_dir: &Path) -> String {
+        let source = generation.display();
+        let target = self.target.as_deref().unwrap_or(secret_dir).display();
+        match self.format {
+            BindFormat::Nspawn => format!(
+                "# Written by credible system mount\n[Files]\nBindReadOnly={source}:{target}\n"
+            ),
+            BindFormat::NspawnArgs => format!("--bind-ro={source}:{target}\n"),
+            BindFormat::PodmanArgs => format!("--volume={source}:{target}:ro\n"),
+        }
+    }
+
+    /// Writes the file, binding the given generation.
+    pub async fn write(
+        &self,
+        generation: &Path,
+        secret_dir: &Path,
+    ) -> Result<(), MountSecretsError> {
+        let writing = |e| MountSecretsError::WritingContainerBind(self.path.clone(), e);
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await.map_err(writing)?;
+        }
+
+        // Write-then-rename, so that a container starting meanwhile never
+        // sees a partial file
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, self.render(generation, secret_dir))
+            .await
+            .map_err(writing)?;
+        fs::rename(&temp, &self.path).await.map_err(writing)?;
+        log::debug!("wrote container bind to {}", self.path.display());
+
+        Ok(())
+    }
+}
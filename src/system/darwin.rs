@@ -5,6 +5,7 @@ use std::str::FromStr;
 use thiserror::Error;
 use tokio::process::Command;
 
+use super::flags::check_mount_flags;
 use crate::process_utils::process_msg;
 
 #[derive(Error, Debug)]
@@ -34,6 +35,8 @@ pub enum MountRamfsError {
     MountingRamfs(String),
     #[error("did not find a device name from hdiutil")]
     NoDeviceFromHdiutil,
+    #[error("ramfs was mounted without required flags: {0}")]
+    MissingFlags(String),
 }
 
 #[derive(Error, Debug)]
@@ -90,7 +93,7 @@ pub async fn mount_persistent_ramfs(dir: &Path) -> Result<(), MountRamfsError> {
         .arg("-t")
         .arg("hfs")
         .arg("-o")
-        .arg("nobrowse,nodev,nosuid,-m=0751")
+        .arg("nobrowse,nodev,nosuid,noexec,-m=0751")
         .arg(&device_string)
         .arg(dir)
         .output()
@@ -100,6 +103,14 @@ pub async fn mount_persistent_ramfs(dir: &Path) -> Result<(), MountRamfsError> {
         let msg = process_msg("mount", mount_proc.stderr);
         return Err(MountRamfsError::MountingRamfs(msg));
     }
+
+    if let Err(msg) = check_mount_flags(dir) {
+        if let Err(e) = unmount_persistent_ramfs(dir).await {
+            log::warn!("couldn't unmount {}: {e}", dir.to_string_lossy());
+        }
+        return Err(MountRamfsError::MissingFlags(msg));
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use age::Identity;
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+
+use super::DiffGenerationError;
+use crate::hooks::NoHooks;
+use crate::secret::{
+    plaintext_digest,
+    render_template,
+    FileExposeArgs,
+    FileExposureError,
+    LinkMode,
+    TemplateExposureError,
+    FILE_PERMISSIONS,
+};
+use crate::util::map_secrets;
+use crate::{Exposures, MultiError, Secret, SecretStorage};
+
+/// A file in a generation (a secret, rendered template or copy of either),
+/// by what it holds and who can read it.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct GenerationFile {
+    pub sha256: String,
+    #[serde(serialize_with = "octal")]
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+fn octal<S: Serializer>(mode: &u32, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&format!("{mode:04o}"))
+}
+
+/// How a file differs between the current generation and a new one, by its
+/// path within the generation.
+#[derive(Serialize, Debug)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum GenerationChange {
+    Added {
+        path: String,
+        after: GenerationFile,
+    },
+    Removed {
+        path: String,
+        before: GenerationFile,
+    },
+    Changed {
+        path: String,
+        before: GenerationFile,
+        after: GenerationFile,
+    },
+}
+
+/// The generation currently linked from `secret_dir`, if there is one.
+pub async fn current_generation(secret_dir: &Path) -> Result<Option<PathBuf>, DiffGenerationError> {
+    match fs::read_link(secret_dir).await {
+        Ok(p) => Ok(Some(p)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(DiffGenerationError::ResolvingSecretDir(e)),
+    }
+}
+
+/// Files in an existing generation. Symlinks (to other files in it) are left
+/// out, as are files still being written.
+pub async fn read_generation(
+    generation: &Path,
+) -> Result<BTreeMap<String, GenerationFile>, DiffGenerationError> {
+    let reading = DiffGenerationError::ReadingGeneration;
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![generation.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir).await.map_err(reading)?;
+        while let Some(entry) = entries.next_entry().await.map_err(reading)? {
+            let path = entry.path();
+            let metadata = fs::symlink_metadata(&path).await.map_err(reading)?;
+            if metadata.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let name = path.to_string_lossy();
+            if !metadata.is_file() || name.ends_with(".credible-tmp") {
+                continue;
+            }
+
+            let sha256 = file_digest(&path).await.map_err(reading)?;
+            let relative = path.strip_prefix(generation).unwrap_or(&path);
+            files.insert(
+                relative.to_string_lossy().into_owned(),
+                GenerationFile {
+                    sha256,
+                    mode: metadata.mode() & 0o7777,
+                    uid: metadata.uid(),
+                    gid: metadata.gid(),
+                },
+            );
+        }
+    }
+
+    Ok(files)
+}
+
+/// SHA-256 digest (in hex) of a file, read a chunk at a time.
+async fn file_digest(path: &Path) -> Result<String, std::io::Error> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Files a new generation at `generation` would have, from fetching (and
+/// rendering) everything exposed, without writing anything.
+pub async fn plan_generation<S>(
+    generation: &Path,
+    secrets: &HashMap<String, Secret>,
+    exposures: &Exposures,
+    identities: &[Box<dyn Identity>],
+    storage: &S,
+    threshold: u64,
+) -> Result<BTreeMap<String, GenerationFile>, DiffGenerationError>
+where
+    S: SecretStorage,
+    <S as SecretStorage>::Error: 'static,
+{
+    let exposures = exposures.with_root(generation);
+    let mut files = BTreeMap::new();
+
+    let file_pairs =
+        map_secrets(secrets, exposures.files.iter()).map_err(DiffGenerationError::NoSuchSecret)?;
+    let mut errors = MultiError::default();
+    for (secret, exposure_set) in file_pairs {
+        let digest = plaintext_digest(storage, secret, identities, &NoHooks, threshold);
+        let digest = match digest.await {
+            Ok(digest) => digest,
+            Err(FileExposureError::NotInStorage) if exposure_set.iter().all(|s| s.optional) => {
+                continue
+            }
+            Err(e) => {
+                errors.push(&secret.name, e);
+                continue;
+            }
+        };
+        for spec in exposure_set {
+            add_planned(&mut files, generation, spec, &digest);
+        }
+    }
+    errors
+        .into_result()
+        .map_err(DiffGenerationError::FetchingSecrets)?;
+
+    let mut errors = MultiError::<TemplateExposureError>::default();
+    for template in exposures.templates.iter() {
+        let rendered = render_template(storage, secrets, template, identities, &NoHooks, threshold);
+        match rendered.await {
+            Ok((rendered, _)) => {
+                let digest = format!("{:x}", Sha256::digest(rendered));
+                add_planned(&mut files, generation, &template.file_spec(), &digest);
+            }
+            Err(TemplateExposureError::FetchingSecret(_, FileExposureError::NotInStorage))
+                if template.optional => {}
+            Err(e) => errors.push(&template.name, e),
+        }
+    }
+    errors
+        .into_result()
+        .map_err(DiffGenerationError::RenderingTemplates)?;
+
+    Ok(files)
+}
+
+/// Adds the files an exposure writes within the generation: its file in the
+/// secrets directory, and its vanity path if that's a copy in the generation.
+fn add_planned(
+    files: &mut BTreeMap<String, GenerationFile>,
+    generation: &Path,
+    spec: &FileExposeArgs,
+    sha256: &str,
+) {
+    let file = GenerationFile {
+        sha256: sha256.to_string(),
+        mode: spec.mode.unwrap_or(FILE_PERMISSIONS) & 0o7777,
+        uid: match &spec.owner {
+            Some(owner) => owner.as_ref().uid.as_raw(),
+            None => nix::unistd::geteuid().as_raw(),
+        },
+        gid: match &spec.group {
+            Some(group) => group.as_ref().gid.as_raw(),
+            None => nix::unistd::getegid().as_raw(),
+        },
+    };
+
+    let vanity = spec
+        .vanity_path
+        .as_deref()
+        .filter(|_| spec.link_mode != LinkMode::Symlink)
+        .and_then(|p| p.strip_prefix(generation).ok());
+    if let Some(path) = vanity {
+        files.insert(path.to_string_lossy().into_owned(), file.clone());
+    }
+    files.insert(spec.secret_name.clone(), file);
+}
+
+/// Every file that's added, removed or changed between the two generations,
+/// ordered by path.
+pub fn diff_generations(
+    mut current: BTreeMap<String, GenerationFile>,
+    planned: BTreeMap<String, GenerationFile>,
+) -> Vec<GenerationChange> {
+    let mut changes = Vec::new();
+    for (path, after) in planned {
+        match current.remove(&path) {
+            None => changes.push(GenerationChange::Added { path, after }),
+            Some(before) if before != after => changes.push(GenerationChange::Changed {
+                path,
+                before,
+                after,
+            }),
+            Some(_) => (),
+        }
+    }
+    changes.extend(
+        current
+            .into_iter()
+            .map(|(path, before)| GenerationChange::Removed { path, before }),
+    );
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+
+    changes
+}
+
+impl GenerationChange {
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Added { path, .. } | Self::Removed { path, .. } | Self::Changed { path, .. } => {
+                path
+            }
+        }
+    }
+}
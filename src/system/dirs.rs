@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use tokio::fs;
+
+use super::MountSecretsError;
+use crate::{GroupWrapper, UserWrapper};
+
+/// Mode and ownership for a directory `system mount` creates. Anything left
+/// unset keeps its default.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct DirPermissions {
+    pub mode: Option<u32>,
+    pub owner: Option<UserWrapper>,
+    pub group: Option<GroupWrapper>,
+}
+
+/// Mode and ownership for the directories `system mount` creates: the mount
+/// point itself (0751 by default), and directories created inside it for
+/// nested exposure paths.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct MountDirs {
+    /// The mount point of each generation
+    #[serde(default)]
+    pub root: DirPermissions,
+    /// Every directory created inside the mount point, unless overridden in
+    /// `paths`
+    #[serde(default)]
+    pub subdirs: DirPermissions,
+    /// Directories created inside the mount point, relative to it
+    #[serde(default)]
+    pub paths: HashMap<PathBuf, DirPermissions>,
+}
+
+impl MountDirs {
+    /// Applies the configured permissions for the mount point.
+    pub(super) async fn apply_root(&self, mount_point: &Path) -> Result<(), MountSecretsError> {
+        apply(mount_point, &self.root).await
+    }
+
+    /// Creates the given directory inside the mount point, applying the
+    /// configured permissions to it and every directory created on the way.
+    pub(super) async fn create_subdir(
+        &self,
+        mount_point: &Path,
+        dir: &Path,
+    ) -> Result<(), MountSecretsError> {
+        let Ok(relative) = dir.strip_prefix(mount_point) else {
+            return Ok(());
+        };
+
+        let mut current = mount_point.to_owned();
+        for component in relative.components() {
+            current.push(component);
+            if fs::symlink_metadata(&current).await.is_ok() {
+                continue;
+            }
+
+            fs::create_dir(&current)
+                .await
+                .map_err(MountSecretsError::CreatingFilesFailure)?;
+            let relative = current.strip_prefix(mount_point).unwrap_or(&current);
+            let permissions = self.paths.get(relative).unwrap_or(&self.subdirs);
+            apply(&current, permissions).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn apply(dir: &Path, permissions: &DirPermissions) -> Result<(), MountSecretsError> {
+    if permissions.owner.is_some() || permissions.group.is_some() {
+        let owner = permissions.owner.as_ref().map(|o| o.as_ref().uid);
+        let group = permissions.group.as_ref().map(|g| g.as_ref().gid);
+        nix::unistd::chown(dir, owner, group)
+            .map_err(MountSecretsError::PermissionSettingFailure)?;
+    }
+
+    if let Some(mode) = permissions.mode {
+        fs::set_permissions(dir, std::fs::Permissions::from_mode(mode))
+            .await
+            .map_err(MountSecretsError::CreatingFilesFailure)?;
+    }
+
+    Ok(())
+}
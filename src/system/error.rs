@@ -1,10 +1,13 @@
 use thiserror::Error;
 
-use crate::secret::FileExposureError;
+use crate::secret::{FileExposureError, TemplateExposureError};
 #[cfg(target_os = "macos")]
 use crate::system::darwin::*;
+#[cfg(target_os = "illumos")]
+use crate::system::illumos::*;
 #[cfg(target_os = "linux")]
 use crate::system::linux::*;
+use crate::MultiError;
 
 #[derive(Error, Debug)]
 pub enum MountSecretsError {
@@ -14,6 +17,8 @@ pub enum MountSecretsError {
     MountCheckFailure(#[from] CheckMountedError),
     #[error("failed to create ramfs: {0}")]
     RamfsCreationFailure(MountRamfsError),
+    #[error("failed to remount ramfs: {0}")]
+    RemountingFailure(MountRamfsError),
     // NOTE: The type system makes it hard to return a Box<dyn ...Error> trait
     // other than std::error::Error
     #[error("failed to read from backing store: {0}")]
@@ -34,9 +39,15 @@ pub enum MountSecretsError {
     #[error("no secret with name: {0}")]
     NoSuchSecret(String),
     #[error("error exposing secrets as files: {0}")]
-    ExposingFilesFailure(#[from] FileExposureError),
+    ExposingFilesFailure(MultiError<FileExposureError>),
+    #[error("error rendering templates: {0}")]
+    RenderingTemplatesFailure(MultiError<TemplateExposureError>),
     #[error("error unmounting old generation: {0}")]
     UnmountingOldGeneration(#[from] UnmountSecretsError),
+    #[error("error finding current generation: {0}")]
+    ResolvingSecretDir(std::io::Error),
+    #[error("error writing container bind to {}: {1}", .0.display())]
+    WritingContainerBind(std::path::PathBuf, std::io::Error),
 }
 
 #[derive(Error, Debug)]
@@ -52,3 +63,19 @@ pub enum UnmountSecretsError {
     #[error("failed to remove old symlink: {0}")]
     RemovingSymlink(std::io::Error),
 }
+
+#[derive(Error, Debug)]
+pub enum DiffGenerationError {
+    #[error("failed to load identities: {0}")]
+    LoadingIdentities(#[from] crate::age::DecryptionError),
+    #[error("failed to resolve secret dir: {0}")]
+    ResolvingSecretDir(std::io::Error),
+    #[error("failed to read current generation: {0}")]
+    ReadingGeneration(std::io::Error),
+    #[error("no secret with name: {0}")]
+    NoSuchSecret(String),
+    #[error("failed to fetch secrets: {0}")]
+    FetchingSecrets(MultiError<FileExposureError>),
+    #[error("failed to render templates: {0}")]
+    RenderingTemplates(MultiError<TemplateExposureError>),
+}
@@ -0,0 +1,30 @@
+use std::path::Path;
+
+use nix::sys::statvfs::{statvfs, FsFlags};
+
+/// Checks that a freshly-mounted secrets filesystem has the flags it was
+/// mounted with, so that it can't be used to run (or escalate privileges
+/// with) anything put in it. Returns the flags that are missing, if any.
+pub(super) fn check_mount_flags(dir: &Path) -> Result<(), String> {
+    let stat = statvfs(dir).map_err(|e| format!("couldn't read mount flags: {e}"))?;
+
+    #[allow(unused_mut)]
+    let mut required = vec![(FsFlags::ST_NOSUID, "nosuid")];
+    // Other platforms don't report these
+    #[cfg(target_os = "linux")]
+    required.extend([(FsFlags::ST_NOEXEC, "noexec"), (FsFlags::ST_NODEV, "nodev")]);
+
+    let missing = required
+        .into_iter()
+        .filter(|(flag, _)| !stat.flags().contains(*flag))
+        .map(|(_, name)| name)
+        .collect::<Vec<_>>();
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "{} is missing {}",
+            dir.to_string_lossy(),
+            missing.join(", ")
+        )),
+    }
+}
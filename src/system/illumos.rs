@@ -0,0 +1,87 @@
+use std::io;
+use std::path::Path;
+
+use thiserror::Error;
+use tokio::process::Command;
+
+use super::flags::check_mount_flags;
+use crate::process_utils::process_msg;
+
+const MNTTAB: &str = "/etc/mnttab";
+
+#[derive(Error, Debug)]
+#[error("failed to check if device mounted: {0}")]
+pub struct CheckMountedError(#[from] io::Error);
+
+pub async fn device_mounted(dir: &Path) -> Result<bool, CheckMountedError> {
+    // mnttab entries are tab-separated: special, mount point, fstype,
+    // options, time
+    let mnttab = tokio::fs::read_to_string(MNTTAB).await?;
+    let present = mnttab
+        .lines()
+        .filter_map(|line| line.split('\t').nth(1))
+        .any(|mount_point| Path::new(mount_point) == dir);
+
+    Ok(present)
+}
+
+#[derive(Error, Debug)]
+pub enum MountRamfsError {
+    #[error("unable to run mount: {0}")]
+    InvokingProcess(#[from] io::Error),
+    #[error("unable to mount tmpfs: {0}")]
+    MountingRamfs(String),
+    #[error("tmpfs was mounted without required flags: {0}")]
+    MissingFlags(String),
+}
+
+#[derive(Error, Debug)]
+pub enum UnmountRamfsError {
+    #[error("unable to run umount: {0}")]
+    InvokingProcess(#[from] io::Error),
+    #[error("unable to unmount tmpfs: {0}")]
+    UnmountingRamfs(String),
+}
+
+pub async fn mount_persistent_ramfs(dir: &Path) -> Result<(), MountRamfsError> {
+    // NOTE: illumos has no ramfs, tmpfs is the closest thing we have. Unlike
+    // ramfs, its pages may be written to swap under memory pressure.
+    let cmd = Command::new("mount")
+        .arg("-F")
+        .arg("tmpfs")
+        .arg("-o")
+        .arg("nosuid,noexec,mode=0751")
+        .arg("swap")
+        .arg(dir)
+        .output()
+        .await?;
+
+    if !cmd.status.success() {
+        let msg = process_msg("mount", cmd.stderr);
+        return Err(MountRamfsError::MountingRamfs(msg));
+    }
+
+    if let Err(msg) = check_mount_flags(dir) {
+        if let Err(e) = unmount_persistent_ramfs(dir).await {
+            log::warn!("couldn't unmount {}: {e}", dir.to_string_lossy());
+        }
+        return Err(MountRamfsError::MissingFlags(msg));
+    }
+
+    Ok(())
+}
+
+pub async fn unmount_persistent_ramfs(p: &Path) -> Result<(), UnmountRamfsError> {
+    let result = Command::new("umount")
+        .arg(p)
+        .output()
+        .await
+        .map_err(UnmountRamfsError::InvokingProcess)?;
+
+    if !result.status.success() {
+        let msg = process_msg("umount", result.stderr);
+        return Err(UnmountRamfsError::UnmountingRamfs(msg));
+    }
+
+    Ok(())
+}
@@ -2,9 +2,11 @@ use std::io;
 use std::path::Path;
 
 use block_utils::{get_mount_device, BlockUtilsError};
+use nix::mount::{mount, MsFlags};
 use thiserror::Error;
 use tokio::process::Command;
 
+use super::flags::check_mount_flags;
 use crate::process_utils::process_msg;
 
 #[derive(Error, Debug)]
@@ -23,6 +25,10 @@ pub enum MountRamfsError {
     InvokingProcess(#[from] io::Error),
     #[error("unable to mount ramfs: {0}")]
     MountingRamfs(String),
+    #[error("unable to remount ramfs: {0}")]
+    RemountingRamfs(nix::errno::Errno),
+    #[error("ramfs was mounted without required flags: {0}")]
+    MissingFlags(String),
 }
 
 #[derive(Error, Debug)]
@@ -42,7 +48,7 @@ pub async fn mount_persistent_ramfs(dir: &Path) -> Result<(), MountRamfsError> {
         .arg("none")
         .arg(dir)
         .arg("-o")
-        .arg("nodev,nosuid,mode=0751")
+        .arg("nodev,nosuid,noexec,mode=0751")
         .output()
         .await?;
 
@@ -51,9 +57,33 @@ pub async fn mount_persistent_ramfs(dir: &Path) -> Result<(), MountRamfsError> {
         return Err(MountRamfsError::MountingRamfs(msg));
     }
 
+    if let Err(msg) = check_mount_flags(dir) {
+        if let Err(e) = unmount_persistent_ramfs(dir).await {
+            log::warn!("couldn't unmount {}: {e}", dir.to_string_lossy());
+        }
+        return Err(MountRamfsError::MissingFlags(msg));
+    }
+
     Ok(())
 }
 
+/// Makes a mounted ramfs read-only, or writable again. This only changes the
+/// flags of this mount (a bind remount), and keeps the ones it was mounted
+/// with.
+pub fn remount_ramfs(dir: &Path, read_only: bool) -> Result<(), MountRamfsError> {
+    let mut flags = MsFlags::MS_REMOUNT
+        | MsFlags::MS_BIND
+        | MsFlags::MS_NODEV
+        | MsFlags::MS_NOSUID
+        | MsFlags::MS_NOEXEC;
+    if read_only {
+        flags |= MsFlags::MS_RDONLY;
+    }
+
+    mount(None::<&str>, dir, None::<&str>, flags, None::<&str>)
+        .map_err(MountRamfsError::RemountingRamfs)
+}
+
 pub async fn unmount_persistent_ramfs(p: &Path) -> Result<(), UnmountRamfsError> {
     let result = Command::new("umount")
         .arg(p)
@@ -1,17 +1,38 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::path::Path;
 
 use age::Identity;
-use nix::sys::time::TimeValLike;
-use nix::time::{clock_gettime, ClockId};
 use tokio::fs;
 
-use crate::secret::{expose_files, FileExposeArgs};
+use crate::hooks::Hooks;
+use crate::secret::{expose_files, expose_templates, read_template_secrets};
 use crate::util::map_secrets;
-use crate::{Secret, SecretStorage};
+use crate::{Exposures, Secret, SecretStorage};
+
+mod binds;
+pub use binds::{BindFormat, ContainerBind};
+
+mod diff;
+pub use diff::{
+    current_generation,
+    diff_generations,
+    plan_generation,
+    read_generation,
+    GenerationChange,
+    GenerationFile,
+};
+
+mod dirs;
+pub use dirs::{DirPermissions, MountDirs};
+
+mod flags;
 
 mod error;
-pub use error::{MountSecretsError, UnmountSecretsError};
+pub use error::{DiffGenerationError, MountSecretsError, UnmountSecretsError};
+
+mod platform;
+pub use platform::{Host, Platform};
 
 #[cfg(target_os = "macos")]
 mod darwin;
@@ -23,22 +44,29 @@ mod linux;
 #[cfg(target_os = "linux")]
 pub use linux::*;
 
+#[cfg(target_os = "illumos")]
+mod illumos;
+#[cfg(target_os = "illumos")]
+pub use illumos::*;
+
+#[allow(clippy::too_many_arguments)]
 pub async fn mount<S: SecretStorage>(
     base_mount_point: &Path,
     secret_dir: &Path,
     secrets: &HashMap<String, Secret>,
-    exposures: &HashMap<String, Vec<FileExposeArgs>>,
+    exposures: &Exposures,
+    dirs: &MountDirs,
     identities: &[Box<dyn Identity>],
     storage: &S,
+    threshold: u64,
+    hooks: &dyn Hooks,
+    platform: &dyn Platform,
 ) -> Result<(), MountSecretsError>
 where
     <S as SecretStorage>::Error: 'static,
 {
     // Get time since boot in ms
-    let time_ms = clock_gettime(ClockId::CLOCK_MONOTONIC)
-        .expect("failed to get time of day")
-        .num_milliseconds()
-        .to_string();
+    let time_ms = platform.monotonic_ms().to_string();
     let mount_point = base_mount_point.join(&time_ms);
 
     // NOTE: Because we mount a tmpfs, and use the ms since boot in our
@@ -48,7 +76,7 @@ where
     // If the directory exists, but isn't mounted, then we'll write to our
     // tmpfs without writing to whatever is currently backing this
     // directory anyway.
-    if device_mounted(&mount_point).await? {
+    if platform.device_mounted(&mount_point).await? {
         return Err(MountSecretsError::AlreadyMounted);
     }
 
@@ -58,15 +86,59 @@ where
             .map_err(MountSecretsError::CreatingFilesFailure);
     }
 
-    log::debug!("system-mounting {} exposures", exposures.len());
+    log::debug!("system-mounting {} exposures", exposures.files.len());
 
-    mount_persistent_ramfs(&mount_point)
+    platform
+        .mount_ramfs(&mount_point)
         .await
         .map_err(MountSecretsError::RamfsCreationFailure)?;
-    let file_pairs =
-        map_secrets(secrets, exposures.iter()).map_err(MountSecretsError::NoSuchSecret)?;
+    dirs.apply_root(&mount_point).await?;
 
-    expose_files(&mount_point, storage, &file_pairs, identities).await?;
+    // Relative vanity paths default to living alongside the secrets, which
+    // we do by placing them in this generation (which secret_dir will point
+    // to)
+    let exposures = exposures.with_root(&mount_point);
+    let parents = exposures
+        .vanity_paths()
+        .filter_map(|(p, _)| p.parent())
+        .filter(|p| p.starts_with(&mount_point));
+    for parent in parents {
+        dirs.create_subdir(&mount_point, parent).await?;
+    }
+
+    // Each stage is only exposed once everything it's after has been
+    for stage in exposures.stages() {
+        let file_pairs =
+            map_secrets(secrets, stage.files.iter()).map_err(MountSecretsError::NoSuchSecret)?;
+        expose_files(
+            &mount_point,
+            storage,
+            &file_pairs,
+            identities,
+            hooks,
+            threshold,
+        )
+        .await
+        .map_err(MountSecretsError::ExposingFilesFailure)?;
+        let templates = &stage.templates;
+        expose_templates(
+            &mount_point,
+            storage,
+            secrets,
+            templates,
+            identities,
+            hooks,
+            threshold,
+        )
+        .await
+        .map_err(MountSecretsError::RenderingTemplatesFailure)?;
+    }
+
+    // Nothing should change a generation once it's populated, not even root
+    platform
+        .set_read_only(&mount_point, true)
+        .await
+        .map_err(MountSecretsError::RemountingFailure)?;
 
     if secret_dir.exists() {
         tokio::fs::remove_file(secret_dir)
@@ -78,37 +150,170 @@ where
         .map_err(MountSecretsError::SymlinkCreationFailure)?;
 
     // Remove any old symlinks
-    unmount(base_mount_point, None, Some(&time_ms)).await?;
+    unmount(base_mount_point, None, Some(&time_ms), hooks, platform).await?;
+
+    Ok(())
+}
+
+/// Re-exposes only the given secrets (and the templates that use them) in the
+/// generation currently linked from `secret_dir`, instead of mounting a new
+/// generation.
+#[allow(clippy::too_many_arguments)]
+pub async fn refresh<S: SecretStorage>(
+    secret_dir: &Path,
+    changed: &[&Secret],
+    secrets: &HashMap<String, Secret>,
+    exposures: &Exposures,
+    identities: &[Box<dyn Identity>],
+    storage: &S,
+    threshold: u64,
+    hooks: &dyn Hooks,
+    platform: &dyn Platform,
+) -> Result<(), MountSecretsError>
+where
+    <S as SecretStorage>::Error: 'static,
+{
+    let mount_point = fs::read_link(secret_dir)
+        .await
+        .map_err(MountSecretsError::ResolvingSecretDir)?;
+    platform
+        .set_read_only(&mount_point, false)
+        .await
+        .map_err(MountSecretsError::RemountingFailure)?;
+    let res = refresh_generation(
+        &mount_point,
+        changed,
+        secrets,
+        exposures,
+        identities,
+        storage,
+        threshold,
+        hooks,
+    )
+    .await;
+    // Made read-only again even if refreshing failed part-way through
+    platform
+        .set_read_only(&mount_point, true)
+        .await
+        .map_err(MountSecretsError::RemountingFailure)?;
+
+    res
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn refresh_generation<S: SecretStorage>(
+    mount_point: &Path,
+    changed: &[&Secret],
+    secrets: &HashMap<String, Secret>,
+    exposures: &Exposures,
+    identities: &[Box<dyn Identity>],
+    storage: &S,
+    threshold: u64,
+    hooks: &dyn Hooks,
+) -> Result<(), MountSecretsError>
+where
+    <S as SecretStorage>::Error: 'static,
+{
+    let exposures = exposures.with_root(mount_point);
+    let names = changed
+        .iter()
+        .map(|s| s.name.as_str())
+        .collect::<HashSet<_>>();
+
+    for stage in exposures.stages() {
+        let files = stage
+            .files
+            .iter()
+            .filter(|(name, _)| names.contains(name.as_str()));
+        let file_pairs = map_secrets(secrets, files).map_err(MountSecretsError::NoSuchSecret)?;
+        expose_files(
+            mount_point,
+            storage,
+            &file_pairs,
+            identities,
+            hooks,
+            threshold,
+        )
+        .await
+        .map_err(MountSecretsError::ExposingFilesFailure)?;
+
+        let mut templates = Vec::new();
+        for template in stage.templates.iter() {
+            // Templates we can't read are re-rendered anyway, so that the
+            // error is reported
+            let uses_changed = match read_template_secrets(&template.template).await {
+                Ok(used) => used.iter().any(|n| names.contains(n.as_str())),
+                Err(_) => true,
+            };
+            if uses_changed {
+                templates.push(template.clone());
+            }
+        }
+        let res = expose_templates(
+            mount_point,
+            storage,
+            secrets,
+            &templates,
+            identities,
+            hooks,
+            threshold,
+        );
+        res.await
+            .map_err(MountSecretsError::RenderingTemplatesFailure)?;
+    }
+
+    for secret in changed {
+        log::info!("refreshed {}", secret.name);
+        hooks.on_refresh(secret);
+    }
 
     Ok(())
 }
 
+/// Sort key for generation directories, which are named after the time they
+/// were created (in ms since boot).
+fn generation_order(name: &OsStr) -> (Option<u64>, OsString) {
+    let time = name.to_str().and_then(|n| n.parse().ok());
+    (time, name.to_owned())
+}
+
 pub async fn unmount(
     base_mount_point: &Path,
     unlink_dir: Option<&Path>,
     skip: Option<&str>,
+    hooks: &dyn Hooks,
+    platform: &dyn Platform,
 ) -> Result<(), UnmountSecretsError> {
     let mut dir_entries = fs::read_dir(base_mount_point)
         .await
         .map_err(UnmountSecretsError::ListingOldSymlinks)?;
 
+    // Generations are cleaned up oldest-first, so a failure part-way through
+    // always leaves the newest ones behind
+    let mut generations = Vec::new();
     while let Some(entry) = dir_entries
         .next_entry()
         .await
         .map_err(UnmountSecretsError::ListingOldSymlinks)?
     {
+        generations.push(entry);
+    }
+    generations.sort_by_key(|e| generation_order(&e.file_name()));
+
+    for entry in generations {
         let file_name = entry.file_name();
         let dir_name = file_name.to_str().expect("path is not UTF-8 compatible");
         if Some(dir_name) != skip {
             let p = entry.path();
-            if device_mounted(&p).await? {
-                unmount_persistent_ramfs(&p).await?
+            if platform.device_mounted(&p).await? {
+                platform.unmount_ramfs(&p).await?
             }
 
             // TODO: better error
             fs::remove_dir(&p)
                 .await
                 .map_err(UnmountSecretsError::DeletingOldDir)?;
+            hooks.on_cleanup(&p);
         }
     }
 
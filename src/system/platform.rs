@@ -0,0 +1,64 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use nix::sys::time::TimeValLike;
+use nix::time::{clock_gettime, ClockId};
+
+#[cfg(target_os = "macos")]
+use crate::system::darwin::*;
+#[cfg(target_os = "illumos")]
+use crate::system::illumos::*;
+#[cfg(target_os = "linux")]
+use crate::system::linux::*;
+
+/// The host facilities that system mounts depend on: the clock used to name
+/// generations, and mounting ramfs filesystems for them.
+///
+/// [Host] is the real thing. Swapping it out lets mounts be exercised without
+/// root (see the `test-env` feature).
+#[async_trait]
+pub trait Platform: Send + Sync {
+    /// Milliseconds since boot, which new generations are named after.
+    fn monotonic_ms(&self) -> u64;
+
+    async fn device_mounted(&self, dir: &Path) -> Result<bool, CheckMountedError>;
+
+    async fn mount_ramfs(&self, dir: &Path) -> Result<(), MountRamfsError>;
+
+    async fn unmount_ramfs(&self, dir: &Path) -> Result<(), UnmountRamfsError>;
+
+    /// Makes a mounted generation read-only (or writable again, to refresh
+    /// it), where supported.
+    async fn set_read_only(&self, _dir: &Path, _read_only: bool) -> Result<(), MountRamfsError> {
+        Ok(())
+    }
+}
+
+/// The running system.
+pub struct Host;
+
+#[async_trait]
+impl Platform for Host {
+    fn monotonic_ms(&self) -> u64 {
+        clock_gettime(ClockId::CLOCK_MONOTONIC)
+            .expect("failed to get time of day")
+            .num_milliseconds() as u64
+    }
+
+    async fn device_mounted(&self, dir: &Path) -> Result<bool, CheckMountedError> {
+        device_mounted(dir).await
+    }
+
+    async fn mount_ramfs(&self, dir: &Path) -> Result<(), MountRamfsError> {
+        mount_persistent_ramfs(dir).await
+    }
+
+    async fn unmount_ramfs(&self, dir: &Path) -> Result<(), UnmountRamfsError> {
+        unmount_persistent_ramfs(dir).await
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn set_read_only(&self, dir: &Path, read_only: bool) -> Result<(), MountRamfsError> {
+        remount_ramfs(dir, read_only)
+    }
+}
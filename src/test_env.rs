@@ -0,0 +1,226 @@
+//! Hermetic environments for testing code that uses credible, behind the
+//! `test-env` feature.
+//!
+//! A [TestEnv] bundles everything a system mount or process run needs without
+//! touching the real system: a temporary root to mount under, in-memory
+//! storage, a fresh key, and a [Platform] with a fake clock that "mounts"
+//! plain directories (so no root is needed).
+
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use age::secrecy::ExposeSecret;
+use age::x25519;
+use async_trait::async_trait;
+use tempfile::TempDir;
+
+use crate::age::encrypt_bytes;
+pub use crate::age::EncryptionError;
+pub use crate::secret::{MemoryStorage, MemoryStorageError};
+use crate::system::{CheckMountedError, MountRamfsError, Platform, UnmountRamfsError};
+use crate::Secret;
+
+/// A monotonic clock that only moves when told to. Clones share the same
+/// time.
+#[derive(Debug, Clone, Default)]
+pub struct FakeClock {
+    ms: Arc<AtomicU64>,
+}
+
+impl FakeClock {
+    pub fn new(start: Duration) -> Self {
+        Self {
+            ms: Arc::new(AtomicU64::new(start.as_millis() as u64)),
+        }
+    }
+
+    pub fn now(&self) -> Duration {
+        Duration::from_millis(self.ms.load(Ordering::SeqCst))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.ms.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+/// A [Platform] that mounts nothing, using plain directories as generations
+/// (emptied on "unmount", like a ramfs) and a [FakeClock] to name them.
+///
+/// Every reading of the clock advances it by 1ms, so consecutive mounts get
+/// distinct (and predictable) generation names.
+#[derive(Debug, Default)]
+pub struct TestPlatform {
+    clock: FakeClock,
+    mounted: Mutex<HashSet<PathBuf>>,
+}
+
+impl TestPlatform {
+    pub fn new(clock: FakeClock) -> Self {
+        Self {
+            clock,
+            mounted: Mutex::default(),
+        }
+    }
+
+    /// Generation directories currently "mounted".
+    pub fn mounted(&self) -> HashSet<PathBuf> {
+        self.mounted.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Platform for TestPlatform {
+    fn monotonic_ms(&self) -> u64 {
+        let now = self.clock.now();
+        self.clock.advance(Duration::from_millis(1));
+        now.as_millis() as u64
+    }
+
+    async fn device_mounted(&self, dir: &Path) -> Result<bool, CheckMountedError> {
+        Ok(self.mounted.lock().unwrap().contains(dir))
+    }
+
+    async fn mount_ramfs(&self, dir: &Path) -> Result<(), MountRamfsError> {
+        self.mounted.lock().unwrap().insert(dir.to_owned());
+        Ok(())
+    }
+
+    async fn unmount_ramfs(&self, dir: &Path) -> Result<(), UnmountRamfsError> {
+        // Unmounting a ramfs discards everything in it, leaving the empty
+        // mount point behind
+        let emptied = match tokio::fs::remove_dir_all(dir).await {
+            Ok(()) => tokio::fs::create_dir(dir).await,
+            Err(e) => Err(e),
+        };
+        emptied.map_err(|e| UnmountRamfsError::UnmountingRamfs(e.to_string()))?;
+        self.mounted.lock().unwrap().remove(dir);
+
+        Ok(())
+    }
+}
+
+/// Everything needed to run credible hermetically, removed when dropped.
+pub struct TestEnv {
+    root: TempDir,
+    identity: x25519::Identity,
+    clock: FakeClock,
+    platform: TestPlatform,
+    storage: MemoryStorage,
+}
+
+impl TestEnv {
+    /// Creates a new environment in a fresh temporary directory, with the
+    /// clock starting at 1s after "boot".
+    pub fn new() -> std::io::Result<Self> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir(root.path().join("mnt"))?;
+
+        let identity = x25519::Identity::generate();
+        std::fs::write(
+            root.path().join("identity.txt"),
+            identity.to_string().expose_secret(),
+        )?;
+
+        let clock = FakeClock::new(Duration::from_secs(1));
+        Ok(Self {
+            root,
+            identity,
+            platform: TestPlatform::new(clock.clone()),
+            clock,
+            storage: MemoryStorage::new(),
+        })
+    }
+
+    /// Directory everything in this environment lives under.
+    pub fn root(&self) -> &Path {
+        self.root.path()
+    }
+
+    /// Where to mount generations (i.e. `system mount --mount-point`).
+    pub fn mount_point(&self) -> PathBuf {
+        self.root().join("mnt")
+    }
+
+    /// Where to link the current generation (i.e. `system mount
+    /// --secret-dir`).
+    pub fn secret_dir(&self) -> PathBuf {
+        self.root().join("secrets")
+    }
+
+    pub fn clock(&self) -> &FakeClock {
+        &self.clock
+    }
+
+    pub fn platform(&self) -> &TestPlatform {
+        &self.platform
+    }
+
+    pub fn storage(&self) -> &MemoryStorage {
+        &self.storage
+    }
+
+    /// Path to a file holding this environment's private key.
+    pub fn identity_path(&self) -> PathBuf {
+        self.root().join("identity.txt")
+    }
+
+    /// This environment's private key, which decrypts everything added with
+    /// [TestEnv::add_secret].
+    pub fn identities(&self) -> Vec<Box<dyn age::Identity>> {
+        let identity = self
+            .identity
+            .to_string()
+            .expose_secret()
+            .parse::<x25519::Identity>()
+            .expect("identity round-trips");
+        vec![Box::new(identity)]
+    }
+
+    pub fn public_key(&self) -> String {
+        self.identity.to_public().to_string()
+    }
+
+    /// A secret named `name`, stored at `<name>.age` and encrypted to this
+    /// environment's key.
+    pub fn secret(&self, name: &str) -> Secret {
+        Secret {
+            name: name.to_string(),
+            encryption_keys: vec![self.public_key()],
+            path: PathBuf::from(format!("{name}.age")),
+            value: None,
+            mount_path: None,
+            owner_user: None,
+            owner_group: None,
+            pin: None,
+            signing_keys: Vec::new(),
+            identities: Vec::new(),
+            on_change: None,
+            allowed_uids: Vec::new(),
+            allowed_gids: Vec::new(),
+            metadata: Default::default(),
+            deprecated: false,
+            replaced_by: None,
+            defined_in: None,
+            tenant: None,
+        }
+    }
+
+    /// Encrypts `plaintext` and puts it in storage as the secret `name`,
+    /// returning the secret's definition.
+    pub async fn add_secret(
+        &self,
+        name: &str,
+        plaintext: &[u8],
+    ) -> Result<Secret, EncryptionError> {
+        let secret = self.secret(name);
+        let ciphertext =
+            encrypt_bytes(Cursor::new(plaintext.to_vec()), &secret.encryption_keys).await?;
+        self.storage.insert(secret.path.clone(), ciphertext);
+
+        Ok(secret)
+    }
+}
@@ -0,0 +1,28 @@
+//! Internals exposed for property tests and fuzzing, behind the `testing`
+//! feature. Nothing here is a stable API.
+
+pub use crate::age::{decrypt_bytes, encrypt_bytes, DecryptionError, EncryptionError};
+pub use crate::process::VanityPathRecord;
+pub use crate::secret::{
+    expose_files,
+    read_secret,
+    read_secret_if_changed,
+    write_secret,
+    write_secret_if_unchanged,
+    FileExposeArgs,
+    FileExposureError,
+    KeepPrevious,
+    LinkMode,
+    ObjectMetadata,
+    ReadSecretError,
+    Spooled,
+    WriteSecretError,
+    GENERATION_XATTR,
+    SECRET_XATTR,
+};
+use crate::SecretManagerConfig;
+
+/// Parses a config file, the same way `credible` does on startup.
+pub fn parse_config(data: &[u8]) -> Result<Vec<SecretManagerConfig>, serde_yaml::Error> {
+    SecretManagerConfig::from_documents(data, &mut Default::default())
+}
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 use std::pin::Pin;
+use std::process::ExitStatus;
 
+use tokio::fs::OpenOptions;
 use tokio::io::AsyncRead;
 
-use crate::secret::{EnvExposeArgs, FileExposeArgs};
+use crate::secret::{EnvExposeArgs, FileExposeArgs, TemplateExposeArgs};
 use crate::{ExposureSpec, Secret};
 
 pub struct BoxedAsyncReader {
@@ -31,7 +33,8 @@ impl AsyncRead for BoxedAsyncReader {
 
 /// Maps (name, exposure_set) pairs into (Secret, exposure_set) pairs.
 /// Required because we can't use generics in a closure, and ideally I want to
-/// avoid copy-pasting this block
+/// avoid copy-pasting this block. Fails with the names of all missing
+/// secrets.
 pub fn map_secrets<'a, A, I>(
     secrets: &'a HashMap<String, Secret>,
     items: I,
@@ -40,27 +43,137 @@ where
     I: Iterator<Item = (&'a String, &'a Vec<A>)>,
     A: 'static,
 {
-    items
-        .map(|(name, item)| {
-            secrets
-                .get(name.as_str())
-                .map(|secret| (secret, item))
-                .ok_or_else(|| name.into())
-        })
-        .collect::<Result<Vec<_>, _>>()
+    let mut missing = Vec::new();
+    let mut pairs = Vec::new();
+    for (name, item) in items {
+        match secrets.get(name.as_str()) {
+            Some(secret) => pairs.push((secret, item)),
+            None => missing.push(name.as_str()),
+        }
+    }
+
+    match missing.is_empty() {
+        true => Ok(pairs),
+        false => Err(missing.join(", ")),
+    }
 }
 
 pub fn partition_specs<I: IntoIterator<Item = ExposureSpec>>(
     items: I,
-) -> (Vec<FileExposeArgs>, Vec<EnvExposeArgs>) {
-    items
-        .into_iter()
-        .fold((vec![], vec![]), |(mut fs, mut es), item| {
+) -> (
+    Vec<FileExposeArgs>,
+    Vec<EnvExposeArgs>,
+    Vec<TemplateExposeArgs>,
+) {
+    items.into_iter().fold(
+        (vec![], vec![], vec![]),
+        |(mut fs, mut es, mut ts), item| {
             match item {
                 ExposureSpec::Env(s) => es.push(s),
                 ExposureSpec::File(s) => fs.push(*s),
+                ExposureSpec::Template(s) => ts.push(*s),
             };
 
-            (fs, es)
-        })
+            (fs, es, ts)
+        },
+    )
+}
+
+/// Variables set by a credentials file's `KEY=VALUE` lines (other lines are
+/// ignored).
+pub fn parse_credentials(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.lines().filter_map(|line| line.split_once('='))
+}
+
+/// Name of the user we're running as.
+#[cfg(unix)]
+pub fn current_user() -> String {
+    use nix::unistd::{getuid, User};
+
+    User::from_uid(getuid())
+        .ok()
+        .flatten()
+        .map(|u| u.name)
+        .unwrap_or_else(|| getuid().to_string())
+}
+
+/// Name of the user we're running as.
+#[cfg(windows)]
+pub fn current_user() -> String {
+    std::env::var("USERNAME").unwrap_or_else(|_| String::from("<unknown>"))
+}
+
+/// Hostname of the machine we're running on.
+#[cfg(unix)]
+pub fn current_host() -> String {
+    nix::unistd::gethostname()
+        .map(|h| h.to_string_lossy().to_string())
+        .unwrap_or_else(|_| String::from("<unknown>"))
+}
+
+/// Hostname of the machine we're running on.
+#[cfg(windows)]
+pub fn current_host() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("<unknown>"))
+}
+
+/// Builds an [ExitStatus] for a process that exited with the given code.
+pub fn exit_status(code: i32) -> ExitStatus {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(code << 8)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+}
+
+/// Code to exit with to pass on the given status, following the shell
+/// convention of 128 + N for processes killed by signal N.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+
+    status.code().unwrap_or(1)
+}
+
+/// Options for opening a file that will be created with the given permissions.
+///
+/// Permissions are only applied on unix, on Windows new files inherit the ACL
+/// of their directory instead.
+pub fn open_options_with_mode(mode: u32) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    options.mode(mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    options
+}
+
+/// Options for creating a new file with the given permissions, failing if
+/// anything (including a symlink) already exists at the path.
+pub fn create_new_with_mode(mode: u32) -> OpenOptions {
+    let mut options = open_options_with_mode(mode);
+    options.write(true).create_new(true);
+    no_follow(&mut options);
+
+    options
+}
+
+/// Refuses to open the file if the last component of its path is a symlink.
+/// Only applies on unix.
+pub fn no_follow(options: &mut OpenOptions) -> &mut OpenOptions {
+    #[cfg(unix)]
+    options.custom_flags(nix::fcntl::OFlag::O_NOFOLLOW.bits());
+
+    options
 }
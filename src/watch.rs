@@ -0,0 +1,390 @@
+//! Detecting changes to stored secrets, so that long-running mounts can be
+//! kept up to date without re-exposing everything.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rand::Rng;
+
+use crate::secret::{
+    read_secret_if_changed,
+    CiphertextPin,
+    ConditionalRead,
+    ReadSecretError,
+    Spooled,
+};
+use crate::{MultiError, Secret, SecretStorage};
+
+/// A secret whose ciphertext has changed since it was last recorded.
+#[derive(Debug, Clone)]
+pub struct Change<'a> {
+    pub secret: &'a Secret,
+    pub digest: CiphertextPin,
+    /// Storage's version of the ciphertext, if it has them
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Seen {
+    digest: CiphertextPin,
+    version: Option<String>,
+}
+
+/// Remembers the digest (and storage version) of each watched secret's
+/// ciphertext.
+///
+/// Checking and recording are separate steps, so that a change that couldn't
+/// be applied is reported again on the next check.
+#[derive(Debug)]
+pub struct DigestTracker {
+    seen: HashMap<String, Seen>,
+    /// Size above which ciphertext is spooled to disk while it's digested
+    threshold: u64,
+}
+
+impl DigestTracker {
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            seen: HashMap::new(),
+            threshold,
+        }
+    }
+
+    /// Fetches the ciphertext of each of the given secrets, returning those
+    /// whose digest differs from the one last recorded. Secrets that have
+    /// never been recorded are always returned.
+    ///
+    /// Ciphertext still at the version last recorded isn't downloaded again,
+    /// where storage supports it.
+    pub async fn check<'a, S>(
+        &mut self,
+        storage: &S,
+        secrets: &[&'a Secret],
+    ) -> Result<Vec<Change<'a>>, MultiError<ReadSecretError<S::Error>>>
+    where
+        S: SecretStorage + Sync,
+    {
+        let mut changes = Vec::new();
+        let mut errors = MultiError::default();
+        for secret in secrets {
+            let seen = self.seen.get_mut(&secret.name);
+            let last_version = seen.as_ref().and_then(|s| s.version.as_deref());
+            let res =
+                match read_secret_if_changed(storage, secret, last_version, self.threshold).await {
+                    Ok(ConditionalRead::Unchanged) => {
+                        log::debug!("{} is unchanged", secret.name);
+                        continue;
+                    }
+                    Ok(ConditionalRead::Changed { reader, version }) => {
+                        Spooled::read(reader, &secret.name, self.threshold)
+                            .await
+                            .map(|(_, digest)| (digest, version))
+                            .map_err(ReadSecretError::ReadingCiphertext)
+                    }
+                    Err(e) => Err(e),
+                };
+            let (digest, version) = match res {
+                Ok(r) => r,
+                Err(e) => {
+                    errors.push(&secret.name, e);
+                    continue;
+                }
+            };
+
+            match seen {
+                // Re-uploaded without changing, there's nothing to apply
+                Some(seen) if seen.digest == digest => seen.version = version,
+                _ => {
+                    log::debug!("ciphertext for {} is now {}", secret.name, digest);
+                    changes.push(Change {
+                        secret,
+                        digest,
+                        version,
+                    });
+                }
+            }
+        }
+
+        errors.into_result()?;
+        Ok(changes)
+    }
+
+    /// Records the given changes as seen.
+    pub fn record(&mut self, changes: &[Change<'_>]) {
+        for change in changes {
+            let seen = Seen {
+                digest: change.digest.clone(),
+                version: change.version.clone(),
+            };
+            self.seen.insert(change.secret.name.clone(), seen);
+        }
+    }
+}
+
+/// When to next poll storage for changes.
+///
+/// Every delay is randomly spread by up to `jitter` (a fraction of the
+/// delay), so that a fleet of hosts started together doesn't poll in
+/// lockstep. Consecutive failures double the delay, up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PollSchedule {
+    pub interval: Duration,
+    pub jitter: f64,
+    pub max_backoff: Duration,
+}
+
+impl PollSchedule {
+    /// Delay before the next poll, given how many polls in a row have failed.
+    pub fn next_delay(&self, failures: u32) -> Duration {
+        let delay = self
+            .interval
+            .saturating_mul(2u32.saturating_pow(failures))
+            .min(self.max_backoff.max(self.interval));
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        let spread = rand::thread_rng().gen_range(-jitter..=jitter);
+        delay.mul_f64(1.0 + spread)
+    }
+}
+
+/// What a [ChangeNotifier] knows about what changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeHint {
+    /// Anything may have changed (e.g. a poll interval elapsed)
+    Any,
+    /// Only the objects at these storage paths changed
+    Paths(Vec<PathBuf>),
+}
+
+impl ChangeHint {
+    /// Narrows the given secrets down to those that may have changed.
+    pub fn filter<'a>(&self, secrets: &[&'a Secret]) -> Vec<&'a Secret> {
+        match self {
+            Self::Any => secrets.to_vec(),
+            Self::Paths(paths) => secrets
+                .iter()
+                .filter(|s| paths.contains(&s.path))
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ChangeNotifierError {
+    #[error("error receiving change notification: {0}")]
+    Receiving(Box<dyn std::error::Error + Send + Sync>),
+    #[error("error watching {0} for changes: {1}")]
+    Watching(PathBuf, std::io::Error),
+}
+
+/// A source of notifications that stored secrets may have changed, which
+/// tells watch mode when to check for changes.
+///
+/// Notifications are only hints: changed secrets are still confirmed by
+/// comparing ciphertext digests, so a source that fires spuriously only costs
+/// an extra fetch.
+#[async_trait]
+pub trait ChangeNotifier: Send {
+    /// Waits until secrets may have changed.
+    async fn wait(&mut self) -> Result<ChangeHint, ChangeNotifierError>;
+
+    /// Called after each check for changes, so that sources can back off
+    /// while storage is failing.
+    fn checked(&mut self, _succeeded: bool) {}
+}
+
+/// Notifies on a [PollSchedule], for storage that can't push notifications.
+pub struct PollNotifier {
+    schedule: PollSchedule,
+    failures: u32,
+}
+
+impl PollNotifier {
+    pub fn new(schedule: PollSchedule) -> Self {
+        Self {
+            schedule,
+            failures: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl ChangeNotifier for PollNotifier {
+    async fn wait(&mut self) -> Result<ChangeHint, ChangeNotifierError> {
+        let delay = self.schedule.next_delay(self.failures);
+        log::debug!(
+            "checking for changes in {}",
+            humantime::format_duration(delay)
+        );
+        tokio::time::sleep(delay).await;
+
+        Ok(ChangeHint::Any)
+    }
+
+    fn checked(&mut self, succeeded: bool) {
+        self.failures = match succeeded {
+            true => 0,
+            false => self.failures.saturating_add(1),
+        };
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use inotify::InotifyNotifier;
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::path::{Path, PathBuf};
+
+    use async_trait::async_trait;
+    use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+    use tokio::io::unix::AsyncFd;
+
+    use super::{ChangeHint, ChangeNotifier, ChangeNotifierError, PollNotifier, PollSchedule};
+    use crate::secret::relative_path;
+    use crate::Secret;
+
+    /// Closes the inotify instance when dropped (nix's handle is `Copy`, so
+    /// it doesn't).
+    struct Handle(Inotify);
+
+    impl AsRawFd for Handle {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            let _ = nix::unistd::close(self.0.as_raw_fd());
+        }
+    }
+
+    /// Notifies when files of watched secrets change in a local storage
+    /// directory, using inotify.
+    ///
+    /// Storage directories may be network mounts, where changes made on other
+    /// hosts don't raise events, so it still polls on a [PollSchedule] too.
+    pub struct InotifyNotifier {
+        inotify: AsyncFd<Handle>,
+        /// Storage path of each watched directory
+        dirs: HashMap<WatchDescriptor, BTreeSet<PathBuf>>,
+        poll: PollNotifier,
+    }
+
+    impl InotifyNotifier {
+        /// Watches the directories that the given secrets are stored in,
+        /// under `root`.
+        pub fn new(
+            root: &Path,
+            secrets: &[&Secret],
+            schedule: PollSchedule,
+        ) -> Result<Self, ChangeNotifierError> {
+            let watching = |e| ChangeNotifierError::Watching(root.to_owned(), e);
+            let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+                .map_err(|e| watching(e.into()))?;
+            let inotify = AsyncFd::new(Handle(inotify)).map_err(watching)?;
+
+            // Files are replaced by renaming over them, or written in place
+            let flags = AddWatchFlags::IN_CLOSE_WRITE
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_MOVED_FROM
+                | AddWatchFlags::IN_DELETE
+                | AddWatchFlags::IN_ONLYDIR;
+            // Each directory is recorded as written in secrets' paths, so
+            // that changed paths can be matched against them
+            let mut parents = BTreeMap::<_, BTreeSet<_>>::new();
+            for secret in secrets {
+                let relative =
+                    relative_path(&secret.path).and_then(|p| Some(p.parent()?.to_owned()));
+                if let (Some(relative), Some(parent)) = (relative, secret.path.parent()) {
+                    parents
+                        .entry(relative)
+                        .or_default()
+                        .insert(parent.to_owned());
+                }
+            }
+            let mut dirs = HashMap::new();
+            for (relative, parents) in parents {
+                let dir = root.join(relative);
+                let wd = inotify
+                    .get_ref()
+                    .0
+                    .add_watch(&dir, flags)
+                    .map_err(|e| ChangeNotifierError::Watching(dir, e.into()))?;
+                dirs.insert(wd, parents);
+            }
+
+            Ok(Self {
+                inotify,
+                dirs,
+                poll: PollNotifier::new(schedule),
+            })
+        }
+    }
+
+    /// Waits for events on any of the watched directories.
+    async fn changes(
+        inotify: &AsyncFd<Handle>,
+        dirs: &HashMap<WatchDescriptor, BTreeSet<PathBuf>>,
+    ) -> Result<ChangeHint, ChangeNotifierError> {
+        loop {
+            let mut guard = inotify
+                .readable()
+                .await
+                .map_err(|e| ChangeNotifierError::Receiving(Box::new(e)))?;
+            let events = guard.try_io(|i| i.get_ref().0.read_events().map_err(Into::into));
+            match events {
+                Ok(Ok(events)) => return Ok(hint(dirs, events)),
+                Ok(Err(e)) => return Err(ChangeNotifierError::Receiving(Box::new(e))),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Storage paths that the given events were for.
+    fn hint(
+        dirs: &HashMap<WatchDescriptor, BTreeSet<PathBuf>>,
+        events: Vec<InotifyEvent>,
+    ) -> ChangeHint {
+        let mut paths = Vec::new();
+        for event in events {
+            // Events were dropped, or a watched directory went away
+            if event
+                .mask
+                .intersects(AddWatchFlags::IN_Q_OVERFLOW | AddWatchFlags::IN_IGNORED)
+            {
+                log::debug!("lost track of changes, checking all secrets");
+                return ChangeHint::Any;
+            }
+            match (dirs.get(&event.wd), event.name) {
+                (Some(dirs), Some(name)) => paths.extend(dirs.iter().map(|d| d.join(&name))),
+                _ => return ChangeHint::Any,
+            }
+        }
+
+        ChangeHint::Paths(paths)
+    }
+
+    #[async_trait]
+    impl ChangeNotifier for InotifyNotifier {
+        async fn wait(&mut self) -> Result<ChangeHint, ChangeNotifierError> {
+            tokio::select! {
+                hint = changes(&self.inotify, &self.dirs) => hint,
+                hint = self.poll.wait() => hint,
+            }
+        }
+
+        fn checked(&mut self, succeeded: bool) {
+            self.poll.checked(succeeded);
+        }
+    }
+}
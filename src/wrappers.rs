@@ -1,11 +1,15 @@
+use std::fmt::Display;
 use std::str::FromStr;
 
+#[cfg(unix)]
 use nix::unistd::{Group, User};
 use serde_with::DeserializeFromStr;
 
+#[cfg(unix)]
 #[derive(DeserializeFromStr, Clone, Debug, PartialEq, Eq)]
 pub struct UserWrapper(User);
 
+#[cfg(unix)]
 impl FromStr for UserWrapper {
     type Err = &'static str;
 
@@ -24,18 +28,28 @@ impl FromStr for UserWrapper {
     }
 }
 
+#[cfg(unix)]
 impl From<UserWrapper> for User {
     fn from(value: UserWrapper) -> Self {
         value.0
     }
 }
 
+#[cfg(unix)]
 impl AsRef<User> for UserWrapper {
     fn as_ref(&self) -> &User {
         &self.0
     }
 }
 
+#[cfg(unix)]
+impl Display for UserWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.name)
+    }
+}
+
+#[cfg(unix)]
 impl FromStr for GroupWrapper {
     type Err = &'static str;
 
@@ -54,17 +68,69 @@ impl FromStr for GroupWrapper {
     }
 }
 
+#[cfg(unix)]
 #[derive(DeserializeFromStr, Clone, Debug, PartialEq, Eq)]
 pub struct GroupWrapper(Group);
 
+#[cfg(unix)]
 impl From<GroupWrapper> for Group {
     fn from(value: GroupWrapper) -> Self {
         value.0
     }
 }
 
+#[cfg(unix)]
 impl AsRef<Group> for GroupWrapper {
     fn as_ref(&self) -> &Group {
         &self.0
     }
 }
+
+#[cfg(unix)]
+impl Display for GroupWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.name)
+    }
+}
+
+/// Users and groups can't be resolved on Windows, where ownership isn't
+/// applied to exposed files. We only keep the name so configs still parse.
+#[cfg(windows)]
+#[derive(DeserializeFromStr, Clone, Debug, PartialEq, Eq)]
+pub struct UserWrapper(String);
+
+#[cfg(windows)]
+impl FromStr for UserWrapper {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(UserWrapper(s.to_string()))
+    }
+}
+
+#[cfg(windows)]
+impl Display for UserWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(windows)]
+#[derive(DeserializeFromStr, Clone, Debug, PartialEq, Eq)]
+pub struct GroupWrapper(String);
+
+#[cfg(windows)]
+impl FromStr for GroupWrapper {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(GroupWrapper(s.to_string()))
+    }
+}
+
+#[cfg(windows)]
+impl Display for GroupWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
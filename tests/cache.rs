@@ -0,0 +1,47 @@
+use credible::test_env::TestEnv;
+use credible::{
+    CacheMode,
+    CachedSecretStorage,
+    SecretError,
+    SecretStorage,
+    DEFAULT_LARGE_SECRET_THRESHOLD,
+};
+use tokio::io::AsyncReadExt;
+
+#[tokio::test]
+async fn deleted_secrets_arent_served_from_cache() {
+    let env = TestEnv::new().unwrap();
+    let secret = env.add_secret("sample", b"hello cache").await.unwrap();
+    let cache = CachedSecretStorage::new(
+        env.storage(),
+        env.root().join("cache"),
+        CacheMode::Fallback,
+        DEFAULT_LARGE_SECRET_THRESHOLD,
+    );
+
+    // Fetching it once fills the cache
+    let mut cached = Vec::new();
+    cache
+        .read(&secret.path)
+        .await
+        .unwrap()
+        .read_to_end(&mut cached)
+        .await
+        .unwrap();
+    assert_eq!(Some(cached), env.storage().get(&secret.path));
+
+    env.storage().delete(&secret.path).await.unwrap();
+    match cache.read(&secret.path).await {
+        Err(e) => assert!(e.is_not_found(), "unexpected error: {e}"),
+        Ok(_) => panic!("deleted secret was served from cache"),
+    }
+
+    // Nor is it kept around for offline reads
+    let offline = CachedSecretStorage::new(
+        env.storage(),
+        env.root().join("cache"),
+        CacheMode::Offline,
+        DEFAULT_LARGE_SECRET_THRESHOLD,
+    );
+    assert!(offline.read(&secret.path).await.is_err());
+}
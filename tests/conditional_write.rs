@@ -0,0 +1,92 @@
+use std::io::Cursor;
+
+use credible::test_env::TestEnv;
+use credible::testing::{
+    encrypt_bytes,
+    read_secret_if_changed,
+    write_secret,
+    write_secret_if_unchanged,
+    KeepPrevious,
+    WriteSecretError,
+};
+use credible::{ConditionalRead, Secret, DEFAULT_LARGE_SECRET_THRESHOLD};
+
+const KEEP: KeepPrevious = KeepPrevious {
+    threshold: DEFAULT_LARGE_SECRET_THRESHOLD,
+    large: false,
+};
+
+async fn encrypt(secret: &Secret, plaintext: &[u8]) -> Vec<u8> {
+    encrypt_bytes(Cursor::new(plaintext.to_vec()), &secret.encryption_keys)
+        .await
+        .unwrap()
+}
+
+async fn version(env: &TestEnv, secret: &Secret) -> String {
+    let read = read_secret_if_changed(env.storage(), secret, None, DEFAULT_LARGE_SECRET_THRESHOLD);
+    match read.await.unwrap() {
+        ConditionalRead::Changed {
+            version: Some(version),
+            ..
+        } => version,
+        _ => panic!("memory storage should have versions"),
+    }
+}
+
+#[tokio::test]
+async fn changes_since_reading_arent_overwritten() {
+    let env = TestEnv::new().unwrap();
+    let secret = env.secret("log");
+    let keys = &secret.encryption_keys;
+    let first = encrypt(&secret, b"one").await;
+    write_secret(env.storage(), &secret, first.into(), keys, None, KEEP)
+        .await
+        .unwrap();
+    let read_version = version(&env, &secret).await;
+
+    // Someone else appends in between reading and writing
+    let theirs = encrypt(&secret, b"one\ntwo").await;
+    write_secret(
+        env.storage(),
+        &secret,
+        theirs.clone().into(),
+        keys,
+        None,
+        KEEP,
+    )
+    .await
+    .unwrap();
+
+    let ours = encrypt(&secret, b"one\nthree").await;
+    let res = write_secret_if_unchanged(
+        env.storage(),
+        &secret,
+        ours.clone().into(),
+        keys,
+        None,
+        KEEP,
+        Some(&read_version),
+    )
+    .await;
+    match res {
+        Err(WriteSecretError::Changed(name)) => assert_eq!(name, "log"),
+        Err(e) => panic!("unexpected error: {e}"),
+        Ok(()) => panic!("a stale version overwrote the other change"),
+    }
+    assert_eq!(env.storage().get(&secret.path), Some(theirs));
+
+    // Retrying against the current version goes through
+    let current = version(&env, &secret).await;
+    write_secret_if_unchanged(
+        env.storage(),
+        &secret,
+        ours.clone().into(),
+        keys,
+        None,
+        KEEP,
+        Some(&current),
+    )
+    .await
+    .unwrap();
+    assert_eq!(env.storage().get(&secret.path), Some(ours));
+}
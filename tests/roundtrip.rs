@@ -0,0 +1,87 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use age::secrecy::ExposeSecret;
+use age::x25519;
+use credible::testing::{decrypt_bytes, encrypt_bytes, parse_config};
+use credible::ExposureSpec;
+use proptest::prelude::*;
+use tokio::io::AsyncReadExt;
+
+fn roundtrip(data: Vec<u8>, recipients: usize) -> Vec<u8> {
+    let identities = (0..recipients)
+        .map(|_| x25519::Identity::generate())
+        .collect::<Vec<_>>();
+    let public_keys = identities
+        .iter()
+        .map(|i| i.to_public().to_string())
+        .collect::<Vec<_>>();
+    // Any one of the recipients should be able to decrypt
+    let identity: Box<dyn age::Identity> = Box::new(
+        identities
+            .last()
+            .unwrap()
+            .to_string()
+            .expose_secret()
+            .parse::<x25519::Identity>()
+            .unwrap(),
+    );
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let encrypted = encrypt_bytes(Cursor::new(data), &public_keys)
+            .await
+            .unwrap();
+        let mut decrypted = Vec::new();
+        decrypt_bytes(Cursor::new(encrypted), &[identity])
+            .await
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .await
+            .unwrap();
+        decrypted
+    })
+}
+
+proptest! {
+    #[test]
+    fn encryption_roundtrips(data in proptest::collection::vec(any::<u8>(), 0..65536), recipients in 1usize..4) {
+        prop_assert_eq!(roundtrip(data.clone(), recipients), data);
+    }
+
+    #[test]
+    fn file_specs_parse(name in "[^:]*", path in "[^:]*") {
+        let spec = format!("file:{name}:{path}").parse::<ExposureSpec>().unwrap();
+        match spec {
+            ExposureSpec::File(args) => {
+                prop_assert_eq!(args.secret_name, name);
+                prop_assert_eq!(args.vanity_path, Some(PathBuf::from(path)));
+            }
+            other => prop_assert!(false, "parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn env_specs_parse(name in "[^:]*", env in "[^:]*") {
+        let spec = format!("env:{name}:{env}").parse::<ExposureSpec>().unwrap();
+        match spec {
+            ExposureSpec::Env(args) => {
+                prop_assert_eq!(args.secret_name, name);
+                prop_assert_eq!(args.name, env);
+            }
+            other => prop_assert!(false, "parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn specs_never_panic(s in ".*") {
+        let _ = s.parse::<ExposureSpec>();
+    }
+
+    #[test]
+    fn configs_never_panic(data in proptest::collection::vec(any::<u8>(), 0..4096)) {
+        let _ = parse_config(&data);
+    }
+}
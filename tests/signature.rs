@@ -0,0 +1,69 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use credible::test_env::TestEnv;
+use credible::testing::{
+    encrypt_bytes,
+    read_secret,
+    write_secret,
+    KeepPrevious,
+    ReadSecretError,
+    Spooled,
+};
+use credible::{Secret, DEFAULT_LARGE_SECRET_THRESHOLD};
+use ssh_key::private::{Ed25519Keypair, PrivateKey};
+use ssh_key::LineEnding;
+
+const KEEP: KeepPrevious = KeepPrevious {
+    threshold: DEFAULT_LARGE_SECRET_THRESHOLD,
+    large: false,
+};
+
+/// Writes an unencrypted signing key, returning its public half.
+fn signing_key(path: &Path) -> String {
+    let key = PrivateKey::from(Ed25519Keypair::from_seed(&[7; 32]));
+    key.write_openssh_file(path, LineEnding::LF).unwrap();
+    key.public_key().to_openssh().unwrap()
+}
+
+async fn upload(env: &TestEnv, secret: &Secret, plaintext: &[u8], key: &Path) {
+    let ciphertext = encrypt_bytes(Cursor::new(plaintext.to_vec()), &secret.encryption_keys)
+        .await
+        .unwrap();
+    write_secret(
+        env.storage(),
+        secret,
+        Spooled::from(ciphertext),
+        &secret.encryption_keys,
+        Some(key),
+        KEEP,
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn signatures_from_other_secrets_are_rejected() {
+    let env = TestEnv::new().unwrap();
+    let key = env.root().join("id_ed25519");
+    let public_key = signing_key(&key);
+    let [mut a, mut b] = [env.secret("a"), env.secret("b")];
+    a.signing_keys = vec![public_key.clone()];
+    b.signing_keys = vec![public_key];
+    upload(&env, &a, b"hello a", &key).await;
+    upload(&env, &b, b"hello b", &key).await;
+
+    let read = read_secret(env.storage(), &b, DEFAULT_LARGE_SECRET_THRESHOLD).await;
+    assert!(read.is_ok(), "b's own signature should verify");
+
+    // A compromised store serves a's ciphertext and signature as b
+    let storage = env.storage();
+    let a_sig = storage.get(Path::new("a.age.sig")).unwrap();
+    storage.insert(b.path.clone(), storage.get(&a.path).unwrap());
+    storage.insert("b.age.sig".into(), a_sig);
+    match read_secret(storage, &b, DEFAULT_LARGE_SECRET_THRESHOLD).await {
+        Err(ReadSecretError::VerifyingSignature(name, _)) => assert_eq!(name, "b"),
+        Err(e) => panic!("unexpected error: {e}"),
+        Ok(_) => panic!("a's signature was accepted for b"),
+    }
+}
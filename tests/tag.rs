@@ -0,0 +1,111 @@
+#![cfg(unix)]
+
+use std::path::Path;
+
+use credible::hooks::NoHooks;
+use credible::test_env::TestEnv;
+use credible::testing::{
+    expose_files,
+    FileExposeArgs,
+    LinkMode,
+    VanityPathRecord,
+    GENERATION_XATTR,
+    SECRET_XATTR,
+};
+use credible::{read_exposure_tag, ExposureTag, RunRecord, DEFAULT_LARGE_SECRET_THRESHOLD};
+
+/// Whether files in the given directory can have user xattrs (e.g. not on
+/// ramfs, or older tmpfs).
+fn xattrs_supported(dir: &Path) -> bool {
+    let probe = dir.join(".xattr-probe");
+    std::fs::write(&probe, b"").unwrap();
+    let supported = xattr::set(&probe, "user.credible.probe", b"1").is_ok();
+    std::fs::remove_file(&probe).unwrap();
+    supported
+}
+
+fn copied_to(vanity: &Path) -> FileExposeArgs {
+    FileExposeArgs {
+        secret_name: "token".to_string(),
+        vanity_path: Some(vanity.to_owned()),
+        mode: None,
+        owner: None,
+        group: None,
+        link_mode: LinkMode::Copy,
+        acl: Vec::new(),
+        optional: false,
+        after: Vec::new(),
+        force_adopt: false,
+    }
+}
+
+#[tokio::test]
+async fn exposed_files_are_tagged() {
+    let env = TestEnv::new().unwrap();
+    if !xattrs_supported(env.root()) {
+        eprintln!("skipping: user xattrs aren't supported here");
+        return;
+    }
+    let secret = env.add_secret("token", b"hunter2").await.unwrap();
+    let secret_dir = env.root().join(".credible-gen");
+    std::fs::create_dir(&secret_dir).unwrap();
+    let vanity = env.root().join("token.txt");
+    let specs = vec![copied_to(&vanity)];
+
+    expose_files(
+        &secret_dir,
+        env.storage(),
+        &[(&secret, &specs)],
+        &env.identities(),
+        &NoHooks,
+        DEFAULT_LARGE_SECRET_THRESHOLD,
+    )
+    .await
+    .unwrap();
+
+    let expected = ExposureTag {
+        secret: "token".to_string(),
+        generation: ".credible-gen".to_string(),
+    };
+    let exposed = read_exposure_tag(&secret_dir.join("token")).unwrap();
+    assert_eq!(exposed, Some(expected.clone()));
+    let copied = read_exposure_tag(&vanity).unwrap();
+    assert_eq!(copied, Some(expected));
+}
+
+#[tokio::test]
+async fn clean_up_only_removes_copies_tagged_by_the_run() {
+    let env = TestEnv::new().unwrap();
+    let tmpdir = env.root();
+    let secrets_dir = tmpdir.join(".credible-gen");
+    std::fs::create_dir(&secrets_dir).unwrap();
+    let untagged = tmpdir.join("untagged.txt");
+    std::fs::write(&untagged, b"the user's own file").unwrap();
+    let tagged = tmpdir.join("tagged.txt");
+    std::fs::write(&tagged, b"hunter2").unwrap();
+    let supported = xattrs_supported(tmpdir);
+    if supported {
+        xattr::set(&tagged, SECRET_XATTR, b"token").unwrap();
+        xattr::set(&tagged, GENERATION_XATTR, b".credible-gen").unwrap();
+    }
+
+    let record = RunRecord {
+        pid: std::process::id(),
+        started: 0,
+        secrets_dir: secrets_dir.clone(),
+        vanity_paths: [&untagged, &tagged]
+            .into_iter()
+            .map(|path| VanityPathRecord {
+                path: path.clone(),
+                link_mode: LinkMode::Copy,
+            })
+            .collect(),
+    };
+    record.clean_up(tmpdir, &NoHooks).await.unwrap();
+
+    assert!(untagged.exists(), "an untagged copy was removed");
+    assert!(!secrets_dir.exists());
+    // Where tags can't be written, they can't be read back either, so the
+    // copy is left alone rather than guessed at
+    assert_eq!(tagged.exists(), !supported);
+}
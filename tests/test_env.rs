@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use credible::hooks::NoHooks;
+use credible::system::mount;
+use credible::test_env::TestEnv;
+use credible::{ExposureSpec, Exposures, DEFAULT_LARGE_SECRET_THRESHOLD};
+
+#[tokio::test]
+async fn mounts_generations_hermetically() {
+    let env = TestEnv::new().unwrap();
+    let secret = env.add_secret("sample", b"hello test").await.unwrap();
+    let secrets = HashMap::from([(secret.name.clone(), secret)]);
+
+    let mut exposures = Exposures::default();
+    if let ExposureSpec::File(args) = ExposureSpec::file_from_str("sample".into(), "sample.txt") {
+        exposures.add_files([*args]);
+    }
+
+    let mount_point = env.mount_point();
+    let secret_dir = env.secret_dir();
+    let identities = env.identities();
+    for generation in ["1000", "1001"] {
+        mount(
+            &mount_point,
+            &secret_dir,
+            &secrets,
+            &exposures,
+            &Default::default(),
+            &identities,
+            env.storage(),
+            DEFAULT_LARGE_SECRET_THRESHOLD,
+            &NoHooks,
+            env.platform(),
+        )
+        .await
+        .unwrap();
+
+        // Generations are named after the fake clock, and older ones are
+        // cleaned up
+        assert_eq!(
+            std::fs::read_link(&secret_dir).unwrap(),
+            mount_point.join(generation)
+        );
+        assert_eq!(
+            env.platform().mounted(),
+            [mount_point.join(generation)].into()
+        );
+        assert_eq!(std::fs::read_dir(&mount_point).unwrap().count(), 1);
+    }
+
+    let exposed = std::fs::read_to_string(secret_dir.join("sample.txt")).unwrap();
+    assert_eq!(exposed, "hello test");
+}
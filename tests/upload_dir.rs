@@ -0,0 +1,58 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+use age::x25519;
+
+fn credible(config: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_credible"))
+        .arg("--config-file")
+        .arg(config)
+        .arg("--no-local-config")
+        .args(args)
+        .env("CREDIBLE_ALLOW_ROOT", "true")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "credible {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn generated_config_verifies() {
+    let root = tempfile::tempdir().unwrap();
+    let user_key = x25519::Identity::generate().to_public().to_string();
+    let break_glass_key = x25519::Identity::generate().to_public().to_string();
+    let storage = format!(
+        "storage:\n  type: File\n  root: {}\n",
+        root.path().join("store").display()
+    );
+    let base = format!("{storage}break_glass:\n  encryption_keys: [{break_glass_key}]\n");
+    let config = root.path().join("credible.yaml");
+    std::fs::write(&config, format!("{base}secrets: []\n")).unwrap();
+    let source = root.path().join("source");
+    std::fs::create_dir(&source).unwrap();
+    std::fs::write(source.join("sample"), "hello upload-dir").unwrap();
+
+    let uploaded = credible(
+        &config,
+        &[
+            "secret",
+            "upload-dir",
+            "--generate-config",
+            "--encryption-key",
+            &user_key,
+            source.to_str().unwrap(),
+        ],
+    );
+
+    // Pasting the generated config in describes what was stored, even
+    // without break-glass keys being added to it on load
+    let generated = String::from_utf8(uploaded.stdout).unwrap();
+    std::fs::write(&config, format!("{base}{generated}")).unwrap();
+    credible(&config, &["secret", "verify"]);
+    std::fs::write(&config, format!("{storage}{generated}")).unwrap();
+    credible(&config, &["secret", "verify"]);
+}